@@ -29,3 +29,18 @@ pub mod bits {
     }
   }
 }
+
+pub mod hash {
+  // CRC-32 (IEEE), as used by NesCartDB and most ROM databases.
+  pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for &b in bytes {
+      crc ^= b as u32;
+      for _ in 0..8 {
+        let mask = (crc & 1).wrapping_neg();
+        crc = (crc >> 1) ^ (0xedb88320 & mask);
+      }
+    }
+    !crc
+  }
+}
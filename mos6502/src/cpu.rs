@@ -4,7 +4,9 @@ use crate::instructions::Opcode;
 use crate::instructions::Operands;
 use crate::memory::Bus;
 
+use alloc::rc::Rc;
 use bitflags::bitflags;
+use core::cell::Cell;
 
 pub const AC: usize = 0;
 pub const X: usize = 1;
@@ -26,12 +28,119 @@ bitflags! {
   }
 }
 
+// Which chip is being emulated, they run the same code differently
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Variant {
+  // The original NMOS 6502, as in the Apple II and C64
+  #[default]
+  Nmos,
+  // The NES's Ricoh 2A03, an NMOS 6502 with decimal mode cut out. D can be set but does nothing.
+  Rp2a03,
+  // The CMOS 65C02. New instructions instead of illegal opcodes, JMP ($xxFF) fixed,
+  // valid N and Z in decimal mode, and D cleared on interrupts.
+  Cmos65c02,
+  // Rockwell's 65C02, adds RMB, SMB, BBR and BBS
+  Rockwell65c02,
+  // WDC's 65C02, Rockwell's plus WAI and STP
+  Wdc65c02,
+}
+
+impl Variant {
+  pub fn has_decimal_mode(&self) -> bool {
+    *self != Variant::Rp2a03
+  }
+
+  pub fn is_cmos(&self) -> bool {
+    matches!(
+      self,
+      Variant::Cmos65c02 | Variant::Rockwell65c02 | Variant::Wdc65c02
+    )
+  }
+}
+
+// WAI and STP
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Halt {
+  // Until an interrupt
+  Wait,
+  // Until reset
+  Stop,
+}
+
+// How execute drives the bus
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Stepping {
+  // A whole instruction at once, cycles are looked up in the instruction table.
+  // Interrupts are polled once per instruction, not on its last cycle, so
+  // their exact timing needs Cycle.
+  #[default]
+  Instruction,
+  // One bus access per cycle with Bus::on_cycle before each, including the dummy
+  // reads and writes of every addressing mode. Cycles are the accesses made.
+  Cycle,
+}
+
+// The /NMI and /IRQ inputs, asserted and released by whatever is wired to them.
+// /IRQ is shared, each source holds its own bit of it.
+// https://www.nesdev.org/wiki/CPU_interrupts
+#[derive(Debug, Default)]
+pub struct InterruptLines {
+  nmi: Cell<bool>,
+  irq: Cell<u32>,
+}
+
+impl InterruptLines {
+  // The /IRQ source Cpu::irq asserts, released when the CPU takes it
+  pub const IRQ_DIRECT: u32 = 1 << 31;
+
+  pub fn set_nmi(&self, asserted: bool) {
+    self.nmi.set(asserted);
+  }
+
+  pub fn set_irq(&self, source: u32, asserted: bool) {
+    let sources = self.irq.get() & !source;
+    self
+      .irq
+      .set(if asserted { sources | source } else { sources });
+  }
+
+  pub fn nmi(&self) -> bool {
+    self.nmi.get()
+  }
+
+  pub fn irq(&self) -> bool {
+    self.irq.get() != 0
+  }
+}
+
+// What the CPU has seen of its interrupt lines
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptState {
+  // Last sampled levels
+  pub nmi_line: bool,
+  pub irq_line: bool,
+  // /NMI went low and it hasn't been taken yet
+  pub nmi_pending: bool,
+  // Take an interrupt before the next instruction
+  pub polled: bool,
+  // Instruction-stepped, the I flag the last instruction polled with
+  pub irq_masked: bool,
+  // Instruction-stepped, the last instruction was a taken branch that didn't poll again
+  pub delayed: bool,
+}
+
 pub struct Cpu<B> {
   pub pc: u16,
   pub flags: Flag,
   pub regs: [u8; 4],
   pub bus: B,
   pub extra_cycles: usize,
+  pub halt: Option<Halt>,
+  pub stepping: Stepping,
+  pub lines: Rc<InterruptLines>,
+  pub interrupt: InterruptState,
+  variant: Variant,
+  cycles: usize,
 }
 
 impl<B: Bus> Cpu<B> {
@@ -44,36 +153,71 @@ impl<B: Bus> Cpu<B> {
   const IRQ_VECTOR: u16 = 0xfffe;
 
   pub fn new(mem: B) -> Self {
+    Self::with_variant(mem, Variant::default())
+  }
+
+  pub fn with_variant(mem: B, variant: Variant) -> Self {
     Self {
       pc: 0,
       flags: Flag::empty(),
       regs: [0; 4],
       bus: mem,
       extra_cycles: 0,
+      halt: None,
+      stepping: Stepping::default(),
+      lines: Rc::default(),
+      interrupt: InterruptState::default(),
+      variant,
+      cycles: 0,
     }
   }
 
+  pub fn variant(&self) -> Variant {
+    self.variant
+  }
+
   pub fn fetch_next_instruction<'a>(&mut self) -> (&'a Instruction, Operands) {
     self.extra_cycles = 0;
-    let opbyte = self.bus.read8(self.pc);
-    let inst = Instruction::disassemble(opbyte);
-    let operands = (self.bus.read8(self.pc + 1), self.bus.read8(self.pc + 2));
+    let opbyte = self.read8(self.pc);
+    let inst = Instruction::disassemble(opbyte, self.variant);
+    let operands = match self.stepping {
+      Stepping::Instruction => (self.bus.read8(self.pc + 1), self.bus.read8(self.pc + 2)),
+      Stepping::Cycle => {
+        // The byte after the opcode is read even by implied instructions, only the
+        // 65C02's single cycle NOPs skip it
+        let low = if inst.size > 1 || inst.cycles > 1 {
+          self.read8(self.pc.wrapping_add(1))
+        } else {
+          0
+        };
+        let high = if inst.size == 3 {
+          self.read8(self.pc.wrapping_add(2))
+        } else {
+          0
+        };
+        (low, high)
+      }
+    };
     (inst, operands)
   }
 
   pub fn execute(&mut self, inst: &Instruction, operands: Operands) -> usize {
     let pc_before_exec = self.pc;
     let opcode = &inst.opcode;
+    let irq_masked = self.flags.contains(Flag::I);
 
     if opcode == &Opcode::JMP {
       let address = inst.resolve_operand_address(self, &operands);
-      if inst.mode == AddressMode::Ind {
+      if inst.mode == AddressMode::Ind || inst.mode == AddressMode::AbsIndX {
+        if self.variant.is_cmos() {
+          self.dummy_read(self.pc.wrapping_add(2));
+        }
         self.set_pc(address + 1);
       }
       self.set_pc(address);
     } else if opcode == &Opcode::STA {
       let address = inst.resolve_operand_address(self, &operands);
-      self.bus.write8(self.regs[AC], address);
+      self.write8(self.regs[AC], address);
     } else if opcode == &Opcode::LDA {
       let val = inst.resolve_operand_value(self, &operands);
       self.regs[AC] = val;
@@ -89,29 +233,36 @@ impl<B: Bus> Cpu<B> {
           // cycles on absX nops
           if inst.mode == AddressMode::AbsX {
             let _ = inst.resolve_operand_value(self, &operands);
+          } else if inst.mode == AddressMode::Nop {
+            // The rest read their operand, or something, for the remaining cycles
+            for _ in (inst.size.max(2) as usize)..inst.cycles {
+              self.dummy_read(operands.0 as u16);
+            }
           }
         }
         Opcode::DEX => self.dec_reg(X),
         Opcode::DEY => self.dec_reg(Y),
         Opcode::INX => self.inc_reg(X),
         Opcode::INY => self.inc_reg(Y),
+        Opcode::DEC if inst.mode == AddressMode::Impl => self.dec_reg(AC),
+        Opcode::INC if inst.mode == AddressMode::Impl => self.inc_reg(AC),
         Opcode::DEC => {
           let (val, address) = inst.resolve_operand_value_and_address(self, &operands);
           let res = val.wrapping_sub(1);
           self.flags_set_neg_zero(res);
-          self.bus.write8(res, address);
+          self.write8(res, address);
         }
         Opcode::INC => {
           let (val, address) = inst.resolve_operand_value_and_address(self, &operands);
           let res = val.wrapping_add(1);
           self.flags_set_neg_zero(res);
-          self.bus.write8(res, address);
+          self.write8(res, address);
         }
         Opcode::DCP => {
           // DEC oper
           let (val, address) = inst.resolve_operand_value_and_address(self, &operands);
           let res = val.wrapping_sub(1);
-          self.bus.write8(res, address);
+          self.write8(res, address);
 
           // CMP oper
           self.cmp(AC, res);
@@ -134,7 +285,7 @@ impl<B: Bus> Cpu<B> {
         Opcode::SAX => {
           let address = inst.resolve_operand_address(self, &operands);
           let res = self.regs[AC] & self.regs[X];
-          self.bus.write8(res, address);
+          self.write8(res, address);
         }
         Opcode::TAX => self.mv_with_neg_zero(AC, X),
         Opcode::TAY => self.mv_with_neg_zero(AC, Y),
@@ -152,61 +303,55 @@ impl<B: Bus> Cpu<B> {
         }
         Opcode::STX => {
           let address = inst.resolve_operand_address(self, &operands);
-          self.bus.write8(self.regs[X], address);
+          self.write8(self.regs[X], address);
         }
         Opcode::STY => {
           let address = inst.resolve_operand_address(self, &operands);
-          self.bus.write8(self.regs[Y], address);
+          self.write8(self.regs[Y], address);
         }
         Opcode::JSR => {
+          self.dummy_read(self.stack_address());
           self.push_word(self.pc + 2);
           let address = inst.resolve_operand_address(self, &operands);
           self.set_pc(address);
         }
         Opcode::RTS => {
+          self.dummy_read(self.stack_address());
           let ret = self.pop_word();
+          self.dummy_read(ret);
           self.set_pc(ret + 1); // pull PC, PC+1 -> PC
         }
-        Opcode::BRK => {
-          self.push_word(self.pc + 2);
-
-          let mut res = self.flags.bits();
-          res |= Flag::BUNUSEDMASK.bits(); // break and 5 should always be set to 1 on stack
-          self.push(res);
-          self.flags |= Flag::I;
-
-          // Jump to IRQ vector, TODO cycles
-          self.set_pc(self.read16(Self::IRQ_VECTOR));
-        }
+        Opcode::BRK => self.interrupt_sequence(self.pc + 2, true),
         Opcode::RTI => {
+          self.dummy_read(self.stack_address());
           let flags = self.pop();
           self.set_flags_ignore_5_4(flags);
           let ret = self.pop_word();
           self.set_pc(ret);
         }
         Opcode::BNE => {
-          self.branch_if(operands.0, !self.flags.contains(Flag::Z));
+          self.branch_if(inst.size, operands.0, !self.flags.contains(Flag::Z));
         }
         Opcode::BEQ => {
-          self.branch_if(operands.0, self.flags.contains(Flag::Z));
+          self.branch_if(inst.size, operands.0, self.flags.contains(Flag::Z));
         }
         Opcode::BPL => {
-          self.branch_if(operands.0, !self.flags.contains(Flag::N));
+          self.branch_if(inst.size, operands.0, !self.flags.contains(Flag::N));
         }
         Opcode::BMI => {
-          self.branch_if(operands.0, self.flags.contains(Flag::N));
+          self.branch_if(inst.size, operands.0, self.flags.contains(Flag::N));
         }
         Opcode::BCC => {
-          self.branch_if(operands.0, !self.flags.contains(Flag::C));
+          self.branch_if(inst.size, operands.0, !self.flags.contains(Flag::C));
         }
         Opcode::BCS => {
-          self.branch_if(operands.0, self.flags.contains(Flag::C));
+          self.branch_if(inst.size, operands.0, self.flags.contains(Flag::C));
         }
         Opcode::BVC => {
-          self.branch_if(operands.0, !self.flags.contains(Flag::V));
+          self.branch_if(inst.size, operands.0, !self.flags.contains(Flag::V));
         }
         Opcode::BVS => {
-          self.branch_if(operands.0, self.flags.contains(Flag::V));
+          self.branch_if(inst.size, operands.0, self.flags.contains(Flag::V));
         }
         Opcode::CPY => {
           let val = inst.resolve_operand_value(self, &operands);
@@ -220,7 +365,7 @@ impl<B: Bus> Cpu<B> {
           // LSR oper
           let (val, address) = inst.resolve_operand_value_and_address(self, &operands);
           let res = self.shift_right(val);
-          self.bus.write8(res, address);
+          self.write8(res, address);
 
           // EOR oper
           let res = self.regs[AC] ^ res;
@@ -236,7 +381,7 @@ impl<B: Bus> Cpu<B> {
             _ => {
               let (val, address) = inst.resolve_operand_value_and_address(self, &operands);
               let res = self.shift_right(val);
-              self.bus.write8(res, address);
+              self.write8(res, address);
             }
           };
         }
@@ -244,7 +389,7 @@ impl<B: Bus> Cpu<B> {
           // ASL oper
           let (val, address) = inst.resolve_operand_value_and_address(self, &operands);
           let res = self.shift_left(val);
-          self.bus.write8(res, address);
+          self.write8(res, address);
 
           // ORA oper
           let res = self.regs[AC] | res;
@@ -255,7 +400,7 @@ impl<B: Bus> Cpu<B> {
           // ROL oper
           let (val, address) = inst.resolve_operand_value_and_address(self, &operands);
           let res = self.rotate_left(val);
-          self.bus.write8(res, address);
+          self.write8(res, address);
 
           // AND oper
           let res = self.regs[AC] & res;
@@ -266,7 +411,7 @@ impl<B: Bus> Cpu<B> {
           // ROR oper
           let (val, address) = inst.resolve_operand_value_and_address(self, &operands);
           let res = self.rotate_right(val);
-          self.bus.write8(res, address);
+          self.write8(res, address);
 
           // ADC oper
           self.regs[AC] = self.add_with_carry(self.regs[AC], res);
@@ -280,7 +425,7 @@ impl<B: Bus> Cpu<B> {
             _ => {
               let (val, address) = inst.resolve_operand_value_and_address(self, &operands);
               let res = self.shift_left(val);
-              self.bus.write8(res, address);
+              self.write8(res, address);
             }
           };
         }
@@ -293,7 +438,7 @@ impl<B: Bus> Cpu<B> {
             _ => {
               let (val, address) = inst.resolve_operand_value_and_address(self, &operands);
               let res = self.rotate_left(val);
-              self.bus.write8(res, address);
+              self.write8(res, address);
             }
           };
         }
@@ -306,7 +451,7 @@ impl<B: Bus> Cpu<B> {
             _ => {
               let (val, address) = inst.resolve_operand_value_and_address(self, &operands);
               let res = self.rotate_right(val);
-              self.bus.write8(res, address);
+              self.write8(res, address);
             }
           };
         }
@@ -322,7 +467,7 @@ impl<B: Bus> Cpu<B> {
           // INC oper
           let (val, address) = inst.resolve_operand_value_and_address(self, &operands);
           let res = val.wrapping_add(1);
-          self.bus.write8(res, address);
+          self.write8(res, address);
 
           // SBC oper
           self.regs[AC] = self.sub_with_borrow(self.regs[AC], res);
@@ -350,6 +495,7 @@ impl<B: Bus> Cpu<B> {
           self.push(self.regs[AC]);
         }
         Opcode::PLA => {
+          self.dummy_read(self.stack_address());
           let res = self.pop();
           self.regs[AC] = res;
           self.flags_set_neg_zero(res);
@@ -357,16 +503,19 @@ impl<B: Bus> Cpu<B> {
         Opcode::PHX => self.push(self.regs[X]),
         Opcode::PHY => self.push(self.regs[Y]),
         Opcode::PLX => {
+          self.dummy_read(self.stack_address());
           let res = self.pop();
           self.regs[X] = res;
           self.flags_set_neg_zero(res);
         }
         Opcode::PLY => {
+          self.dummy_read(self.stack_address());
           let res = self.pop();
           self.regs[Y] = res;
           self.flags_set_neg_zero(res);
         }
         Opcode::PLP => {
+          self.dummy_read(self.stack_address());
           let res = self.pop();
           self.set_flags_ignore_5_4(res);
         }
@@ -379,8 +528,11 @@ impl<B: Bus> Cpu<B> {
           let val = inst.resolve_operand_value(self, &operands);
           let res = self.regs[AC] & val;
           self.flags.set(Flag::Z, res == 0);
-          self.flags.set(Flag::N, (val & (1 << 7)) != 0);
-          self.flags.set(Flag::V, (val & (1 << 6)) != 0);
+          // 65C02 BIT # only sets Z
+          if inst.mode != AddressMode::Imm {
+            self.flags.set(Flag::N, (val & (1 << 7)) != 0);
+            self.flags.set(Flag::V, (val & (1 << 6)) != 0);
+          }
         }
         Opcode::ANC | Opcode::ANC2 => {
           let val = inst.resolve_operand_value(self, &operands);
@@ -388,6 +540,47 @@ impl<B: Bus> Cpu<B> {
           self.flags.set(Flag::C, common::bits::is_signed(res));
           self.flags_set_neg_zero(res);
         }
+        Opcode::BRA => self.branch_if(inst.size, operands.0, true),
+        Opcode::STZ => {
+          let address = inst.resolve_operand_address(self, &operands);
+          self.write8(0, address);
+        }
+        Opcode::TSB => {
+          let (val, address) = inst.resolve_operand_value_and_address(self, &operands);
+          self.flags.set(Flag::Z, self.regs[AC] & val == 0);
+          self.write8(val | self.regs[AC], address);
+        }
+        Opcode::TRB => {
+          let (val, address) = inst.resolve_operand_value_and_address(self, &operands);
+          self.flags.set(Flag::Z, self.regs[AC] & val == 0);
+          self.write8(val & !self.regs[AC], address);
+        }
+        Opcode::RMB(bit) => {
+          let (val, address) = inst.resolve_operand_value_and_address(self, &operands);
+          self.write8(val & !(1 << bit), address);
+        }
+        Opcode::SMB(bit) => {
+          let (val, address) = inst.resolve_operand_value_and_address(self, &operands);
+          self.write8(val | (1 << bit), address);
+        }
+        Opcode::BBR(bit) => {
+          let val = inst.resolve_operand_value(self, &operands);
+          self.dummy_read(operands.0 as u16);
+          self.branch_if(inst.size, operands.1, val & (1 << bit) == 0);
+        }
+        Opcode::BBS(bit) => {
+          let val = inst.resolve_operand_value(self, &operands);
+          self.dummy_read(operands.0 as u16);
+          self.branch_if(inst.size, operands.1, val & (1 << bit) != 0);
+        }
+        Opcode::WAI => {
+          self.dummy_read(self.pc);
+          self.halt = Some(Halt::Wait);
+        }
+        Opcode::STP => {
+          self.dummy_read(self.pc);
+          self.halt = Some(Halt::Stop);
+        }
         Opcode::ALR => {
           let val = inst.resolve_operand_value(self, &operands);
           let res = self.regs[AC] & val;
@@ -405,7 +598,111 @@ impl<B: Bus> Cpu<B> {
       self.inc_pc(inst.size);
     }
 
-    inst.cycles + self.extra_cycles
+    // CLI, SEI and PLP change I after polling, so the next instruction runs first
+    self.interrupt.irq_masked = match opcode {
+      Opcode::CLI | Opcode::SEI | Opcode::PLP => irq_masked,
+      _ => self.flags.contains(Flag::I),
+    };
+
+    match self.stepping {
+      Stepping::Instruction => inst.cycles + self.extra_cycles,
+      Stepping::Cycle => core::mem::take(&mut self.cycles),
+    }
+  }
+
+  pub(crate) fn read8(&mut self, address: u16) -> u8 {
+    self.cycle();
+    self.bus.read8(address)
+  }
+
+  pub(crate) fn write8(&mut self, val: u8, address: u16) {
+    self.cycle();
+    self.bus.write8(val, address);
+  }
+
+  // Accesses the chip makes and throws away. Instruction-stepped, they're only
+  // counted in the instruction table.
+  pub(crate) fn dummy_read(&mut self, address: u16) {
+    if self.stepping == Stepping::Cycle {
+      self.read8(address);
+    }
+  }
+
+  pub(crate) fn dummy_write(&mut self, val: u8, address: u16) {
+    if self.stepping == Stepping::Cycle {
+      self.write8(val, address);
+    }
+  }
+
+  fn cycle(&mut self) {
+    if self.stepping == Stepping::Cycle {
+      // An instruction is followed by an interrupt if it was seen by the end of
+      // its second to last cycle
+      self.interrupt.polled = self.interrupt_signalled(self.flags.contains(Flag::I));
+      self.bus.on_cycle();
+      self.sample_interrupt_lines();
+      self.cycles += 1;
+    }
+  }
+
+  // A cycle halted by WAI or STP. WAI wakes on any interrupt, even a masked IRQ.
+  pub fn idle(&mut self) {
+    match self.stepping {
+      Stepping::Instruction => self.sample_interrupt_lines(),
+      Stepping::Cycle => {
+        self.cycle();
+        self.cycles = 0;
+      }
+    }
+    if self.halt == Some(Halt::Wait) && (self.interrupt.nmi_pending || self.interrupt.irq_line) {
+      self.halt = None;
+    }
+  }
+
+  // Takes the interrupt polled during the last instruction, if any, and returns
+  // the cycles it took. Call before fetching the next instruction.
+  pub fn poll_interrupts(&mut self) -> usize {
+    if self.stepping == Stepping::Instruction {
+      if !core::mem::take(&mut self.interrupt.delayed) {
+        self.sample_interrupt_lines();
+      }
+      self.interrupt.polled = self.interrupt_signalled(self.interrupt.irq_masked);
+    }
+    if !self.interrupt.polled {
+      return 0;
+    }
+
+    // The byte at PC is read twice and thrown away, instead of an opcode
+    self.dummy_read(self.pc);
+    self.dummy_read(self.pc);
+    self.interrupt_sequence(self.pc, false);
+
+    match self.stepping {
+      Stepping::Instruction => 7,
+      Stepping::Cycle => core::mem::take(&mut self.cycles),
+    }
+  }
+
+  fn sample_interrupt_lines(&mut self) {
+    let nmi = self.lines.nmi();
+    self.interrupt.nmi_pending |= nmi && !self.interrupt.nmi_line;
+    self.interrupt.nmi_line = nmi;
+    self.interrupt.irq_line = self.lines.irq();
+  }
+
+  fn interrupt_signalled(&self, irq_masked: bool) -> bool {
+    self.interrupt.nmi_pending || (self.interrupt.irq_line && !irq_masked)
+  }
+
+  // An /NMI edge, taken before the next instruction
+  pub fn nmi(&mut self) {
+    self.interrupt.nmi_pending = true;
+    self.interrupt.polled = true;
+  }
+
+  // Holds /IRQ until it's taken, which waits for I to clear
+  pub fn irq(&mut self) {
+    self.lines.set_irq(InterruptLines::IRQ_DIRECT, true);
   }
 
   pub fn add_extra_cycles(&mut self, cycles: usize) {
@@ -429,34 +726,47 @@ impl<B: Bus> Cpu<B> {
 
     self.flags = Flag::empty();
     self.flags = Flag::I | Flag::UNUSED;
+    self.halt = None;
+    self.interrupt = InterruptState {
+      nmi_line: self.lines.nmi(),
+      irq_masked: true,
+      ..Default::default()
+    };
 
     let start = self.read16(Self::RESET_VECTOR);
     self.set_pc(start);
   }
 
-  pub fn nmi(&mut self) {
-    self.interrupt(Self::NMI_VECTOR);
-  }
-
-  pub fn irq(&mut self) {
-    if !self.flags.contains(Flag::I) {
-      self.interrupt(Self::IRQ_VECTOR);
-    }
-  }
-
-  fn interrupt(&mut self, vector: u16) {
-    // TODO: Cycles
-    self.push_word(self.pc);
+  // BRK, IRQ and NMI. Pushing the return address takes long enough for an NMI
+  // to hijack the vector, BRK's B flag is pushed all the same.
+  fn interrupt_sequence(&mut self, ret: u16, brk: bool) {
+    self.push_word(ret);
+    let vector = if core::mem::take(&mut self.interrupt.nmi_pending) {
+      Self::NMI_VECTOR
+    } else {
+      if !brk {
+        self.lines.set_irq(InterruptLines::IRQ_DIRECT, false);
+      }
+      Self::IRQ_VECTOR
+    };
 
-    let mut stackflags = self.flags.bits();
-    stackflags &= 0b11101111; // B should be off
-    stackflags |= 0b00100000; // unused should be on
+    let mut stackflags = self.flags.bits() | Flag::UNUSED.bits(); // unused should be on
+    stackflags = if brk {
+      stackflags | Flag::B.bits()
+    } else {
+      stackflags & !Flag::B.bits()
+    };
     self.push(stackflags);
     self.flags |= Flag::I;
+    if self.variant.is_cmos() {
+      self.flags.remove(Flag::D);
+    }
 
-    // TODO cycles
     let vector = self.read16(vector);
     self.set_pc(vector);
+
+    // Nothing is polled during the sequence, the handler's first instruction always runs
+    self.interrupt.polled = false;
   }
 
   fn push_word(&mut self, val: u16) {
@@ -551,11 +861,80 @@ impl<B: Bus> Cpu<B> {
     }
   }
 
+  fn decimal_mode(&self) -> bool {
+    self.flags.contains(Flag::D) && self.variant.has_decimal_mode()
+  }
+
+  // http://www.6502.org/tutorials/decimal_mode.html#A
   fn add_with_carry(&mut self, lhs: u8, rhs: u8) -> u8 {
-    // if self[Flag::D] == 1 {
-    // panic!("implement decimal mode");
-    // }
+    let carry = self.flags.contains(Flag::C) as u16;
+    let binary = self.add_binary(lhs, rhs);
+    if !self.decimal_mode() {
+      return binary;
+    }
+
+    // NMOS: Z is from the binary sum, N and V from the sum before the high
+    // nibble is adjusted, C after.
+    let mut low = (lhs & 0x0f) as u16 + (rhs & 0x0f) as u16 + carry;
+    if low >= 0x0a {
+      low = ((low + 0x06) & 0x0f) + 0x10;
+    }
+    let mut sum = (lhs & 0xf0) as u16 + (rhs & 0xf0) as u16 + low;
+    let signed = (lhs & 0xf0) as i8 as i16 + (rhs & 0xf0) as i8 as i16 + low as i16;
+    self.flags.set(Flag::N, sum & 0x80 != 0);
+    self.flags.set(Flag::V, !(-128..=127).contains(&signed));
+    if sum >= 0xa0 {
+      sum += 0x60;
+    }
+    self.flags.set(Flag::C, sum > 0xff);
+    self.decimal_flags_cmos(sum as u8)
+  }
+
+  fn sub_with_borrow(&mut self, lhs: u8, rhs: u8) -> u8 {
+    let borrow = !self.flags.contains(Flag::C) as i16;
+    // Do not understand how this works, but it works.
+    let binary = self.add_binary(lhs, rhs ^ 0xff);
+    if !self.decimal_mode() {
+      return binary;
+    }
+
+    // NMOS: All flags are from the binary subtraction
+    let low = (lhs & 0x0f) as i16 - (rhs & 0x0f) as i16 - borrow;
+    let res = if self.variant.is_cmos() {
+      let mut res = lhs as i16 - rhs as i16 - borrow;
+      if res < 0 {
+        res -= 0x60;
+      }
+      if low < 0 {
+        res -= 0x06;
+      }
+      res
+    } else {
+      let low = if low < 0 {
+        ((low - 0x06) & 0x0f) - 0x10
+      } else {
+        low
+      };
+      let mut res = (lhs & 0xf0) as i16 - (rhs & 0xf0) as i16 + low;
+      if res < 0 {
+        res -= 0x60;
+      }
+      res
+    };
+    self.decimal_flags_cmos(res as u8)
+  }
 
+  // The 65C02 spends a cycle fixing N and Z to match the decimal result
+  fn decimal_flags_cmos(&mut self, res: u8) -> u8 {
+    if self.variant.is_cmos() {
+      self.flags_set_neg_zero(res);
+      self.add_extra_cycles(1);
+      self.dummy_read(self.pc);
+    }
+    res
+  }
+
+  fn add_binary(&mut self, lhs: u8, rhs: u8) -> u8 {
     let (step1, carry1) = lhs.overflowing_add(self.flags.contains(Flag::C) as u8);
     let (res, carry2) = step1.overflowing_add(rhs);
     self
@@ -566,23 +945,18 @@ impl<B: Bus> Cpu<B> {
     res
   }
 
-  fn sub_with_borrow(&mut self, lhs: u8, rhs: u8) -> u8 {
-    // Do not understand how this works, but it works.
-    self.add_with_carry(lhs, rhs ^ 0xff)
-  }
-
   fn push(&mut self, val: u8) {
-    let sp = self.regs[SP] as usize;
-    let address = (Cpu::<B>::STACK_TOP + sp) as u16;
-    self.bus.write8(val, address);
+    self.write8(val, self.stack_address());
     self.regs[SP] = self.regs[SP].wrapping_sub(1);
   }
 
   fn pop(&mut self) -> u8 {
     self.regs[SP] = self.regs[SP].wrapping_add(1);
-    let sp = self.regs[SP] as usize;
-    let address = (Cpu::<B>::STACK_TOP + sp) as u16;
-    self.bus.read8(address)
+    self.read8(self.stack_address())
+  }
+
+  fn stack_address(&self) -> u16 {
+    (Cpu::<B>::STACK_TOP + self.regs[SP] as usize) as u16
   }
 
   fn set_flags_ignore_5_4(&mut self, val: u8) {
@@ -590,13 +964,13 @@ impl<B: Bus> Cpu<B> {
     self.flags = Flag::from_bits_truncate((val & !0b00110000) | original_b_and_unused);
   }
 
-  fn branch_if(&mut self, offset: u8, cond: bool) {
+  fn branch_if(&mut self, size: u8, offset: u8, cond: bool) {
     if offset == 0 {
       // (An offset of #0 corresponds to the immedately following address — or a rather odd and expensive NOP.)
       return;
     }
     if cond {
-      self.inc_pc(2);
+      self.inc_pc(size);
       let branch_target = self.calc_offset_pc(offset);
 
       // if hi byte changes, we crossed a page boundary and should add extra cycles
@@ -608,13 +982,24 @@ impl<B: Bus> Cpu<B> {
         self.add_extra_cycles(1);
       }
 
+      // Reads the next opcode, then again from the wrong page while fixing PCH.
+      // Without the fix the lines aren't polled again, delaying interrupts.
+      let polled = self.interrupt.polled;
+      self.dummy_read(self.pc);
+      if crossed_page {
+        self.dummy_read((self.pc & 0xff00) | (branch_target & 0x00ff));
+      } else {
+        self.interrupt.polled = polled;
+        self.interrupt.delayed = self.stepping == Stepping::Instruction;
+      }
+
       self.set_pc(branch_target);
     }
   }
 
-  fn read16(&self, address: u16) -> u16 {
-    let val_low = self.bus.read8(address) as u16;
-    let val_high = self.bus.read8(address + 1) as u16;
+  fn read16(&mut self, address: u16) -> u16 {
+    let val_low = self.read8(address) as u16;
+    let val_high = self.read8(address + 1) as u16;
     (val_high << 8) | val_low
   }
 }
@@ -622,6 +1007,9 @@ impl<B: Bus> Cpu<B> {
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::instructions::INSTRUCTIONS;
+  use alloc::vec::Vec;
+  use core::cell::RefCell;
 
   struct TestBus([u8; 0xffff + 1]);
 
@@ -743,6 +1131,290 @@ mod tests {
     assert!(cpu.flags.contains(Flag::N));
   }
 
+  #[test]
+  fn decimal_mode() {
+    let mut cpu = sut();
+    cpu.flags |= Flag::D;
+
+    assert_eq!(cpu.add_with_carry(0x58, 0x46), 0x04);
+    assert!(cpu.flags.contains(Flag::C));
+
+    cpu.flags.remove(Flag::C);
+    assert_eq!(cpu.add_with_carry(0x12, 0x34), 0x46);
+    assert!(!cpu.flags.contains(Flag::C));
+
+    // NMOS quirks, Z from the binary sum, N and V half-adjusted
+    assert_eq!(cpu.add_with_carry(0x99, 0x01), 0x00);
+    assert!(cpu.flags.contains(Flag::C));
+    assert!(!cpu.flags.contains(Flag::Z));
+    assert!(cpu.flags.contains(Flag::N));
+
+    cpu.flags.remove(Flag::C);
+    assert_eq!(cpu.add_with_carry(0x79, 0x01), 0x80);
+    assert!(cpu.flags.contains(Flag::V));
+
+    cpu.flags |= Flag::C;
+    assert_eq!(cpu.sub_with_borrow(0x46, 0x12), 0x34);
+    assert!(cpu.flags.contains(Flag::C));
+    assert_eq!(cpu.sub_with_borrow(0x12, 0x21), 0x91);
+    assert!(!cpu.flags.contains(Flag::C));
+    assert_eq!(cpu.sub_with_borrow(0x32, 0x02), 0x29);
+  }
+
+  #[test]
+  fn no_decimal_mode_on_2a03() {
+    let mut cpu = Cpu::with_variant(TestBus([0; 0xffff + 1]), Variant::Rp2a03);
+    cpu.flags |= Flag::D;
+    assert_eq!(cpu.add_with_carry(0x58, 0x46), 0x9e);
+  }
+
+  #[test]
+  fn jmp_indirect_page_wrap() {
+    for (variant, target) in [(Variant::Nmos, 0x1234), (Variant::Cmos65c02, 0x5634)] {
+      let mut mem = TestBus([0; 0xffff + 1]);
+      mem.0[..3].copy_from_slice(&[0x6c, 0xff, 0x02]); // JMP ($02FF)
+      mem.0[0x02ff] = 0x34;
+      mem.0[0x0200] = 0x12;
+      mem.0[0x0300] = 0x56;
+      let mut cpu = Cpu::with_variant(mem, variant);
+      let (i, o) = cpu.fetch_next_instruction();
+      cpu.execute(i, o);
+      assert_eq!(cpu.pc, target);
+    }
+  }
+
+  #[test]
+  fn wai_until_interrupt() {
+    let mut mem = TestBus([0; 0xffff + 1]);
+    mem.0[0] = 0xcb; // WAI
+    let mut cpu = Cpu::with_variant(mem, Variant::Wdc65c02);
+    cpu.flags |= Flag::I;
+    let (i, o) = cpu.fetch_next_instruction();
+    cpu.execute(i, o);
+    assert_eq!(cpu.halt, Some(Halt::Wait));
+    assert_eq!(cpu.pc, 1);
+
+    // Masked IRQ resumes without taking it
+    cpu.lines.set_irq(1, true);
+    cpu.idle();
+    assert_eq!(cpu.halt, None);
+    assert_eq!(cpu.poll_interrupts(), 0);
+    assert_eq!(cpu.pc, 1);
+  }
+
+  // Like Mos6502::tick
+  fn step(cpu: &mut Cpu<impl Bus>) -> usize {
+    let interrupt_cycles = cpu.poll_interrupts();
+    let (i, o) = cpu.fetch_next_instruction();
+    interrupt_cycles + cpu.execute(i, o)
+  }
+
+  fn with_vectors(program: &[u8]) -> TestBus {
+    let mut mem = TestBus([0xea; 0xffff + 1]); // NOPs
+    mem.0[..program.len()].copy_from_slice(program);
+    mem.0[0xfffa..].copy_from_slice(&[0x00, 0x40, 0x00, 0x00, 0x00, 0x30]);
+    mem
+  }
+
+  #[test]
+  fn irq_is_shared_and_level_triggered() {
+    let mut mem = with_vectors(&[]);
+    mem.0[0x3000] = 0x58; // CLI
+    let mut cpu = Cpu::new(mem);
+    cpu.lines.set_irq(0b01, true);
+    cpu.lines.set_irq(0b10, true);
+    cpu.lines.set_irq(0b01, false);
+    assert!(cpu.lines.irq());
+
+    assert_eq!(step(&mut cpu), 7 + 2);
+    assert_eq!(cpu.pc, 0x3001);
+    step(&mut cpu);
+    // Still asserted, taken again as soon as I is cleared
+    step(&mut cpu);
+    assert_eq!(cpu.pc, 0x3001);
+
+    cpu.lines.set_irq(0b10, false);
+    step(&mut cpu);
+    step(&mut cpu);
+    assert_eq!(cpu.pc, 0x3003);
+  }
+
+  #[test]
+  fn cli_and_sei_take_effect_after_the_next_instruction() {
+    for stepping in [Stepping::Instruction, Stepping::Cycle] {
+      let mut cpu = Cpu::new(with_vectors(&[0x58, 0x78])); // CLI, SEI
+      cpu.stepping = stepping;
+      cpu.reset();
+      cpu.lines.set_irq(1, true);
+
+      step(&mut cpu);
+      // Still masked when CLI polled
+      assert_eq!(step(&mut cpu), 2);
+      assert_eq!(cpu.pc, 2);
+      // Not yet masked when SEI polled
+      assert_eq!(step(&mut cpu), 7 + 2);
+      assert_eq!(cpu.pc, 0x3001);
+    }
+  }
+
+  #[test]
+  fn nmi_is_edge_triggered() {
+    let mut cpu = Cpu::new(with_vectors(&[]));
+    cpu.lines.set_nmi(true);
+    step(&mut cpu);
+    assert_eq!(cpu.pc, 0x4001);
+
+    // Held, not taken again
+    step(&mut cpu);
+    assert_eq!(cpu.pc, 0x4002);
+
+    cpu.lines.set_nmi(false);
+    step(&mut cpu);
+    cpu.lines.set_nmi(true);
+    step(&mut cpu);
+    assert_eq!(cpu.pc, 0x4001);
+  }
+
+  #[test]
+  fn direct_nmi_and_irq() {
+    for stepping in [Stepping::Instruction, Stepping::Cycle] {
+      let mut cpu = Cpu::new(with_vectors(&[0xea, 0x58])); // NOP, CLI
+      cpu.stepping = stepping;
+      cpu.reset();
+
+      // Held while masked
+      cpu.irq();
+      step(&mut cpu);
+      step(&mut cpu);
+      // CLI polled with I still set
+      assert_eq!(step(&mut cpu), 2);
+      assert_eq!(cpu.pc, 3);
+      assert_eq!(step(&mut cpu), 7 + 2);
+      assert_eq!(cpu.pc, 0x3001);
+      // Taken once
+      assert!(!cpu.lines.irq());
+
+      cpu.nmi();
+      assert_eq!(step(&mut cpu), 7 + 2);
+      assert_eq!(cpu.pc, 0x4001);
+    }
+  }
+
+  #[test]
+  fn nmi_hijacks_brk() {
+    struct NmiAt(TestBus, Rc<InterruptLines>, usize, usize);
+
+    impl Bus for NmiAt {
+      fn read8(&self, address: u16) -> u8 {
+        self.0.read8(address)
+      }
+
+      fn write8(&mut self, val: u8, address: u16) {
+        self.0.write8(val, address)
+      }
+
+      fn on_cycle(&mut self) {
+        self.2 += 1;
+        self.1.set_nmi(self.2 >= self.3);
+      }
+    }
+
+    // Asserted during the fourth cycle of BRK it's seen in time, during the fifth it's not
+    for (cycle, vector) in [(4, 0x4000), (5, 0x3000)] {
+      let lines = Rc::new(InterruptLines::default());
+      let mut cpu = Cpu::new(NmiAt(with_vectors(&[0x00]), lines.clone(), 0, cycle));
+      cpu.lines = lines;
+      cpu.stepping = Stepping::Cycle;
+      cpu.regs[SP] = 0xff;
+      assert_eq!(step(&mut cpu), 7);
+      assert_eq!(cpu.pc, vector);
+      // B is pushed either way
+      assert_ne!(cpu.bus.0.read8(0x01fd) & Flag::B.bits(), 0);
+      assert_eq!(cpu.interrupt.nmi_pending, vector == 0x3000);
+    }
+  }
+
+  #[test]
+  fn cycle_stepped_matches_table() {
+    for variant in [Variant::Nmos, Variant::Wdc65c02] {
+      for op in 0..=0xff {
+        // Unlisted NMOS opcodes jam
+        if !variant.is_cmos() && INSTRUCTIONS[op as usize].opcode == Opcode::JAM {
+          continue;
+        }
+        // Indexed by 0xff crosses pages
+        for index in [1, 0xff] {
+          let cycles = [Stepping::Instruction, Stepping::Cycle].map(|stepping| {
+            let mut mem = TestBus([0; 0xffff + 1]);
+            mem.0[0x0200..0x0203].copy_from_slice(&[op, 0x10, 0x03]);
+            mem.0[0x0010..0x0012].copy_from_slice(&[0x80, 0x04]);
+            let mut cpu = Cpu::with_variant(mem, variant);
+            cpu.stepping = stepping;
+            cpu.pc = 0x0200;
+            cpu.regs = [0, index, index, 0xfd];
+            let (i, o) = cpu.fetch_next_instruction();
+            cpu.execute(i, o)
+          });
+          assert_eq!(cycles[0], cycles[1], "{:?} ${:02X}", variant, op);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn dummy_reads_and_writes() {
+    struct RecordingBus(TestBus, RefCell<Vec<(char, u16)>>, usize);
+
+    impl Bus for RecordingBus {
+      fn read8(&self, address: u16) -> u8 {
+        self.1.borrow_mut().push(('r', address));
+        self.0.read8(address)
+      }
+
+      fn write8(&mut self, val: u8, address: u16) {
+        self.1.borrow_mut().push(('w', address));
+        self.0.write8(val, address)
+      }
+
+      fn on_cycle(&mut self) {
+        self.2 += 1;
+      }
+    }
+
+    let run = |program: &[u8], x: u8| {
+      let mut mem = TestBus([0; 0xffff + 1]);
+      mem.0[..program.len()].copy_from_slice(program);
+      let mut cpu = Cpu::new(RecordingBus(mem, RefCell::new(Vec::new()), 0));
+      cpu.stepping = Stepping::Cycle;
+      cpu.regs[X] = x;
+      let (i, o) = cpu.fetch_next_instruction();
+      let cycles = cpu.execute(i, o);
+      assert_eq!(cycles, cpu.bus.2);
+      cpu.bus.1.take()
+    };
+
+    // STA $12F0,X reads the wrong page first
+    assert_eq!(
+      run(&[0x9d, 0xf0, 0x12], 0x20),
+      [('r', 0), ('r', 1), ('r', 2), ('r', 0x1210), ('w', 0x1310)]
+    );
+    // LDA $12F0,X doesn't unless it crosses
+    assert_eq!(
+      run(&[0xbd, 0xf0, 0x12], 0x05),
+      [('r', 0), ('r', 1), ('r', 2), ('r', 0x12f5)]
+    );
+    // ASL $10 writes twice
+    assert_eq!(
+      run(&[0x06, 0x10], 0),
+      [('r', 0), ('r', 1), ('r', 0x10), ('w', 0x10), ('w', 0x10)]
+    );
+    // LDA $F0,X reads the base while indexing
+    assert_eq!(
+      run(&[0xb5, 0xf0], 0x20),
+      [('r', 0), ('r', 1), ('r', 0xf0), ('r', 0x10)]
+    );
+  }
+
   #[test]
   fn cmp() {
     let mut cpu = sut();
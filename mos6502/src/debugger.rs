@@ -2,7 +2,6 @@ use alloc::vec::Vec;
 use core::marker::PhantomData;
 use std::collections::HashMap;
 use std::collections::VecDeque;
-use std::fmt::Write;
 use std::ops::RangeInclusive;
 
 use getch::Getch;
@@ -13,6 +12,7 @@ use crate::cpu::AC;
 use crate::cpu::SP;
 use crate::cpu::X;
 use crate::cpu::Y;
+use crate::disassembler::Disassembler;
 use crate::instructions::Instruction;
 use crate::instructions::Opcode;
 use crate::memory::Bus;
@@ -39,7 +39,6 @@ pub struct AttachedDebugger<'cpu, B> {
 struct BacktraceEntry {
   inst: &'static Instruction,
   pc: u16,
-  opbyte: u8,
 }
 
 #[derive(PartialEq, Eq)]
@@ -100,21 +99,19 @@ impl<B: Bus> Debugger<B> {
 
   pub(crate) fn on_tick(&mut self, cpu: &Cpu<B>, next_inst: &'static Instruction) {
     let pc = cpu.pc;
-    let opbyte = cpu.bus.read8(pc);
 
     *self.opcodes.entry(&next_inst.opcode).or_insert(0) += 1;
 
     self.backtrace.push_back(BacktraceEntry {
       inst: next_inst,
       pc,
-      opbyte,
     });
     if self.backtrace.len() == BACKTRACE_LIMIT {
       self.backtrace.remove(0);
     }
 
     if self.suspended || self.verbose {
-      Debugger::print_instruction(&cpu.bus, pc, opbyte, next_inst);
+      Debugger::print_instruction(cpu, pc);
     }
 
     self.check_watches(cpu);
@@ -201,7 +198,7 @@ impl<B: Bus> Debugger<B> {
   fn dump_backtrace(&mut self, cpu: &Cpu<impl Bus>) {
     println!("...");
     for entry in self.backtrace.iter() {
-      Debugger::print_instruction(&cpu.bus, entry.pc, entry.opbyte, entry.inst);
+      Debugger::print_instruction(cpu, entry.pc);
     }
   }
 
@@ -256,31 +253,8 @@ impl<B: Bus> Debugger<B> {
     }
   }
 
-  fn print_instruction(bus: &B, pc: u16, opbyte: u8, inst: &Instruction) {
-    let mut pc_str = String::new();
-    write!(&mut pc_str, "{:#06x}", pc).unwrap();
-
-    let mut opbyte_str = String::new();
-    write!(&mut opbyte_str, "{:#04x}", opbyte).unwrap();
-
-    let mut operands_str = String::new();
-    for o in 1..=inst.size - 1 {
-      let operand = bus.read8(pc + o as u16);
-      write!(&mut operands_str, "{:#04x} ", operand).unwrap();
-    }
-
-    let mut mnemonic_str = String::new();
-    write!(
-      &mut mnemonic_str,
-      "{:?} {:?} {}",
-      inst.opcode, inst.mode, operands_str
-    )
-    .unwrap();
-
-    println!(
-      "{:<10} {} {:<10} {}",
-      pc_str, opbyte_str, operands_str, mnemonic_str
-    );
+  fn print_instruction(cpu: &Cpu<B>, pc: u16) {
+    println!("{}", Disassembler::new(cpu.variant()).line(&cpu.bus, pc));
   }
 }
 
@@ -368,9 +342,3 @@ impl<B: Bus> std::fmt::Display for Cpu<B> {
     )
   }
 }
-
-impl std::fmt::Display for Opcode {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    write!(f, "{:?}", self)
-  }
-}
@@ -0,0 +1,230 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::RangeInclusive;
+
+use crate::cpu::Variant;
+pub use crate::instructions::AddressMode;
+pub use crate::instructions::Instruction;
+pub use crate::instructions::Opcode;
+use crate::memory::Bus;
+
+// One decoded instruction
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Line {
+  pub address: u16,
+  // The symbol at `address`, if any
+  pub label: Option<String>,
+  // Opcode and operand bytes, 1-3
+  pub bytes: Vec<u8>,
+  pub opcode: Opcode,
+  pub mode: AddressMode,
+  // "#$10", "($20),Y", "$8000,X", or with labels "reset", "table,X"
+  pub operand: String,
+  // What the instruction reads, writes or jumps to, when the bytes alone tell.
+  // None for indexed and indirect modes, they depend on registers and memory.
+  pub effective_address: Option<u16>,
+}
+
+impl fmt::Display for Line {
+  // 8000  BD 00 02  LDA $0200,X
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let mut bytes = String::new();
+    for b in &self.bytes {
+      bytes += &format!("{:02X} ", b);
+    }
+    write!(f, "{:04X}  {:<9} {}", self.address, bytes, self.opcode)?;
+    if !self.operand.is_empty() {
+      write!(f, " {}", self.operand)?;
+    }
+    Ok(())
+  }
+}
+
+// Turns memory into listings, without running anything. Operands are read
+// through the bus, so memory-mapped registers see the reads.
+pub struct Disassembler {
+  variant: Variant,
+  symbols: BTreeMap<u16, String>,
+}
+
+impl Disassembler {
+  pub fn new(variant: Variant) -> Self {
+    Self {
+      variant,
+      symbols: BTreeMap::new(),
+    }
+  }
+
+  // Shown instead of the address, both on the line at `address` and in operands
+  pub fn add_label(&mut self, address: u16, label: impl Into<String>) {
+    self.symbols.insert(address, label.into());
+  }
+
+  // Whole instructions starting in `range`, the last one may reach past its end
+  pub fn disassemble(&self, bus: &impl Bus, range: RangeInclusive<u16>) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut address = *range.start() as usize;
+    while address <= *range.end() as usize {
+      let line = self.line(bus, address as u16);
+      address += line.bytes.len();
+      lines.push(line);
+    }
+    lines
+  }
+
+  pub fn line(&self, bus: &impl Bus, address: u16) -> Line {
+    // Data, or illegal opcodes the table doesn't know, decodes as 1 byte JAMs
+    let inst: &Instruction = &Instruction::table(self.variant)[bus.read8(address) as usize];
+    let bytes: Vec<u8> = (0..inst.size as u16)
+      .map(|i| bus.read8(address.wrapping_add(i)))
+      .collect();
+    let zp = bytes.get(1).copied().unwrap_or(0);
+    let abs = ((bytes.get(2).copied().unwrap_or(0) as u16) << 8) | zp as u16;
+    let next = address.wrapping_add(inst.size as u16);
+    let branch = |offset: u8| next.wrapping_add(offset as i8 as u16);
+
+    let (operand, effective_address) = match (inst.mode, inst.size) {
+      (AddressMode::Impl, _) if accumulator(inst.opcode) => (String::from("A"), None),
+      (AddressMode::Impl, _) => (String::new(), None),
+      (AddressMode::Imm, _) => (format!("#${:02X}", zp), None),
+      (AddressMode::Zero, _) => (self.zp(zp), Some(zp as u16)),
+      (AddressMode::ZeroX, _) => (format!("{},X", self.zp(zp)), None),
+      (AddressMode::ZeroY, _) => (format!("{},Y", self.zp(zp)), None),
+      (AddressMode::ZeroInd, _) => (format!("({})", self.zp(zp)), None),
+      (AddressMode::IndX, _) => (format!("({},X)", self.zp(zp)), None),
+      (AddressMode::IndY, _) => (format!("({}),Y", self.zp(zp)), None),
+      (AddressMode::Abs, _) => (self.abs(abs), Some(abs)),
+      (AddressMode::AbsX, _) => (format!("{},X", self.abs(abs)), None),
+      (AddressMode::AbsY, _) => (format!("{},Y", self.abs(abs)), None),
+      (AddressMode::Ind, _) => (format!("({})", self.abs(abs)), None),
+      (AddressMode::AbsIndX, _) => (format!("({},X)", self.abs(abs)), None),
+      (AddressMode::Rel, _) => (self.abs(branch(zp)), Some(branch(zp))),
+      (AddressMode::ZeroRel, _) => {
+        let target = branch(bytes[2]);
+        (
+          format!("{},{}", self.zp(zp), self.abs(target)),
+          Some(target),
+        )
+      }
+      // Illegal NOPs, the operand is skipped
+      (AddressMode::Nop, 2) => (format!("${:02X}", zp), None),
+      (AddressMode::Nop, 3) => (format!("${:04X}", abs), None),
+      (AddressMode::Nop, _) => (String::new(), None),
+    };
+
+    Line {
+      address,
+      label: self.symbols.get(&address).cloned(),
+      bytes,
+      opcode: inst.opcode,
+      mode: inst.mode,
+      operand,
+      effective_address,
+    }
+  }
+
+  fn zp(&self, address: u8) -> String {
+    match self.symbols.get(&(address as u16)) {
+      Some(label) => label.clone(),
+      None => format!("${:02X}", address),
+    }
+  }
+
+  fn abs(&self, address: u16) -> String {
+    match self.symbols.get(&address) {
+      Some(label) => label.clone(),
+      None => format!("${:04X}", address),
+    }
+  }
+}
+
+// Implied shifts, rotates, INC and DEC work on A
+fn accumulator(opcode: Opcode) -> bool {
+  matches!(
+    opcode,
+    Opcode::ASL | Opcode::LSR | Opcode::ROL | Opcode::ROR | Opcode::INC | Opcode::DEC
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::string::ToString;
+
+  use super::*;
+  use crate::memory::Memory;
+
+  fn lines(variant: Variant, program: &[u8]) -> Vec<String> {
+    let mem = Memory::load(program, 0x8000);
+    Disassembler::new(variant)
+      .disassemble(&mem, 0x8000..=0x8000 + program.len() as u16 - 1)
+      .iter()
+      .map(|l| l.to_string())
+      .collect()
+  }
+
+  #[test]
+  fn address_modes() {
+    #[rustfmt::skip]
+    let program = [
+      0xa9, 0x10,       // LDA #$10
+      0xb1, 0x20,       // LDA ($20),Y
+      0x81, 0x30,       // STA ($30,X)
+      0xbd, 0x00, 0x02, // LDA $0200,X
+      0x0a,             // ASL A
+      0x6c, 0xfc, 0xff, // JMP ($FFFC)
+      0xd0, 0xf0,       // BNE -16
+      0xea,             // NOP
+    ];
+    assert_eq!(
+      lines(Variant::Nmos, &program),
+      [
+        "8000  A9 10     LDA #$10",
+        "8002  B1 20     LDA ($20),Y",
+        "8004  81 30     STA ($30,X)",
+        "8006  BD 00 02  LDA $0200,X",
+        "8009  0A        ASL A",
+        "800A  6C FC FF  JMP ($FFFC)",
+        "800D  D0 F0     BNE $7FFF",
+        "800F  EA        NOP",
+      ]
+    );
+  }
+
+  #[test]
+  fn variants() {
+    let program = [0x72, 0x40, 0x0f, 0x40, 0x02];
+    assert_eq!(
+      lines(Variant::Rockwell65c02, &program),
+      [
+        "8000  72 40     ADC ($40)",
+        "8002  0F 40 02  BBR0 $40,$8007"
+      ]
+    );
+    // JAM and SLO zp on NMOS
+    assert_eq!(
+      lines(Variant::Nmos, &[0x72, 0x07, 0x40]),
+      ["8000  72        JAM", "8001  07 40     SLO $40"]
+    );
+  }
+
+  #[test]
+  fn labels_and_effective_addresses() {
+    let program = [0x20, 0x06, 0x80, 0x8d, 0x00, 0x20, 0x60];
+    let mem = Memory::load(&program, 0x8000);
+    let mut disassembler = Disassembler::new(Variant::Nmos);
+    disassembler.add_label(0x8006, "done");
+    disassembler.add_label(0x2000, "PPUCTRL");
+
+    let lines = disassembler.disassemble(&mem, 0x8000..=0x8006);
+    assert_eq!(lines[0].operand, "done");
+    assert_eq!(lines[0].effective_address, Some(0x8006));
+    assert_eq!(lines[1].operand, "PPUCTRL");
+    assert_eq!(lines[1].bytes, [0x8d, 0x00, 0x20]);
+    assert_eq!(lines[2].label.as_deref(), Some("done"));
+    assert_eq!(lines[2].opcode, Opcode::RTS);
+    assert_eq!(lines[2].effective_address, None);
+  }
+}
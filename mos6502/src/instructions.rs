@@ -1,12 +1,20 @@
 use core::panic;
-use std::sync::LazyLock;
 
-use crate::cpu::{Cpu, X, Y};
+use crate::cpu::{Cpu, Variant, X, Y};
 use crate::memory::Bus;
 
 pub type Operands = (u8, u8);
 
-#[derive(Debug, PartialEq, Eq)]
+// What an instruction does at its effective address, decides the dummy
+// accesses when cycle-stepped
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Access {
+  Read,
+  Write,
+  Modify,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum AddressMode {
   Abs,
   AbsX,
@@ -20,10 +28,13 @@ pub enum AddressMode {
   Zero,
   ZeroX,
   ZeroY,
-  Nop, // Not official.. used for dev
+  ZeroInd, // 65C02 (zp)
+  AbsIndX, // 65C02 (abs,X), JMP only
+  ZeroRel, // 65C02 zp,rel, BBR and BBS
+  Nop,     // Not official.. used for dev
 }
 
-#[derive(Debug, PartialEq, Eq, Hash)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 #[allow(clippy::upper_case_acronyms)]
 pub enum Opcode {
   ADC, // Add Memory to Accumulator with Carry
@@ -101,9 +112,33 @@ pub enum Opcode {
   ANC,  // AND oper + set C as ASL
   ANC2, // effectively the same as instr. 0B (ANC)
   ALR,  // AND oper + LSR
+
+  // 65C02
+  BRA,     // Branch always
+  STZ,     // Store zero in memory
+  TRB,     // Test and reset memory bits with accumulator
+  TSB,     // Test and set memory bits with accumulator
+  RMB(u8), // Reset memory bit n (Rockwell)
+  SMB(u8), // Set memory bit n (Rockwell)
+  BBR(u8), // Branch on memory bit n reset (Rockwell)
+  BBS(u8), // Branch on memory bit n set (Rockwell)
+  WAI,     // Wait for interrupt (WDC)
+  STP,     // Stop until reset (WDC)
+}
+
+impl core::fmt::Display for Opcode {
+  fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+    match self {
+      Opcode::RMB(bit) => write!(f, "RMB{}", bit),
+      Opcode::SMB(bit) => write!(f, "SMB{}", bit),
+      Opcode::BBR(bit) => write!(f, "BBR{}", bit),
+      Opcode::BBS(bit) => write!(f, "BBS{}", bit),
+      _ => write!(f, "{:?}", self),
+    }
+  }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct Instruction {
   pub opcode: Opcode,
   pub mode: AddressMode,
@@ -120,7 +155,13 @@ const NOP_2_4: Instruction = Instruction::two(Opcode::NOP, 4, AddressMode::Nop);
 const NOP_3_4: Instruction = Instruction::thr(Opcode::NOP, 4, AddressMode::Nop);
 const NOP_3_A: Instruction = Instruction::thr(Opcode::NOP, 4, AddressMode::AbsX);
 
-static INSTRUCTIONS: LazyLock<[Instruction; 256]> = LazyLock::new(|| {
+pub(crate) static INSTRUCTIONS: [Instruction; 256] = nmos();
+static INSTRUCTIONS_65C02: [Instruction; 256] = cmos(false, false);
+static INSTRUCTIONS_R65C02: [Instruction; 256] = cmos(true, false);
+static INSTRUCTIONS_W65C02: [Instruction; 256] = cmos(true, true);
+
+// Built at compile time, so no_std builds need no lazy statics
+const fn nmos() -> [Instruction; 256] {
   let mut i = [UNINIT; 256];
 
   i[0x02] = JAM;
@@ -340,7 +381,7 @@ static INSTRUCTIONS: LazyLock<[Instruction; 256]> = LazyLock::new(|| {
   i[0xff] = Instruction::thr(Opcode::ISC, 7, AddressMode::AbsX);
   i[0xfb] = Instruction::thr(Opcode::ISC, 7, AddressMode::AbsY);
   i[0xe3] = Instruction::two(Opcode::ISC, 8, AddressMode::IndX);
-  i[0xf3] = Instruction::two(Opcode::ISC, 4, AddressMode::IndY);
+  i[0xf3] = Instruction::two(Opcode::ISC, 8, AddressMode::IndY);
 
   i[0x07] = Instruction::two(Opcode::SLO, 5, AddressMode::Zero);
   i[0x17] = Instruction::two(Opcode::SLO, 6, AddressMode::ZeroX);
@@ -415,7 +456,98 @@ static INSTRUCTIONS: LazyLock<[Instruction; 256]> = LazyLock::new(|| {
   i[0xfc] = NOP_3_A;
 
   i
-});
+}
+
+// The 65C02 keeps the documented NMOS opcodes and turns the rest into new
+// instructions or NOPs of fixed size.
+// http://www.6502.org/tutorials/65c02opcodes.html
+const fn cmos(bit_ops: bool, wai_stp: bool) -> [Instruction; 256] {
+  let mut i = [UNINIT; 256];
+
+  let nmos = nmos();
+  let mut op = 0;
+  while op < 256 {
+    if nmos[op].is_documented() || op == 0xea {
+      i[op] = nmos[op];
+    } else {
+      match op & 0x0f {
+        0x03 | 0x0b | 0x07 | 0x0f => i[op] = Instruction::imp(Opcode::NOP, 1),
+        0x02 if op & 0x10 == 0 => i[op] = NOP_2_2,
+        _ => (),
+      }
+    }
+    op += 1;
+  }
+  i[0x44] = NOP_2_3;
+  i[0x54] = NOP_2_4;
+  i[0xd4] = NOP_2_4;
+  i[0xf4] = NOP_2_4;
+  i[0x5c] = Instruction::thr(Opcode::NOP, 8, AddressMode::Nop);
+  i[0xdc] = NOP_3_4;
+  i[0xfc] = NOP_3_4;
+
+  // Fixed page wrap, one cycle more
+  i[0x6c] = Instruction::thr(Opcode::JMP, 6, AddressMode::Ind);
+  i[0x7c] = Instruction::thr(Opcode::JMP, 6, AddressMode::AbsIndX);
+
+  // Shifts and rotates on abs,X only take the extra cycle on page cross
+  i[0x1e] = Instruction::thr(Opcode::ASL, 6, AddressMode::AbsX);
+  i[0x3e] = Instruction::thr(Opcode::ROL, 6, AddressMode::AbsX);
+  i[0x5e] = Instruction::thr(Opcode::LSR, 6, AddressMode::AbsX);
+  i[0x7e] = Instruction::thr(Opcode::ROR, 6, AddressMode::AbsX);
+
+  i[0x12] = Instruction::two(Opcode::ORA, 5, AddressMode::ZeroInd);
+  i[0x32] = Instruction::two(Opcode::AND, 5, AddressMode::ZeroInd);
+  i[0x52] = Instruction::two(Opcode::EOR, 5, AddressMode::ZeroInd);
+  i[0x72] = Instruction::two(Opcode::ADC, 5, AddressMode::ZeroInd);
+  i[0x92] = Instruction::two(Opcode::STA, 5, AddressMode::ZeroInd);
+  i[0xb2] = Instruction::two(Opcode::LDA, 5, AddressMode::ZeroInd);
+  i[0xd2] = Instruction::two(Opcode::CMP, 5, AddressMode::ZeroInd);
+  i[0xf2] = Instruction::two(Opcode::SBC, 5, AddressMode::ZeroInd);
+
+  i[0x89] = Instruction::two(Opcode::BIT, 2, AddressMode::Imm);
+  i[0x34] = Instruction::two(Opcode::BIT, 4, AddressMode::ZeroX);
+  i[0x3c] = Instruction::thr(Opcode::BIT, 4, AddressMode::AbsX);
+
+  i[0x1a] = Instruction::imp(Opcode::INC, 2);
+  i[0x3a] = Instruction::imp(Opcode::DEC, 2);
+
+  i[0x80] = Instruction::two(Opcode::BRA, 2, AddressMode::Rel);
+
+  i[0xda] = Instruction::imp(Opcode::PHX, 3);
+  i[0x5a] = Instruction::imp(Opcode::PHY, 3);
+  i[0xfa] = Instruction::imp(Opcode::PLX, 4);
+  i[0x7a] = Instruction::imp(Opcode::PLY, 4);
+
+  i[0x64] = Instruction::two(Opcode::STZ, 3, AddressMode::Zero);
+  i[0x74] = Instruction::two(Opcode::STZ, 4, AddressMode::ZeroX);
+  i[0x9c] = Instruction::thr(Opcode::STZ, 4, AddressMode::Abs);
+  i[0x9e] = Instruction::thr(Opcode::STZ, 5, AddressMode::AbsX);
+
+  i[0x14] = Instruction::two(Opcode::TRB, 5, AddressMode::Zero);
+  i[0x1c] = Instruction::thr(Opcode::TRB, 6, AddressMode::Abs);
+  i[0x04] = Instruction::two(Opcode::TSB, 5, AddressMode::Zero);
+  i[0x0c] = Instruction::thr(Opcode::TSB, 6, AddressMode::Abs);
+
+  if bit_ops {
+    let mut bit = 0;
+    while bit < 8 {
+      let row = (bit as usize) << 4;
+      i[row | 0x07] = Instruction::two(Opcode::RMB(bit), 5, AddressMode::Zero);
+      i[row | 0x87] = Instruction::two(Opcode::SMB(bit), 5, AddressMode::Zero);
+      i[row | 0x0f] = Instruction::thr(Opcode::BBR(bit), 5, AddressMode::ZeroRel);
+      i[row | 0x8f] = Instruction::thr(Opcode::BBS(bit), 5, AddressMode::ZeroRel);
+      bit += 1;
+    }
+  }
+
+  if wai_stp {
+    i[0xcb] = Instruction::imp(Opcode::WAI, 3);
+    i[0xdb] = Instruction::imp(Opcode::STP, 3);
+  }
+
+  i
+}
 
 impl Instruction {
   pub const fn imp(opcode: Opcode, cycles: usize) -> Self {
@@ -445,28 +577,47 @@ impl Instruction {
     }
   }
 
-  fn num_extra_cycles(&self) -> usize {
-    match self.opcode {
-      // these instructions don't add a cycle when they cross page bounds
-      Opcode::DCP => 0,
-      Opcode::STA => 0,
-      Opcode::SLO => 0,
-      Opcode::RLA => 0,
-      Opcode::SRE => 0,
-      Opcode::RRA => 0,
-      // isc in indy apparently adds 4 cycles.. bc many instructions in one i guess
-      Opcode::ISC => match self.mode {
-        AddressMode::IndY => 4,
-        _ => 0,
-      },
-      _ => 1,
+  // Everything but illegal opcodes and NOPs, the one real NOP is $EA
+  const fn is_documented(&self) -> bool {
+    !matches!(
+      self.opcode,
+      Opcode::NOP
+        | Opcode::JAM
+        | Opcode::LAX
+        | Opcode::SAX
+        | Opcode::USBC
+        | Opcode::DCP
+        | Opcode::ISC
+        | Opcode::SLO
+        | Opcode::RLA
+        | Opcode::SRE
+        | Opcode::RRA
+        | Opcode::PHX
+        | Opcode::PHY
+        | Opcode::PLX
+        | Opcode::PLY
+        | Opcode::ANC
+        | Opcode::ANC2
+        | Opcode::ALR
+    )
+  }
+
+  // All 256 opcodes of a variant, unknown ones are JAMs
+  pub fn table(variant: Variant) -> &'static [Instruction; 256] {
+    match variant {
+      Variant::Nmos | Variant::Rp2a03 => &INSTRUCTIONS,
+      Variant::Cmos65c02 => &INSTRUCTIONS_65C02,
+      Variant::Rockwell65c02 => &INSTRUCTIONS_R65C02,
+      Variant::Wdc65c02 => &INSTRUCTIONS_W65C02,
     }
   }
 
-  pub fn disassemble(opbyte: u8) -> &'static Instruction {
+  pub fn disassemble(opbyte: u8, variant: Variant) -> &'static Instruction {
+    let table = Self::table(variant);
+
     #[cfg(debug_assertions)]
     {
-      let inst = &INSTRUCTIONS[opbyte as usize];
+      let inst = &table[opbyte as usize];
       if inst == &UNINIT {
         panic!("Uninitialized instruction: {:02X}", opbyte);
       }
@@ -474,7 +625,7 @@ impl Instruction {
     }
 
     #[cfg(not(debug_assertions))]
-    &INSTRUCTIONS[opbyte as usize]
+    &table[opbyte as usize]
   }
 
   pub fn resolve_operand_value_and_address(
@@ -482,8 +633,14 @@ impl Instruction {
     cpu: &mut Cpu<impl Bus>,
     operands: &Operands,
   ) -> (u8, u16) {
-    let address = self.resolve(cpu, operands, self.num_extra_cycles());
-    let value = cpu.bus.read8(address);
+    let address = self.resolve(cpu, operands, Access::Modify);
+    let value = cpu.read8(address);
+    // NMOS writes the unmodified value back while modifying, the 65C02 reads it again
+    if cpu.variant().is_cmos() {
+      cpu.dummy_read(address);
+    } else {
+      cpu.dummy_write(value, address);
+    }
     (value, address)
   }
 
@@ -491,19 +648,19 @@ impl Instruction {
     match self.mode {
       AddressMode::Imm => operands.0,
       _ => {
-        let address = self.resolve(cpu, operands, self.num_extra_cycles());
-        cpu.bus.read8(address)
+        let address = self.resolve(cpu, operands, Access::Read);
+        cpu.read8(address)
       }
     }
   }
 
   pub fn resolve_operand_address(&self, cpu: &mut Cpu<impl Bus>, operands: &Operands) -> u16 {
-    self.resolve(cpu, operands, self.num_extra_cycles())
+    self.resolve(cpu, operands, Access::Write)
   }
 
-  fn resolve<B: Bus>(&self, cpu: &mut Cpu<B>, operands: &Operands, num_extra_cycles: usize) -> u16 {
+  fn resolve<B: Bus>(&self, cpu: &mut Cpu<B>, operands: &Operands, access: Access) -> u16 {
     if self.size == 2 {
-      self.resolve_zeropage(cpu, operands.0, num_extra_cycles)
+      self.resolve_zeropage(cpu, operands.0, access)
     } else {
       let low = operands.0;
       let high = operands.1;
@@ -511,58 +668,124 @@ impl Instruction {
 
       match self.mode {
         AddressMode::Abs => address,
-        AddressMode::AbsX => self.cycle_aware_add(cpu, address, cpu.regs[X], num_extra_cycles),
-        AddressMode::AbsY => self.cycle_aware_add(cpu, address, cpu.regs[Y], num_extra_cycles),
-        AddressMode::Ind => self.read16(&cpu.bus, low, high),
+        AddressMode::AbsX => self.cycle_aware_add(cpu, address, cpu.regs[X], access),
+        AddressMode::AbsY => self.cycle_aware_add(cpu, address, cpu.regs[Y], access),
+        // NMOS wraps within the page when the pointer is at $xxFF, the 65C02 doesn't
+        AddressMode::Ind if cpu.variant().is_cmos() => read16_linear(cpu, address),
+        AddressMode::Ind => self.read16(cpu, low, high),
+        AddressMode::AbsIndX => read16_linear(cpu, address.wrapping_add(cpu.regs[X] as u16)),
+        AddressMode::ZeroRel => low as u16,
         _ => panic!(),
       }
     }
   }
 
-  fn resolve_zeropage<B: Bus>(
-    &self,
-    cpu: &mut Cpu<B>,
-    operand: u8,
-    likes_extra_cycles: usize,
-  ) -> u16 {
+  fn resolve_zeropage<B: Bus>(&self, cpu: &mut Cpu<B>, operand: u8, access: Access) -> u16 {
+    // Indexed zeropage reads the unindexed address while adding
+    if matches!(
+      self.mode,
+      AddressMode::IndX | AddressMode::ZeroX | AddressMode::ZeroY
+    ) {
+      cpu.dummy_read(operand as u16);
+    }
+
     // Zeropage indices should wrap!
     // Casting everything to u16 here is safe because hi == 0x00 == zeropage!
     match self.mode {
-      AddressMode::IndX => self.read16(&cpu.bus, operand.wrapping_add(cpu.regs[X]), 0x00), // Zeropage, no carry
+      AddressMode::IndX => self.read16(cpu, operand.wrapping_add(cpu.regs[X]), 0x00), // Zeropage, no carry
       AddressMode::IndY => {
-        let address = self.read16(&cpu.bus, operand, 0x00);
-        self.cycle_aware_add(cpu, address, cpu.regs[Y], likes_extra_cycles)
+        let address = self.read16(cpu, operand, 0x00);
+        self.cycle_aware_add(cpu, address, cpu.regs[Y], access)
       }
+      AddressMode::ZeroInd => self.read16(cpu, operand, 0x00),
       AddressMode::Zero => operand as u16,
       AddressMode::ZeroX => operand.wrapping_add(cpu.regs[X]) as u16, // Zeropage
       AddressMode::ZeroY => operand.wrapping_add(cpu.regs[Y]) as u16, // zeropage
-      _ => {
-        println!("{:?}", self);
-        panic!()
-      }
+      _ => panic!("{:?} has no operand address", self.mode),
     }
   }
 
-  fn cycle_aware_add<B: Bus>(
-    &self,
-    cpu: &mut Cpu<B>,
-    address: u16,
-    v: u8,
-    likes_extra_cycles: usize,
-  ) -> u16 {
+  fn cycle_aware_add<B: Bus>(&self, cpu: &mut Cpu<B>, address: u16, v: u8, access: Access) -> u16 {
     let res = address.wrapping_add(v as u16);
-    if res & 0xff00 != address & 0xff00 {
-      // page cross
-      cpu.add_extra_cycles(likes_extra_cycles);
+    let crossed_page = res & 0xff00 != address & 0xff00;
+
+    // The low byte is added first and read from before the high byte is fixed,
+    // costing an extra cycle on page cross. Writes can't take the chance so they
+    // always spend it, except 65C02 shifts.
+    let always_fixed = match access {
+      Access::Read => false,
+      Access::Write => true,
+      Access::Modify => !(cpu.variant().is_cmos() && self.is_shift()),
+    };
+    if crossed_page && !always_fixed {
+      cpu.add_extra_cycles(1);
+    }
+    if crossed_page || always_fixed {
+      cpu.dummy_read((address & 0xff00) | (res & 0x00ff));
     }
     res
   }
 
-  fn read16(&self, mem: &impl Bus, address_low: u8, address_hi: u8) -> u16 {
+  fn is_shift(&self) -> bool {
+    matches!(
+      self.opcode,
+      Opcode::ASL | Opcode::LSR | Opcode::ROL | Opcode::ROR
+    )
+  }
+
+  fn read16(&self, cpu: &mut Cpu<impl Bus>, address_low: u8, address_hi: u8) -> u16 {
     let byte1_address = ((address_hi as u16) << 8) | address_low as u16;
     let byte2_address = ((address_hi as u16) << 8) | address_low.wrapping_add(1) as u16;
-    let val_low = mem.read8(byte1_address) as u16;
-    let val_high = mem.read8(byte2_address) as u16;
+    let val_low = cpu.read8(byte1_address) as u16;
+    let val_high = cpu.read8(byte2_address) as u16;
     (val_high << 8) | val_low
   }
 }
+
+fn read16_linear(cpu: &mut Cpu<impl Bus>, address: u16) -> u16 {
+  let val_low = cpu.read8(address) as u16;
+  let val_high = cpu.read8(address.wrapping_add(1)) as u16;
+  (val_high << 8) | val_low
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn cmos_tables_complete() {
+    for variant in [
+      Variant::Cmos65c02,
+      Variant::Rockwell65c02,
+      Variant::Wdc65c02,
+    ] {
+      for op in 0..=0xff {
+        assert_ne!(Instruction::disassemble(op, variant).opcode, Opcode::JAM);
+      }
+    }
+  }
+
+  #[test]
+  fn cmos_extensions() {
+    assert_eq!(
+      Instruction::disassemble(0x37, Variant::Cmos65c02).opcode,
+      Opcode::NOP
+    );
+    assert_eq!(
+      Instruction::disassemble(0x37, Variant::Rockwell65c02).opcode,
+      Opcode::RMB(3)
+    );
+    assert_eq!(
+      Instruction::disassemble(0xcb, Variant::Rockwell65c02).opcode,
+      Opcode::NOP
+    );
+    assert_eq!(
+      Instruction::disassemble(0xcb, Variant::Wdc65c02).opcode,
+      Opcode::WAI
+    );
+    assert_eq!(
+      Instruction::disassemble(0xa7, Variant::Nmos).opcode,
+      Opcode::LAX
+    );
+  }
+}
@@ -5,6 +5,7 @@ extern crate alloc;
 pub mod cpu;
 #[cfg(feature = "debugger")]
 pub mod debugger;
+pub mod disassembler;
 mod instructions;
 pub mod memory;
 pub mod mos6502;
@@ -8,6 +8,10 @@ pub trait Bus {
   fn read8(&self, address: u16) -> u8;
   fn write8(&mut self, val: u8, address: u16);
 
+  // Called before every read and write when the CPU is cycle-stepped, so the
+  // rest of the system can be clocked between bus accesses. Not required.
+  fn on_cycle(&mut self) {}
+
   fn read_range(&self, range: RangeInclusive<u16>) -> Vec<u8> {
     range.map(|a| self.read8(a)).collect()
   }
@@ -34,18 +34,28 @@ impl<B: Bus> Mos6502<B> {
   }
 
   #[cfg(feature = "debugger")]
-  pub fn debugger(&mut self) -> AttachedDebugger<B> {
+  pub fn debugger(&mut self) -> AttachedDebugger<'_, B> {
     self.debugger.attach(&mut self.cpu)
   }
 
   // The clock ticks Hzhzhzhz
   pub fn tick(&mut self) -> usize {
+    if self.cpu.halt.is_some() {
+      // WAI or STP, idle until an interrupt or reset
+      self.cpu.idle();
+      self.total_cycles += 1;
+      return 1;
+    }
+
+    // An interrupt runs the handler's first instruction in the same tick
+    let interrupt_cycles = self.cpu.poll_interrupts();
+
     let (inst, operands) = self.cpu.fetch_next_instruction();
 
     #[cfg(feature = "debugger")]
     self.debugger.on_tick(&self.cpu, inst);
 
-    let cycles = self.cpu.execute(inst, operands);
+    let cycles = interrupt_cycles + self.cpu.execute(inst, operands);
 
     self.total_cycles += cycles;
     cycles
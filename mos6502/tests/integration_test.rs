@@ -1,9 +1,11 @@
 use mos6502::cpu::Cpu;
+use mos6502::cpu::Variant;
 use mos6502::memory::Memory;
 use mos6502::mos6502::Mos6502;
 
 fn run_test_rom(
   file: &str,
+  variant: Variant,
   load_base: u16,
   entry_point: u16,
   success_address: u16,
@@ -12,7 +14,7 @@ fn run_test_rom(
   let program = std::fs::read(path).expect("failed to load test rom");
 
   let mem = Memory::load(&program[..], load_base);
-  let mut cpu = Cpu::new(mem);
+  let mut cpu = Cpu::with_variant(mem, variant);
   cpu.set_pc(entry_point);
 
   // debugger.add_breakpoint(Breakpoint::Opcode("DEX".into()));
@@ -52,7 +54,13 @@ fn run_test_rom(
 #[test]
 fn functional_test_bcd_disabled() {
   let expected_ticks = 26765879;
-  let res = run_test_rom("functional_test_bcd_disabled.bin", 0x000, 0x400, 0x336d);
+  let res = run_test_rom(
+    "functional_test_bcd_disabled.bin",
+    Variant::Nmos,
+    0x000,
+    0x400,
+    0x336d,
+  );
   assert!(res.0, "trapped");
   assert_eq!(expected_ticks, res.1, "wrong tick count");
 }
@@ -60,25 +68,29 @@ fn functional_test_bcd_disabled() {
 #[test]
 fn ttl6502() {
   let expected_ticks = 2738;
-  let res = run_test_rom("TTL6502.bin", 0xe000, 0xe000, 0xf5b6);
+  let res = run_test_rom("TTL6502.bin", Variant::Nmos, 0xe000, 0xe000, 0xf5b6);
   assert!(res.0, "trapped");
   assert_eq!(expected_ticks, res.1, "wrong tick count");
 }
 
 #[test]
-#[ignore = "BCD is not implemented yet"]
 fn functional_test_full() {
-  let expected_ticks = 0;
-  let res = run_test_rom("functional_test_full.bin", 0x000, 0x400, 0x3469);
+  let expected_ticks = 30648048;
+  let res = run_test_rom(
+    "functional_test_full.bin",
+    Variant::Nmos,
+    0x000,
+    0x400,
+    0x3469,
+  );
   assert!(res.0, "trapped");
   assert_eq!(expected_ticks, res.1, "wrong tick count");
 }
 
 #[test]
-#[ignore = "BCD is not implemented yet"]
 fn functional_test_extended_opcodes() {
-  let expected_ticks = 26765879;
-  let res = run_test_rom("extended_test.bin", 0x000, 0x400, 0x336d);
+  let expected_ticks = 21986985;
+  let res = run_test_rom("extended_test.bin", Variant::Wdc65c02, 0x000, 0x400, 0x24f1);
   assert!(res.0, "trapped");
   assert_eq!(expected_ticks, res.1, "wrong tick count");
 }
@@ -398,15 +398,18 @@ fn pipe_invalid_rom() -> Result<(), Box<dyn std::error::Error>> {
 
 #[test]
 fn pipe_unsupported_rom() -> Result<(), Box<dyn std::error::Error>> {
-  let rom = include_bytes!("../../test-roms/nes-test-roms/other/oam3.nes");
+  // Mapper 30, UNROM 512
+  let mut rom = include_bytes!("../../test-roms/nestest/nestest.nes").to_vec();
+  rom[6] = (rom[6] & 0x0f) | 0xe0;
+  rom[7] = (rom[7] & 0x0f) | 0x10;
 
   let _child = start_app()?;
   let mut client = Client::connect()?;
 
-  client.input(rom);
+  client.input(&rom);
 
   client.expect_welcome_and_rom_prompt();
-  client.expect_server_message(&RES.fmt(StrId::InvalidRom, &["NotYetImplemented(\"Mapper 7\")"]));
+  client.expect_server_message(&RES.fmt(StrId::InvalidRom, &["NotYetImplemented(\"Mapper 30\")"]));
   client.expect_disconnected();
 
   Ok(())
@@ -1,14 +1,18 @@
+use std::path::Path;
 use std::path::PathBuf;
 
 use common::utils;
 use nes::cartridge::Cartridge;
 use nes::mos6502::debugger::Breakpoint;
 use nes::nes::Nes;
+use nes::region::Region;
 use structopt::StructOpt;
 
 mod sdl;
 use crate::sdl::SdlHostPlatform;
 
+const BATTERY_FLUSH_INTERVAL_SECONDS: usize = 5;
+
 #[derive(StructOpt, Debug)]
 struct Cli {
   path: PathBuf,
@@ -20,17 +24,40 @@ struct Cli {
   verbose: bool,
   #[structopt(short, long)]
   debug: bool,
+  // ntsc, pal or dendy, overrides the header
+  #[structopt(long)]
+  region: Option<Region>,
+  // true or false, for discrete boards the header doesn't say about
+  #[structopt(long)]
+  bus_conflicts: Option<bool>,
+  // Clock the PPU and APU between every CPU bus access, slower but more accurate
+  #[structopt(long)]
+  cycle_stepped: bool,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
   let args: Cli = Cli::from_args();
   println!("Loading {:?}.", args.path);
 
-  let cartridge = Cartridge::blow_dust(args.path)?;
+  let save_path = args.path.with_extension("sav");
+  let mut cartridge = Cartridge::blow_dust(args.path)?;
+  if let Some(region) = args.region {
+    cartridge.set_region(region);
+  }
+  if let Some(enabled) = args.bus_conflicts {
+    cartridge.set_bus_conflicts(enabled);
+  }
   println!("Loaded! {}", cartridge);
 
   let mut nes = Nes::insert(cartridge, SdlHostPlatform::new());
+
+  // Through the mapper, which knows where the battery RAM is (e.g. N163's internal RAM)
+  if nes.has_battery() && save_path.exists() {
+    nes.import_prg_ram(&std::fs::read(&save_path)?)?;
+    println!("Loaded battery save {:?}.", save_path);
+  }
   nes.show_fps(std::env::var("SHOW_FPS").is_ok());
+  nes.cycle_stepped(args.cycle_stepped);
 
   let mut debugger = nes.debugger();
   debugger.verbose(args.verbose);
@@ -47,9 +74,31 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     debugger.suspend();
   }
 
+  let mut battery_ram = nes.export_prg_ram();
+  let mut last_flush = 0;
+  let flush_interval = BATTERY_FLUSH_INTERVAL_SECONDS * nes.region().cpu_clock();
   while nes.powered_on() {
     nes.tick();
+
+    if nes.has_battery() && nes.cpu_cycles() - last_flush >= flush_interval {
+      flush_battery(&nes, &save_path, &mut battery_ram)?;
+      last_flush = nes.cpu_cycles();
+    }
   }
 
+  if nes.has_battery() {
+    flush_battery(&nes, &save_path, &mut battery_ram)?;
+  }
+
+  Ok(())
+}
+
+// Writes PRG RAM to disk if it changed since last time
+fn flush_battery(nes: &Nes, path: &Path, last: &mut Vec<u8>) -> std::io::Result<()> {
+  let ram = nes.export_prg_ram();
+  if ram != *last {
+    std::fs::write(path, &ram)?;
+    *last = ram;
+  }
   Ok(())
 }
@@ -22,6 +22,8 @@ pub struct SdlHostPlatform<'a> {
   texture: Texture<'a>,
   _creator: TextureCreator<WindowContext>,
   time: Instant,
+  // Player 2's events come out of the same pump as player 1's
+  joypad_2_events: Vec<JoypadEvent>,
 }
 
 impl SdlHostPlatform<'_> {
@@ -56,6 +58,7 @@ impl SdlHostPlatform<'_> {
       canvas,
       texture,
       time: Instant::now(),
+      joypad_2_events: Vec::new(),
     }
   }
 }
@@ -73,10 +76,14 @@ impl HostPlatform for SdlHostPlatform<'_> {
 
   fn poll_events(&mut self, joypad: &mut Joypad) -> Shutdown {
     for event in self.context.event_pump().unwrap().poll_iter() {
-      if let Some(joypad_ev) = map_joypad(&event) {
+      if let Some(joypad_ev) = map_joypad(&event, map_button) {
         joypad.on_event(joypad_ev);
         continue;
       }
+      if let Some(joypad_ev) = map_joypad(&event, map_button_2) {
+        self.joypad_2_events.push(joypad_ev);
+        continue;
+      }
 
       match event {
         Event::Quit { .. }
@@ -98,6 +105,12 @@ impl HostPlatform for SdlHostPlatform<'_> {
     Shutdown::No
   }
 
+  fn poll_joypad_2(&mut self, joypad: &mut Joypad) {
+    for event in self.joypad_2_events.drain(..) {
+      joypad.on_event(event);
+    }
+  }
+
   fn elapsed_millis(&self) -> usize {
     self.time.elapsed().as_millis() as usize
   }
@@ -108,16 +121,16 @@ impl HostPlatform for SdlHostPlatform<'_> {
   }
 }
 
-fn map_joypad(sdlev: &Event) -> Option<JoypadEvent> {
+fn map_joypad(sdlev: &Event, map: fn(&Keycode) -> Option<JoypadButton>) -> Option<JoypadEvent> {
   match sdlev {
     Event::KeyDown {
       keycode: Some(keycode),
       ..
-    } => map_button(keycode).map(JoypadEvent::Press),
+    } => map(keycode).map(JoypadEvent::Press),
     Event::KeyUp {
       keycode: Some(keycode),
       ..
-    } => map_button(keycode).map(JoypadEvent::Release),
+    } => map(keycode).map(JoypadEvent::Release),
     _ => None,
   }
 }
@@ -135,3 +148,17 @@ fn map_button(keycode: &Keycode) -> Option<JoypadButton> {
     _ => None,
   }
 }
+
+fn map_button_2(keycode: &Keycode) -> Option<JoypadButton> {
+  match keycode {
+    Keycode::Up => Some(JoypadButton::UP),
+    Keycode::Left => Some(JoypadButton::LEFT),
+    Keycode::Down => Some(JoypadButton::DOWN),
+    Keycode::Right => Some(JoypadButton::RIGHT),
+    Keycode::Comma => Some(JoypadButton::B),
+    Keycode::Period => Some(JoypadButton::A),
+    Keycode::RShift => Some(JoypadButton::START),
+    Keycode::RCtrl => Some(JoypadButton::SELECT),
+    _ => None,
+  }
+}
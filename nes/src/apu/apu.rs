@@ -0,0 +1,276 @@
+use alloc::rc::Rc;
+use core::cell::RefCell;
+
+use super::dmc::Dmc;
+use super::frame_counter::FrameCounter;
+use super::frame_counter::FrameEvent;
+use super::noise::Noise;
+use super::pulse::Pulse;
+use super::pulse::PulseChannel;
+use super::triangle::Triangle;
+use crate::audio::Resampler;
+use crate::mappers::Mapper;
+use crate::region::Region;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+pub struct Apu {
+  pulse1: Pulse,
+  pulse2: Pulse,
+  triangle: Triangle,
+  noise: Noise,
+  dmc: Dmc,
+  // For expansion audio
+  rom_mapper: Rc<RefCell<dyn Mapper>>,
+  frame_counter: FrameCounter,
+  odd_cycle: bool,
+
+  // https://www.nesdev.org/wiki/APU_Mixer#Lookup_Table
+  pulse_table: [f32; 31],
+  tnd_table: [f32; 203],
+
+  // None if the host doesn't want audio
+  resampler: Option<Resampler>,
+  cpu_clock: usize,
+}
+
+impl Apu {
+  pub fn new(rom_mapper: Rc<RefCell<dyn Mapper>>, region: Region) -> Self {
+    let mut pulse_table = [0.0; 31];
+    for (n, p) in pulse_table.iter_mut().enumerate().skip(1) {
+      *p = 95.52 / (8128.0 / n as f32 + 100.0);
+    }
+
+    let mut tnd_table = [0.0; 203];
+    for (n, t) in tnd_table.iter_mut().enumerate().skip(1) {
+      *t = 163.67 / (24329.0 / n as f32 + 100.0);
+    }
+
+    Self {
+      pulse1: Pulse::new(PulseChannel::One),
+      pulse2: Pulse::new(PulseChannel::Two),
+      triangle: Triangle::default(),
+      noise: Noise::new(region),
+      dmc: Dmc::new(rom_mapper.clone(), region),
+      rom_mapper,
+      frame_counter: FrameCounter::new(region),
+      odd_cycle: false,
+      pulse_table,
+      tnd_table,
+      resampler: None,
+      cpu_clock: region.cpu_clock(),
+    }
+  }
+
+  pub fn set_sample_rate(&mut self, sample_rate: Option<usize>) {
+    self.resampler = sample_rate.map(|rate| Resampler::new(self.cpu_clock, rate));
+  }
+
+  pub fn cpu_read_register(&mut self, address: u16) -> u8 {
+    if address != 0x15 {
+      // Everything but $4015 is write-only
+      return 0;
+    }
+
+    let mut status = 0;
+    if self.pulse1.length.active() {
+      status |= 0x01;
+    }
+    if self.pulse2.length.active() {
+      status |= 0x02;
+    }
+    if self.triangle.length.active() {
+      status |= 0x04;
+    }
+    if self.noise.length.active() {
+      status |= 0x08;
+    }
+    if self.dmc.active() {
+      status |= 0x10;
+    }
+    if self.frame_counter.irq() {
+      status |= 0x40;
+    }
+    if self.dmc.irq() {
+      status |= 0x80;
+    }
+
+    // Reading clears the frame interrupt flag (but not the DMC interrupt flag).
+    self.frame_counter.acknowledge_irq();
+    status
+  }
+
+  pub fn cpu_write_register(&mut self, val: u8, address: u16) {
+    let register = address & 0x03;
+    match address {
+      0x00..=0x03 => self.pulse1.write(val, register),
+      0x04..=0x07 => self.pulse2.write(val, register),
+      0x08..=0x0b => self.triangle.write(val, register),
+      0x0c..=0x0f => self.noise.write(val, register),
+      0x10..=0x13 => self.dmc.write(val, register),
+      0x15 => {
+        // ---D NT21
+        self.pulse1.length.set_enabled(val & 0x01 != 0);
+        self.pulse2.length.set_enabled(val & 0x02 != 0);
+        self.triangle.length.set_enabled(val & 0x04 != 0);
+        self.noise.length.set_enabled(val & 0x08 != 0);
+        self.dmc.set_enabled(val & 0x10 != 0);
+      }
+      0x17 => {
+        let event = self.frame_counter.write(val);
+        self.clock_frame_event(event);
+      }
+      _ => (),
+    }
+  }
+
+  pub fn tick(&mut self, cpu_cycles: usize) {
+    for _ in 0..cpu_cycles {
+      let event = self.frame_counter.tick();
+      self.clock_frame_event(event);
+
+      self.triangle.clock_timer();
+      self.noise.clock_timer();
+      self.dmc.clock_timer();
+
+      // Pulse timers are clocked every APU cycle
+      if self.odd_cycle {
+        self.pulse1.clock_timer();
+        self.pulse2.clock_timer();
+      }
+      self.odd_cycle = !self.odd_cycle;
+
+      if self.resampler.is_some() {
+        let output = self.mix();
+        if let Some(resampler) = &mut self.resampler {
+          resampler.push(output);
+        }
+      }
+    }
+  }
+
+  fn clock_frame_event(&mut self, event: FrameEvent) {
+    if event == FrameEvent::Nothing {
+      return;
+    }
+
+    self.pulse1.clock_quarter_frame();
+    self.pulse2.clock_quarter_frame();
+    self.triangle.clock_quarter_frame();
+    self.noise.clock_quarter_frame();
+
+    if event == FrameEvent::HalfFrame {
+      self.pulse1.clock_half_frame();
+      self.pulse2.clock_half_frame();
+      self.triangle.clock_half_frame();
+      self.noise.clock_half_frame();
+    }
+  }
+
+  fn mix(&self) -> f32 {
+    let pulse = self.pulse1.output() + self.pulse2.output();
+    let tnd = 3 * self.triangle.output() as usize
+      + 2 * self.noise.output() as usize
+      + self.dmc.output() as usize;
+    let expansion = self.rom_mapper.borrow().audio_output();
+    (self.pulse_table[pulse as usize] + self.tnd_table[tnd] + expansion).clamp(0.0, 1.0)
+  }
+
+  // Mixed output, 0.0..=1.0, at the rate given to set_sample_rate.
+  pub fn drain_samples(&mut self) -> impl Iterator<Item = f32> + '_ {
+    self.resampler.iter_mut().flat_map(|r| r.drain())
+  }
+
+  pub fn irq(&self) -> bool {
+    self.frame_counter.irq() || self.dmc.irq()
+  }
+
+  // The resampler is host output, not machine state, and is left alone.
+  pub fn save_state(&self, w: &mut StateWriter) {
+    self.pulse1.save_state(w);
+    self.pulse2.save_state(w);
+    self.triangle.save_state(w);
+    self.noise.save_state(w);
+    self.dmc.save_state(w);
+    self.frame_counter.save_state(w);
+    w.write_bool(self.odd_cycle);
+  }
+
+  pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.pulse1.load_state(r)?;
+    self.pulse2.load_state(r)?;
+    self.triangle.load_state(r)?;
+    self.noise.load_state(r)?;
+    self.dmc.load_state(r)?;
+    self.frame_counter.load_state(r)?;
+    self.odd_cycle = r.read_bool()?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use mos6502::memory::Bus;
+
+  struct TestBus {}
+
+  impl Mapper for TestBus {}
+
+  impl Bus for TestBus {
+    fn read8(&self, _: u16) -> u8 {
+      0xaa
+    }
+
+    fn write8(&mut self, _: u8, _: u16) {}
+  }
+
+  fn sut() -> Apu {
+    Apu::new(Rc::new(RefCell::new(TestBus {})), Region::Ntsc)
+  }
+
+  #[test]
+  fn status_length_counters() {
+    let mut apu = sut();
+    apu.cpu_write_register(0x0f, 0x15);
+    apu.cpu_write_register(0x08, 0x03); // Pulse 1 length index 1
+    apu.cpu_write_register(0x08, 0x0f); // Noise length index 1
+    assert_eq!(apu.cpu_read_register(0x15), 0x09);
+
+    apu.cpu_write_register(0x00, 0x15);
+    assert_eq!(apu.cpu_read_register(0x15), 0x00);
+  }
+
+  #[test]
+  fn frame_irq_cleared_on_status_read() {
+    let mut apu = sut();
+    apu.tick(29829);
+    assert!(apu.irq());
+    assert_eq!(apu.cpu_read_register(0x15) & 0x40, 0x40);
+    assert!(!apu.irq());
+  }
+
+  #[test]
+  fn dmc_irq_when_sample_done() {
+    let mut apu = sut();
+    apu.cpu_write_register(0x80, 0x10); // IRQ enabled, no loop
+    apu.cpu_write_register(0x00, 0x13); // 1 byte sample
+    apu.cpu_write_register(0x10, 0x15);
+    // The single byte is fetched immediately
+    assert!(apu.irq());
+    assert_eq!(apu.cpu_read_register(0x15) & 0x90, 0x80);
+  }
+
+  #[test]
+  fn produces_samples() {
+    let mut apu = sut();
+    apu.tick(Region::Ntsc.cpu_clock() / 60);
+    assert_eq!(apu.drain_samples().count(), 0);
+
+    apu.set_sample_rate(Some(44_100));
+    apu.tick(Region::Ntsc.cpu_clock() / 60);
+    assert!((734..=735).contains(&apu.drain_samples().count()));
+    assert_eq!(apu.drain_samples().count(), 0);
+  }
+}
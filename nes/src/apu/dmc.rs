@@ -0,0 +1,211 @@
+use alloc::rc::Rc;
+use core::cell::RefCell;
+
+use crate::mappers::Mapper;
+use crate::region::Region;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+// https://www.nesdev.org/wiki/APU_DMC
+// In CPU cycles
+const NTSC_RATES: [u16; 16] = [
+  428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+const PAL_RATES: [u16; 16] = [
+  398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+pub(crate) struct Dmc {
+  rom_mapper: Rc<RefCell<dyn Mapper>>,
+
+  irq_enabled: bool,
+  irq: bool,
+  looping: bool,
+  timer_period: u16,
+  timer: u16,
+
+  // Output unit
+  output_level: u8, // 7 bits
+  shift_register: u8,
+  bits_remaining: u8,
+  silence: bool,
+
+  // Memory reader
+  sample_address: u16,
+  sample_length: u16,
+  current_address: u16,
+  bytes_remaining: u16,
+  sample_buffer: Option<u8>,
+
+  rates: &'static [u16; 16],
+}
+
+impl Dmc {
+  pub fn new(rom_mapper: Rc<RefCell<dyn Mapper>>, region: Region) -> Self {
+    let rates = match region {
+      Region::Pal => &PAL_RATES,
+      Region::Ntsc | Region::Dendy => &NTSC_RATES,
+    };
+    Self {
+      rom_mapper,
+      irq_enabled: false,
+      irq: false,
+      looping: false,
+      timer_period: rates[0],
+      timer: 0,
+      output_level: 0,
+      shift_register: 0,
+      bits_remaining: 8,
+      silence: true,
+      sample_address: 0xc000,
+      sample_length: 1,
+      current_address: 0xc000,
+      bytes_remaining: 0,
+      sample_buffer: None,
+      rates,
+    }
+  }
+
+  pub fn write(&mut self, val: u8, register: u16) {
+    match register {
+      0 => {
+        // IL-- RRRR
+        self.irq_enabled = val & 0x80 != 0;
+        if !self.irq_enabled {
+          self.irq = false;
+        }
+        self.looping = val & 0x40 != 0;
+        self.timer_period = self.rates[(val & 0x0f) as usize];
+      }
+      1 => self.output_level = val & 0x7f,
+      2 => self.sample_address = 0xc000 | ((val as u16) << 6),
+      3 => self.sample_length = ((val as u16) << 4) | 1,
+      _ => unreachable!(),
+    }
+  }
+
+  pub fn set_enabled(&mut self, enabled: bool) {
+    self.irq = false;
+    if !enabled {
+      self.bytes_remaining = 0;
+    } else if self.bytes_remaining == 0 {
+      self.restart();
+      self.fill_sample_buffer();
+    }
+  }
+
+  fn restart(&mut self) {
+    self.current_address = self.sample_address;
+    self.bytes_remaining = self.sample_length;
+  }
+
+  fn fill_sample_buffer(&mut self) {
+    if self.sample_buffer.is_some() || self.bytes_remaining == 0 {
+      return;
+    }
+
+    // TODO: The CPU is stalled for up to 4 cycles while the sample is fetched.
+    self.sample_buffer = Some(self.rom_mapper.borrow().read8(self.current_address));
+    self.current_address = match self.current_address {
+      0xffff => 0x8000,
+      a => a + 1,
+    };
+    self.bytes_remaining -= 1;
+
+    if self.bytes_remaining == 0 {
+      if self.looping {
+        self.restart();
+      } else if self.irq_enabled {
+        self.irq = true;
+      }
+    }
+  }
+
+  // Every CPU cycle
+  pub fn clock_timer(&mut self) {
+    if self.timer == 0 {
+      self.timer = self.timer_period - 1;
+      self.clock_output_unit();
+    } else {
+      self.timer -= 1;
+    }
+  }
+
+  fn clock_output_unit(&mut self) {
+    if !self.silence {
+      if self.shift_register & 1 == 1 {
+        if self.output_level <= 125 {
+          self.output_level += 2;
+        }
+      } else if self.output_level >= 2 {
+        self.output_level -= 2;
+      }
+    }
+    self.shift_register >>= 1;
+    self.bits_remaining -= 1;
+
+    if self.bits_remaining == 0 {
+      // New output cycle
+      self.bits_remaining = 8;
+      match self.sample_buffer.take() {
+        Some(sample) => {
+          self.silence = false;
+          self.shift_register = sample;
+        }
+        None => self.silence = true,
+      }
+      self.fill_sample_buffer();
+    }
+  }
+
+  pub fn active(&self) -> bool {
+    self.bytes_remaining > 0
+  }
+
+  pub fn irq(&self) -> bool {
+    self.irq
+  }
+
+  pub fn output(&self) -> u8 {
+    self.output_level
+  }
+
+  pub fn save_state(&self, w: &mut StateWriter) {
+    w.write_bool(self.irq_enabled);
+    w.write_bool(self.irq);
+    w.write_bool(self.looping);
+    w.write_u16(self.timer_period);
+    w.write_u16(self.timer);
+    w.write_u8(self.output_level);
+    w.write_u8(self.shift_register);
+    w.write_u8(self.bits_remaining);
+    w.write_bool(self.silence);
+    w.write_u16(self.sample_address);
+    w.write_u16(self.sample_length);
+    w.write_u16(self.current_address);
+    w.write_u16(self.bytes_remaining);
+    w.write_bool(self.sample_buffer.is_some());
+    w.write_u8(self.sample_buffer.unwrap_or(0));
+  }
+
+  pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.irq_enabled = r.read_bool()?;
+    self.irq = r.read_bool()?;
+    self.looping = r.read_bool()?;
+    self.timer_period = r.read_u16()?.max(1);
+    self.timer = r.read_u16()?;
+    self.output_level = r.read_u8()? & 0x7f;
+    self.shift_register = r.read_u8()?;
+    self.bits_remaining = r.read_u8()?.clamp(1, 8);
+    self.silence = r.read_bool()?;
+    self.sample_address = r.read_u16()?;
+    self.sample_length = r.read_u16()?;
+    self.current_address = r.read_u16()?;
+    self.bytes_remaining = r.read_u16()?;
+    let has_sample = r.read_bool()?;
+    let sample = r.read_u8()?;
+    self.sample_buffer = has_sample.then_some(sample);
+    Ok(())
+  }
+}
@@ -0,0 +1,72 @@
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+// https://www.nesdev.org/wiki/APU_Envelope
+#[derive(Default)]
+pub(crate) struct Envelope {
+  start: bool,
+  looping: bool,
+  constant_volume: bool,
+  volume: u8, // Also the divider period
+  divider: u8,
+  decay: u8,
+}
+
+impl Envelope {
+  // --LC VVVV
+  pub fn write(&mut self, val: u8) {
+    self.looping = val & 0x20 != 0;
+    self.constant_volume = val & 0x10 != 0;
+    self.volume = val & 0x0f;
+  }
+
+  pub fn restart(&mut self) {
+    self.start = true;
+  }
+
+  // Quarter frame
+  pub fn clock(&mut self) {
+    if self.start {
+      self.start = false;
+      self.decay = 15;
+      self.divider = self.volume;
+    } else if self.divider == 0 {
+      self.divider = self.volume;
+      if self.decay > 0 {
+        self.decay -= 1;
+      } else if self.looping {
+        self.decay = 15;
+      }
+    } else {
+      self.divider -= 1;
+    }
+  }
+
+  pub fn output(&self) -> u8 {
+    if self.constant_volume {
+      self.volume
+    } else {
+      self.decay
+    }
+  }
+
+  pub fn save_state(&self, w: &mut StateWriter) {
+    w.write_bool(self.start);
+    w.write_bool(self.looping);
+    w.write_bool(self.constant_volume);
+    w.write_u8(self.volume);
+    w.write_u8(self.divider);
+    w.write_u8(self.decay);
+  }
+
+  pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.start = r.read_bool()?;
+    self.looping = r.read_bool()?;
+    self.constant_volume = r.read_bool()?;
+    self.volume = r.read_u8()?;
+    self.divider = r.read_u8()?;
+    self.decay = r.read_u8()?;
+    Ok(())
+  }
+}
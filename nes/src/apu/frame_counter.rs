@@ -0,0 +1,171 @@
+use crate::region::Region;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+// https://www.nesdev.org/wiki/APU_Frame_Counter
+// Step timings are in CPU cycles (the wiki lists them in APU cycles, x.5).
+const NTSC_STEPS: [usize; 5] = [7457, 14913, 22371, 29829, 37281];
+const PAL_STEPS: [usize; 5] = [8313, 16627, 24939, 33253, 41565];
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Mode {
+  FourStep,
+  FiveStep,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum FrameEvent {
+  Nothing,
+  QuarterFrame,
+  HalfFrame, // Clocks the quarter frame units as well
+}
+
+pub(crate) struct FrameCounter {
+  mode: Mode,
+  irq_inhibit: bool,
+  irq: bool,
+  cycle: usize,
+  steps: [usize; 5],
+}
+
+impl FrameCounter {
+  pub fn new(region: Region) -> Self {
+    Self {
+      mode: Mode::FourStep,
+      irq_inhibit: false,
+      irq: false,
+      cycle: 0,
+      steps: match region {
+        Region::Pal => PAL_STEPS,
+        Region::Ntsc | Region::Dendy => NTSC_STEPS,
+      },
+    }
+  }
+
+  // $4017: MI-- ----
+  pub fn write(&mut self, val: u8) -> FrameEvent {
+    self.mode = if val & 0x80 != 0 {
+      Mode::FiveStep
+    } else {
+      Mode::FourStep
+    };
+
+    self.irq_inhibit = val & 0x40 != 0;
+    if self.irq_inhibit {
+      self.irq = false;
+    }
+
+    // TODO: The reset actually happens 3 or 4 CPU cycles after the write.
+    self.cycle = 0;
+
+    // Writing with the 5-step mode bit set immediately clocks all units.
+    match self.mode {
+      Mode::FiveStep => FrameEvent::HalfFrame,
+      Mode::FourStep => FrameEvent::Nothing,
+    }
+  }
+
+  pub fn tick(&mut self) -> FrameEvent {
+    self.cycle += 1;
+
+    let step = self.steps.iter().position(|&s| s == self.cycle);
+    match (self.mode, step) {
+      (_, Some(0 | 2)) => FrameEvent::QuarterFrame,
+      (_, Some(1)) => FrameEvent::HalfFrame,
+      (Mode::FourStep, Some(3)) => {
+        if !self.irq_inhibit {
+          self.irq = true;
+        }
+        self.cycle = 0;
+        FrameEvent::HalfFrame
+      }
+      (Mode::FiveStep, Some(4)) => {
+        self.cycle = 0;
+        FrameEvent::HalfFrame
+      }
+      _ => FrameEvent::Nothing,
+    }
+  }
+
+  pub fn irq(&self) -> bool {
+    self.irq
+  }
+
+  pub fn acknowledge_irq(&mut self) {
+    self.irq = false;
+  }
+
+  pub fn save_state(&self, w: &mut StateWriter) {
+    w.write_bool(self.mode == Mode::FiveStep);
+    w.write_bool(self.irq_inhibit);
+    w.write_bool(self.irq);
+    w.write_usize(self.cycle);
+  }
+
+  pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.mode = if r.read_bool()? {
+      Mode::FiveStep
+    } else {
+      Mode::FourStep
+    };
+    self.irq_inhibit = r.read_bool()?;
+    self.irq = r.read_bool()?;
+    self.cycle = r.read_usize()?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::FrameCounter;
+  use super::FrameEvent;
+  use crate::region::Region;
+
+  fn run(fc: &mut FrameCounter, cycles: usize) -> (usize, usize) {
+    let (mut quarters, mut halves) = (0, 0);
+    for _ in 0..cycles {
+      match fc.tick() {
+        FrameEvent::QuarterFrame => quarters += 1,
+        FrameEvent::HalfFrame => halves += 1,
+        FrameEvent::Nothing => (),
+      }
+    }
+    (quarters, halves)
+  }
+
+  #[test]
+  fn four_step_sequence_sets_irq() {
+    let mut fc = FrameCounter::new(Region::Ntsc);
+    assert_eq!(run(&mut fc, 29829), (2, 2));
+    assert!(fc.irq());
+
+    fc.acknowledge_irq();
+    assert!(!fc.irq());
+  }
+
+  #[test]
+  fn five_step_sequence_no_irq() {
+    let mut fc = FrameCounter::new(Region::Ntsc);
+    assert_eq!(fc.write(0x80), FrameEvent::HalfFrame);
+    assert_eq!(run(&mut fc, 37281), (2, 2));
+    assert!(!fc.irq());
+  }
+
+  #[test]
+  fn irq_inhibit() {
+    let mut fc = FrameCounter::new(Region::Ntsc);
+    fc.write(0x40);
+    run(&mut fc, 29829);
+    assert!(!fc.irq());
+  }
+
+  #[test]
+  fn pal_sequence_is_longer() {
+    let mut fc = FrameCounter::new(Region::Pal);
+    assert_eq!(run(&mut fc, 29829), (2, 1));
+    assert!(!fc.irq());
+    run(&mut fc, 33253 - 29829);
+    assert!(fc.irq());
+  }
+}
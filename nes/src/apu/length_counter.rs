@@ -0,0 +1,93 @@
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+// https://www.nesdev.org/wiki/APU_Length_Counter
+const LENGTH_TABLE: [u8; 32] = [
+  10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+  192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+#[derive(Default)]
+pub(crate) struct LengthCounter {
+  enabled: bool,
+  halt: bool,
+  counter: u8,
+}
+
+impl LengthCounter {
+  pub fn set_enabled(&mut self, enabled: bool) {
+    self.enabled = enabled;
+    if !enabled {
+      self.counter = 0;
+    }
+  }
+
+  pub fn set_halt(&mut self, halt: bool) {
+    self.halt = halt;
+  }
+
+  pub fn load(&mut self, index: u8) {
+    // Writes are ignored while the channel is disabled in $4015
+    if self.enabled {
+      self.counter = LENGTH_TABLE[(index & 0x1f) as usize];
+    }
+  }
+
+  // Half frame
+  pub fn clock(&mut self) {
+    if !self.halt && self.counter > 0 {
+      self.counter -= 1;
+    }
+  }
+
+  pub fn active(&self) -> bool {
+    self.counter > 0
+  }
+
+  pub fn save_state(&self, w: &mut StateWriter) {
+    w.write_bool(self.enabled);
+    w.write_bool(self.halt);
+    w.write_u8(self.counter);
+  }
+
+  pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.enabled = r.read_bool()?;
+    self.halt = r.read_bool()?;
+    self.counter = r.read_u8()?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::LengthCounter;
+
+  #[test]
+  fn load_ignored_when_disabled() {
+    let mut lc = LengthCounter::default();
+    lc.load(0);
+    assert!(!lc.active());
+
+    lc.set_enabled(true);
+    lc.load(3); // 2
+    assert!(lc.active());
+    lc.clock();
+    lc.clock();
+    assert!(!lc.active());
+  }
+
+  #[test]
+  fn halt() {
+    let mut lc = LengthCounter::default();
+    lc.set_enabled(true);
+    lc.set_halt(true);
+    lc.load(3);
+    lc.clock();
+    lc.clock();
+    assert!(lc.active());
+
+    lc.set_enabled(false);
+    assert!(!lc.active());
+  }
+}
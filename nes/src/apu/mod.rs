@@ -0,0 +1,10 @@
+mod dmc;
+mod envelope;
+mod frame_counter;
+mod length_counter;
+mod noise;
+mod pulse;
+mod triangle;
+
+#[allow(clippy::module_inception)]
+pub(crate) mod apu;
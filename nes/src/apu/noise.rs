@@ -0,0 +1,112 @@
+use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
+use crate::region::Region;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+// https://www.nesdev.org/wiki/APU_Noise
+// In CPU cycles
+const NTSC_PERIODS: [u16; 16] = [
+  4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+const PAL_PERIODS: [u16; 16] = [
+  4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+pub(crate) struct Noise {
+  pub(crate) length: LengthCounter,
+  envelope: Envelope,
+  mode: bool,
+  shift_register: u16, // 15 bits
+  timer_period: u16,
+  timer: u16,
+  periods: &'static [u16; 16],
+}
+
+impl Noise {
+  pub fn new(region: Region) -> Self {
+    let periods = match region {
+      Region::Pal => &PAL_PERIODS,
+      Region::Ntsc | Region::Dendy => &NTSC_PERIODS,
+    };
+    Self {
+      length: LengthCounter::default(),
+      envelope: Envelope::default(),
+      mode: false,
+      // On power-up, the shift register is loaded with the value 1.
+      shift_register: 1,
+      timer_period: periods[0],
+      timer: 0,
+      periods,
+    }
+  }
+
+  pub fn write(&mut self, val: u8, register: u16) {
+    match register {
+      0 => {
+        // --LC VVVV
+        self.length.set_halt(val & 0x20 != 0);
+        self.envelope.write(val);
+      }
+      1 => (), // Unused
+      2 => {
+        // M--- PPPP
+        self.mode = val & 0x80 != 0;
+        self.timer_period = self.periods[(val & 0x0f) as usize];
+      }
+      3 => {
+        self.length.load(val >> 3);
+        self.envelope.restart();
+      }
+      _ => unreachable!(),
+    }
+  }
+
+  // Every CPU cycle
+  pub fn clock_timer(&mut self) {
+    if self.timer == 0 {
+      self.timer = self.timer_period - 1;
+      let tap = if self.mode { 6 } else { 1 };
+      let feedback = (self.shift_register & 1) ^ ((self.shift_register >> tap) & 1);
+      self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+    } else {
+      self.timer -= 1;
+    }
+  }
+
+  pub fn clock_quarter_frame(&mut self) {
+    self.envelope.clock();
+  }
+
+  pub fn clock_half_frame(&mut self) {
+    self.length.clock();
+  }
+
+  pub fn output(&self) -> u8 {
+    if self.shift_register & 1 == 1 || !self.length.active() {
+      0
+    } else {
+      self.envelope.output()
+    }
+  }
+
+  pub fn save_state(&self, w: &mut StateWriter) {
+    self.length.save_state(w);
+    self.envelope.save_state(w);
+    w.write_bool(self.mode);
+    w.write_u16(self.shift_register);
+    w.write_u16(self.timer_period);
+    w.write_u16(self.timer);
+  }
+
+  pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.length.load_state(r)?;
+    self.envelope.load_state(r)?;
+    self.mode = r.read_bool()?;
+    self.shift_register = r.read_u16()?;
+    self.timer_period = r.read_u16()?.max(1);
+    self.timer = r.read_u16()?;
+    Ok(())
+  }
+}
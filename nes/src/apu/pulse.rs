@@ -0,0 +1,175 @@
+use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+// https://www.nesdev.org/wiki/APU_Pulse
+const DUTY_TABLE: [[u8; 8]; 4] = [
+  [0, 1, 0, 0, 0, 0, 0, 0], // 12.5%
+  [0, 1, 1, 0, 0, 0, 0, 0], // 25%
+  [0, 1, 1, 1, 1, 0, 0, 0], // 50%
+  [1, 0, 0, 1, 1, 1, 1, 1], // 25% negated
+];
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub(crate) enum PulseChannel {
+  One,
+  Two,
+}
+
+pub(crate) struct Pulse {
+  channel: PulseChannel,
+  pub(crate) length: LengthCounter,
+  envelope: Envelope,
+
+  duty: u8,
+  sequence: u8,
+  timer_period: u16, // 11 bits
+  timer: u16,
+
+  sweep_enabled: bool,
+  sweep_period: u8,
+  sweep_negate: bool,
+  sweep_shift: u8,
+  sweep_reload: bool,
+  sweep_divider: u8,
+}
+
+impl Pulse {
+  pub fn new(channel: PulseChannel) -> Self {
+    Self {
+      channel,
+      length: LengthCounter::default(),
+      envelope: Envelope::default(),
+      duty: 0,
+      sequence: 0,
+      timer_period: 0,
+      timer: 0,
+      sweep_enabled: false,
+      sweep_period: 0,
+      sweep_negate: false,
+      sweep_shift: 0,
+      sweep_reload: false,
+      sweep_divider: 0,
+    }
+  }
+
+  pub fn write(&mut self, val: u8, register: u16) {
+    match register {
+      0 => {
+        // DDLC VVVV
+        self.duty = val >> 6;
+        self.length.set_halt(val & 0x20 != 0);
+        self.envelope.write(val);
+      }
+      1 => {
+        // EPPP NSSS
+        self.sweep_enabled = val & 0x80 != 0;
+        self.sweep_period = (val >> 4) & 0x7;
+        self.sweep_negate = val & 0x08 != 0;
+        self.sweep_shift = val & 0x7;
+        self.sweep_reload = true;
+      }
+      2 => self.timer_period = (self.timer_period & 0x700) | val as u16,
+      3 => {
+        // LLLL LTTT
+        self.timer_period = (self.timer_period & 0xff) | ((val as u16 & 0x7) << 8);
+        self.length.load(val >> 3);
+        self.sequence = 0;
+        self.envelope.restart();
+      }
+      _ => unreachable!(),
+    }
+  }
+
+  // Every APU cycle (every second CPU cycle)
+  pub fn clock_timer(&mut self) {
+    if self.timer == 0 {
+      self.timer = self.timer_period;
+      self.sequence = (self.sequence + 1) & 0x7;
+    } else {
+      self.timer -= 1;
+    }
+  }
+
+  pub fn clock_quarter_frame(&mut self) {
+    self.envelope.clock();
+  }
+
+  pub fn clock_half_frame(&mut self) {
+    self.length.clock();
+
+    // https://www.nesdev.org/wiki/APU_Sweep
+    if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+      self.timer_period = self.sweep_target_period();
+    }
+
+    if self.sweep_divider == 0 || self.sweep_reload {
+      self.sweep_divider = self.sweep_period;
+      self.sweep_reload = false;
+    } else {
+      self.sweep_divider -= 1;
+    }
+  }
+
+  fn sweep_target_period(&self) -> u16 {
+    let change = self.timer_period >> self.sweep_shift;
+    if self.sweep_negate {
+      // Pulse 1 adds the ones' complement, pulse 2 the two's complement.
+      match self.channel {
+        PulseChannel::One => self.timer_period.saturating_sub(change + 1),
+        PulseChannel::Two => self.timer_period.saturating_sub(change),
+      }
+    } else {
+      self.timer_period + change
+    }
+  }
+
+  fn muted(&self) -> bool {
+    // The sweep unit mutes the channel even when it's disabled.
+    self.timer_period < 8 || self.sweep_target_period() > 0x7ff
+  }
+
+  pub fn output(&self) -> u8 {
+    if self.muted()
+      || !self.length.active()
+      || DUTY_TABLE[self.duty as usize][self.sequence as usize] == 0
+    {
+      0
+    } else {
+      self.envelope.output()
+    }
+  }
+
+  pub fn save_state(&self, w: &mut StateWriter) {
+    self.length.save_state(w);
+    self.envelope.save_state(w);
+    w.write_u8(self.duty);
+    w.write_u8(self.sequence);
+    w.write_u16(self.timer_period);
+    w.write_u16(self.timer);
+    w.write_bool(self.sweep_enabled);
+    w.write_u8(self.sweep_period);
+    w.write_bool(self.sweep_negate);
+    w.write_u8(self.sweep_shift);
+    w.write_bool(self.sweep_reload);
+    w.write_u8(self.sweep_divider);
+  }
+
+  pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.length.load_state(r)?;
+    self.envelope.load_state(r)?;
+    self.duty = r.read_u8()? & 0x3;
+    self.sequence = r.read_u8()? & 0x7;
+    self.timer_period = r.read_u16()?;
+    self.timer = r.read_u16()?;
+    self.sweep_enabled = r.read_bool()?;
+    self.sweep_period = r.read_u8()?;
+    self.sweep_negate = r.read_bool()?;
+    self.sweep_shift = r.read_u8()? & 0x7;
+    self.sweep_reload = r.read_bool()?;
+    self.sweep_divider = r.read_u8()?;
+    Ok(())
+  }
+}
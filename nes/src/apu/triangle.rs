@@ -0,0 +1,99 @@
+use super::length_counter::LengthCounter;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+// https://www.nesdev.org/wiki/APU_Triangle
+const SEQUENCE: [u8; 32] = [
+  15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+  13, 14, 15,
+];
+
+#[derive(Default)]
+pub(crate) struct Triangle {
+  pub(crate) length: LengthCounter,
+  control: bool, // Also the length counter halt flag
+  linear_counter: u8,
+  linear_reload_value: u8,
+  linear_reload: bool,
+  sequence: u8,
+  timer_period: u16,
+  timer: u16,
+}
+
+impl Triangle {
+  pub fn write(&mut self, val: u8, register: u16) {
+    match register {
+      0 => {
+        // CRRR RRRR
+        self.control = val & 0x80 != 0;
+        self.length.set_halt(self.control);
+        self.linear_reload_value = val & 0x7f;
+      }
+      1 => (), // Unused
+      2 => self.timer_period = (self.timer_period & 0x700) | val as u16,
+      3 => {
+        self.timer_period = (self.timer_period & 0xff) | ((val as u16 & 0x7) << 8);
+        self.length.load(val >> 3);
+        self.linear_reload = true;
+      }
+      _ => unreachable!(),
+    }
+  }
+
+  // Every CPU cycle
+  pub fn clock_timer(&mut self) {
+    if self.timer == 0 {
+      self.timer = self.timer_period;
+      if self.length.active() && self.linear_counter > 0 {
+        self.sequence = (self.sequence + 1) & 0x1f;
+      }
+    } else {
+      self.timer -= 1;
+    }
+  }
+
+  pub fn clock_quarter_frame(&mut self) {
+    if self.linear_reload {
+      self.linear_counter = self.linear_reload_value;
+    } else if self.linear_counter > 0 {
+      self.linear_counter -= 1;
+    }
+
+    if !self.control {
+      self.linear_reload = false;
+    }
+  }
+
+  pub fn clock_half_frame(&mut self) {
+    self.length.clock();
+  }
+
+  pub fn output(&self) -> u8 {
+    // Silencing by halting the sequencer means it keeps outputting its last value.
+    SEQUENCE[self.sequence as usize]
+  }
+
+  pub fn save_state(&self, w: &mut StateWriter) {
+    self.length.save_state(w);
+    w.write_bool(self.control);
+    w.write_u8(self.linear_counter);
+    w.write_u8(self.linear_reload_value);
+    w.write_bool(self.linear_reload);
+    w.write_u8(self.sequence);
+    w.write_u16(self.timer_period);
+    w.write_u16(self.timer);
+  }
+
+  pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.length.load_state(r)?;
+    self.control = r.read_bool()?;
+    self.linear_counter = r.read_u8()?;
+    self.linear_reload_value = r.read_u8()?;
+    self.linear_reload = r.read_bool()?;
+    self.sequence = r.read_u8()? & 0x1f;
+    self.timer_period = r.read_u16()?;
+    self.timer = r.read_u16()?;
+    Ok(())
+  }
+}
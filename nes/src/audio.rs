@@ -0,0 +1,156 @@
+use alloc::vec::Vec;
+
+use crate::nes::HostPlatform;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum AudioChannels {
+  #[default]
+  Mono,
+  Stereo, // Interleaved L/R, the NES is mono so both carry the same signal
+}
+
+impl AudioChannels {
+  pub fn count(&self) -> usize {
+    match self {
+      AudioChannels::Mono => 1,
+      AudioChannels::Stereo => 2,
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct AudioSpec {
+  pub sample_rate: usize,
+  pub buffer_size: usize, // In sample frames, i.e. one sample per channel
+  pub channels: AudioChannels,
+}
+
+impl Default for AudioSpec {
+  fn default() -> Self {
+    Self {
+      sample_rate: 44_100,
+      buffer_size: 735, // ~1 NTSC frame
+      channels: AudioChannels::Mono,
+    }
+  }
+}
+
+// Downsamples the APU output (one value per CPU cycle) to the host sample rate
+// by averaging everything mixed since the last output sample.
+pub(crate) struct Resampler {
+  cycles_per_sample: f32,
+  clock: f32,
+  sum: f32,
+  n: usize,
+  samples: Vec<f32>,
+  max_samples: usize,
+}
+
+impl Resampler {
+  pub fn new(cpu_clock: usize, sample_rate: usize) -> Self {
+    Self {
+      cycles_per_sample: cpu_clock as f32 / sample_rate as f32,
+      clock: 0.0,
+      sum: 0.0,
+      n: 0,
+      samples: Vec::with_capacity(sample_rate / 50),
+      // Samples are dropped if no one consumes them.
+      max_samples: sample_rate,
+    }
+  }
+
+  pub fn push(&mut self, value: f32) {
+    self.sum += value;
+    self.n += 1;
+    self.clock += 1.0;
+
+    if self.clock >= self.cycles_per_sample {
+      self.clock -= self.cycles_per_sample;
+      if self.samples.len() < self.max_samples {
+        self.samples.push(self.sum / self.n as f32);
+      }
+      self.sum = 0.0;
+      self.n = 0;
+    }
+  }
+
+  pub fn drain(&mut self) -> impl Iterator<Item = f32> + '_ {
+    self.samples.drain(..)
+  }
+}
+
+// Chops resampled audio into buffers of the size the host asked for.
+pub(crate) struct AudioOutput {
+  spec: AudioSpec,
+  buf: Vec<f32>,
+}
+
+impl AudioOutput {
+  pub fn new(spec: AudioSpec) -> Self {
+    Self {
+      spec,
+      buf: Vec::with_capacity(spec.buffer_size * spec.channels.count() * 2),
+    }
+  }
+
+  pub fn push(&mut self, samples: impl Iterator<Item = f32>) {
+    for sample in samples {
+      // The mixer outputs 0.0..=1.0, hosts want it centered around 0.
+      let pcm = sample * 2.0 - 1.0;
+      for _ in 0..self.spec.channels.count() {
+        self.buf.push(pcm);
+      }
+    }
+  }
+
+  pub fn flush(&mut self, host: &mut dyn HostPlatform) {
+    let len = self.spec.buffer_size * self.spec.channels.count();
+    if len == 0 {
+      return;
+    }
+
+    while self.buf.len() >= len {
+      host.play_audio(&self.buf[..len]);
+      self.buf.drain(..len);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn resampler_rate() {
+    let mut r = Resampler::new(1000, 100);
+    for _ in 0..1000 {
+      r.push(1.0);
+    }
+    let samples: Vec<f32> = r.drain().collect();
+    assert_eq!(samples.len(), 100);
+    assert!(samples.iter().all(|&s| s == 1.0));
+    assert_eq!(r.drain().count(), 0);
+  }
+
+  #[test]
+  fn resampler_averages() {
+    let mut r = Resampler::new(4, 1);
+    r.push(0.0);
+    r.push(1.0);
+    r.push(0.0);
+    r.push(1.0);
+    assert_eq!(r.drain().collect::<Vec<f32>>(), [0.5]);
+  }
+
+  #[test]
+  fn output_interleaves() {
+    let spec = AudioSpec {
+      sample_rate: 4,
+      buffer_size: 2,
+      channels: AudioChannels::Stereo,
+    };
+    let mut out = AudioOutput::new(spec);
+    out.push([0.0, 1.0, 0.5].into_iter());
+    assert_eq!(out.buf, [-1.0, -1.0, 1.0, 1.0, 0.0, 0.0]);
+  }
+}
@@ -6,11 +6,19 @@ use core::ops::Range;
 use common::kilobytes;
 
 use self::error::CartridgeError;
+use crate::gamedb;
+use crate::region::Region;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
 
 pub const MAGIC: [u8; 4] = [0x4e, 0x45, 0x53, 0x1a];
 pub const HEADER_SIZE: usize = 16;
 const PRG_ROM_BLOCK_SIZE: usize = kilobytes::KB16;
 const CHR_ROM_BLOCK_SIZE: usize = kilobytes::KB8;
+const TRAINER_SIZE: usize = 512;
+// $7000-$71FF
+const TRAINER_PRG_RAM_OFFSET: usize = 0x1000;
 
 pub mod error {
   use alloc::string::String;
@@ -40,18 +48,87 @@ pub mod error {
   }
 }
 
-#[derive(Debug)]
-#[allow(dead_code)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Format {
+  Nes2,
+  Ines,
+}
+
+// CPU/PPU timing
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Timing {
+  Ntsc,
+  Pal,
+  MultiRegion,
+  Dendy,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ConsoleType {
+  Nes,
+  VsSystem { ppu: u8, hardware: u8 },
+  Playchoice10,
+  Extended(u8),
+}
+
+// https://www.nesdev.org/wiki/NES_2.0#Default_Expansion_Device
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ExpansionDevice {
+  Unspecified,
+  StandardControllers,
+  FourScore,
+  FamicomFourPlayersAdapter,
+  VsSystem,
+  Zapper,
+  TwoZappers,
+  PowerPadSideA,
+  PowerPadSideB,
+  ArkanoidNes,
+  ArkanoidFamicom,
+  Other(u8),
+}
+
+impl From<u8> for ExpansionDevice {
+  fn from(id: u8) -> Self {
+    match id {
+      0x00 => ExpansionDevice::Unspecified,
+      0x01 => ExpansionDevice::StandardControllers,
+      0x02 => ExpansionDevice::FourScore,
+      0x03 => ExpansionDevice::FamicomFourPlayersAdapter,
+      0x04 => ExpansionDevice::VsSystem,
+      0x08 => ExpansionDevice::Zapper,
+      0x09 => ExpansionDevice::TwoZappers,
+      0x0b => ExpansionDevice::PowerPadSideA,
+      0x0c => ExpansionDevice::PowerPadSideB,
+      0x0f => ExpansionDevice::ArkanoidNes,
+      0x10 => ExpansionDevice::ArkanoidFamicom,
+      _ => ExpansionDevice::Other(id),
+    }
+  }
+}
+
+// https://www.nesdev.org/wiki/INES
+// https://www.nesdev.org/wiki/NES_2.0
+// Sizes are in bytes. iNES headers are mapped to the same fields, with the defaults
+// an iNES cartridge is assumed to have.
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Header {
-  magic: [u8; 4],
-  prg_rom_blocks: u8,
-  chr_rom_blocks: u8,
-  flags6: u8,
-  flags7: u8,
-  flags8: u8,
-  flags9: u8,
-  flags10: u8,
-  padding: [u8; 5],
+  pub format: Format,
+  pub mapper: u16,
+  pub submapper: u8,
+  pub prg_rom_size: usize,
+  pub chr_rom_size: usize,
+  pub prg_ram_size: usize,
+  pub prg_nvram_size: usize,
+  pub chr_ram_size: usize,
+  pub chr_nvram_size: usize,
+  pub mirroring: Mirroring, // Hardwired, mapper may override
+  pub battery: bool,
+  pub trainer: bool,
+  pub timing: Timing,
+  pub console_type: ConsoleType,
+  pub misc_roms: u8,
+  pub expansion_device: ExpansionDevice,
 }
 
 impl Header {
@@ -60,38 +137,155 @@ impl Header {
       return Err(CartridgeError::InvalidCartridge("strange size"));
     }
 
-    let magic = &bin[0..4];
-    if magic != MAGIC {
+    if bin[0..4] != MAGIC {
       return Err(CartridgeError::InvalidCartridge("magic"));
     }
 
+    let flags6 = bin[6];
+    let flags7 = bin[7];
+
+    let mirroring = if flags6 & 0b1000 != 0 {
+      Mirroring::HardwiredFourScreen
+    } else if flags6 & 1 == 1 {
+      Mirroring::Vertical
+    } else {
+      Mirroring::Horizontal
+    };
+    let battery = flags6 & 0b10 != 0;
+    let trainer = flags6 & 0b100 != 0;
+
+    let console_type = match flags7 & 0b11 {
+      0 => ConsoleType::Nes,
+      1 => ConsoleType::VsSystem {
+        ppu: bin[13] & 0x0f,
+        hardware: bin[13] >> 4,
+      },
+      2 => ConsoleType::Playchoice10,
+      _ => ConsoleType::Extended(bin[13] & 0x0f),
+    };
+
+    if (flags7 & 0x0c) != 0x08 {
+      return Ok(Self::parse_ines(
+        bin,
+        mirroring,
+        battery,
+        trainer,
+        console_type,
+      ));
+    }
+
+    let prg_rom_size = Self::rom_size(bin[4], bin[9] & 0x0f, PRG_ROM_BLOCK_SIZE);
+    let chr_rom_size = Self::rom_size(bin[5], bin[9] >> 4, CHR_ROM_BLOCK_SIZE);
+
+    let timing = match bin[12] & 0b11 {
+      0 => Timing::Ntsc,
+      1 => Timing::Pal,
+      2 => Timing::MultiRegion,
+      _ => Timing::Dendy,
+    };
+
     Ok(Header {
-      magic: magic
-        .try_into()
-        .map_err(|_| CartridgeError::InvalidCartridge("magic 2"))?,
-      prg_rom_blocks: bin[4],
-      chr_rom_blocks: bin[5],
-      flags6: bin[6],
-      flags7: bin[7],
-      flags8: bin[8],
-      flags9: bin[9],
-      flags10: bin[10],
-      padding: bin[11..16]
-        .try_into()
-        .map_err(|_| CartridgeError::InvalidCartridge("padding"))?,
+      format: Format::Nes2,
+      mapper: (((bin[8] & 0x0f) as u16) << 8) | (flags7 & 0xf0) as u16 | (flags6 >> 4) as u16,
+      submapper: bin[8] >> 4,
+      prg_rom_size,
+      chr_rom_size,
+      prg_ram_size: Self::ram_size(bin[10] & 0x0f),
+      prg_nvram_size: Self::ram_size(bin[10] >> 4),
+      chr_ram_size: Self::ram_size(bin[11] & 0x0f),
+      chr_nvram_size: Self::ram_size(bin[11] >> 4),
+      mirroring,
+      battery,
+      trainer,
+      timing,
+      console_type,
+      misc_roms: bin[14] & 0b11,
+      expansion_device: (bin[15] & 0x3f).into(),
     })
   }
 
-  pub fn total_size_excluding_header(&self) -> usize {
-    (self.prg_rom_blocks as usize * PRG_ROM_BLOCK_SIZE)
-      + (self.chr_rom_blocks as usize * CHR_ROM_BLOCK_SIZE)
+  fn parse_ines(
+    bin: &[u8],
+    mirroring: Mirroring,
+    battery: bool,
+    trainer: bool,
+    console_type: ConsoleType,
+  ) -> Header {
+    // Bytes 7-15 of old headers sometimes have junk in them ("DiskDude!"),
+    // only trust byte 7 if the padding is clean.
+    let clean = bin[12..16].iter().all(|&b| b == 0);
+    let flags7 = if clean { bin[7] } else { 0 };
+
+    // "Size of PRG RAM in 8 KB units (Value 0 infers 8 KB for compatibility)"
+    let prg_ram_size = bin[8].max(1) as usize * kilobytes::KB8;
+    let (prg_ram_size, prg_nvram_size) = if battery {
+      (0, prg_ram_size)
+    } else {
+      (prg_ram_size, 0)
+    };
+
+    let chr_rom_size = bin[5] as usize * CHR_ROM_BLOCK_SIZE;
+
+    Header {
+      format: Format::Ines,
+      mapper: ((flags7 & 0xf0) | (bin[6] >> 4)) as u16,
+      submapper: 0,
+      prg_rom_size: bin[4] as usize * PRG_ROM_BLOCK_SIZE,
+      chr_rom_size,
+      prg_ram_size,
+      prg_nvram_size,
+      chr_ram_size: if chr_rom_size == 0 {
+        CHR_ROM_BLOCK_SIZE
+      } else {
+        0
+      },
+      chr_nvram_size: 0,
+      mirroring,
+      battery,
+      trainer,
+      timing: if clean && bin[9] & 1 == 1 {
+        Timing::Pal
+      } else {
+        Timing::Ntsc
+      },
+      console_type: if clean {
+        console_type
+      } else {
+        ConsoleType::Nes
+      },
+      misc_roms: 0,
+      expansion_device: ExpansionDevice::Unspecified,
+    }
   }
-}
 
-#[derive(Debug, PartialEq, Eq)]
-enum Format {
-  Nes2,
-  Ines,
+  // https://www.nesdev.org/wiki/NES_2.0#PRG-ROM_Area
+  fn rom_size(lsb: u8, msb: u8, block_size: usize) -> usize {
+    if msb == 0x0f {
+      // Exponent-multiplier notation, EEEEEEMM: 2^E * (MM*2+1)
+      let exponent = (lsb >> 2) as u32;
+      let multiplier = (lsb & 0b11) as usize * 2 + 1;
+      2usize.saturating_pow(exponent).saturating_mul(multiplier)
+    } else {
+      (((msb as usize) << 8) | lsb as usize) * block_size
+    }
+  }
+
+  // "If the shift count is zero, there is no [RAM]. If the shift count is non-zero,
+  // the actual size is 64 << shift count bytes"
+  fn ram_size(shift: u8) -> usize {
+    if shift == 0 {
+      0
+    } else {
+      64 << shift
+    }
+  }
+
+  pub fn total_size_excluding_header(&self) -> usize {
+    let trainer_size = if self.trainer { TRAINER_SIZE } else { 0 };
+    trainer_size
+      .saturating_add(self.prg_rom_size)
+      .saturating_add(self.chr_rom_size)
+  }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -101,20 +295,58 @@ pub enum MapperType {
   Uxrom = 2,
   Cnrom = 3,
   Mmc3 = 4,
+  Mmc5 = 5,
+  Axrom = 7,
+  Mmc2 = 9,
+  Mmc4 = 10,
+  ColorDreams = 11,
+  N163 = 19,
+  Vrc4ac = 21,
+  Vrc2a = 22,
+  Vrc2b4ef = 23,
+  Vrc6a = 24,
+  Vrc4bd2c = 25,
+  Vrc6b = 26,
+  Bnrom = 34,
+  Gxrom = 66,
+  Fme7 = 69,
+  Camerica = 71,
+  Vrc7 = 85,
+  Namco108 = 206,
 }
 
 impl TryFrom<&Header> for MapperType {
   type Error = CartridgeError;
 
   fn try_from(header: &Header) -> Result<Self, Self::Error> {
-    let id: u8 = (header.flags7 & 0xf0) | header.flags6 >> 4;
-    match id {
+    match header.mapper {
       0 => Ok(MapperType::Nrom),
       1 => Ok(MapperType::Mmc1),
       2 => Ok(MapperType::Uxrom),
       3 => Ok(MapperType::Cnrom),
       4 => Ok(MapperType::Mmc3),
-      _ => Err(CartridgeError::NotYetImplemented(format!("Mapper {}", id))),
+      5 => Ok(MapperType::Mmc5),
+      7 => Ok(MapperType::Axrom),
+      9 => Ok(MapperType::Mmc2),
+      10 => Ok(MapperType::Mmc4),
+      11 => Ok(MapperType::ColorDreams),
+      19 => Ok(MapperType::N163),
+      21 => Ok(MapperType::Vrc4ac),
+      22 => Ok(MapperType::Vrc2a),
+      23 => Ok(MapperType::Vrc2b4ef),
+      24 => Ok(MapperType::Vrc6a),
+      25 => Ok(MapperType::Vrc4bd2c),
+      26 => Ok(MapperType::Vrc6b),
+      34 => Ok(MapperType::Bnrom),
+      66 => Ok(MapperType::Gxrom),
+      69 => Ok(MapperType::Fme7),
+      71 => Ok(MapperType::Camerica),
+      85 => Ok(MapperType::Vrc7),
+      206 => Ok(MapperType::Namco108),
+      _ => Err(CartridgeError::NotYetImplemented(format!(
+        "Mapper {}",
+        header.mapper
+      ))),
     }
   }
 }
@@ -126,8 +358,26 @@ pub enum Mirroring {
   HardwiredFourScreen,
   SingleScreenUpper,
   SingleScreenLower,
+  // Set by the mapper, one entry per $2000/$2400/$2800/$2C00
+  Custom([Nametable; 4]),
 }
 
+// Where a 1 KB nametable is fetched from
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Nametable {
+  // The console's 2 KB VRAM (CIRAM), page 0 or 1
+  Ciram(u8),
+  // The extra 2 KB on four-screen boards, page 0 or 1
+  CartridgeRam(u8),
+  // A 1 KB CHR ROM bank, read only. Read through Mapper::read_nametable
+  ChrRom(u16),
+  // Mapper-owned memory, e.g. MMC5 ExRAM. Read through Mapper::read_nametable
+  Mapper(u8),
+  // Every tile is `tile`, every attribute is `attribute` (2 bits)
+  Fill { tile: u8, attribute: u8 },
+}
+
+#[allow(clippy::len_without_is_empty)]
 pub trait Rom {
   fn len(&self) -> usize;
   fn get(&self) -> &[u8];
@@ -160,15 +410,18 @@ impl Rom for EmbeddedRom {
 #[derive(PartialEq, Eq)]
 pub struct Cartridge<R: Rom> {
   rom: R,
-  mirroring: Mirroring,
+  header: Header,
   prg: Range<usize>,
   chr: Range<usize>,
-  chr_ram: Option<Box<[u8; CHR_ROM_BLOCK_SIZE]>>,
-  // PRG RAM is optional for some mappers, but 8kb is wastable.
-  // It's also used by some test ROMs anyways.
-  prg_ram: Box<[u8; kilobytes::KB8]>,
+  chr_ram: Option<Box<[u8]>>,
+  prg_ram: Box<[u8]>,
+  // The other 2 KB of nametables on four-screen boards
+  nametable_ram: Box<[u8]>,
   mapper: MapperType,
-  format: Format,
+  bus_conflicts: Option<bool>,
+  region: Option<Region>,
+  // The header as it was, if the game database corrected it
+  original_header: Option<Header>,
 }
 
 impl Cartridge<HeapRom> {
@@ -191,81 +444,113 @@ impl Cartridge<EmbeddedRom> {
 
 impl<R: Rom> Cartridge<R> {
   pub fn load(rom: R) -> Result<Cartridge<R>, CartridgeError> {
+    Self::load_with_db(rom, gamedb::lookup)
+  }
+
+  fn load_with_db(
+    rom: R,
+    lookup: fn(u32) -> Option<&'static gamedb::Entry>,
+  ) -> Result<Cartridge<R>, CartridgeError> {
     let bin = rom.get();
     if bin.len() < HEADER_SIZE + PRG_ROM_BLOCK_SIZE || bin[0..4] != MAGIC {
       return Err(CartridgeError::InvalidCartridge("strange size"));
     }
 
-    let header = Header::parse(bin)?;
-    if header.magic != MAGIC {
-      return Err(CartridgeError::InvalidCartridge("magic"));
-    }
+    let mut header = Header::parse(bin)?;
 
-    let format = if (header.flags7 & 0x0c) == 0x08 {
-      Format::Nes2
+    // The trainer sits between the header and PRG ROM
+    let trainer_start = HEADER_SIZE;
+    let prg_start = if header.trainer {
+      trainer_start + TRAINER_SIZE
     } else {
-      Format::Ines
+      trainer_start
     };
-    // if format == Format::Nes2 {
-    // return Err(CartridgeError::NotYetImplemented("NES 2.0".into()));
-    // }
-
-    let mapper = MapperType::try_from(&header)?;
-
-    let skip_trainer = header.flags6 & 0b100 != 0;
-    if skip_trainer || (header.flags6 & (1 << 3)) != 0 {
-      return Err(CartridgeError::NotYetImplemented("Trainer".into()));
+    // Exponent-multiplier sizes go up to usize::MAX
+    let prg_end = prg_start
+      .checked_add(header.prg_rom_size)
+      .ok_or(CartridgeError::InvalidCartridge("prg rom size"))?;
+    let chr_start = prg_end;
+    let chr_end = chr_start
+      .checked_add(header.chr_rom_size)
+      .ok_or(CartridgeError::InvalidCartridge("chr rom size"))?;
+    if bin.len() < chr_end {
+      return Err(CartridgeError::InvalidCartridge("truncated"));
+    }
+    // NES 2.0 sizes go below iNES' 16 KB unit, but mappers bank in whole 16 KB
+    // (or 8 KB) banks and need at least 16 KB of PRG ROM to fill $8000-$FFFF
+    if header.prg_rom_size < PRG_ROM_BLOCK_SIZE {
+      return Err(CartridgeError::InvalidCartridge("prg rom size"));
     }
 
-    // if header.flags6 & 0b10 != 0 {
-    //   return Err(CartridgeError::NotYetImplemented("Cartridge contains battery-backed PRG RAM ($6000-7FFF) or other persistent memory".into()));
-    // }
-
-    if header.flags6 & 0b1000 != 0 {
-      return Err(CartridgeError::NotYetImplemented(
-        "cartidge fiddles w VRAM address space..".into(),
-      ));
+    // NES 2.0 headers are trusted, iNES ones are too often wrong
+    let mut original_header = None;
+    if header.format == Format::Ines {
+      if let Some(entry) = lookup(common::hash::crc32(&bin[prg_start..chr_end])) {
+        let original = header.clone();
+        if entry.correct(&mut header) {
+          original_header = Some(original);
+        }
+      }
     }
+    let mapper = MapperType::try_from(&header)?;
 
-    let mut mirroring = match header.flags6 & 1 {
-      1 => Mirroring::Vertical,
-      _ => Mirroring::Horizontal,
+    // Mappers always map at least 8kb of RAM, even if the header says less.
+    // PRG RAM is optional for some mappers, but 8kb is wastable.
+    // It's also used by some test ROMs anyways.
+    // Only MMC5 and FME-7 bank it, the rest see 8kb at $6000-$7FFF and get no more
+    // (MMC1 SOROM/SXROM's banked RAM isn't supported).
+    let prg_ram_size = match mapper {
+      MapperType::Mmc5 | MapperType::Fme7 => {
+        (header.prg_ram_size + header.prg_nvram_size).max(kilobytes::KB8)
+      }
+      _ => kilobytes::KB8,
     };
-
-    if header.flags6 & 0b1000 != 0 {
-      mirroring = Mirroring::HardwiredFourScreen
+    let mut prg_ram = vec![0; prg_ram_size].into_boxed_slice();
+    if header.trainer {
+      prg_ram[TRAINER_PRG_RAM_OFFSET..TRAINER_PRG_RAM_OFFSET + TRAINER_SIZE]
+        .copy_from_slice(&bin[trainer_start..prg_start]);
     }
 
-    let prg_size = (header.prg_rom_blocks as usize) * PRG_ROM_BLOCK_SIZE;
-    let prg_start = HEADER_SIZE;
-    let prg_end = prg_start + prg_size;
-
-    let uses_chr_ram = header.chr_rom_blocks == 0;
+    let uses_chr_ram = header.chr_rom_size == 0;
+    let chr_ram_size = (header.chr_ram_size + header.chr_nvram_size).max(CHR_ROM_BLOCK_SIZE);
+    let chr_ram = uses_chr_ram.then(|| vec![0; chr_ram_size].into_boxed_slice());
     let chr_range = if uses_chr_ram {
-      0..CHR_ROM_BLOCK_SIZE
+      0..chr_ram_size
     } else {
-      let chr_start = prg_end;
-      let chr_size = (header.chr_rom_blocks as usize) * CHR_ROM_BLOCK_SIZE;
-      let chr_end = chr_start + chr_size;
       chr_start..chr_end
     };
 
-    let chr_ram = uses_chr_ram.then_some(Box::new([0; CHR_ROM_BLOCK_SIZE]));
+    let nametable_ram_size = match header.mirroring {
+      Mirroring::HardwiredFourScreen => kilobytes::KB2,
+      _ => 0,
+    };
 
     Ok(Cartridge {
       prg: prg_start..prg_end,
       chr: chr_range,
       rom,
-      mirroring,
+      header,
       mapper,
-      format,
       chr_ram,
-      prg_ram: Box::new([0; kilobytes::KB8]),
+      prg_ram,
+      nametable_ram: vec![0; nametable_ram_size].into_boxed_slice(),
+      bus_conflicts: None,
+      region: None,
+      original_header,
     })
   }
 
+  pub fn header(&self) -> &Header {
+    &self.header
+  }
+
+  // Set if the header was corrected from the game database on load
+  pub fn original_header(&self) -> Option<&Header> {
+    self.original_header.as_ref()
+  }
+
   pub fn mirroring(&self) -> Mirroring {
-    self.mirroring
+    self.header.mirroring
   }
 
   pub fn prg(&self) -> &[u8] {
@@ -287,16 +572,93 @@ impl<R: Rom> Cartridge<R> {
   }
 
   pub fn prg_ram_mut(&mut self) -> &mut [u8] {
-    &mut self.prg_ram.as_mut()[..]
+    &mut self.prg_ram[..]
   }
 
   pub fn prg_ram(&self) -> &[u8] {
     &self.prg_ram[..]
   }
 
+  pub fn has_battery(&self) -> bool {
+    self.header.battery
+  }
+
+  // For discrete boards whose header doesn't say if the ROM fights the mapper over the
+  // data bus on writes. Takes precedence over the NES 2.0 submapper.
+  pub fn set_bus_conflicts(&mut self, enabled: bool) {
+    self.bus_conflicts = Some(enabled);
+  }
+
+  pub fn bus_conflicts(&self) -> Option<bool> {
+    self.bus_conflicts
+  }
+
+  // Forces the console the game runs on, instead of going by the header's timing
+  pub fn set_region(&mut self, region: Region) {
+    self.region = Some(region);
+  }
+
+  pub fn region(&self) -> Region {
+    self.region.unwrap_or(self.header.timing.into())
+  }
+
+  // Restores PRG RAM, e.g. from a .sav file
+  pub fn import_prg_ram(&mut self, ram: &[u8]) -> Result<(), CartridgeError> {
+    if ram.len() != self.prg_ram.len() {
+      return Err(CartridgeError::InvalidCartridge("prg ram size"));
+    }
+    self.prg_ram.copy_from_slice(ram);
+    Ok(())
+  }
+
   pub fn mapper_type(&self) -> MapperType {
     self.mapper
   }
+
+  // CRC-32 of PRG and CHR ROM, header excluded
+  pub fn crc32(&self) -> u32 {
+    let end = if self.chr_ram.is_some() {
+      self.prg.end
+    } else {
+      self.chr.end
+    };
+    common::hash::crc32(&self.rom.get()[self.prg.start..end])
+  }
+
+  // Nametable::CartridgeRam pages, reads 0 on boards without it
+  pub fn read_nametable_ram(&self, page: u8, offset: u16) -> u8 {
+    self
+      .nametable_ram
+      .get(page as usize * kilobytes::KB1 + offset as usize)
+      .copied()
+      .unwrap_or(0)
+  }
+
+  pub fn write_nametable_ram(&mut self, val: u8, page: u8, offset: u16) {
+    if let Some(byte) = self
+      .nametable_ram
+      .get_mut(page as usize * kilobytes::KB1 + offset as usize)
+    {
+      *byte = val;
+    }
+  }
+
+  pub(crate) fn save_state(&self, w: &mut StateWriter) {
+    w.write_bytes(&self.prg_ram[..]);
+    if let Some(chr_ram) = &self.chr_ram {
+      w.write_bytes(&chr_ram[..]);
+    }
+    w.write_bytes(&self.nametable_ram[..]);
+  }
+
+  pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    r.read_bytes(&mut self.prg_ram[..])?;
+    if let Some(chr_ram) = &mut self.chr_ram {
+      r.read_bytes(&mut chr_ram[..])?;
+    }
+    r.read_bytes(&mut self.nametable_ram[..])?;
+    Ok(())
+  }
 }
 
 impl<R: Rom> Display for Cartridge<R> {
@@ -305,15 +667,94 @@ impl<R: Rom> Display for Cartridge<R> {
     write!(
       f,
       "[{:?}] Mapper: {:?}, Mirroring: {:?}, CHR{}: {}x{}K, PRG: {}x{}K",
-      self.format,
+      self.header.format,
       self.mapper,
-      self.mirroring,
+      self.header.mirroring,
       chr_ram_or_rom,
       self.chr().len() / CHR_ROM_BLOCK_SIZE,
       CHR_ROM_BLOCK_SIZE / 1000,
       self.prg().len() / PRG_ROM_BLOCK_SIZE,
       PRG_ROM_BLOCK_SIZE / 1000
-    )
+    )?;
+
+    let h = &self.header;
+    if h.format == Format::Ines {
+      if h.battery {
+        write!(f, ", Battery")?;
+      }
+      if let Some(original) = &self.original_header {
+        write!(f, ", Corrected: {}", Corrections(original, h))?;
+      }
+      return Ok(());
+    }
+
+    write!(
+      f,
+      ", Submapper: {}, PRG RAM: {}, PRG NVRAM: {}, CHR RAM: {}, CHR NVRAM: {}, Timing: {:?}, Console: {:?}, Expansion: {:?}",
+      h.submapper,
+      Size(h.prg_ram_size),
+      Size(h.prg_nvram_size),
+      Size(h.chr_ram_size),
+      Size(h.chr_nvram_size),
+      h.timing,
+      h.console_type,
+      h.expansion_device
+    )?;
+
+    if h.battery {
+      write!(f, ", Battery")?;
+    }
+    if h.misc_roms > 0 {
+      write!(f, ", Misc ROMs: {}", h.misc_roms)?;
+    }
+    Ok(())
+  }
+}
+
+// What the game database changed, old -> new
+struct Corrections<'a>(&'a Header, &'a Header);
+
+impl Display for Corrections<'_> {
+  fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+    let (old, new) = (self.0, self.1);
+    let mut changes = Vec::new();
+    if old.mapper != new.mapper {
+      changes.push(format!("Mapper {} -> {}", old.mapper, new.mapper));
+    }
+    if old.submapper != new.submapper {
+      changes.push(format!("Submapper {} -> {}", old.submapper, new.submapper));
+    }
+    if old.mirroring != new.mirroring {
+      changes.push(format!(
+        "Mirroring {:?} -> {:?}",
+        old.mirroring, new.mirroring
+      ));
+    }
+    if old.battery != new.battery {
+      changes.push(format!("Battery {} -> {}", old.battery, new.battery));
+    }
+    if old.timing != new.timing {
+      changes.push(format!("Timing {:?} -> {:?}", old.timing, new.timing));
+    }
+    if old.expansion_device != new.expansion_device {
+      changes.push(format!(
+        "Expansion {:?} -> {:?}",
+        old.expansion_device, new.expansion_device
+      ));
+    }
+    write!(f, "{}", changes.join(", "))
+  }
+}
+
+struct Size(usize);
+
+impl Display for Size {
+  fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+    if self.0 < kilobytes::KB1 {
+      write!(f, "{}B", self.0)
+    } else {
+      write!(f, "{}K", self.0 / kilobytes::KB1)
+    }
   }
 }
 
@@ -326,15 +767,16 @@ impl<R: Rom> core::fmt::Debug for Cartridge<R> {
 #[cfg(test)]
 mod tests {
   use alloc::string::ToString;
+  use alloc::vec::Vec;
 
-  use super::Cartridge;
-  use crate::cartridge::EmbeddedRom;
+  use super::*;
 
   fn assert_cart(r: &'static [u8], s: &str) {
     assert_eq!(Cartridge::load(EmbeddedRom(r)).unwrap().to_string(), s);
   }
 
   #[test]
+  #[allow(clippy::byte_char_slices)]
   fn cart_invalid_len() {
     assert!(Cartridge::load(EmbeddedRom(&[b'N', b'E', b'S'])).is_err())
   }
@@ -362,4 +804,214 @@ mod tests {
       "[Ines] Mapper: Nrom, Mirroring: Horizontal, CHR RAM: 1x8K, PRG: 1x16K",
     );
   }
+
+  fn nes2(header: [u8; 12], prg: usize, chr: usize) -> Vec<u8> {
+    let mut rom = MAGIC.to_vec();
+    rom.extend(header);
+    rom.resize(HEADER_SIZE + prg + chr, 0);
+    rom
+  }
+
+  #[test]
+  fn nes2_header() {
+    #[rustfmt::skip]
+    let rom = nes2([
+      0x02, 0x01, // 32K PRG, 8K CHR
+      0x13,       // Mapper low nibble 1, battery, vertical
+      0x08,       // NES 2.0
+      0x31,       // Submapper 3, mapper bits 8-11 = 1
+      0x00,
+      0x70,       // 8K PRG NVRAM
+      0x07,       // 8K CHR RAM
+      0x01,       // PAL
+      0x00, 0x00,
+      0x02,       // Four Score
+    ], 0, 0);
+    let header = Header::parse(&rom).unwrap();
+
+    assert_eq!(header.format, Format::Nes2);
+    assert_eq!(header.mapper, 0x101);
+    assert_eq!(header.submapper, 3);
+    assert_eq!(header.prg_rom_size, kilobytes::KB32);
+    assert_eq!(header.chr_rom_size, kilobytes::KB8);
+    assert_eq!(header.prg_ram_size, 0);
+    assert_eq!(header.prg_nvram_size, kilobytes::KB8);
+    assert_eq!(header.chr_ram_size, kilobytes::KB8);
+    assert_eq!(header.chr_nvram_size, 0);
+    assert_eq!(header.mirroring, Mirroring::Vertical);
+    assert!(header.battery);
+    assert!(!header.trainer);
+    assert_eq!(header.timing, Timing::Pal);
+    assert_eq!(header.console_type, ConsoleType::Nes);
+    assert_eq!(header.expansion_device, ExpansionDevice::FourScore);
+  }
+
+  #[test]
+  fn region_from_timing_or_forced() {
+    let mut rom = nes2(
+      [0x01, 0x00, 0, 0x08, 0, 0, 0, 0, 0x03, 0, 0, 0],
+      kilobytes::KB16,
+      0,
+    );
+    let mut cart = Cartridge::blow_dust_vec(rom.clone()).unwrap();
+    assert_eq!(cart.region(), Region::Dendy);
+    cart.set_region(Region::Pal);
+    assert_eq!(cart.region(), Region::Pal);
+
+    // Multi-region games get NTSC
+    rom[12] = 0x02;
+    assert_eq!(
+      Cartridge::blow_dust_vec(rom).unwrap().region(),
+      Region::Ntsc
+    );
+  }
+
+  #[test]
+  fn nes2_exponent_size() {
+    // 2^2 * (2*2+1)
+    let rom = nes2([0x0a, 0x00, 0, 0x08, 0, 0x0f, 0, 0, 0, 0, 0, 0], 0, 0);
+    assert_eq!(Header::parse(&rom).unwrap().prg_rom_size, 20);
+  }
+
+  #[test]
+  fn nes2_exponent_size_overflow() {
+    // 2^63 * 1, for both PRG and CHR
+    let rom = nes2(
+      [0xfc, 0xfc, 0, 0x08, 0, 0xff, 0, 0, 0, 0, 0, 0],
+      kilobytes::KB16,
+      0,
+    );
+    assert_eq!(Header::parse(&rom).unwrap().prg_rom_size, 1 << 63);
+    assert!(matches!(
+      Cartridge::blow_dust_vec(rom),
+      Err(CartridgeError::InvalidCartridge(_))
+    ));
+  }
+
+  #[test]
+  fn prg_rom_under_16kb() {
+    // UxROM and Camerica with 8K PRG, 2^13 * 1
+    for (flags6, flags7) in [(0x20, 0x08), (0x70, 0x48)] {
+      let rom = nes2(
+        [0x34, 0x01, flags6, flags7, 0, 0x0f, 0, 0, 0, 0, 0, 0],
+        kilobytes::KB8,
+        kilobytes::KB8,
+      );
+      assert!(matches!(
+        Cartridge::blow_dust_vec(rom),
+        Err(CartridgeError::InvalidCartridge("prg rom size"))
+      ));
+    }
+  }
+
+  #[test]
+  fn prg_ram_banked_by_mmc5() {
+    // 32K PRG RAM
+    let rom = nes2(
+      [0x02, 0x01, 0x50, 0x08, 0, 0, 0x09, 0, 0, 0, 0, 0],
+      kilobytes::KB32,
+      kilobytes::KB8,
+    );
+    let cart = Cartridge::blow_dust_vec(rom).unwrap();
+    assert_eq!(cart.mapper_type(), MapperType::Mmc5);
+    assert_eq!(cart.prg_ram().len(), kilobytes::KB32);
+  }
+
+  #[test]
+  fn ines_ignores_junk_padding() {
+    let mut rom = MAGIC.to_vec();
+    rom.extend([0x01, 0x01, 0x10]);
+    rom.extend(b"DiskDude!");
+    let header = Header::parse(&rom).unwrap();
+    assert_eq!(header.format, Format::Ines);
+    assert_eq!(header.mapper, 1);
+    assert_eq!(header.console_type, ConsoleType::Nes);
+  }
+
+  #[test]
+  fn nes2_ram_sizes() {
+    #[rustfmt::skip]
+    let rom = nes2([
+      0x01, 0x00, 0x10, 0x08, 0x00, 0x00,
+      0x99, // 32K PRG RAM + 32K PRG NVRAM
+      0x09, // 32K CHR RAM
+      0x00, 0x00, 0x00, 0x01,
+    ], kilobytes::KB16, 0);
+    let mut cart = Cartridge::blow_dust_vec(rom).unwrap();
+    // MMC1 doesn't bank it, the header's sizes are still reported below
+    assert_eq!(cart.prg_ram().len(), kilobytes::KB8);
+    assert_eq!(cart.chr_ram().len(), kilobytes::KB32);
+    assert_eq!(
+      cart.to_string(),
+      "[Nes2] Mapper: Mmc1, Mirroring: Horizontal, CHR RAM: 4x8K, PRG: 1x16K, Submapper: 0, \
+       PRG RAM: 32K, PRG NVRAM: 32K, CHR RAM: 32K, CHR NVRAM: 0B, Timing: Ntsc, Console: Nes, \
+       Expansion: StandardControllers"
+    );
+  }
+
+  #[test]
+  fn ines_corrected_from_database() {
+    fn lookup(_: u32) -> Option<&'static gamedb::Entry> {
+      static ENTRY: gamedb::Entry = gamedb::Entry {
+        crc32: 0,
+        mapper: 2,
+        submapper: None,
+        mirroring: Some(Mirroring::Vertical),
+        battery: true,
+        timing: None,
+        expansion_device: Some(ExpansionDevice::Zapper),
+      };
+      Some(&ENTRY)
+    }
+
+    // Mapper 71, horizontal, no battery
+    let mut rom = MAGIC.to_vec();
+    rom.extend([0x01, 0x00, 0x70, 0x40]);
+    rom.resize(HEADER_SIZE + kilobytes::KB16, 0);
+    let cart = Cartridge::load_with_db(HeapRom(rom.clone()), lookup).unwrap();
+    assert_eq!(cart.mapper_type(), MapperType::Uxrom);
+    assert_eq!(cart.header().prg_nvram_size, kilobytes::KB8);
+    assert_eq!(cart.original_header().unwrap().mapper, 71);
+    assert_eq!(
+      cart.to_string(),
+      "[Ines] Mapper: Uxrom, Mirroring: Vertical, CHR RAM: 1x8K, PRG: 1x16K, Battery, \
+       Corrected: Mapper 71 -> 2, Mirroring Horizontal -> Vertical, Battery false -> true, \
+       Expansion Unspecified -> Zapper"
+    );
+
+    // NES 2.0 headers are left alone
+    rom[7] |= 0x08;
+    let cart = Cartridge::load_with_db(HeapRom(rom), lookup).unwrap();
+    assert_eq!(cart.mapper_type(), MapperType::Camerica);
+    assert!(cart.original_header().is_none());
+  }
+
+  #[test]
+  fn cart_truncated() {
+    let rom = nes2(
+      [0x02, 0x01, 0, 0x08, 0, 0, 0, 0, 0, 0, 0, 0],
+      kilobytes::KB16,
+      0,
+    );
+    assert!(Cartridge::blow_dust_vec(rom).is_err());
+  }
+
+  #[test]
+  fn trainer() {
+    let mut rom = nes2([0x01, 0x01, 0b100, 0x08, 0, 0, 0, 0, 0, 0, 0, 0], 0, 0);
+    rom.extend([0xaa; TRAINER_SIZE]);
+    rom.extend([0xbb; kilobytes::KB16]);
+    rom.extend([0xcc; kilobytes::KB8]);
+
+    let cart = Cartridge::blow_dust_vec(rom).unwrap();
+    assert_eq!(
+      cart.header().total_size_excluding_header(),
+      512 + 0x4000 + 0x2000
+    );
+    assert!(cart.prg().iter().all(|&b| b == 0xbb));
+    assert!(cart.chr().iter().all(|&b| b == 0xcc));
+    assert!(cart.prg_ram()[..0x1000].iter().all(|&b| b == 0));
+    assert!(cart.prg_ram()[0x1000..0x1200].iter().all(|&b| b == 0xaa));
+    assert!(cart.prg_ram()[0x1200..].iter().all(|&b| b == 0));
+  }
 }
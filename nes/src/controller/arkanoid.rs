@@ -0,0 +1,70 @@
+use super::ControllerPortDevice;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+// The Vaus controller that came with Arkanoid. The knob's position is latched
+// on strobe and shifted out inverted, most significant bit first, on D4.
+// https://www.nesdev.org/wiki/Arkanoid_controller
+#[derive(Default)]
+pub struct ArkanoidPaddle {
+  position: u8,
+  button: bool,
+  out: u8,
+}
+
+impl ArkanoidPaddle {
+  // Arkanoid expects roughly 98 (left) to 242 (right)
+  pub fn turn_to(&mut self, position: u8) {
+    self.position = position;
+  }
+
+  pub fn press(&mut self, pressed: bool) {
+    self.button = pressed;
+  }
+}
+
+impl ControllerPortDevice for ArkanoidPaddle {
+  fn strobe(&mut self, val: u8) {
+    if val & 1 == 1 {
+      self.out = !self.position;
+    }
+  }
+
+  // D3 is the button, D4 the position
+  fn read(&mut self) -> u8 {
+    let bit = self.out >> 7;
+    self.out <<= 1;
+    let button = if self.button { 0x08 } else { 0 };
+    button | (bit << 4)
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    w.write_u8(self.out);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.out = r.read_u8()?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+
+  use super::*;
+
+  #[test]
+  fn position_inverted_msb_first() {
+    let mut paddle = ArkanoidPaddle::default();
+    paddle.turn_to(0b1010_0000);
+    paddle.press(true);
+    paddle.strobe(1);
+    paddle.strobe(0);
+    paddle.turn_to(0);
+
+    let reads: Vec<u8> = (0..8).map(|_| paddle.read()).collect();
+    assert_eq!(reads, [0x08, 0x18, 0x08, 0x18, 0x18, 0x18, 0x18, 0x18]);
+  }
+}
@@ -0,0 +1,93 @@
+use alloc::rc::Rc;
+use core::cell::RefCell;
+
+use super::ControllerPortDevice;
+use crate::joypad::Joypad;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+// Four joypads on two ports. Each port shifts out two pads, 8 bits each,
+// then a signature telling the game the adapter is there. 1s after that.
+// https://www.nesdev.org/wiki/Four_Score
+pub struct FourScore {
+  first: Rc<RefCell<Joypad>>,
+  second: Rc<RefCell<Joypad>>,
+  signature: u8,
+  out: u32,
+}
+
+impl FourScore {
+  // Players 1 and 3 on $4016
+  pub(crate) fn port_1(joypad_1: Rc<RefCell<Joypad>>, joypad_3: Rc<RefCell<Joypad>>) -> Self {
+    Self::new(joypad_1, joypad_3, 0b0001_0000)
+  }
+
+  // Players 2 and 4 on $4017
+  pub(crate) fn port_2(joypad_2: Rc<RefCell<Joypad>>, joypad_4: Rc<RefCell<Joypad>>) -> Self {
+    Self::new(joypad_2, joypad_4, 0b0010_0000)
+  }
+
+  fn new(first: Rc<RefCell<Joypad>>, second: Rc<RefCell<Joypad>>, signature: u8) -> Self {
+    Self {
+      first,
+      second,
+      signature,
+      out: 0,
+    }
+  }
+}
+
+impl ControllerPortDevice for FourScore {
+  fn strobe(&mut self, val: u8) {
+    if val & 1 == 1 {
+      self.out = self.first.borrow().buttons().bits() as u32
+        | (self.second.borrow().buttons().bits() as u32) << 8
+        | (self.signature.reverse_bits() as u32) << 16
+        | 0xff00_0000;
+    }
+  }
+
+  fn read(&mut self) -> u8 {
+    let val = (self.out & 1) as u8;
+    self.out = (self.out >> 1) | 0x8000_0000;
+    val
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    w.write_u32(self.out);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.out = r.read_u32()?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+
+  use super::*;
+  use crate::joypad::JoypadButton;
+  use crate::joypad::JoypadEvent;
+
+  #[test]
+  fn players_then_signature() {
+    let pads: Vec<Rc<RefCell<Joypad>>> = (0..2).map(|_| Rc::default()).collect();
+    pads[1]
+      .borrow_mut()
+      .on_event(JoypadEvent::Press(JoypadButton::START));
+    let mut port_2 = FourScore::port_2(pads[0].clone(), pads[1].clone());
+
+    port_2.strobe(1);
+    port_2.strobe(0);
+    let bits: Vec<u8> = (0..26).map(|_| port_2.read()).collect();
+    assert_eq!(bits[..8], [0; 8]);
+    // Player 4's start
+    assert_eq!(bits[8..16], [0, 0, 0, 1, 0, 0, 0, 0]);
+    // $20, most significant bit first
+    assert_eq!(bits[16..24], [0, 0, 1, 0, 0, 0, 0, 0]);
+    assert_eq!(bits[24..], [1, 1]);
+  }
+}
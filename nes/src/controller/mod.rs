@@ -0,0 +1,228 @@
+use alloc::rc::Rc;
+use core::cell::RefCell;
+
+use crate::cartridge::ExpansionDevice;
+use crate::joypad::Joypad;
+use crate::nes::HostPlatform;
+use crate::nes::Shutdown;
+use crate::ppu::ppu::Ppu;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+mod arkanoid;
+mod four_score;
+mod power_pad;
+mod zapper;
+
+pub use arkanoid::ArkanoidPaddle;
+pub use four_score::FourScore;
+pub use power_pad::PowerPad;
+pub use zapper::Zapper;
+
+// Something plugged into a controller port, $4016 for port 1 and $4017 for port 2.
+// https://www.nesdev.org/wiki/Input_devices
+pub trait ControllerPortDevice {
+  // $4016 writes, bit 0 (OUT0) goes to both ports
+  fn strobe(&mut self, val: u8);
+
+  // D0-D4 of the port's register
+  fn read(&mut self) -> u8;
+
+  // Not required for devices that latch nothing
+  fn save_state(&self, _: &mut StateWriter) {}
+  fn load_state(&mut self, _: &mut StateReader) -> Result<(), SaveStateError> {
+    Ok(())
+  }
+}
+
+// What the cartridge wants plugged in besides joypads 1 and 2, for the host to drive.
+// See HostPlatform::poll_peripheral.
+pub enum Peripheral<'a> {
+  // Players 3 and 4
+  FourScore(&'a mut Joypad, &'a mut Joypad),
+  Zapper(&'a mut Zapper),
+  // Vs. System, ports 1 and 2
+  TwoZappers(&'a mut Zapper, &'a mut Zapper),
+  ArkanoidPaddle(&'a mut ArkanoidPaddle),
+  PowerPad(&'a mut PowerPad),
+}
+
+enum Attached {
+  Nothing,
+  FourScore([Rc<RefCell<Joypad>>; 2]),
+  Zapper(Rc<RefCell<Zapper>>),
+  TwoZappers([Rc<RefCell<Zapper>>; 2]),
+  ArkanoidPaddle(Rc<RefCell<ArkanoidPaddle>>),
+  PowerPad(Rc<RefCell<PowerPad>>),
+}
+
+pub(crate) struct Controllers {
+  ports: [Rc<RefCell<dyn ControllerPortDevice>>; 2],
+  joypads: [Rc<RefCell<Joypad>>; 2],
+  attached: Attached,
+}
+
+impl Controllers {
+  // https://www.nesdev.org/wiki/NES_2.0#Default_Expansion_Device
+  // Zappers, paddles and mats go in port 2, like on a real NES.
+  pub fn new(device: ExpansionDevice, ppu: Rc<RefCell<Ppu>>) -> Self {
+    let joypads: [Rc<RefCell<Joypad>>; 2] = Default::default();
+    let mut ports: [Rc<RefCell<dyn ControllerPortDevice>>; 2] =
+      [joypads[0].clone(), joypads[1].clone()];
+
+    let attached = match device {
+      ExpansionDevice::FourScore => {
+        let extra: [Rc<RefCell<Joypad>>; 2] = Default::default();
+        ports = [
+          Rc::new(RefCell::new(FourScore::port_1(
+            joypads[0].clone(),
+            extra[0].clone(),
+          ))),
+          Rc::new(RefCell::new(FourScore::port_2(
+            joypads[1].clone(),
+            extra[1].clone(),
+          ))),
+        ];
+        Attached::FourScore(extra)
+      }
+      ExpansionDevice::Zapper => {
+        let zapper = Rc::new(RefCell::new(Zapper::new(ppu)));
+        ports[1] = zapper.clone();
+        Attached::Zapper(zapper)
+      }
+      ExpansionDevice::TwoZappers => {
+        let zappers = [
+          Rc::new(RefCell::new(Zapper::new(ppu.clone()))),
+          Rc::new(RefCell::new(Zapper::new(ppu))),
+        ];
+        ports = [zappers[0].clone(), zappers[1].clone()];
+        Attached::TwoZappers(zappers)
+      }
+      ExpansionDevice::ArkanoidNes => {
+        let paddle = Rc::new(RefCell::new(ArkanoidPaddle::default()));
+        ports[1] = paddle.clone();
+        Attached::ArkanoidPaddle(paddle)
+      }
+      ExpansionDevice::PowerPadSideA | ExpansionDevice::PowerPadSideB => {
+        let mat = Rc::new(RefCell::new(PowerPad::default()));
+        ports[1] = mat.clone();
+        Attached::PowerPad(mat)
+      }
+      _ => Attached::Nothing,
+    };
+
+    Self {
+      ports,
+      joypads,
+      attached,
+    }
+  }
+
+  pub fn ports(&self) -> [Rc<RefCell<dyn ControllerPortDevice>>; 2] {
+    self.ports.clone()
+  }
+
+  pub fn poll(&self, host: &mut dyn HostPlatform) -> Shutdown {
+    let shutdown = host.poll_events(&mut self.joypads[0].borrow_mut());
+    host.poll_joypad_2(&mut self.joypads[1].borrow_mut());
+
+    match &self.attached {
+      Attached::Nothing => (),
+      Attached::FourScore([joypad_3, joypad_4]) => host.poll_peripheral(Peripheral::FourScore(
+        &mut joypad_3.borrow_mut(),
+        &mut joypad_4.borrow_mut(),
+      )),
+      Attached::Zapper(zapper) => {
+        host.poll_peripheral(Peripheral::Zapper(&mut zapper.borrow_mut()))
+      }
+      Attached::TwoZappers([zapper_1, zapper_2]) => host.poll_peripheral(Peripheral::TwoZappers(
+        &mut zapper_1.borrow_mut(),
+        &mut zapper_2.borrow_mut(),
+      )),
+      Attached::ArkanoidPaddle(paddle) => {
+        host.poll_peripheral(Peripheral::ArkanoidPaddle(&mut paddle.borrow_mut()))
+      }
+      Attached::PowerPad(mat) => host.poll_peripheral(Peripheral::PowerPad(&mut mat.borrow_mut())),
+    }
+    shutdown
+  }
+
+  pub fn save_state(&self, w: &mut StateWriter) {
+    self.ports.iter().for_each(|p| p.borrow().save_state(w));
+  }
+
+  pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    for port in &self.ports {
+      port.borrow_mut().load_state(r)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+
+  use super::*;
+  use crate::cartridge::Mirroring;
+  use crate::frame::PixelFormatRGB888;
+  use crate::frame::RenderFrame;
+  use crate::mappers::Mapper;
+  use crate::region::Region;
+  use mos6502::memory::Bus;
+
+  struct TestBus {}
+
+  impl Mapper for TestBus {}
+
+  impl Bus for TestBus {
+    fn read8(&self, _: u16) -> u8 {
+      0
+    }
+
+    fn write8(&mut self, _: u8, _: u16) {}
+  }
+
+  pub(super) fn ppu() -> Rc<RefCell<Ppu>> {
+    Rc::new(RefCell::new(Ppu::new(
+      Rc::new(RefCell::new(TestBus {})),
+      Mirroring::Horizontal,
+      RenderFrame::new::<PixelFormatRGB888>(),
+      Region::Ntsc,
+    )))
+  }
+
+  fn read(port: &Rc<RefCell<dyn ControllerPortDevice>>, n: usize) -> Vec<u8> {
+    let mut port = port.borrow_mut();
+    port.strobe(1);
+    port.strobe(0);
+    (0..n).map(|_| port.read()).collect()
+  }
+
+  #[test]
+  fn standard_controllers_by_default() {
+    let controllers = Controllers::new(ExpansionDevice::Unspecified, ppu());
+    assert!(matches!(controllers.attached, Attached::Nothing));
+    assert_eq!(read(&controllers.ports[1], 8), [0; 8]);
+  }
+
+  #[test]
+  fn picked_by_expansion_device() {
+    let controllers = Controllers::new(ExpansionDevice::FourScore, ppu());
+    assert!(matches!(controllers.attached, Attached::FourScore(_)));
+    // Four Score signature on the 20th read of $4016
+    assert_eq!(read(&controllers.ports[0], 24)[19], 1);
+
+    let controllers = Controllers::new(ExpansionDevice::Zapper, ppu());
+    assert!(matches!(controllers.attached, Attached::Zapper(_)));
+    // No light, trigger released
+    assert_eq!(controllers.ports[1].borrow_mut().read(), 0x08);
+
+    let controllers = Controllers::new(ExpansionDevice::TwoZappers, ppu());
+    assert!(matches!(controllers.attached, Attached::TwoZappers(_)));
+    for port in &controllers.ports {
+      assert_eq!(port.borrow_mut().read(), 0x08);
+    }
+  }
+}
@@ -0,0 +1,89 @@
+use super::ControllerPortDevice;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+// The order buttons come out on D3 and D4, 1s after that
+// https://www.nesdev.org/wiki/Power_Pad
+const D3_BUTTONS: [u8; 8] = [2, 1, 5, 9, 6, 10, 11, 7];
+const D4_BUTTONS: [u8; 4] = [4, 3, 12, 8];
+
+// A floor mat with 12 buttons, numbered like side B. Side A only
+// hides some of them behind different pictures.
+#[derive(Default)]
+pub struct PowerPad {
+  // Bit n is button n + 1
+  buttons: u16,
+  d3: u16,
+  d4: u16,
+}
+
+impl PowerPad {
+  // 1-12
+  pub fn step(&mut self, button: u8, pressed: bool) {
+    if (1..=12).contains(&button) {
+      let bit = 1 << (button - 1);
+      if pressed {
+        self.buttons |= bit;
+      } else {
+        self.buttons &= !bit;
+      }
+    }
+  }
+
+  fn serialize(&self, order: &[u8]) -> u16 {
+    let bits = order
+      .iter()
+      .enumerate()
+      .map(|(i, b)| ((self.buttons >> (b - 1)) & 1) << i)
+      .fold(0, |acc, bit| acc | bit);
+    bits | (0xffff << order.len())
+  }
+}
+
+impl ControllerPortDevice for PowerPad {
+  fn strobe(&mut self, val: u8) {
+    if val & 1 == 1 {
+      self.d3 = self.serialize(&D3_BUTTONS);
+      self.d4 = self.serialize(&D4_BUTTONS);
+    }
+  }
+
+  fn read(&mut self) -> u8 {
+    let val = ((self.d3 & 1) << 3 | (self.d4 & 1) << 4) as u8;
+    self.d3 = (self.d3 >> 1) | 0x8000;
+    self.d4 = (self.d4 >> 1) | 0x8000;
+    val
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    w.write_u16(self.d3);
+    w.write_u16(self.d4);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.d3 = r.read_u16()?;
+    self.d4 = r.read_u16()?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+
+  use super::*;
+
+  #[test]
+  fn serial_order() {
+    let mut mat = PowerPad::default();
+    mat.step(1, true);
+    mat.step(12, true);
+    mat.strobe(1);
+    mat.strobe(0);
+
+    let reads: Vec<u8> = (0..9).map(|_| mat.read()).collect();
+    // 1 is second on D3, 12 third on D4
+    assert_eq!(reads, [0, 0x08, 0x10, 0, 0x10, 0x10, 0x10, 0x10, 0x18]);
+  }
+}
@@ -0,0 +1,99 @@
+use alloc::rc::Rc;
+use core::cell::RefCell;
+
+use super::ControllerPortDevice;
+use crate::frame::NES_HEIGHT;
+use crate::frame::NES_WIDTH;
+use crate::ppu::ppu::Ppu;
+
+// The photodiode stays lit for about this many lines after the beam passes
+// https://www.nesdev.org/wiki/Zapper#Light_sensing
+const LIT_SCANLINES: usize = 20;
+
+// White and the palest colors, what the games flash on hit boxes
+const BRIGHT: u8 = 0xc0;
+
+// A light gun, no strobe. It senses light where it's aimed, in the frame
+// being drawn, right after the PPU draws that pixel.
+// https://www.nesdev.org/wiki/Zapper
+pub struct Zapper {
+  ppu: Rc<RefCell<Ppu>>,
+  aim: Option<(usize, usize)>,
+  trigger: bool,
+}
+
+impl Zapper {
+  pub(crate) fn new(ppu: Rc<RefCell<Ppu>>) -> Self {
+    Self {
+      ppu,
+      aim: None,
+      trigger: false,
+    }
+  }
+
+  // A pixel in the 256x240 frame, None when pointed away from the screen
+  pub fn aim(&mut self, pixel: Option<(usize, usize)>) {
+    self.aim = pixel.filter(|&(x, y)| x < NES_WIDTH && y < NES_HEIGHT);
+  }
+
+  pub fn pull_trigger(&mut self, pulled: bool) {
+    self.trigger = pulled;
+  }
+
+  fn senses_light(&self) -> bool {
+    let Some((x, y)) = self.aim else {
+      return false;
+    };
+    let ppu = self.ppu.borrow();
+    let scanline = ppu.scanline();
+    let drawn =
+      (scanline == y && ppu.cycle() >= x) || (scanline > y && scanline <= y + LIT_SCANLINES);
+    drawn && ppu.frame().brightness(x, y) >= BRIGHT
+  }
+}
+
+impl ControllerPortDevice for Zapper {
+  fn strobe(&mut self, _: u8) {}
+
+  // D3 is 0 when light is sensed, D4 is the trigger
+  fn read(&mut self) -> u8 {
+    let light = if self.senses_light() { 0 } else { 0x08 };
+    let trigger = if self.trigger { 0x10 } else { 0 };
+    light | trigger
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::controller::tests::ppu;
+
+  #[test]
+  fn senses_light_behind_the_beam() {
+    let ppu = ppu();
+    let mut zapper = Zapper::new(ppu.clone());
+    zapper.aim(Some((100, 0)));
+    zapper.pull_trigger(true);
+    ppu
+      .borrow_mut()
+      .frame_mut()
+      .set_pixel_xy(100, 0, (0xff, 0xff, 0xff));
+
+    // Not drawn yet this frame
+    assert_eq!(zapper.read(), 0x18);
+
+    ppu.borrow_mut().tick(101);
+    assert_eq!((ppu.borrow().scanline(), ppu.borrow().cycle()), (0, 100));
+    assert_eq!(zapper.read(), 0x10);
+
+    // Dark again once the diode has cooled down
+    ppu.borrow_mut().tick(341 * LIT_SCANLINES);
+    assert_eq!(zapper.read(), 0x10);
+    ppu.borrow_mut().tick(341);
+    assert_eq!(zapper.read(), 0x18);
+
+    zapper.aim(None);
+    zapper.pull_trigger(false);
+    assert_eq!(zapper.read(), 0x08);
+  }
+}
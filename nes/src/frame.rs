@@ -80,6 +80,21 @@ impl RenderFrame {
     (self.set_pixel_fn)(&mut self.buf, i, rgb);
   }
 
+  // 0-255, the average of R, G and B at (x, y). What a light gun sees.
+  pub fn brightness(&self, x: usize, y: usize) -> u8 {
+    let i = ((y * NES_WIDTH) + x) * self.bytes_per_pixel;
+    let (r, g, b) = match &self.buf[i..i + self.bytes_per_pixel] {
+      [hi, lo] => {
+        let p = ((*hi as u16) << 8) | *lo as u16;
+        ((p >> 11) << 3, ((p >> 5) & 0x3f) << 2, (p & 0x1f) << 3)
+      }
+      // RGB888, or RGBA from a host's own SetPixel
+      [r, g, b, ..] => (*r as u16, *g as u16, *b as u16),
+      _ => (0, 0, 0),
+    };
+    ((r + g + b) / 3) as u8
+  }
+
   pub fn replace_buf(&mut self, buf: &[u8]) {
     self.buf = buf.to_vec();
   }
@@ -0,0 +1,131 @@
+use crate::cartridge::ExpansionDevice;
+use crate::cartridge::Header;
+use crate::cartridge::Mirroring;
+use crate::cartridge::Timing;
+
+// Known good header fields for dumps that commonly float around with broken iNES
+// headers, keyed by the CRC-32 of PRG and CHR ROM (see Cartridge::crc32).
+// Entries follow NesCartDB, https://nescartdb.com, and FCEUX's header fixes.
+// Fields that are None are left as the header has them.
+//
+// Scope, for now:
+// - CRC-32 only, no SHA-1. It tells the dumps below apart and keeps the table small.
+// - The entries only fix mapper, mirroring and battery, the bits iNES dumps get wrong
+//   most. Submapper, timing and expansion device are applied when an entry sets them,
+//   but none does yet.
+// - Bus conflicts on UxROM, CNROM and AxROM follow the NES 2.0 submapper (1 none,
+//   2 AND), so an entry setting `submapper` is how a game would override them.
+pub(crate) struct Entry {
+  pub crc32: u32,
+  pub mapper: u16,
+  pub submapper: Option<u8>,
+  // Also None where the mapper controls mirroring and the header bit means nothing
+  pub mirroring: Option<Mirroring>,
+  pub battery: bool,
+  pub timing: Option<Timing>,
+  pub expansion_device: Option<ExpansionDevice>,
+}
+
+const fn game(crc32: u32, mapper: u16, mirroring: Option<Mirroring>, battery: bool) -> Entry {
+  Entry {
+    crc32,
+    mapper,
+    submapper: None,
+    mirroring,
+    battery,
+    timing: None,
+    expansion_device: None,
+  }
+}
+
+const H: Option<Mirroring> = Some(Mirroring::Horizontal);
+const V: Option<Mirroring> = Some(Mirroring::Vertical);
+const MAPPER: Option<Mirroring> = None;
+
+// Sorted by CRC. Add to it from NesCartDB as broken dumps turn up.
+#[rustfmt::skip]
+pub(crate) const GAMES: &[Entry] = &[
+  game(0x1d0f4d6b, 2, V, false),     // Black Bass, The (Japan)
+  game(0x28c11d24, 2, V, false),     // Sukeban Deka III (Japan)
+  game(0x2bb6a0f8, 2, V, false),     // Sherlock Holmes - Hakushaku Reijou Yuukai Jiken (Japan)
+  game(0x3337ec46, 0, V, false),     // Super Mario Bros. (World)
+  game(0x3fe272fb, 1, MAPPER, true), // Legend of Zelda, The (USA)
+  game(0x55773880, 2, V, false),     // Gilligan's Island, The Adventures of (USA)
+  game(0x6d65cac6, 2, H, false),     // Terra Cresta (Japan)
+  game(0x6e0eb43e, 2, V, false),     // Nagagutsu o Haita Neko - Sekai Isshuu 80 Nichi Daibouken (Japan)
+  game(0x804f898a, 2, V, false),     // Dragon Unit (Japan)
+  game(0x9ea1dc76, 2, H, false),     // Rainbow Islands - The Story of Bubble Bobble 2 (Japan)
+  game(0xe1b260da, 2, V, false),     // Argos no Senshi (Japan)
+];
+
+pub(crate) fn lookup(crc32: u32) -> Option<&'static Entry> {
+  GAMES
+    .binary_search_by_key(&crc32, |e| e.crc32)
+    .ok()
+    .map(|i| &GAMES[i])
+}
+
+impl Entry {
+  // Overwrites what the database knows better, true if anything changed
+  pub fn correct(&self, header: &mut Header) -> bool {
+    let before = header.clone();
+    header.mapper = self.mapper;
+    if let Some(submapper) = self.submapper {
+      header.submapper = submapper;
+    }
+    if let Some(mirroring) = self.mirroring {
+      header.mirroring = mirroring;
+    }
+    if let Some(timing) = self.timing {
+      header.timing = timing;
+    }
+    if let Some(expansion_device) = self.expansion_device {
+      header.expansion_device = expansion_device;
+    }
+
+    // iNES puts all PRG RAM on one side of the battery
+    if header.battery != self.battery {
+      header.battery = self.battery;
+      core::mem::swap(&mut header.prg_ram_size, &mut header.prg_nvram_size);
+    }
+
+    *header != before
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn sorted_and_unique() {
+    assert!(GAMES.windows(2).all(|w| w[0].crc32 < w[1].crc32));
+  }
+
+  #[test]
+  fn lookup_by_crc() {
+    assert_eq!(lookup(0x3337ec46).unwrap().mapper, 0);
+    assert!(lookup(0x12345678).is_none());
+  }
+
+  #[test]
+  fn unknown_fields_left_alone() {
+    // Super Mario Bros. with the header it should have
+    let mut rom = crate::cartridge::MAGIC.to_vec();
+    rom.extend([0x02, 0x01, 0x01, 0x00]);
+    rom.resize(16, 0);
+    let mut header = Header::parse(&rom).unwrap();
+    let right = header.clone();
+    assert!(!lookup(0x3337ec46).unwrap().correct(&mut header));
+    assert_eq!(header, right);
+
+    // Horizontal, what Gilligan's Island dumps tend to say
+    rom[6] = 0x20;
+    let mut header = Header::parse(&rom).unwrap();
+    assert!(lookup(0x55773880).unwrap().correct(&mut header));
+    assert_eq!(header.mapper, 2);
+    assert_eq!(header.mirroring, Mirroring::Vertical);
+    assert_eq!(header.expansion_device, right.expansion_device);
+    assert_eq!(header.timing, right.timing);
+  }
+}
@@ -1,3 +1,7 @@
+use crate::controller::ControllerPortDevice;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
 use bitflags::bitflags;
 
 bitflags! {
@@ -35,6 +39,10 @@ impl Joypad {
     Self::default()
   }
 
+  pub fn buttons(&self) -> JoypadButton {
+    self.state
+  }
+
   pub fn read(&mut self) -> u8 {
     // It reads 8 times, once per button
     let val = self.out & 1;
@@ -56,3 +64,24 @@ impl Joypad {
     }
   }
 }
+
+impl ControllerPortDevice for Joypad {
+  fn strobe(&mut self, val: u8) {
+    Joypad::strobe(self, val)
+  }
+
+  fn read(&mut self) -> u8 {
+    Joypad::read(self)
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    w.write_u8(self.state.bits);
+    w.write_u8(self.out);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.state = JoypadButton::from_bits_truncate(r.read_u8()?);
+    self.out = r.read_u8()?;
+    Ok(())
+  }
+}
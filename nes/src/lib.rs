@@ -5,12 +5,18 @@ extern crate alloc;
 
 pub use mos6502;
 
+mod apu;
 mod fonts;
+mod gamedb;
 mod mappers;
 mod nesbus;
 mod ppu;
+mod savestate;
 
+pub mod audio;
 pub mod cartridge;
+pub mod controller;
 pub mod frame;
 pub mod joypad;
 pub mod nes;
+pub mod region;
@@ -0,0 +1,133 @@
+use common::kilobytes;
+use mos6502::memory::Bus;
+
+use super::bus_conflicts;
+use super::notify_mirroring;
+use super::Mapper;
+use super::MirroringCallback;
+use crate::cartridge::error::CartridgeError;
+use crate::cartridge::Cartridge;
+use crate::cartridge::Mirroring;
+use crate::cartridge::Rom;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+// Mapper 7
+// https://www.nesdev.org/wiki/AxROM
+pub struct AxROM<R: Rom> {
+  cart: Cartridge<R>,
+  bank: u8,
+  num_banks: usize,
+  bus_conflicts: bool,
+  mirroring_cb: Option<MirroringCallback>,
+}
+
+impl<R: Rom> Mapper for AxROM<R> {
+  fn on_runtime_mirroring(&mut self, cb: MirroringCallback) {
+    self.mirroring_cb = Some(cb);
+  }
+
+  fn prg_ram(&self) -> &[u8] {
+    self.cart.prg_ram()
+  }
+
+  fn import_prg_ram(&mut self, ram: &[u8]) -> Result<(), CartridgeError> {
+    self.cart.import_prg_ram(ram)
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    self.cart.save_state(w);
+    w.write_u8(self.bank);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.cart.load_state(r)?;
+    self.bank = r.read_u8()?;
+    Ok(())
+  }
+}
+
+impl<R: Rom> AxROM<R> {
+  pub fn new(cart: Cartridge<R>) -> Self {
+    Self {
+      num_banks: (cart.prg().len() / kilobytes::KB32).max(1),
+      // ANROM has none, and games made for it break with them
+      bus_conflicts: bus_conflicts(&cart, true, false),
+      cart,
+      bank: 0,
+      mirroring_cb: None,
+    }
+  }
+
+  fn select(&mut self, val: u8) {
+    // xxxM xPPP
+    self.bank = val & 0b111;
+
+    let runtime_mirroring = if val & 0x10 == 0 {
+      Mirroring::SingleScreenLower
+    } else {
+      Mirroring::SingleScreenUpper
+    };
+    notify_mirroring(&mut self.mirroring_cb, runtime_mirroring);
+  }
+}
+
+impl<R: Rom> Bus for AxROM<R> {
+  fn read8(&self, address: u16) -> u8 {
+    let address = address as usize;
+    match address {
+      0x0000..=0x1fff => self.cart.chr()[address],
+      0x6000..=0x7fff => self.cart.prg_ram()[address - 0x6000],
+      0x8000..=0xffff => {
+        let bank = self.bank as usize % self.num_banks;
+        // 16 KB of PRG ROM shows up twice
+        let prg = self.cart.prg();
+        prg[((bank * kilobytes::KB32) + (address - 0x8000)) % prg.len()]
+      }
+      _ => 0,
+    }
+  }
+
+  fn write8(&mut self, val: u8, address: u16) {
+    match address {
+      0x0000..=0x1fff => self.cart.chr_ram()[address as usize] = val,
+      0x6000..=0x7fff => self.cart.prg_ram_mut()[address as usize - 0x6000] = val,
+      0x8000..=0xffff if self.bus_conflicts => self.select(val & self.read8(address)),
+      0x8000..=0xffff => self.select(val),
+      _ => (),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mappers::tests::cart;
+  use crate::mappers::tests::spy_mirroring;
+
+  #[test]
+  fn switches_32kb_banks_and_nametable() {
+    let mut axrom = AxROM::new(cart(7, kilobytes::KB32 * 4, 0));
+    let mirroring = spy_mirroring(&mut axrom);
+    assert_eq!(axrom.read8(0x8000), 0);
+    assert_eq!(axrom.read8(0xffff), 3);
+
+    axrom.write8(0x12, 0x8000);
+    assert_eq!(axrom.read8(0x8000), 8);
+    assert_eq!(axrom.read8(0xffff), 11);
+    assert_eq!(*mirroring.borrow(), Some(Mirroring::SingleScreenUpper));
+
+    axrom.write8(0x03, 0xc000);
+    assert_eq!(axrom.read8(0x8000), 12);
+    assert_eq!(*mirroring.borrow(), Some(Mirroring::SingleScreenLower));
+  }
+
+  #[test]
+  fn prg_16kb_mirrored() {
+    let axrom = AxROM::new(cart(7, kilobytes::KB16, 0));
+    assert_eq!(axrom.read8(0xa000), 1);
+    assert_eq!(axrom.read8(0xc000), 0);
+    assert_eq!(axrom.read8(0xffff), 1);
+  }
+}
@@ -0,0 +1,180 @@
+use common::kilobytes;
+use mos6502::memory::Bus;
+
+use super::bus_conflicts;
+use super::Mapper;
+use crate::cartridge::error::CartridgeError;
+use crate::cartridge::Cartridge;
+use crate::cartridge::Rom;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Board {
+  // 32 KB PRG banks at $8000-$FFFF, CHR RAM
+  Bnrom,
+  // 32 KB PRG and two 4 KB CHR banks at $7FFD-$7FFF
+  Nina001,
+}
+
+// Mapper 34, two unrelated boards. Submapper 1 is NINA-001, 2 is BNROM,
+// otherwise CHR ROM larger than 8 KB means NINA-001.
+// https://www.nesdev.org/wiki/INES_Mapper_034
+#[allow(clippy::upper_case_acronyms)]
+pub struct BNROM<R: Rom> {
+  cart: Cartridge<R>,
+  board: Board,
+  prg_bank: u8,
+  chr_banks: [u8; 2],
+  num_prg_banks: usize,
+  bus_conflicts: bool,
+}
+
+impl<R: Rom> Mapper for BNROM<R> {
+  fn prg_ram(&self) -> &[u8] {
+    self.cart.prg_ram()
+  }
+
+  fn import_prg_ram(&mut self, ram: &[u8]) -> Result<(), CartridgeError> {
+    self.cart.import_prg_ram(ram)
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    self.cart.save_state(w);
+    w.write_u8(self.prg_bank);
+    w.write_bytes(&self.chr_banks);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.cart.load_state(r)?;
+    self.prg_bank = r.read_u8()?;
+    r.read_bytes(&mut self.chr_banks)
+  }
+}
+
+impl<R: Rom> BNROM<R> {
+  pub fn new(cart: Cartridge<R>) -> Self {
+    let header = cart.header();
+    let board = match header.submapper {
+      1 => Board::Nina001,
+      2 => Board::Bnrom,
+      _ if header.chr_rom_size > kilobytes::KB8 => Board::Nina001,
+      _ => Board::Bnrom,
+    };
+
+    Self {
+      num_prg_banks: (cart.prg().len() / kilobytes::KB32).max(1),
+      // The submapper picks the board here
+      bus_conflicts: bus_conflicts(&cart, false, true),
+      cart,
+      board,
+      prg_bank: 0,
+      chr_banks: [0, 1],
+    }
+  }
+
+  fn chr_index(&self, address: u16) -> usize {
+    let address = address as usize;
+    let bank = self.chr_banks[address / kilobytes::KB4] as usize;
+    (bank * kilobytes::KB4 + (address & 0xfff)) % self.cart.chr().len()
+  }
+}
+
+impl<R: Rom> Bus for BNROM<R> {
+  fn read8(&self, address: u16) -> u8 {
+    match address {
+      0x0000..=0x1fff if self.board == Board::Nina001 => self.cart.chr()[self.chr_index(address)],
+      0x0000..=0x1fff => self.cart.chr()[address as usize],
+      0x6000..=0x7fff => self.cart.prg_ram()[address as usize - 0x6000],
+      0x8000..=0xffff => {
+        let bank = self.prg_bank as usize % self.num_prg_banks;
+        // 16 KB of PRG ROM shows up twice
+        let prg = self.cart.prg();
+        prg[((bank * kilobytes::KB32) + (address as usize - 0x8000)) % prg.len()]
+      }
+      _ => 0,
+    }
+  }
+
+  fn write8(&mut self, val: u8, address: u16) {
+    match (address, self.board) {
+      (0x0000..=0x1fff, Board::Bnrom) => self.cart.chr_ram()[address as usize] = val,
+      (0x6000..=0x7fff, _) => {
+        // NINA-001's registers are written to the RAM underneath too
+        self.cart.prg_ram_mut()[address as usize - 0x6000] = val;
+        match (address, self.board) {
+          (0x7ffd, Board::Nina001) => self.prg_bank = val & 0b1,
+          (0x7ffe, Board::Nina001) => self.chr_banks[0] = val & 0x0f,
+          (0x7fff, Board::Nina001) => self.chr_banks[1] = val & 0x0f,
+          _ => (),
+        }
+      }
+      (0x8000..=0xffff, Board::Bnrom) if self.bus_conflicts => {
+        self.prg_bank = val & self.read8(address)
+      }
+      (0x8000..=0xffff, Board::Bnrom) => self.prg_bank = val,
+      _ => (),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mappers::tests::cart;
+  use crate::mappers::tests::cart_with_prg;
+  use crate::mappers::tests::nes2_cart;
+
+  #[test]
+  fn bnrom() {
+    let mut prg = vec![0xff; kilobytes::KB32];
+    prg.extend([1; kilobytes::KB32]);
+    prg.extend([2; kilobytes::KB32 * 2]);
+    let mut bnrom = BNROM::new(cart_with_prg(34, prg, 0));
+    assert_eq!(bnrom.board, Board::Bnrom);
+
+    bnrom.write8(0x03, 0x8000);
+    assert_eq!(bnrom.read8(0x8000), 2);
+    // Bus conflict, $02 & $01
+    bnrom.write8(0x01, 0x8000);
+    assert_eq!(bnrom.read8(0x8000), 0xff);
+
+    bnrom.write8(0x42, 0x0000);
+    assert_eq!(bnrom.read8(0x0000), 0x42);
+  }
+
+  #[test]
+  fn nina001() {
+    let mut nina = BNROM::new(cart(34, kilobytes::KB32 * 2, kilobytes::KB8 * 8));
+    assert_eq!(nina.board, Board::Nina001);
+
+    nina.write8(1, 0x7ffd);
+    nina.write8(3, 0x7ffe);
+    nina.write8(4, 0x7fff);
+    assert_eq!(nina.read8(0x8000), 4);
+    assert_eq!(nina.read8(0x0000), 12);
+    assert_eq!(nina.read8(0x1000), 16);
+    assert_eq!(nina.read8(0x7fff), 4);
+
+    // Registers are only at $7FFD-$7FFF
+    nina.write8(0, 0x8000);
+    assert_eq!(nina.read8(0x8000), 4);
+  }
+
+  #[test]
+  fn submapper() {
+    let bnrom = BNROM::new(nes2_cart(34, 2, kilobytes::KB32 * 2, kilobytes::KB8 * 8));
+    assert_eq!(bnrom.board, Board::Bnrom);
+    let nina = BNROM::new(nes2_cart(34, 1, kilobytes::KB32 * 2, kilobytes::KB8));
+    assert_eq!(nina.board, Board::Nina001);
+  }
+
+  #[test]
+  fn prg_16kb_mirrored() {
+    let bnrom = BNROM::new(cart(34, kilobytes::KB16, 0));
+    assert_eq!(bnrom.read8(0xa000), 1);
+    assert_eq!(bnrom.read8(0xc000), 0);
+    assert_eq!(bnrom.read8(0xffff), 1);
+  }
+}
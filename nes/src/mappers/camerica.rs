@@ -0,0 +1,124 @@
+use common::kilobytes;
+use mos6502::memory::Bus;
+
+use super::notify_mirroring;
+use super::Mapper;
+use super::MirroringCallback;
+use crate::cartridge::error::CartridgeError;
+use crate::cartridge::Cartridge;
+use crate::cartridge::Mirroring;
+use crate::cartridge::Rom;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+// Mapper 71, Camerica/Codemasters BF909x. Like UxROM, with the bank register at $C000.
+// https://www.nesdev.org/wiki/INES_Mapper_071
+pub struct Camerica<R: Rom> {
+  cart: Cartridge<R>,
+  bank: u8,
+  num_banks: usize,
+  mirroring_cb: Option<MirroringCallback>,
+}
+
+impl<R: Rom> Mapper for Camerica<R> {
+  fn on_runtime_mirroring(&mut self, cb: MirroringCallback) {
+    self.mirroring_cb = Some(cb);
+  }
+
+  fn prg_ram(&self) -> &[u8] {
+    self.cart.prg_ram()
+  }
+
+  fn import_prg_ram(&mut self, ram: &[u8]) -> Result<(), CartridgeError> {
+    self.cart.import_prg_ram(ram)
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    self.cart.save_state(w);
+    w.write_u8(self.bank);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.cart.load_state(r)?;
+    self.bank = r.read_u8()?;
+    Ok(())
+  }
+}
+
+impl<R: Rom> Camerica<R> {
+  pub fn new(cart: Cartridge<R>) -> Self {
+    Self {
+      num_banks: cart.prg().len() / kilobytes::KB16,
+      cart,
+      bank: 0,
+      mirroring_cb: None,
+    }
+  }
+
+  // Only Fire Hawk's board (submapper 1) has this, but no other game writes here,
+  // and Fire Hawk is mostly found in iNES dumps.
+  fn select_mirroring(&mut self, val: u8) {
+    let runtime_mirroring = if val & 0x10 == 0 {
+      Mirroring::SingleScreenLower
+    } else {
+      Mirroring::SingleScreenUpper
+    };
+    notify_mirroring(&mut self.mirroring_cb, runtime_mirroring);
+  }
+}
+
+impl<R: Rom> Bus for Camerica<R> {
+  fn read8(&self, address: u16) -> u8 {
+    let address = address as usize;
+    let last_bank = self.num_banks - 1;
+    match address {
+      0x0000..=0x1fff => self.cart.chr()[address],
+      0x6000..=0x7fff => self.cart.prg_ram()[address - 0x6000],
+      0x8000..=0xbfff => {
+        let bank = self.bank as usize % self.num_banks;
+        self.cart.prg()[(bank * kilobytes::KB16) + (address - 0x8000)]
+      }
+      0xc000..=0xffff => self.cart.prg()[(last_bank * kilobytes::KB16) + (address - 0xc000)],
+      _ => 0,
+    }
+  }
+
+  fn write8(&mut self, val: u8, address: u16) {
+    match address {
+      0x0000..=0x1fff => self.cart.chr_ram()[address as usize] = val,
+      0x6000..=0x7fff => self.cart.prg_ram_mut()[address as usize - 0x6000] = val,
+      0x9000..=0x9fff => self.select_mirroring(val),
+      0xc000..=0xffff => self.bank = val & 0x0f,
+      _ => (),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mappers::tests::cart;
+  use crate::mappers::tests::spy_mirroring;
+
+  #[test]
+  fn switches_16kb_bank_at_c000() {
+    let mut camerica = Camerica::new(cart(71, kilobytes::KB16 * 8, 0));
+    camerica.write8(3, 0x8000);
+    assert_eq!(camerica.read8(0x8000), 0);
+
+    camerica.write8(3, 0xc000);
+    assert_eq!(camerica.read8(0x8000), 6);
+    assert_eq!(camerica.read8(0xc000), 14);
+  }
+
+  #[test]
+  fn fire_hawk_mirroring() {
+    let mut camerica = Camerica::new(cart(71, kilobytes::KB16 * 8, 0));
+    let mirroring = spy_mirroring(&mut camerica);
+    camerica.write8(0x10, 0x9000);
+    assert_eq!(*mirroring.borrow(), Some(Mirroring::SingleScreenUpper));
+    camerica.write8(0x00, 0x9000);
+    assert_eq!(*mirroring.borrow(), Some(Mirroring::SingleScreenLower));
+  }
+}
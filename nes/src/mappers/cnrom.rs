@@ -1,20 +1,46 @@
 use common::kilobytes;
 use mos6502::memory::Bus;
 
+use super::bus_conflicts;
 use super::Mapper;
+use crate::cartridge::error::CartridgeError;
 use crate::cartridge::Cartridge;
 use crate::cartridge::Rom;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
 
 const BANK_SIZE: usize = kilobytes::KB8;
 
 // Mapper 3
+#[allow(clippy::upper_case_acronyms)]
 pub(crate) struct CNROM<R: Rom> {
   cart: Cartridge<R>,
   selected_bank: usize,
   is_16kb: bool,
+  bus_conflicts: bool,
 }
 
-impl<R: Rom> Mapper for CNROM<R> {}
+impl<R: Rom> Mapper for CNROM<R> {
+  fn prg_ram(&self) -> &[u8] {
+    self.cart.prg_ram()
+  }
+
+  fn import_prg_ram(&mut self, ram: &[u8]) -> Result<(), CartridgeError> {
+    self.cart.import_prg_ram(ram)
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    self.cart.save_state(w);
+    w.write_u8(self.selected_bank as u8);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.cart.load_state(r)?;
+    self.selected_bank = (r.read_u8()? & 0b00000011) as usize;
+    Ok(())
+  }
+}
 
 impl<R: Rom> CNROM<R> {
   pub fn new(cart: Cartridge<R>) -> Self {
@@ -25,6 +51,8 @@ impl<R: Rom> CNROM<R> {
     };
 
     Self {
+      // Every CNROM board has them, so unless the header says otherwise
+      bus_conflicts: bus_conflicts(&cart, true, true),
       cart,
       selected_bank: 0,
       is_16kb,
@@ -38,12 +66,14 @@ impl<R: Rom> Bus for CNROM<R> {
       0x0000..=0x1fff => self.cart.chr()[(self.selected_bank * BANK_SIZE) + address as usize],
       0x8000..=0xffff => {
         if self.is_16kb {
-          self.cart.prg()[address as usize - 0x8000 - kilobytes::KB16] // see tests
+          // Mirrored
+          self.cart.prg()[(address as usize - 0x8000) % kilobytes::KB16]
         } else {
           self.cart.prg()[address as usize - 0x8000]
         }
       }
-      _ => self.cart.prg_ram()[address as usize],
+      0x6000..=0x7fff => self.cart.prg_ram()[address as usize - 0x6000],
+      _ => 0,
     }
   }
 
@@ -51,10 +81,42 @@ impl<R: Rom> Bus for CNROM<R> {
     match address {
       // 0x0000..=0x1fff => self.cart.chr_mut()[(self.selected_bank * BANK_SIZE) + address as usize] = val,
       0x8000..=0xffff => {
+        let val = if self.bus_conflicts {
+          val & self.read8(address)
+        } else {
+          val
+        };
         self.selected_bank = (val & 0b00000011) as usize;
         // println!("mapper 3 selected bank: {}", self.selected_bank);
       }
-      _ => self.cart.prg_ram_mut()[address as usize] = val,
+      0x6000..=0x7fff => self.cart.prg_ram_mut()[address as usize - 0x6000] = val,
+      _ => (),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mappers::tests::cart;
+  use crate::mappers::tests::nes2_cart;
+
+  #[test]
+  fn bus_conflicts() {
+    let mut cnrom = CNROM::new(nes2_cart(3, 1, kilobytes::KB32, kilobytes::KB8 * 4));
+    cnrom.write8(3, 0x8000);
+    assert_eq!(cnrom.read8(0x0000), 24);
+
+    // $8000 holds 0, $E000 holds 3
+    for cart in [
+      nes2_cart(3, 2, kilobytes::KB32, kilobytes::KB8 * 4),
+      cart(3, kilobytes::KB32, kilobytes::KB8 * 4),
+    ] {
+      let mut cnrom = CNROM::new(cart);
+      cnrom.write8(3, 0x8000);
+      assert_eq!(cnrom.read8(0x0000), 0);
+      cnrom.write8(2, 0xe000);
+      assert_eq!(cnrom.read8(0x0000), 16);
     }
   }
 }
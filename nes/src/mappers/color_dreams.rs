@@ -0,0 +1,128 @@
+use common::kilobytes;
+use mos6502::memory::Bus;
+
+use super::bus_conflicts;
+use super::Mapper;
+use crate::cartridge::error::CartridgeError;
+use crate::cartridge::Cartridge;
+use crate::cartridge::Rom;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+// Mapper 11
+// https://www.nesdev.org/wiki/Color_Dreams
+pub struct ColorDreams<R: Rom> {
+  cart: Cartridge<R>,
+  prg_bank: u8,
+  chr_bank: u8,
+  num_prg_banks: usize,
+  num_chr_banks: usize,
+  bus_conflicts: bool,
+}
+
+impl<R: Rom> Mapper for ColorDreams<R> {
+  fn prg_ram(&self) -> &[u8] {
+    self.cart.prg_ram()
+  }
+
+  fn import_prg_ram(&mut self, ram: &[u8]) -> Result<(), CartridgeError> {
+    self.cart.import_prg_ram(ram)
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    self.cart.save_state(w);
+    w.write_u8(self.prg_bank);
+    w.write_u8(self.chr_bank);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.cart.load_state(r)?;
+    self.prg_bank = r.read_u8()?;
+    self.chr_bank = r.read_u8()?;
+    Ok(())
+  }
+}
+
+impl<R: Rom> ColorDreams<R> {
+  pub fn new(cart: Cartridge<R>) -> Self {
+    Self {
+      num_prg_banks: (cart.prg().len() / kilobytes::KB32).max(1),
+      num_chr_banks: (cart.chr().len() / kilobytes::KB8).max(1),
+      bus_conflicts: bus_conflicts(&cart, false, true),
+      cart,
+      prg_bank: 0,
+      chr_bank: 0,
+    }
+  }
+}
+
+impl<R: Rom> Bus for ColorDreams<R> {
+  fn read8(&self, address: u16) -> u8 {
+    let address = address as usize;
+    match address {
+      0x0000..=0x1fff => {
+        let bank = self.chr_bank as usize % self.num_chr_banks;
+        self.cart.chr()[(bank * kilobytes::KB8) + address]
+      }
+      0x6000..=0x7fff => self.cart.prg_ram()[address - 0x6000],
+      0x8000..=0xffff => {
+        let bank = self.prg_bank as usize % self.num_prg_banks;
+        // 16 KB of PRG ROM shows up twice
+        let prg = self.cart.prg();
+        prg[((bank * kilobytes::KB32) + (address - 0x8000)) % prg.len()]
+      }
+      _ => 0,
+    }
+  }
+
+  fn write8(&mut self, val: u8, address: u16) {
+    match address {
+      0x6000..=0x7fff => self.cart.prg_ram_mut()[address as usize - 0x6000] = val,
+      0x8000..=0xffff => {
+        // CCCC xxPP
+        let val = if self.bus_conflicts {
+          val & self.read8(address)
+        } else {
+          val
+        };
+        self.prg_bank = val & 0b11;
+        self.chr_bank = val >> 4;
+      }
+      _ => (),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mappers::tests::cart;
+  use crate::mappers::tests::cart_with_prg;
+
+  #[test]
+  fn switches_prg_and_chr() {
+    let mut prg = vec![0xff; kilobytes::KB32];
+    prg.extend([2; kilobytes::KB32 * 3]);
+    let mut cd = ColorDreams::new(cart_with_prg(11, prg, kilobytes::KB8 * 16));
+
+    cd.write8(0x71, 0xffff);
+    assert_eq!(cd.read8(0x8000), 2);
+    assert_eq!(cd.read8(0x1fff), 7 * 8 + 7);
+  }
+
+  #[test]
+  fn bus_conflict() {
+    let mut cd = ColorDreams::new(cart(11, kilobytes::KB32 * 4, kilobytes::KB8 * 16));
+    cd.write8(0xff, 0x8000);
+    assert_eq!(cd.read8(0x8000), 0);
+  }
+
+  #[test]
+  fn prg_16kb_mirrored() {
+    let cd = ColorDreams::new(cart(11, kilobytes::KB16, kilobytes::KB8));
+    assert_eq!(cd.read8(0xa000), 1);
+    assert_eq!(cd.read8(0xc000), 0);
+    assert_eq!(cd.read8(0xffff), 1);
+  }
+}
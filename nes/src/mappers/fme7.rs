@@ -0,0 +1,334 @@
+use common::kilobytes;
+use mos6502::memory::Bus;
+
+use super::fme7_audio::Sunsoft5b;
+use super::notify_mirroring;
+use super::Mapper;
+use super::MirroringCallback;
+use crate::cartridge::error::CartridgeError;
+use crate::cartridge::Cartridge;
+use crate::cartridge::Mirroring;
+use crate::cartridge::Rom;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+// Mapper 69, Sunsoft FME-7 and the 5B (FME-7 with audio)
+// https://www.nesdev.org/wiki/Sunsoft_FME-7
+pub struct FME7<R: Rom> {
+  cart: Cartridge<R>,
+  prg_rom_banks_total: usize,
+  prg_ram_banks_total: usize,
+
+  command: u8,
+  chr_banks: [u8; 8],
+  // $6000, $8000, $A000, $C000
+  prg_banks: [u8; 4],
+  prg_ram_selected: bool,
+  prg_ram_enabled: bool,
+  mirroring_cb: Option<MirroringCallback>,
+
+  irq_enabled: bool,
+  irq_counter_enabled: bool,
+  irq_counter: u16,
+  irq_pending: bool,
+
+  audio: Sunsoft5b,
+}
+
+impl<R: Rom> FME7<R> {
+  pub fn new(cart: Cartridge<R>) -> Self {
+    Self {
+      prg_rom_banks_total: cart.prg().len() / kilobytes::KB8,
+      prg_ram_banks_total: cart.prg_ram().len() / kilobytes::KB8,
+      cart,
+      command: 0,
+      chr_banks: [0; 8],
+      prg_banks: [0; 4],
+      prg_ram_selected: false,
+      prg_ram_enabled: false,
+      mirroring_cb: None,
+      irq_enabled: false,
+      irq_counter_enabled: false,
+      irq_counter: 0,
+      irq_pending: false,
+      audio: Sunsoft5b::new(),
+    }
+  }
+
+  fn read_prg(&self, address: u16) -> u8 {
+    let bank = match address {
+      0x6000..=0xdfff => self.prg_banks[(address as usize - 0x6000) / kilobytes::KB8] as usize,
+      _ => self.prg_rom_banks_total - 1,
+    };
+    let bank = bank % self.prg_rom_banks_total;
+    self.cart.prg()[bank * kilobytes::KB8 + (address as usize & 0x1fff)]
+  }
+
+  fn prg_ram_index(&self, address: u16) -> usize {
+    let bank = self.prg_banks[0] as usize % self.prg_ram_banks_total;
+    bank * kilobytes::KB8 + (address as usize & 0x1fff)
+  }
+
+  fn chr_index(&self, address: u16) -> usize {
+    let bank = self.chr_banks[address as usize / kilobytes::KB1] as usize;
+    bank * kilobytes::KB1 + (address as usize & 0x3ff)
+  }
+
+  // https://www.nesdev.org/wiki/Sunsoft_FME-7#Parameter_Register_($A000-$BFFF)
+  fn write_parameter(&mut self, val: u8) {
+    match self.command {
+      0x0..=0x7 => self.chr_banks[self.command as usize] = val,
+      0x8 => {
+        self.prg_banks[0] = val & 0x3f;
+        self.prg_ram_selected = val & 0x40 != 0;
+        self.prg_ram_enabled = val & 0x80 != 0;
+      }
+      0x9..=0xb => self.prg_banks[self.command as usize - 8] = val & 0x3f,
+      0xc => {
+        let runtime_mirroring = match val & 0b11 {
+          0 => Mirroring::Vertical,
+          1 => Mirroring::Horizontal,
+          2 => Mirroring::SingleScreenLower,
+          _ => Mirroring::SingleScreenUpper,
+        };
+        notify_mirroring(&mut self.mirroring_cb, runtime_mirroring)
+      }
+      // The IRQ stays pending until this register is written, whatever the value
+      0xd => {
+        self.irq_enabled = val & 0x01 != 0;
+        self.irq_counter_enabled = val & 0x80 != 0;
+        self.irq_pending = false;
+      }
+      0xe => self.irq_counter = (self.irq_counter & 0xff00) | val as u16,
+      _ => self.irq_counter = (self.irq_counter & 0x00ff) | ((val as u16) << 8),
+    }
+  }
+}
+
+impl<R: Rom> Mapper for FME7<R> {
+  fn on_runtime_mirroring(&mut self, cb: MirroringCallback) {
+    self.mirroring_cb = Some(cb);
+  }
+
+  // The counter decrements every CPU cycle, and fires when it wraps from 0 to $FFFF
+  fn tick(&mut self, cpu_cycles: usize) {
+    for _ in 0..cpu_cycles {
+      self.audio.tick();
+    }
+
+    if !self.irq_counter_enabled {
+      return;
+    }
+    let wrapped = cpu_cycles > self.irq_counter as usize;
+    self.irq_counter = self.irq_counter.wrapping_sub(cpu_cycles as u16);
+    self.irq_pending |= wrapped && self.irq_enabled;
+  }
+
+  fn irq(&self) -> bool {
+    self.irq_pending
+  }
+
+  fn audio_output(&self) -> f32 {
+    self.audio.output()
+  }
+
+  fn prg_ram(&self) -> &[u8] {
+    self.cart.prg_ram()
+  }
+
+  fn import_prg_ram(&mut self, ram: &[u8]) -> Result<(), CartridgeError> {
+    self.cart.import_prg_ram(ram)
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    self.cart.save_state(w);
+    w.write_u8(self.command);
+    w.write_bytes(&self.chr_banks);
+    w.write_bytes(&self.prg_banks);
+    w.write_bool(self.prg_ram_selected);
+    w.write_bool(self.prg_ram_enabled);
+    w.write_bool(self.irq_enabled);
+    w.write_bool(self.irq_counter_enabled);
+    w.write_u16(self.irq_counter);
+    w.write_bool(self.irq_pending);
+    self.audio.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.cart.load_state(r)?;
+    self.command = r.read_u8()? & 0x0f;
+    r.read_bytes(&mut self.chr_banks)?;
+    r.read_bytes(&mut self.prg_banks)?;
+    self.prg_ram_selected = r.read_bool()?;
+    self.prg_ram_enabled = r.read_bool()?;
+    self.irq_enabled = r.read_bool()?;
+    self.irq_counter_enabled = r.read_bool()?;
+    self.irq_counter = r.read_u16()?;
+    self.irq_pending = r.read_bool()?;
+    self.audio.load_state(r)
+  }
+}
+
+impl<R: Rom> Bus for FME7<R> {
+  fn read8(&self, address: u16) -> u8 {
+    match address {
+      0x0000..=0x1fff => {
+        let chr = self.cart.chr();
+        chr[self.chr_index(address) % chr.len()]
+      }
+      0x6000..=0x7fff if self.prg_ram_selected && self.prg_ram_enabled => {
+        self.cart.prg_ram()[self.prg_ram_index(address)]
+      }
+      // Open bus
+      0x6000..=0x7fff if self.prg_ram_selected => 0,
+      0x6000..=0xffff => self.read_prg(address),
+      _ => 0,
+    }
+  }
+
+  fn write8(&mut self, val: u8, address: u16) {
+    match address {
+      0x0000..=0x1fff => {
+        let index = self.chr_index(address);
+        let chr_ram = self.cart.chr_ram();
+        let len = chr_ram.len();
+        chr_ram[index % len] = val;
+      }
+      0x6000..=0x7fff if self.prg_ram_selected && self.prg_ram_enabled => {
+        let index = self.prg_ram_index(address);
+        self.cart.prg_ram_mut()[index] = val;
+      }
+      0x8000..=0x9fff => self.command = val & 0x0f,
+      0xa000..=0xbfff => self.write_parameter(val),
+      0xc000..=0xdfff => self.audio.write_address(val),
+      0xe000..=0xffff => self.audio.write_data(val),
+      _ => (),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mappers::tests::cart;
+  use crate::mappers::tests::spy_mirroring;
+
+  fn command(fme: &mut FME7<impl Rom>, command: u8, val: u8) {
+    fme.write8(command, 0x8000);
+    fme.write8(val, 0xa000);
+  }
+
+  fn audio(fme: &mut FME7<impl Rom>, register: u8, val: u8) {
+    fme.write8(register, 0xc000);
+    fme.write8(val, 0xe000);
+  }
+
+  #[test]
+  fn banking() {
+    let mut fme = FME7::new(cart(69, kilobytes::KB32 * 4, kilobytes::KB32 * 4));
+    let mirroring = spy_mirroring(&mut fme);
+    command(&mut fme, 0x9, 3);
+    command(&mut fme, 0xa, 4);
+    command(&mut fme, 0xb, 5);
+    assert_eq!(
+      [0x8000, 0xa000, 0xc000, 0xe000].map(|a| fme.read8(a)),
+      [3, 4, 5, 15]
+    );
+
+    command(&mut fme, 0x0, 0x21);
+    command(&mut fme, 0x7, 0x42);
+    assert_eq!(fme.read8(0x0000), 0x21);
+    assert_eq!(fme.read8(0x1c00), 0x42);
+
+    command(&mut fme, 0xc, 1);
+    assert_eq!(*mirroring.borrow(), Some(Mirroring::Horizontal));
+  }
+
+  #[test]
+  fn prg_ram_or_rom_at_6000() {
+    let mut fme = FME7::new(cart(69, kilobytes::KB32 * 4, kilobytes::KB32 * 4));
+    command(&mut fme, 0x8, 7);
+    assert_eq!(fme.read8(0x6000), 7);
+
+    // RAM selected but disabled
+    command(&mut fme, 0x8, 0x40);
+    fme.write8(0x55, 0x6000);
+    assert_eq!(fme.read8(0x6000), 0);
+
+    command(&mut fme, 0x8, 0xc0);
+    fme.write8(0x55, 0x6000);
+    assert_eq!(fme.read8(0x6000), 0x55);
+  }
+
+  #[test]
+  fn irq_counter() {
+    let mut fme = FME7::new(cart(69, kilobytes::KB32 * 4, kilobytes::KB32 * 4));
+    command(&mut fme, 0xe, 0x02);
+    command(&mut fme, 0xf, 0x00);
+    fme.tick(10);
+    assert!(!fme.irq());
+
+    command(&mut fme, 0xd, 0x81);
+    fme.tick(2);
+    assert!(!fme.irq());
+    fme.tick(1);
+    assert!(fme.irq());
+    assert_eq!(fme.irq_counter, 0xffff);
+    // Held until acknowledged
+    fme.tick(100);
+    assert!(fme.irq());
+
+    // Acknowledged, counting, but not firing
+    command(&mut fme, 0xd, 0x80);
+    assert!(!fme.irq());
+    fme.tick(0x10000);
+    assert!(!fme.irq());
+  }
+
+  #[test]
+  fn audio_tone() {
+    let mut fme = FME7::new(cart(69, kilobytes::KB32 * 4, kilobytes::KB32 * 4));
+    assert_eq!(fme.audio_output(), 0.0);
+
+    audio(&mut fme, 0x00, 1); // Tone A, period 1, toggles every 16 CPU cycles
+    audio(&mut fme, 0x07, 0b111_110); // Tone A only
+    audio(&mut fme, 0x08, 0x0f);
+    fme.tick(16);
+    let high = fme.audio_output();
+    assert!(high > 0.1);
+    fme.tick(16);
+    assert_eq!(fme.audio_output(), 0.0);
+
+    // Lower volume is quieter
+    audio(&mut fme, 0x08, 0x07);
+    fme.tick(16);
+    assert!((0.0..high).contains(&fme.audio_output()));
+    assert!(fme.audio_output() > 0.0);
+  }
+
+  #[test]
+  fn audio_envelope() {
+    let mut fme = FME7::new(cart(69, kilobytes::KB32 * 4, kilobytes::KB32 * 4));
+    // Tone and noise off, constant output at the envelope's level
+    audio(&mut fme, 0x07, 0b111_111);
+    audio(&mut fme, 0x08, 0x10);
+    audio(&mut fme, 0x0b, 1);
+    // Attack, then hold high: /‾‾‾
+    audio(&mut fme, 0x0d, 0b1101);
+
+    let mut last = fme.audio_output();
+    for _ in 0..31 {
+      fme.tick(8);
+      assert!(fme.audio_output() > last);
+      last = fme.audio_output();
+    }
+    fme.tick(8 * 100);
+    assert_eq!(fme.audio_output(), last);
+
+    // Decay, then silence: \___
+    audio(&mut fme, 0x0d, 0b0000);
+    fme.tick(8 * 32);
+    assert_eq!(fme.audio_output(), 0.0);
+  }
+}
@@ -0,0 +1,278 @@
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+// The Sunsoft 5B's audio, a YM2149 (AY-3-8910 clone) with three square
+// channels, a noise generator and a 32 step envelope. Only Gimmick! uses it.
+// https://www.nesdev.org/wiki/Sunsoft_5B_audio
+
+// Tones and noise are clocked at CPU / 16, the envelope at CPU / 8
+const TONE_DIVIDER: u8 = 16;
+const ENVELOPE_DIVIDER: u8 = 8;
+
+// Full volume on one channel is about as loud as a full volume APU pulse
+const CHANNEL_SCALE: f32 = 0.15;
+
+// 1.5 dB per envelope step
+const STEP_ATTENUATION: f32 = 0.841_395;
+
+#[derive(Default)]
+struct Tone {
+  period: u16, // 12 bits
+  counter: u16,
+  high: bool,
+  // MEVVVV, envelope flag and 4-bit volume
+  volume: u8,
+}
+
+impl Tone {
+  fn clock(&mut self) {
+    self.counter += 1;
+    if self.counter >= self.period.max(1) {
+      self.counter = 0;
+      self.high = !self.high;
+    }
+  }
+}
+
+pub(crate) struct Sunsoft5b {
+  address: u8,
+  tones: [Tone; 3],
+  // $07, active low: bits 0-2 turn off tones, 3-5 noise
+  mixer: u8,
+
+  noise_period: u8, // 5 bits
+  noise_counter: u8,
+  noise_half: bool,
+  lfsr: u32, // 17 bits
+
+  envelope_period: u16,
+  envelope_counter: u16,
+  envelope_shape: u8,
+  envelope_rising: bool,
+  envelope_step: u8, // 0..32
+  envelope_holding: bool,
+
+  tone_divider: u8,
+  envelope_divider: u8,
+
+  // Amplitude for each 5-bit level
+  levels: [f32; 32],
+}
+
+impl Sunsoft5b {
+  pub fn new() -> Self {
+    // No libm, step down from full volume instead of calling powf
+    let mut levels = [0.0; 32];
+    let mut level = 1.0;
+    for l in levels.iter_mut().skip(1).rev() {
+      *l = level;
+      level *= STEP_ATTENUATION;
+    }
+
+    Self {
+      address: 0,
+      tones: Default::default(),
+      mixer: 0,
+      noise_period: 0,
+      noise_counter: 0,
+      noise_half: false,
+      lfsr: 1,
+      envelope_period: 0,
+      envelope_counter: 0,
+      envelope_shape: 0,
+      envelope_rising: false,
+      envelope_step: 0,
+      envelope_holding: false,
+      tone_divider: 0,
+      envelope_divider: 0,
+      levels,
+    }
+  }
+
+  // $C000-$DFFF
+  pub fn write_address(&mut self, val: u8) {
+    self.address = val;
+  }
+
+  // $E000-$FFFF. The upper nibble of the address must be 0 for the chip to listen.
+  pub fn write_data(&mut self, val: u8) {
+    match self.address {
+      0x00 | 0x02 | 0x04 => {
+        let tone = &mut self.tones[self.address as usize / 2];
+        tone.period = (tone.period & 0xf00) | val as u16;
+      }
+      0x01 | 0x03 | 0x05 => {
+        let tone = &mut self.tones[self.address as usize / 2];
+        tone.period = (tone.period & 0xff) | ((val as u16 & 0x0f) << 8);
+      }
+      0x06 => self.noise_period = val & 0x1f,
+      0x07 => self.mixer = val,
+      0x08..=0x0a => self.tones[self.address as usize - 8].volume = val & 0x1f,
+      0x0b => self.envelope_period = (self.envelope_period & 0xff00) | val as u16,
+      0x0c => self.envelope_period = (self.envelope_period & 0xff) | ((val as u16) << 8),
+      0x0d => {
+        self.envelope_shape = val & 0x0f;
+        self.envelope_rising = val & 0b0100 != 0;
+        self.envelope_step = 0;
+        self.envelope_counter = 0;
+        self.envelope_holding = false;
+      }
+      _ => (),
+    }
+  }
+
+  // Every CPU cycle
+  pub fn tick(&mut self) {
+    self.tone_divider += 1;
+    if self.tone_divider == TONE_DIVIDER {
+      self.tone_divider = 0;
+      self.tones.iter_mut().for_each(Tone::clock);
+      self.clock_noise();
+    }
+
+    self.envelope_divider += 1;
+    if self.envelope_divider == ENVELOPE_DIVIDER {
+      self.envelope_divider = 0;
+      self.clock_envelope();
+    }
+  }
+
+  // The noise period counts at half the tone rate
+  fn clock_noise(&mut self) {
+    self.noise_half = !self.noise_half;
+    if self.noise_half {
+      return;
+    }
+    self.noise_counter += 1;
+    if self.noise_counter >= self.noise_period.max(1) {
+      self.noise_counter = 0;
+      let feedback = (self.lfsr ^ (self.lfsr >> 3)) & 1;
+      self.lfsr = (self.lfsr >> 1) | (feedback << 16);
+    }
+  }
+
+  fn clock_envelope(&mut self) {
+    if self.envelope_holding {
+      return;
+    }
+    self.envelope_counter += 1;
+    if self.envelope_counter < self.envelope_period.max(1) {
+      return;
+    }
+    self.envelope_counter = 0;
+
+    self.envelope_step += 1;
+    if self.envelope_step < 32 {
+      return;
+    }
+
+    // End of a ramp, https://www.nesdev.org/wiki/Sunsoft_5B_audio#Envelope
+    let cont = self.envelope_shape & 0b1000 != 0;
+    let attack = self.envelope_shape & 0b0100 != 0;
+    let alternate = self.envelope_shape & 0b0010 != 0;
+    let hold = self.envelope_shape & 0b0001 != 0;
+    if !cont {
+      self.hold_envelope(0);
+    } else if hold {
+      self.hold_envelope(if attack != alternate { 31 } else { 0 });
+    } else {
+      self.envelope_step = 0;
+      if alternate {
+        self.envelope_rising = !self.envelope_rising;
+      }
+    }
+  }
+
+  fn hold_envelope(&mut self, level: u8) {
+    self.envelope_holding = true;
+    self.envelope_rising = true;
+    self.envelope_step = level;
+  }
+
+  fn envelope_level(&self) -> u8 {
+    if self.envelope_rising {
+      self.envelope_step
+    } else {
+      31 - self.envelope_step
+    }
+  }
+
+  pub fn output(&self) -> f32 {
+    let noise = self.lfsr & 1 != 0;
+    self
+      .tones
+      .iter()
+      .enumerate()
+      .map(|(i, tone)| {
+        let tone_off = self.mixer & (1 << i) != 0;
+        let noise_off = self.mixer & (8 << i) != 0;
+        if !((tone.high || tone_off) && (noise || noise_off)) {
+          return 0.0;
+        }
+        let level = if tone.volume & 0x10 != 0 {
+          self.envelope_level()
+        } else if tone.volume & 0x0f == 0 {
+          0
+        } else {
+          (tone.volume & 0x0f) * 2 + 1
+        };
+        self.levels[level as usize]
+      })
+      .sum::<f32>()
+      * CHANNEL_SCALE
+  }
+
+  pub fn save_state(&self, w: &mut StateWriter) {
+    w.write_u8(self.address);
+    for tone in &self.tones {
+      w.write_u16(tone.period);
+      w.write_u16(tone.counter);
+      w.write_bool(tone.high);
+      w.write_u8(tone.volume);
+    }
+    w.write_u8(self.mixer);
+    w.write_u8(self.noise_period);
+    w.write_u8(self.noise_counter);
+    w.write_bool(self.noise_half);
+    w.write_usize(self.lfsr as usize);
+    w.write_u16(self.envelope_period);
+    w.write_u16(self.envelope_counter);
+    w.write_u8(self.envelope_shape);
+    w.write_bool(self.envelope_rising);
+    w.write_u8(self.envelope_step);
+    w.write_bool(self.envelope_holding);
+    w.write_u8(self.tone_divider);
+    w.write_u8(self.envelope_divider);
+  }
+
+  pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.address = r.read_u8()?;
+    for tone in self.tones.iter_mut() {
+      tone.period = r.read_u16()? & 0xfff;
+      tone.counter = r.read_u16()?;
+      tone.high = r.read_bool()?;
+      tone.volume = r.read_u8()? & 0x1f;
+    }
+    self.mixer = r.read_u8()?;
+    self.noise_period = r.read_u8()? & 0x1f;
+    self.noise_counter = r.read_u8()?;
+    self.noise_half = r.read_bool()?;
+    self.lfsr = r.read_usize()? as u32 & 0x1ffff;
+    self.envelope_period = r.read_u16()?;
+    self.envelope_counter = r.read_u16()?;
+    self.envelope_shape = r.read_u8()? & 0x0f;
+    self.envelope_rising = r.read_bool()?;
+    self.envelope_step = r.read_u8()?;
+    self.envelope_holding = r.read_bool()?;
+    self.tone_divider = r.read_u8()?;
+    self.envelope_divider = r.read_u8()?;
+    if self.envelope_step > 31
+      || self.tone_divider >= TONE_DIVIDER
+      || self.envelope_divider >= ENVELOPE_DIVIDER
+    {
+      return Err(SaveStateError::InvalidSaveState("5b audio"));
+    }
+    Ok(())
+  }
+}
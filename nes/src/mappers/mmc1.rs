@@ -1,13 +1,18 @@
 use core::panic;
 
-use alloc::boxed::Box;
 use common::kilobytes;
 use mos6502::memory::Bus;
 
+use super::notify_mirroring;
 use super::Mapper;
+use super::MirroringCallback;
+use crate::cartridge::error::CartridgeError;
 use crate::cartridge::Cartridge;
 use crate::cartridge::Mirroring;
 use crate::cartridge::Rom;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
 
 #[derive(Debug, PartialEq, Eq)]
 enum PrgBankMode {
@@ -43,16 +48,55 @@ pub struct MMC1<R: Rom> {
   chr_rom_bank_mode: ChrBankMode,
   selected_chr_bank_0: u8,
   selected_chr_bank_1: u8,
-  mirroring_cb: Option<Box<dyn FnMut(&Mirroring)>>,
+  mirroring_cb: Option<MirroringCallback>,
 
   num_shift_writes: u8,
   shift_register: u8,
 }
 
 impl<R: Rom> Mapper for MMC1<R> {
-  fn on_runtime_mirroring(&mut self, cb: Box<dyn FnMut(&Mirroring)>) {
+  fn on_runtime_mirroring(&mut self, cb: MirroringCallback) {
     self.mirroring_cb = Some(cb);
   }
+
+  fn prg_ram(&self) -> &[u8] {
+    self.cart.prg_ram()
+  }
+
+  fn import_prg_ram(&mut self, ram: &[u8]) -> Result<(), CartridgeError> {
+    self.cart.import_prg_ram(ram)
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    self.cart.save_state(w);
+    w.write_u8(match self.prg_rom_bank_mode {
+      PrgBankMode::Switch32Kb => 0,
+      PrgBankMode::FixFirstLowerSwitchUpper => 2,
+      PrgBankMode::FixLastUpperSwitchLower => 3,
+    });
+    w.write_u8(self.selected_prg_bank);
+    w.write_bool(self.chr_rom_bank_mode == ChrBankMode::SwitchTwo4KbBanks);
+    w.write_u8(self.selected_chr_bank_0);
+    w.write_u8(self.selected_chr_bank_1);
+    w.write_u8(self.num_shift_writes);
+    w.write_u8(self.shift_register);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.cart.load_state(r)?;
+    self.prg_rom_bank_mode = (r.read_u8()? & 0b11).into();
+    self.selected_prg_bank = r.read_u8()? & 0b01111;
+    self.chr_rom_bank_mode = if r.read_bool()? {
+      ChrBankMode::SwitchTwo4KbBanks
+    } else {
+      ChrBankMode::Switch8Kb
+    };
+    self.selected_chr_bank_0 = r.read_u8()?;
+    self.selected_chr_bank_1 = r.read_u8()?;
+    self.num_shift_writes = r.read_u8()? % 5;
+    self.shift_register = r.read_u8()?;
+    Ok(())
+  }
 }
 
 impl<R: Rom> MMC1<R> {
@@ -141,13 +185,8 @@ impl<R: Rom> MMC1<R> {
       _ => unreachable!(),
     };
 
-    if self.cart.mirroring() != runtime_mirroring {
-      let cb = self
-        .mirroring_cb
-        .as_mut()
-        .expect("mirroring changed, no one to tell");
-      (*cb)(&runtime_mirroring)
-    }
+    // Always tell, switching back to the header's mirroring is a change too
+    notify_mirroring(&mut self.mirroring_cb, runtime_mirroring);
 
     let chr_rom_bank_mode = (val & 0b10000) >> 4;
     self.chr_rom_bank_mode = match chr_rom_bank_mode {
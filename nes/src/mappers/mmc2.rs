@@ -0,0 +1,247 @@
+use common::kilobytes;
+use mos6502::memory::Bus;
+
+use super::notify_mirroring;
+use super::Mapper;
+use super::MirroringCallback;
+use super::PpuFetch;
+use crate::cartridge::error::CartridgeError;
+use crate::cartridge::Cartridge;
+use crate::cartridge::Mirroring;
+use crate::cartridge::Rom;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Chip {
+  // Mapper 9, Punch-Out!!
+  Mmc2,
+  // Mapper 10, Fire Emblem
+  Mmc4,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Latch {
+  Fd = 0,
+  Fe = 1,
+}
+
+// https://www.nesdev.org/wiki/MMC2
+// https://www.nesdev.org/wiki/MMC4
+pub struct MMC2<R: Rom> {
+  cart: Cartridge<R>,
+  chip: Chip,
+  prg_bank_size: usize,
+  prg_banks_total: usize,
+  prg_bank: u8,
+
+  // [latch][FD, FE] for $0000 and $1000
+  chr_banks: [[u8; 2]; 2],
+  latches: [Latch; 2],
+
+  mirroring_cb: Option<MirroringCallback>,
+}
+
+impl<R: Rom> MMC2<R> {
+  pub fn new(cart: Cartridge<R>, chip: Chip) -> Self {
+    let prg_bank_size = match chip {
+      Chip::Mmc2 => kilobytes::KB8,
+      Chip::Mmc4 => kilobytes::KB16,
+    };
+    Self {
+      prg_banks_total: cart.prg().len() / prg_bank_size,
+      cart,
+      chip,
+      prg_bank_size,
+      prg_bank: 0,
+      chr_banks: [[0; 2]; 2],
+      latches: [Latch::Fe; 2],
+      mirroring_cb: None,
+    }
+  }
+
+  fn read_prg(&self, address: u16) -> u8 {
+    let offset = address as usize - 0x8000;
+    let window = offset / self.prg_bank_size;
+    let bank = if window == 0 {
+      self.prg_bank as usize % self.prg_banks_total
+    } else {
+      // The rest is fixed to the last banks, three 8 KB ones on MMC2 and one 16 KB on MMC4
+      let windows = 0x8000 / self.prg_bank_size;
+      self.prg_banks_total - windows + window
+    };
+    self.cart.prg()[bank * self.prg_bank_size + offset % self.prg_bank_size]
+  }
+
+  fn read_chr(&self, address: u16) -> u8 {
+    let half = (address >> 12) as usize & 1;
+    let bank = self.chr_banks[half][self.latches[half] as usize] as usize;
+    let chr = self.cart.chr();
+    chr[(bank * kilobytes::KB4 + (address as usize & 0x0fff)) % chr.len()]
+  }
+
+  // The latch flips after the fetch that hits the trigger tile, the fetch itself uses the old bank.
+  // MMC2 only looks at the first row for $0FD8/$0FE8, MMC4 at all of them.
+  fn update_latch(&mut self, address: u16) {
+    let exact = self.chip == Chip::Mmc2 && address < 0x1000;
+    let (half, latch) = match address & 0x0ff8 {
+      0x0fd8 if !exact || address == 0x0fd8 => ((address >> 12) as usize, Latch::Fd),
+      0x0fe8 if !exact || address == 0x0fe8 => ((address >> 12) as usize, Latch::Fe),
+      _ => return,
+    };
+    self.latches[half & 1] = latch;
+  }
+}
+
+impl<R: Rom> Mapper for MMC2<R> {
+  fn on_runtime_mirroring(&mut self, cb: MirroringCallback) {
+    self.mirroring_cb = Some(cb);
+  }
+
+  fn ppu_read(&mut self, address: u16, _: PpuFetch) -> u8 {
+    let val = self.read_chr(address);
+    self.update_latch(address);
+    val
+  }
+
+  fn prg_ram(&self) -> &[u8] {
+    self.cart.prg_ram()
+  }
+
+  fn import_prg_ram(&mut self, ram: &[u8]) -> Result<(), CartridgeError> {
+    self.cart.import_prg_ram(ram)
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    self.cart.save_state(w);
+    w.write_u8(self.prg_bank);
+    for banks in &self.chr_banks {
+      w.write_bytes(banks);
+    }
+    for latch in &self.latches {
+      w.write_bool(*latch == Latch::Fe);
+    }
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.cart.load_state(r)?;
+    self.prg_bank = r.read_u8()? & 0x0f;
+    for banks in self.chr_banks.iter_mut() {
+      r.read_bytes(banks)?;
+    }
+    for latch in self.latches.iter_mut() {
+      *latch = if r.read_bool()? { Latch::Fe } else { Latch::Fd };
+    }
+    Ok(())
+  }
+}
+
+impl<R: Rom> Bus for MMC2<R> {
+  fn read8(&self, address: u16) -> u8 {
+    match address {
+      0x0000..=0x1fff => self.read_chr(address),
+      // MMC4 only, but harmless on MMC2 boards
+      0x6000..=0x7fff => self.cart.prg_ram()[address as usize - 0x6000],
+      0x8000..=0xffff => self.read_prg(address),
+      _ => 0,
+    }
+  }
+
+  fn write8(&mut self, val: u8, address: u16) {
+    match address {
+      0x6000..=0x7fff => self.cart.prg_ram_mut()[address as usize - 0x6000] = val,
+      0xa000..=0xafff => self.prg_bank = val & 0x0f,
+      0xb000..=0xbfff => self.chr_banks[0][Latch::Fd as usize] = val & 0x1f,
+      0xc000..=0xcfff => self.chr_banks[0][Latch::Fe as usize] = val & 0x1f,
+      0xd000..=0xdfff => self.chr_banks[1][Latch::Fd as usize] = val & 0x1f,
+      0xe000..=0xefff => self.chr_banks[1][Latch::Fe as usize] = val & 0x1f,
+      0xf000..=0xffff => {
+        let runtime_mirroring = if val & 1 == 0 {
+          Mirroring::Vertical
+        } else {
+          Mirroring::Horizontal
+        };
+        notify_mirroring(&mut self.mirroring_cb, runtime_mirroring)
+      }
+      _ => (),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mappers::tests::cart;
+
+  fn chr_banks(mmc: &mut MMC2<impl Rom>) {
+    mmc.write8(1, 0xb000); // $0000 FD
+    mmc.write8(2, 0xc000); // $0000 FE
+    mmc.write8(3, 0xd000); // $1000 FD
+    mmc.write8(4, 0xe000); // $1000 FE
+  }
+
+  #[test]
+  fn mmc2_prg() {
+    let mut mmc = MMC2::new(
+      cart(9, kilobytes::KB32 * 4, kilobytes::KB32 * 4),
+      Chip::Mmc2,
+    );
+    mmc.write8(5, 0xa000);
+    assert_eq!(mmc.read8(0x8000), 5);
+    assert_eq!(mmc.read8(0xa000), 13);
+    assert_eq!(mmc.read8(0xc000), 14);
+    assert_eq!(mmc.read8(0xe000), 15);
+  }
+
+  #[test]
+  fn mmc4_prg() {
+    let mut mmc = MMC2::new(
+      cart(10, kilobytes::KB32 * 4, kilobytes::KB32 * 4),
+      Chip::Mmc4,
+    );
+    mmc.write8(2, 0xa000);
+    assert_eq!(mmc.read8(0x8000), 4);
+    assert_eq!(mmc.read8(0xbfff), 5);
+    assert_eq!(mmc.read8(0xc000), 14);
+  }
+
+  #[test]
+  fn latches_switch_on_ppu_fetch() {
+    let mut mmc = MMC2::new(
+      cart(9, kilobytes::KB32 * 4, kilobytes::KB32 * 4),
+      Chip::Mmc2,
+    );
+    chr_banks(&mut mmc);
+    // Each 4 KB bank is four 1 KB ones
+    assert_eq!(mmc.ppu_read(0x0000, PpuFetch::Background), 8);
+    assert_eq!(mmc.ppu_read(0x1000, PpuFetch::Background), 16);
+
+    // Tile $FD, the fetch itself still sees the old bank
+    assert_eq!(mmc.ppu_read(0x0fd8, PpuFetch::Background), 11);
+    assert_eq!(mmc.ppu_read(0x0000, PpuFetch::Background), 4);
+    assert_eq!(mmc.ppu_read(0x1fdf, PpuFetch::Background), 19);
+    assert_eq!(mmc.ppu_read(0x1000, PpuFetch::Background), 12);
+
+    // MMC2 latch 0 only triggers on the exact address
+    mmc.ppu_read(0x0fe9, PpuFetch::Background);
+    assert_eq!(mmc.ppu_read(0x0000, PpuFetch::Background), 4);
+    mmc.ppu_read(0x0fe8, PpuFetch::Background);
+    assert_eq!(mmc.ppu_read(0x0000, PpuFetch::Background), 8);
+
+    // CPU side reads don't touch the latches
+    mmc.read8(0x1fe8);
+    assert_eq!(mmc.ppu_read(0x1000, PpuFetch::Background), 12);
+  }
+
+  #[test]
+  fn mmc4_latch_ranges() {
+    let mut mmc = MMC2::new(
+      cart(10, kilobytes::KB32 * 4, kilobytes::KB32 * 4),
+      Chip::Mmc4,
+    );
+    chr_banks(&mut mmc);
+    mmc.ppu_read(0x0fdc, PpuFetch::Background);
+    assert_eq!(mmc.ppu_read(0x0000, PpuFetch::Background), 4);
+  }
+}
@@ -1,13 +1,17 @@
-use alloc::boxed::Box;
-use core::panic;
-
 use common::kilobytes;
 use mos6502::memory::Bus;
 
+use super::notify_mirroring;
 use super::Mapper;
+use super::MirroringCallback;
+use crate::cartridge::error::CartridgeError;
 use crate::cartridge::Cartridge;
 use crate::cartridge::Mirroring;
+use crate::cartridge::Nametable;
 use crate::cartridge::Rom;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 enum PrgBankMode {
@@ -21,6 +25,15 @@ enum ChrBankMode {
   TwoKbAt1000_1 = 1,
 }
 
+// https://www.nesdev.org/wiki/MMC3#IRQ_Specifics
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Revision {
+  // Sharp MMC3B/C, fires on every clock the counter is 0, latch 0 included
+  B,
+  // NEC MMC3A (NES 2.0 submapper 4), only when the counter gets to 0
+  A,
+}
+
 pub struct MMC3<R: Rom> {
   cart: Cartridge<R>,
 
@@ -28,7 +41,7 @@ pub struct MMC3<R: Rom> {
   prg_rom_bank_mode: PrgBankMode,
 
   chr_rom_bank_mode: ChrBankMode,
-  mirroring_cb: Option<Box<dyn FnMut(&Mirroring)>>,
+  mirroring_cb: Option<MirroringCallback>,
 
   registers: [u8; 8],
   register_to_update: u8, // 3 bits
@@ -37,10 +50,16 @@ pub struct MMC3<R: Rom> {
   irq_latch: u8,
   irq_counter: u8,
   irq_reload: bool,
+  irq_pending: bool,
+  revision: Revision,
 }
 
 impl<R: Rom> MMC3<R> {
   pub fn new(cart: Cartridge<R>) -> Self {
+    let revision = match cart.header().submapper {
+      4 => Revision::A,
+      _ => Revision::B,
+    };
     Self {
       prg_rom_banks_total: cart.prg().len() / kilobytes::KB8,
       cart,
@@ -53,6 +72,8 @@ impl<R: Rom> MMC3<R> {
       irq_latch: 0,
       irq_counter: 0,
       irq_reload: false,
+      irq_pending: false,
+      revision,
     }
   }
 
@@ -109,24 +130,86 @@ impl<R: Rom> MMC3<R> {
 }
 
 impl<R: Rom> Mapper for MMC3<R> {
-  fn on_runtime_mirroring(&mut self, cb: Box<dyn FnMut(&Mirroring)>) {
+  fn on_runtime_mirroring(&mut self, cb: MirroringCallback) {
     self.mirroring_cb = Some(cb);
   }
 
-  fn irq(&mut self) -> bool {
-    if self.irq_reload {
-      self.irq_counter = self.irq_latch;
-      self.irq_reload = false;
-      return false;
+  // TVROM and TR1ROM carry four-screen RAM
+  fn read_nametable(&self, nametable: Nametable, offset: u16) -> u8 {
+    match nametable {
+      Nametable::CartridgeRam(page) => self.cart.read_nametable_ram(page, offset),
+      _ => 0,
+    }
+  }
+
+  fn write_nametable(&mut self, val: u8, nametable: Nametable, offset: u16) {
+    if let Nametable::CartridgeRam(page) = nametable {
+      self.cart.write_nametable_ram(val, page, offset);
     }
+  }
+
+  fn prg_ram(&self) -> &[u8] {
+    self.cart.prg_ram()
+  }
+
+  fn import_prg_ram(&mut self, ram: &[u8]) -> Result<(), CartridgeError> {
+    self.cart.import_prg_ram(ram)
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    self.cart.save_state(w);
+    w.write_u8(self.prg_rom_bank_mode as u8);
+    w.write_u8(self.chr_rom_bank_mode as u8);
+    w.write_bytes(&self.registers);
+    w.write_u8(self.register_to_update);
+    w.write_bool(self.irq_enabled);
+    w.write_u8(self.irq_latch);
+    w.write_u8(self.irq_counter);
+    w.write_bool(self.irq_reload);
+    w.write_bool(self.irq_pending);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.cart.load_state(r)?;
+    self.prg_rom_bank_mode = match r.read_u8()? {
+      0 => PrgBankMode::Swap8000FixC000_0,
+      _ => PrgBankMode::SwapC000Fix8000_1,
+    };
+    self.chr_rom_bank_mode = match r.read_u8()? {
+      0 => ChrBankMode::TwoKbAt0000_0,
+      _ => ChrBankMode::TwoKbAt1000_1,
+    };
+    r.read_bytes(&mut self.registers)?;
+    self.register_to_update = r.read_u8()? & 0b111;
+    self.irq_enabled = r.read_bool()?;
+    self.irq_latch = r.read_u8()?;
+    self.irq_counter = r.read_u8()?;
+    self.irq_reload = r.read_bool()?;
+    self.irq_pending = r.read_bool()?;
+    Ok(())
+  }
 
-    if self.irq_counter == 0 {
+  // The counter is clocked by PPU A12 rising, once per scanline when backgrounds
+  // and sprites use different pattern tables.
+  fn ppu_a12_rise(&mut self) {
+    let was_zero = self.irq_counter == 0;
+    let reloaded = self.irq_reload;
+    if was_zero || reloaded {
       self.irq_counter = self.irq_latch;
-      self.irq_enabled
     } else {
       self.irq_counter -= 1;
-      false
     }
+    self.irq_reload = false;
+
+    let fire = match self.revision {
+      Revision::B => self.irq_counter == 0,
+      Revision::A => self.irq_counter == 0 && (!was_zero || reloaded),
+    };
+    self.irq_pending |= fire && self.irq_enabled;
+  }
+
+  fn irq(&self) -> bool {
+    self.irq_pending
   }
 }
 
@@ -141,6 +224,7 @@ impl<R: Rom> Bus for MMC3<R> {
     }
   }
 
+  #[allow(clippy::collapsible_match)]
   fn write8(&mut self, val: u8, address: u16) {
     // println!("Write: {:#06x} {:#04x}", address, val);
     let even = address & 1 == 0;
@@ -182,12 +266,9 @@ impl<R: Rom> Bus for MMC3<R> {
             Mirroring::Vertical
           };
 
-          if self.cart.mirroring() != runtime_mirroring {
-            let cb = self
-              .mirroring_cb
-              .as_mut()
-              .expect("mirroring changed, no one to tell");
-            (*cb)(&runtime_mirroring)
+          // Four-screen boards have their own VRAM and ignore this
+          if self.cart.mirroring() != Mirroring::HardwiredFourScreen {
+            notify_mirroring(&mut self.mirroring_cb, runtime_mirroring)
           }
         }
         // Odd: PRG RAM protect
@@ -201,13 +282,99 @@ impl<R: Rom> Bus for MMC3<R> {
           self.irq_reload = true;
         }
       }
+      // Even disables (and acknowledges), odd enables
       0xe000..=0xffff => {
-        if self.irq_enabled && even && self.irq_counter <= 1 {
-          panic!("acknowledge any pending interrupts. ");
-        }
         self.irq_enabled = !even;
+        self.irq_pending &= !even;
       }
       _ => (),
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+
+  use super::*;
+  use crate::mappers::tests::cart;
+  use crate::mappers::tests::four_screen_cart;
+  use crate::mappers::tests::nes2_cart;
+
+  fn irq_latch(mmc3: &mut MMC3<impl Rom>, latch: u8) {
+    mmc3.write8(latch, 0xc000);
+    mmc3.write8(0, 0xc001);
+    mmc3.write8(0, 0xe001);
+  }
+
+  // Whether each clock asserted /IRQ, acknowledged in between
+  fn fires_after(mmc3: &mut MMC3<impl Rom>, clocks: usize) -> Vec<bool> {
+    (0..clocks)
+      .map(|_| {
+        mmc3.ppu_a12_rise();
+        core::mem::take(&mut mmc3.irq_pending)
+      })
+      .collect()
+  }
+
+  #[test]
+  fn scanline_counter() {
+    let mut mmc3 = MMC3::new(cart(4, kilobytes::KB32, kilobytes::KB8));
+    irq_latch(&mut mmc3, 2);
+    // Reload, 1, 0
+    assert_eq!(
+      fires_after(&mut mmc3, 6),
+      [false, false, true, false, false, true]
+    );
+
+    // Disabling doesn't stop the counter
+    mmc3.write8(0, 0xe000);
+    assert_eq!(fires_after(&mut mmc3, 3), [false, false, false]);
+    mmc3.write8(0, 0xe001);
+    assert_eq!(fires_after(&mut mmc3, 3), [false, false, true]);
+  }
+
+  #[test]
+  fn irq_held_until_acknowledged() {
+    let mut mmc3 = MMC3::new(cart(4, kilobytes::KB32, kilobytes::KB8));
+    irq_latch(&mut mmc3, 0);
+    mmc3.ppu_a12_rise();
+    mmc3.write8(0, 0xe001);
+    assert!(mmc3.irq());
+
+    mmc3.write8(0, 0xe000);
+    assert!(!mmc3.irq());
+  }
+
+  #[test]
+  fn latch_zero_rev_b() {
+    let mut mmc3 = MMC3::new(cart(4, kilobytes::KB32, kilobytes::KB8));
+    irq_latch(&mut mmc3, 0);
+    assert_eq!(fires_after(&mut mmc3, 3), [true, true, true]);
+  }
+
+  #[test]
+  fn latch_zero_rev_a() {
+    let mut mmc3 = MMC3::new(nes2_cart(4, 4, kilobytes::KB32, kilobytes::KB8));
+    assert_eq!(mmc3.revision, Revision::A);
+    irq_latch(&mut mmc3, 0);
+    // Only the $C001 reload counts
+    assert_eq!(fires_after(&mut mmc3, 3), [true, false, false]);
+
+    irq_latch(&mut mmc3, 1);
+    assert_eq!(fires_after(&mut mmc3, 4), [false, true, false, true]);
+  }
+
+  #[test]
+  fn four_screen_ram() {
+    let mut mmc3 = MMC3::new(four_screen_cart(4, kilobytes::KB32, kilobytes::KB8));
+    mmc3.write_nametable(0x42, Nametable::CartridgeRam(1), 0x3ff);
+    assert_eq!(mmc3.read_nametable(Nametable::CartridgeRam(1), 0x3ff), 0x42);
+    assert_eq!(mmc3.read_nametable(Nametable::CartridgeRam(0), 0x3ff), 0);
+
+    // Boards without it read nothing back
+    let mut mmc3 = MMC3::new(cart(4, kilobytes::KB32, kilobytes::KB8));
+    mmc3.write_nametable(0x42, Nametable::CartridgeRam(1), 0x3ff);
+    assert_eq!(mmc3.read_nametable(Nametable::CartridgeRam(1), 0x3ff), 0);
+  }
+}
@@ -0,0 +1,571 @@
+use core::cell::Cell;
+
+use common::kilobytes;
+use mos6502::memory::Bus;
+
+use super::notify_mirroring;
+use super::Mapper;
+use super::MirroringCallback;
+use super::PpuFetch;
+use crate::cartridge::error::CartridgeError;
+use crate::cartridge::Cartridge;
+use crate::cartridge::Mirroring;
+use crate::cartridge::Nametable;
+use crate::cartridge::Rom;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+const EXRAM_SIZE: usize = kilobytes::KB1;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum ExRamMode {
+  Nametable = 0,
+  ExtendedAttributes = 1,
+  Ram = 2,
+  ReadOnlyRam = 3,
+}
+
+impl From<u8> for ExRamMode {
+  fn from(i: u8) -> Self {
+    match i & 0b11 {
+      0 => ExRamMode::Nametable,
+      1 => ExRamMode::ExtendedAttributes,
+      2 => ExRamMode::Ram,
+      _ => ExRamMode::ReadOnlyRam,
+    }
+  }
+}
+
+// $5120-$5127 (A) and $5128-$512B (B)
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum ChrSet {
+  A,
+  B,
+}
+
+// Mapper 5
+// https://www.nesdev.org/wiki/MMC5
+pub struct MMC5<R: Rom> {
+  cart: Cartridge<R>,
+  prg_rom_banks_total: usize,
+  prg_ram_banks_total: usize,
+
+  prg_mode: u8,
+  // $5113-$5117
+  prg_banks: [u8; 5],
+  prg_ram_protect: [u8; 2],
+
+  chr_mode: u8,
+  chr_banks: [u16; 12],
+  chr_upper: u8,
+  last_chr_set: ChrSet,
+  sprite_size_16: bool,
+
+  exram: [u8; EXRAM_SIZE],
+  exram_mode: ExRamMode,
+  // The tile being drawn, for extended attributes
+  background_tile: u16,
+
+  nametable_mapping: u8,
+  fill_tile: u8,
+  fill_attribute: u8,
+  mirroring_cb: Option<MirroringCallback>,
+
+  irq_target: u8,
+  irq_enabled: bool,
+  irq_pending: Cell<bool>,
+  in_frame: bool,
+  scanline_counter: u8,
+
+  multiplicand: u8,
+  multiplier: u8,
+}
+
+impl<R: Rom> MMC5<R> {
+  pub fn new(cart: Cartridge<R>) -> Self {
+    Self {
+      prg_rom_banks_total: cart.prg().len() / kilobytes::KB8,
+      prg_ram_banks_total: cart.prg_ram().len() / kilobytes::KB8,
+      cart,
+      // "the MMC5 seems to power on in mode 3 with $5117 set to $FF"
+      prg_mode: 3,
+      prg_banks: [0, 0, 0, 0, 0xff],
+      prg_ram_protect: [0; 2],
+      chr_mode: 0,
+      chr_banks: [0; 12],
+      chr_upper: 0,
+      last_chr_set: ChrSet::A,
+      sprite_size_16: false,
+      exram: [0; EXRAM_SIZE],
+      exram_mode: ExRamMode::Nametable,
+      background_tile: 0,
+      nametable_mapping: 0,
+      fill_tile: 0,
+      fill_attribute: 0,
+      mirroring_cb: None,
+      irq_target: 0,
+      irq_enabled: false,
+      irq_pending: Cell::new(false),
+      in_frame: false,
+      scanline_counter: 0,
+      multiplicand: 0xff,
+      multiplier: 0xff,
+    }
+  }
+
+  // 8 KB bank for $6000-$FFFF, and whether it's ROM
+  // https://www.nesdev.org/wiki/MMC5#PRG_mode_($5100)
+  fn prg_bank(&self, address: u16) -> (usize, bool) {
+    let window = (address as usize >> 13) & 0b11; // $8000 = 0, .. $E000 = 3
+    let (reg, mask) = match (self.prg_mode, address) {
+      (_, 0x6000..=0x7fff) => return ((self.prg_banks[0] & 0x07) as usize, false),
+      (0, _) => (4, 0x7c),
+      (1, 0x8000..=0xbfff) | (2, 0x8000..=0xbfff) => (2, 0x7e),
+      (1, _) => (4, 0x7e),
+      (2, 0xc000..=0xdfff) => (3, 0x7f),
+      (2, _) => (4, 0x7f),
+      (_, _) => (window + 1, 0x7f),
+    };
+    let val = self.prg_banks[reg];
+    let bank = (val & mask) as usize | (window & !mask as usize & 0b11);
+    // $5117 is always ROM
+    let rom = reg == 4 || val & 0x80 != 0;
+    (bank, rom)
+  }
+
+  fn prg_ram_index(&self, bank: usize, address: u16) -> usize {
+    (bank % self.prg_ram_banks_total) * kilobytes::KB8 + (address as usize & 0x1fff)
+  }
+
+  fn prg_ram_writable(&self) -> bool {
+    self.prg_ram_protect == [0b10, 0b01]
+  }
+
+  fn chr_set(&self, fetch: PpuFetch) -> ChrSet {
+    match fetch {
+      PpuFetch::Sprite if self.sprite_size_16 => ChrSet::A,
+      PpuFetch::Background if self.sprite_size_16 => ChrSet::B,
+      _ => self.last_chr_set,
+    }
+  }
+
+  // https://www.nesdev.org/wiki/MMC5#CHR_mode_($5101)
+  fn chr_index(&self, address: u16, set: ChrSet) -> usize {
+    let a = address as usize & 0x1fff;
+    let (reg, size) = match (set, self.chr_mode) {
+      (ChrSet::A, 0) => (7, kilobytes::KB8),
+      (ChrSet::A, 1) => ((a / kilobytes::KB4) * 4 + 3, kilobytes::KB4),
+      (ChrSet::A, 2) => ((a / kilobytes::KB2) * 2 + 1, kilobytes::KB2),
+      (ChrSet::A, _) => (a / kilobytes::KB1, kilobytes::KB1),
+      // The B set only has four registers, both pattern tables see the same banks
+      (ChrSet::B, 0) => (11, kilobytes::KB8),
+      (ChrSet::B, 1) => (11, kilobytes::KB4),
+      (ChrSet::B, 2) => (((a & 0xfff) / kilobytes::KB2) * 2 + 9, kilobytes::KB2),
+      (ChrSet::B, _) => ((a & 0xfff) / kilobytes::KB1 + 8, kilobytes::KB1),
+    };
+    self.chr_banks[reg] as usize * size + a % size
+  }
+
+  fn read_chr(&self, index: usize) -> u8 {
+    let chr = self.cart.chr();
+    chr[index % chr.len()]
+  }
+
+  fn update_nametables(&mut self) {
+    let mut nametables = [Nametable::Ciram(0); 4];
+    for (i, nametable) in nametables.iter_mut().enumerate() {
+      *nametable = match (self.nametable_mapping >> (i * 2)) & 0b11 {
+        0 => Nametable::Ciram(0),
+        1 => Nametable::Ciram(1),
+        2 => Nametable::Mapper(0),
+        _ => Nametable::Fill {
+          tile: self.fill_tile,
+          attribute: self.fill_attribute,
+        },
+      };
+    }
+
+    notify_mirroring(&mut self.mirroring_cb, Mirroring::Custom(nametables));
+  }
+
+  fn read_register(&self, address: u16) -> u8 {
+    match address {
+      0x5204 => {
+        let mut status = 0;
+        if self.irq_pending.get() {
+          status |= 0x80;
+        }
+        if self.in_frame {
+          status |= 0x40;
+        }
+        // Reading acknowledges the IRQ
+        self.irq_pending.set(false);
+        status
+      }
+      0x5205 => (self.multiplicand as u16 * self.multiplier as u16) as u8,
+      0x5206 => ((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8,
+      0x5c00..=0x5fff => match self.exram_mode {
+        ExRamMode::Ram | ExRamMode::ReadOnlyRam => self.exram[address as usize - 0x5c00],
+        _ => 0,
+      },
+      _ => 0,
+    }
+  }
+
+  fn write_register(&mut self, val: u8, address: u16) {
+    match address {
+      0x5100 => self.prg_mode = val & 0b11,
+      0x5101 => self.chr_mode = val & 0b11,
+      0x5102 => self.prg_ram_protect[0] = val & 0b11,
+      0x5103 => self.prg_ram_protect[1] = val & 0b11,
+      0x5104 => self.exram_mode = val.into(),
+      0x5105 => {
+        self.nametable_mapping = val;
+        self.update_nametables();
+      }
+      0x5106 => {
+        self.fill_tile = val;
+        self.update_nametables();
+      }
+      0x5107 => {
+        self.fill_attribute = val & 0b11;
+        self.update_nametables();
+      }
+      0x5113..=0x5117 => self.prg_banks[address as usize - 0x5113] = val,
+      0x5120..=0x512b => {
+        let reg = address as usize - 0x5120;
+        self.chr_banks[reg] = ((self.chr_upper as u16) << 8) | val as u16;
+        self.last_chr_set = if reg < 8 { ChrSet::A } else { ChrSet::B };
+      }
+      0x5130 => self.chr_upper = val & 0b11,
+      0x5203 => self.irq_target = val,
+      0x5204 => self.irq_enabled = val & 0x80 != 0,
+      0x5205 => self.multiplicand = val,
+      0x5206 => self.multiplier = val,
+      0x5c00..=0x5fff => {
+        let offset = address as usize - 0x5c00;
+        match self.exram_mode {
+          // Only writable while rendering in the nametable modes, zeros otherwise
+          ExRamMode::Nametable | ExRamMode::ExtendedAttributes => {
+            self.exram[offset] = if self.in_frame { val } else { 0 }
+          }
+          ExRamMode::Ram => self.exram[offset] = val,
+          ExRamMode::ReadOnlyRam => (),
+        }
+      }
+      _ => (),
+    }
+  }
+}
+
+impl<R: Rom> Mapper for MMC5<R> {
+  fn on_runtime_mirroring(&mut self, cb: MirroringCallback) {
+    self.mirroring_cb = Some(cb);
+  }
+
+  fn read_nametable(&self, _: Nametable, offset: u16) -> u8 {
+    match self.exram_mode {
+      ExRamMode::Nametable | ExRamMode::ExtendedAttributes => self.exram[offset as usize],
+      _ => 0,
+    }
+  }
+
+  fn write_nametable(&mut self, val: u8, _: Nametable, offset: u16) {
+    if matches!(
+      self.exram_mode,
+      ExRamMode::Nametable | ExRamMode::ExtendedAttributes
+    ) {
+      self.exram[offset as usize] = val;
+    }
+  }
+
+  fn ppu_read(&mut self, address: u16, fetch: PpuFetch) -> u8 {
+    if fetch == PpuFetch::Background && self.exram_mode == ExRamMode::ExtendedAttributes {
+      // 4 KB bank from the tile's ExRAM byte, both pattern tables
+      let bank = (self.exram[self.background_tile as usize] & 0x3f) as usize
+        | (self.chr_upper as usize) << 6;
+      return self.read_chr(bank * kilobytes::KB4 + (address as usize & 0x0fff));
+    }
+    self.read_chr(self.chr_index(address, self.chr_set(fetch)))
+  }
+
+  fn ppu_background_tile(&mut self, offset: u16) -> Option<u8> {
+    if self.exram_mode != ExRamMode::ExtendedAttributes {
+      return None;
+    }
+    self.background_tile = offset;
+    Some(self.exram[offset as usize] >> 6)
+  }
+
+  fn ppu_register_write(&mut self, val: u8, register: u16) {
+    if register == 0 {
+      self.sprite_size_16 = val & 0x20 != 0;
+    }
+  }
+
+  // https://www.nesdev.org/wiki/MMC5#Scanline_Detection_and_Scanline_IRQ
+  fn ppu_scanline(&mut self, scanline: usize, rendering: bool) {
+    if !rendering || scanline >= 240 {
+      self.in_frame = false;
+      return;
+    }
+
+    if !self.in_frame {
+      self.in_frame = true;
+      self.scanline_counter = 0;
+      self.irq_pending.set(false);
+      return;
+    }
+
+    self.scanline_counter = self.scanline_counter.wrapping_add(1);
+    if self.scanline_counter == self.irq_target {
+      self.irq_pending.set(true);
+    }
+  }
+
+  fn irq(&self) -> bool {
+    self.irq_pending.get() && self.irq_enabled
+  }
+
+  fn prg_ram(&self) -> &[u8] {
+    self.cart.prg_ram()
+  }
+
+  fn import_prg_ram(&mut self, ram: &[u8]) -> Result<(), CartridgeError> {
+    self.cart.import_prg_ram(ram)
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    self.cart.save_state(w);
+    w.write_u8(self.prg_mode);
+    w.write_bytes(&self.prg_banks);
+    w.write_bytes(&self.prg_ram_protect);
+    w.write_u8(self.chr_mode);
+    for bank in &self.chr_banks {
+      w.write_u16(*bank);
+    }
+    w.write_u8(self.chr_upper);
+    w.write_bool(self.last_chr_set == ChrSet::B);
+    w.write_bool(self.sprite_size_16);
+    w.write_bytes(&self.exram);
+    w.write_u8(self.exram_mode as u8);
+    w.write_u16(self.background_tile);
+    w.write_u8(self.nametable_mapping);
+    w.write_u8(self.fill_tile);
+    w.write_u8(self.fill_attribute);
+    w.write_u8(self.irq_target);
+    w.write_bool(self.irq_enabled);
+    w.write_bool(self.irq_pending.get());
+    w.write_bool(self.in_frame);
+    w.write_u8(self.scanline_counter);
+    w.write_u8(self.multiplicand);
+    w.write_u8(self.multiplier);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.cart.load_state(r)?;
+    self.prg_mode = r.read_u8()? & 0b11;
+    r.read_bytes(&mut self.prg_banks)?;
+    r.read_bytes(&mut self.prg_ram_protect)?;
+    self.chr_mode = r.read_u8()? & 0b11;
+    for bank in self.chr_banks.iter_mut() {
+      *bank = r.read_u16()?;
+    }
+    self.chr_upper = r.read_u8()? & 0b11;
+    self.last_chr_set = if r.read_bool()? { ChrSet::B } else { ChrSet::A };
+    self.sprite_size_16 = r.read_bool()?;
+    r.read_bytes(&mut self.exram)?;
+    self.exram_mode = r.read_u8()?.into();
+    self.background_tile = r.read_u16()? % 0x3c0;
+    self.nametable_mapping = r.read_u8()?;
+    self.fill_tile = r.read_u8()?;
+    self.fill_attribute = r.read_u8()? & 0b11;
+    self.irq_target = r.read_u8()?;
+    self.irq_enabled = r.read_bool()?;
+    self.irq_pending.set(r.read_bool()?);
+    self.in_frame = r.read_bool()?;
+    self.scanline_counter = r.read_u8()?;
+    self.multiplicand = r.read_u8()?;
+    self.multiplier = r.read_u8()?;
+    Ok(())
+  }
+}
+
+impl<R: Rom> Bus for MMC5<R> {
+  fn read8(&self, address: u16) -> u8 {
+    match address {
+      0x0000..=0x1fff => self.read_chr(self.chr_index(address, self.last_chr_set)),
+      0x5000..=0x5fff => self.read_register(address),
+      0x6000..=0xffff => {
+        let (bank, rom) = self.prg_bank(address);
+        if rom {
+          let bank = bank % self.prg_rom_banks_total;
+          self.cart.prg()[bank * kilobytes::KB8 + (address as usize & 0x1fff)]
+        } else {
+          self.cart.prg_ram()[self.prg_ram_index(bank, address)]
+        }
+      }
+      _ => 0,
+    }
+  }
+
+  fn write8(&mut self, val: u8, address: u16) {
+    match address {
+      0x0000..=0x1fff => {
+        let index = self.chr_index(address, self.last_chr_set);
+        let chr_ram = self.cart.chr_ram();
+        let len = chr_ram.len();
+        chr_ram[index % len] = val;
+      }
+      0x5000..=0x5fff => self.write_register(val, address),
+      0x6000..=0xdfff if self.prg_ram_writable() => {
+        let (bank, rom) = self.prg_bank(address);
+        if !rom {
+          let index = self.prg_ram_index(bank, address);
+          self.cart.prg_ram_mut()[index] = val;
+        }
+      }
+      _ => (),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mappers::tests::cart;
+  use crate::mappers::tests::spy_mirroring;
+
+  fn sut() -> MMC5<impl Rom> {
+    MMC5::new(cart(5, kilobytes::KB32 * 8, kilobytes::KB32 * 8))
+  }
+
+  #[test]
+  fn prg_modes() {
+    let mut mmc = sut();
+    // Power on, mode 3, last bank everywhere $5117 reaches
+    assert_eq!(mmc.read8(0xe000), 31);
+
+    mmc.write8(0x83, 0x5114);
+    mmc.write8(0x85, 0x5115);
+    mmc.write8(0x87, 0x5116);
+    mmc.write8(0x89, 0x5117);
+    assert_eq!(
+      [0x8000, 0xa000, 0xc000, 0xe000].map(|a| mmc.read8(a)),
+      [3, 5, 7, 9]
+    );
+
+    mmc.write8(2, 0x5100);
+    assert_eq!(
+      [0x8000, 0xa000, 0xc000, 0xe000].map(|a| mmc.read8(a)),
+      [4, 5, 7, 9]
+    );
+
+    mmc.write8(1, 0x5100);
+    assert_eq!(
+      [0x8000, 0xa000, 0xc000, 0xe000].map(|a| mmc.read8(a)),
+      [4, 5, 8, 9]
+    );
+
+    mmc.write8(0, 0x5100);
+    assert_eq!(
+      [0x8000, 0xa000, 0xc000, 0xe000].map(|a| mmc.read8(a)),
+      [8, 9, 10, 11]
+    );
+  }
+
+  #[test]
+  fn prg_ram_banks_and_protection() {
+    let mut mmc = sut();
+    mmc.write8(0x00, 0x5114); // RAM bank 0 at $8000
+    mmc.write8(0x42, 0x8000);
+    assert_eq!(mmc.read8(0x8000), 0);
+
+    mmc.write8(0b10, 0x5102);
+    mmc.write8(0b01, 0x5103);
+    mmc.write8(0x42, 0x8000);
+    assert_eq!(mmc.read8(0x8000), 0x42);
+    assert_eq!(mmc.read8(0x6000), 0x42);
+  }
+
+  #[test]
+  fn separate_sprite_and_background_chr() {
+    let mut mmc = sut();
+    mmc.write8(3, 0x5101); // 1 KB banks
+    for (i, reg) in (0x5120..=0x512b).enumerate() {
+      mmc.write8(i as u8 + 0x10, reg);
+    }
+    mmc.ppu_register_write(0x20, 0); // 8x16 sprites
+
+    assert_eq!(mmc.ppu_read(0x1400, PpuFetch::Sprite), 0x15);
+    assert_eq!(mmc.ppu_read(0x1400, PpuFetch::Background), 0x19);
+    assert_eq!(mmc.ppu_read(0x0400, PpuFetch::Background), 0x19);
+
+    // 8x8 sprites, everything uses the last written set
+    mmc.ppu_register_write(0x00, 0);
+    assert_eq!(mmc.ppu_read(0x1400, PpuFetch::Sprite), 0x19);
+    mmc.write8(0x30, 0x5125);
+    assert_eq!(mmc.ppu_read(0x1400, PpuFetch::Background), 0x30);
+  }
+
+  #[test]
+  fn extended_attributes() {
+    let mut mmc = sut();
+    mmc.in_frame = true;
+    mmc.write8(1, 0x5104);
+    mmc.write8(0b10000011, 0x5c00 + 0x21);
+
+    assert_eq!(mmc.ppu_background_tile(0x21), Some(0b10));
+    // 4 KB bank 3 == 1 KB bank 12
+    assert_eq!(mmc.ppu_read(0x0000, PpuFetch::Background), 12);
+    assert_eq!(mmc.ppu_read(0x1000, PpuFetch::Background), 12);
+  }
+
+  #[test]
+  fn fill_mode_nametables() {
+    let mut mmc = sut();
+    let mirroring = spy_mirroring(&mut mmc);
+    mmc.write8(0x42, 0x5106);
+    mmc.write8(0x01, 0x5107);
+    mmc.write8(0b11100100, 0x5105);
+    assert_eq!(
+      *mirroring.borrow(),
+      Some(Mirroring::Custom([
+        Nametable::Ciram(0),
+        Nametable::Ciram(1),
+        Nametable::Mapper(0),
+        Nametable::Fill {
+          tile: 0x42,
+          attribute: 1
+        },
+      ]))
+    );
+  }
+
+  #[test]
+  fn scanline_irq() {
+    let mut mmc = sut();
+    mmc.write8(3, 0x5203);
+    mmc.write8(0x80, 0x5204);
+
+    let fired = (0..262).find(|&scanline| {
+      mmc.ppu_scanline(scanline, true);
+      mmc.irq()
+    });
+    assert_eq!(fired, Some(3));
+    // In frame, held until $5204 is read
+    assert_eq!(mmc.read8(0x5204), 0xc0);
+    assert!(!mmc.irq());
+    assert_eq!(mmc.read8(0x5204), 0x40);
+  }
+
+  #[test]
+  fn multiplier() {
+    let mut mmc = sut();
+    mmc.write8(200, 0x5205);
+    mmc.write8(100, 0x5206);
+    assert_eq!(mmc.read8(0x5205), (20000 & 0xff) as u8);
+    assert_eq!(mmc.read8(0x5206), (20000 >> 8) as u8);
+  }
+}
@@ -4,21 +4,126 @@ use core::cell::RefCell;
 
 use mos6502::memory::Bus;
 
+use crate::cartridge::error::CartridgeError;
 use crate::cartridge::Cartridge;
 use crate::cartridge::Mirroring;
+use crate::cartridge::Nametable;
 use crate::cartridge::Rom;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
 
+mod axrom;
+mod bnrom;
+mod camerica;
 mod cnrom;
+mod color_dreams;
+mod fme7;
+mod fme7_audio;
+mod gxrom;
 mod mmc1;
+mod mmc2;
 mod mmc3;
+mod mmc5;
+mod n163;
+mod n163_audio;
+mod namco108;
 mod nrom;
 mod uxrom;
+mod vrc4;
+mod vrc6;
+mod vrc7;
+mod vrc7_audio;
+mod vrc_irq;
+
+pub type MirroringCallback = Box<dyn FnMut(&Mirroring)>;
+
+// Tells the PPU about a mirroring change. From then on mirroring lives in the
+// PPU's state, so mappers don't save it.
+pub(crate) fn notify_mirroring(cb: &mut Option<MirroringCallback>, mirroring: Mirroring) {
+  let cb = cb.as_mut().expect("mirroring changed, no one to tell");
+  cb(&mirroring)
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PpuFetch {
+  Background,
+  Sprite,
+  // $2007
+  Cpu,
+}
 
 pub trait Mapper: Bus {
-  fn on_runtime_mirroring(&mut self, _: Box<dyn FnMut(&Mirroring)>) {}
-  fn irq(&mut self) -> bool {
+  fn on_runtime_mirroring(&mut self, _: MirroringCallback) {}
+
+  // Nametable::CartridgeRam, ChrRom and Mapper pages, offset is 0..0x400
+  fn read_nametable(&self, _: Nametable, _: u16) -> u8 {
+    0
+  }
+  fn write_nametable(&mut self, _: u8, _: Nametable, _: u16) {}
+
+  // Pattern table fetches by the PPU, $0000-$1FFF. Unlike read8, the mapper
+  // gets to see (and react to) what the PPU is drawing, e.g. MMC2's CHR latches.
+  fn ppu_read(&mut self, address: u16, _: PpuFetch) -> u8 {
+    self.read8(address)
+  }
+
+  // The PPU is about to draw the background tile at `offset` (0..0x3c0) of a nametable.
+  // Some replaces the tile's palette (0-3), e.g. MMC5 extended attributes.
+  fn ppu_background_tile(&mut self, _: u16) -> Option<u8> {
+    None
+  }
+
+  // CPU writes to PPU registers, $2000-$2007 as 0-7
+  fn ppu_register_write(&mut self, _: u8, _: u16) {}
+
+  // Dot 0 of every scanline, 0..=261 (311 on PAL and Dendy)
+  fn ppu_scanline(&mut self, _: usize, _: bool) {}
+
+  // https://www.nesdev.org/wiki/MMC3#IRQ_Specifics
+  // PPU address line A12 went high after being low for a few CPU cycles (the M2 filter,
+  // which hides the toggling between nametable and pattern fetches).
+  fn ppu_a12_rise(&mut self) {}
+
+  // Every CPU instruction (or cycle, when cycle-stepped), with the cycles it took
+  fn tick(&mut self, _: usize) {}
+
+  // The cartridge's /IRQ, held until the game acknowledges it
+  fn irq(&self) -> bool {
     false
   }
+
+  // Expansion audio, mixed in with the APU's output
+  fn audio_output(&self) -> f32 {
+    0.0
+  }
+
+  // The cartridge's PRG RAM, for battery saves
+  fn prg_ram(&self) -> &[u8] {
+    &[]
+  }
+  fn import_prg_ram(&mut self, _: &[u8]) -> Result<(), CartridgeError> {
+    Err(CartridgeError::InvalidCartridge("no prg ram"))
+  }
+
+  // Bank registers, IRQ counters and cartridge RAM
+  fn save_state(&self, _: &mut StateWriter) {}
+  fn load_state(&mut self, _: &mut StateReader) -> Result<(), SaveStateError> {
+    Ok(())
+  }
+}
+
+// https://www.nesdev.org/wiki/Bus_conflict
+// Writes to ROM on discrete boards reach both the mapper and the ROM, which drives
+// the data bus too, so the mapper sees the value ANDed with the ROM byte.
+// `submapper` is for boards where NES 2.0 submapper 1 means none and 2 means AND.
+pub(crate) fn bus_conflicts<R: Rom>(cart: &Cartridge<R>, submapper: bool, default: bool) -> bool {
+  match (cart.bus_conflicts(), cart.header().submapper) {
+    (Some(enabled), _) => enabled,
+    (None, 1) if submapper => false,
+    (None, 2) if submapper => true,
+    _ => default,
+  }
 }
 
 pub(crate) fn for_cart<R: Rom + 'static>(cart: Cartridge<R>) -> Rc<RefCell<dyn Mapper>> {
@@ -28,5 +133,138 @@ pub(crate) fn for_cart<R: Rom + 'static>(cart: Cartridge<R>) -> Rc<RefCell<dyn M
     crate::cartridge::MapperType::Uxrom => Rc::new(RefCell::new(uxrom::UxROM::new(cart))),
     crate::cartridge::MapperType::Cnrom => Rc::new(RefCell::new(cnrom::CNROM::new(cart))),
     crate::cartridge::MapperType::Mmc3 => Rc::new(RefCell::new(mmc3::MMC3::new(cart))),
+    crate::cartridge::MapperType::Axrom => Rc::new(RefCell::new(axrom::AxROM::new(cart))),
+    crate::cartridge::MapperType::Mmc5 => Rc::new(RefCell::new(mmc5::MMC5::new(cart))),
+    crate::cartridge::MapperType::Mmc2 => {
+      Rc::new(RefCell::new(mmc2::MMC2::new(cart, mmc2::Chip::Mmc2)))
+    }
+    crate::cartridge::MapperType::Mmc4 => {
+      Rc::new(RefCell::new(mmc2::MMC2::new(cart, mmc2::Chip::Mmc4)))
+    }
+    crate::cartridge::MapperType::Vrc4ac
+    | crate::cartridge::MapperType::Vrc2a
+    | crate::cartridge::MapperType::Vrc2b4ef
+    | crate::cartridge::MapperType::Vrc4bd2c => Rc::new(RefCell::new(vrc4::VRC4::new(cart))),
+    crate::cartridge::MapperType::Vrc6a | crate::cartridge::MapperType::Vrc6b => {
+      Rc::new(RefCell::new(vrc6::VRC6::new(cart)))
+    }
+    crate::cartridge::MapperType::Vrc7 => Rc::new(RefCell::new(vrc7::VRC7::new(cart))),
+    crate::cartridge::MapperType::Fme7 => Rc::new(RefCell::new(fme7::FME7::new(cart))),
+    crate::cartridge::MapperType::N163 => Rc::new(RefCell::new(n163::N163::new(cart))),
+    crate::cartridge::MapperType::ColorDreams => {
+      Rc::new(RefCell::new(color_dreams::ColorDreams::new(cart)))
+    }
+    crate::cartridge::MapperType::Bnrom => Rc::new(RefCell::new(bnrom::BNROM::new(cart))),
+    crate::cartridge::MapperType::Gxrom => Rc::new(RefCell::new(gxrom::GxROM::new(cart))),
+    crate::cartridge::MapperType::Camerica => Rc::new(RefCell::new(camerica::Camerica::new(cart))),
+    crate::cartridge::MapperType::Namco108 => Rc::new(RefCell::new(namco108::Namco108::new(cart))),
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use alloc::rc::Rc;
+  use alloc::vec::Vec;
+  use core::cell::RefCell;
+
+  use common::kilobytes;
+
+  use super::Mapper;
+  use crate::cartridge::Cartridge;
+  use crate::cartridge::HeapRom;
+  use crate::cartridge::Mirroring;
+
+  // iNES cartridge where every PRG byte holds the number of its 8 KB bank,
+  // and every CHR byte the number of its 1 KB bank. No CHR means CHR RAM.
+  pub fn cart(mapper: u8, prg_size: usize, chr_size: usize) -> Cartridge<HeapRom> {
+    build(mapper, None, banked_prg(prg_size), chr_size, false)
+  }
+
+  // Same as `cart` but with four-screen nametable RAM on the board
+  pub fn four_screen_cart(mapper: u8, prg_size: usize, chr_size: usize) -> Cartridge<HeapRom> {
+    build(mapper, None, banked_prg(prg_size), chr_size, true)
+  }
+
+  // Same as `cart` but with the given PRG ROM, e.g. for bus conflicts
+  pub fn cart_with_prg(mapper: u8, prg: Vec<u8>, chr_size: usize) -> Cartridge<HeapRom> {
+    build(mapper, None, prg, chr_size, false)
+  }
+
+  // Same as `cart` but with an NES 2.0 header, for boards told apart by submapper
+  pub fn nes2_cart(
+    mapper: u8,
+    submapper: u8,
+    prg_size: usize,
+    chr_size: usize,
+  ) -> Cartridge<HeapRom> {
+    build(
+      mapper,
+      Some(submapper),
+      banked_prg(prg_size),
+      chr_size,
+      false,
+    )
+  }
+
+  // Same as `nes2_cart` but battery backed, with 64 << `prg_nvram_shift` bytes of PRG NVRAM
+  pub fn battery_cart(
+    mapper: u8,
+    prg_size: usize,
+    chr_size: usize,
+    prg_nvram_shift: u8,
+  ) -> Cartridge<HeapRom> {
+    let mut rom = rom(mapper, Some(0), banked_prg(prg_size), chr_size, false);
+    rom[6] |= 0x02;
+    rom[10] = prg_nvram_shift << 4;
+    Cartridge::blow_dust_vec(rom).unwrap()
+  }
+
+  fn banked_prg(size: usize) -> Vec<u8> {
+    (0..size).map(|i| (i / kilobytes::KB8) as u8).collect()
+  }
+
+  fn build(
+    mapper: u8,
+    submapper: Option<u8>,
+    prg: Vec<u8>,
+    chr_size: usize,
+    four_screen: bool,
+  ) -> Cartridge<HeapRom> {
+    Cartridge::blow_dust_vec(rom(mapper, submapper, prg, chr_size, four_screen)).unwrap()
+  }
+
+  fn rom(
+    mapper: u8,
+    submapper: Option<u8>,
+    prg: Vec<u8>,
+    chr_size: usize,
+    four_screen: bool,
+  ) -> Vec<u8> {
+    let mut rom = vec![
+      0x4e,
+      0x45,
+      0x53,
+      0x1a,
+      (prg.len() / kilobytes::KB16) as u8,
+      (chr_size / kilobytes::KB8) as u8,
+      (mapper << 4) | ((four_screen as u8) << 3),
+      mapper & 0xf0,
+    ];
+    rom.resize(16, 0);
+    if let Some(submapper) = submapper {
+      rom[7] |= 0x08;
+      rom[8] = submapper << 4;
+    }
+    rom.extend(prg);
+    rom.extend((0..chr_size).map(|i| (i / kilobytes::KB1) as u8));
+    rom
+  }
+
+  // Records the last runtime mirroring the mapper asked for
+  pub fn spy_mirroring(mapper: &mut dyn Mapper) -> Rc<RefCell<Option<Mirroring>>> {
+    let last = Rc::new(RefCell::new(None));
+    let l = last.clone();
+    mapper.on_runtime_mirroring(alloc::boxed::Box::new(move |m| *l.borrow_mut() = Some(*m)));
+    last
   }
 }
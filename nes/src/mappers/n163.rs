@@ -0,0 +1,378 @@
+use core::cell::Cell;
+
+use common::kilobytes;
+use mos6502::memory::Bus;
+
+use super::n163_audio::N163Audio;
+use super::n163_audio::INTERNAL_RAM_SIZE;
+use super::notify_mirroring;
+use super::Mapper;
+use super::MirroringCallback;
+use crate::cartridge::error::CartridgeError;
+use crate::cartridge::Cartridge;
+use crate::cartridge::Format;
+use crate::cartridge::Mirroring;
+use crate::cartridge::Nametable;
+use crate::cartridge::Rom;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+// Bank values from here select CIRAM instead of CHR ROM
+const CIRAM_BANKS: u8 = 0xe0;
+
+// Mapper 19
+// https://www.nesdev.org/wiki/Namco_163
+pub struct N163<R: Rom> {
+  cart: Cartridge<R>,
+  prg_rom_banks_total: usize,
+  // Some boards battery back the chip's 128 bytes instead of PRG RAM (NES 2.0 PRG NVRAM of 128 B)
+  battery_internal_ram: bool,
+
+  prg_banks: [u8; 3],
+  // Pattern tables, then nametables. Pattern tables in CIRAM aren't supported.
+  chr_banks: [u8; 8],
+  nametable_banks: [u8; 4],
+  // $F800, four 2 KB windows of $6000-$7FFF
+  prg_ram_writable: [bool; 4],
+  sound_disabled: bool,
+  mirroring_cb: Option<MirroringCallback>,
+
+  irq_counter: u16, // 15 bits
+  irq_enabled: bool,
+  irq_pending: Cell<bool>,
+
+  audio: N163Audio,
+}
+
+impl<R: Rom> N163<R> {
+  pub fn new(cart: Cartridge<R>) -> Self {
+    let header = cart.header();
+    let battery_internal_ram =
+      header.format == Format::Nes2 && header.prg_nvram_size == INTERNAL_RAM_SIZE;
+    Self {
+      prg_rom_banks_total: cart.prg().len() / kilobytes::KB8,
+      battery_internal_ram,
+      cart,
+      prg_banks: [0; 3],
+      chr_banks: [0; 8],
+      nametable_banks: [0; 4],
+      prg_ram_writable: [false; 4],
+      sound_disabled: false,
+      mirroring_cb: None,
+      irq_counter: 0,
+      irq_enabled: false,
+      irq_pending: Cell::new(false),
+      audio: N163Audio::new(),
+    }
+  }
+
+  fn read_prg(&self, address: u16) -> u8 {
+    let bank = match address {
+      0x8000..=0xdfff => self.prg_banks[(address as usize - 0x8000) / kilobytes::KB8] as usize,
+      _ => self.prg_rom_banks_total - 1,
+    };
+    let bank = bank % self.prg_rom_banks_total;
+    self.cart.prg()[bank * kilobytes::KB8 + (address as usize & 0x1fff)]
+  }
+
+  fn chr_index(&self, address: u16) -> usize {
+    let bank = self.chr_banks[address as usize / kilobytes::KB1] as usize;
+    bank * kilobytes::KB1 + (address as usize & 0x3ff)
+  }
+
+  fn update_nametables(&mut self) {
+    let nametables = self.nametable_banks.map(|bank| {
+      if bank >= CIRAM_BANKS {
+        Nametable::Ciram(bank & 1)
+      } else {
+        Nametable::ChrRom(bank as u16)
+      }
+    });
+    notify_mirroring(&mut self.mirroring_cb, Mirroring::Custom(nametables))
+  }
+
+  // Reading or writing either half acknowledges the IRQ
+  fn read_register(&self, address: u16) -> u8 {
+    match address {
+      0x4800..=0x4fff => self.audio.read_data(),
+      0x5000..=0x57ff => {
+        self.irq_pending.set(false);
+        self.irq_counter as u8
+      }
+      _ => {
+        self.irq_pending.set(false);
+        ((self.irq_enabled as u8) << 7) | (self.irq_counter >> 8) as u8
+      }
+    }
+  }
+
+  fn write_register(&mut self, val: u8, address: u16) {
+    match address {
+      0x4800..=0x4fff => self.audio.write_data(val),
+      0x5000..=0x57ff => {
+        self.irq_counter = (self.irq_counter & 0x7f00) | val as u16;
+        self.irq_pending.set(false);
+      }
+      0x5800..=0x5fff => {
+        self.irq_counter = (self.irq_counter & 0x00ff) | ((val as u16 & 0x7f) << 8);
+        self.irq_enabled = val & 0x80 != 0;
+        self.irq_pending.set(false);
+      }
+      0x8000..=0xbfff => self.chr_banks[(address as usize - 0x8000) / 0x800] = val,
+      0xc000..=0xdfff => {
+        self.nametable_banks[(address as usize - 0xc000) / 0x800] = val;
+        self.update_nametables();
+      }
+      0xe000..=0xe7ff => {
+        self.prg_banks[0] = val & 0x3f;
+        self.sound_disabled = val & 0x40 != 0;
+      }
+      // Bits 6 and 7 only matter for pattern tables in CIRAM
+      0xe800..=0xefff => self.prg_banks[1] = val & 0x3f,
+      0xf000..=0xf7ff => self.prg_banks[2] = val & 0x3f,
+      _ => {
+        // Write protection and the sound address share the register
+        let enabled = val & 0xf0 == 0x40;
+        for (i, writable) in self.prg_ram_writable.iter_mut().enumerate() {
+          *writable = enabled && val & (1 << i) == 0;
+        }
+        self.audio.write_address(val);
+      }
+    }
+  }
+}
+
+impl<R: Rom> Mapper for N163<R> {
+  fn on_runtime_mirroring(&mut self, cb: MirroringCallback) {
+    self.mirroring_cb = Some(cb);
+  }
+
+  fn read_nametable(&self, nametable: Nametable, offset: u16) -> u8 {
+    match nametable {
+      Nametable::ChrRom(bank) => {
+        let chr = self.cart.chr();
+        chr[(bank as usize * kilobytes::KB1 + offset as usize) % chr.len()]
+      }
+      _ => 0,
+    }
+  }
+
+  // The counter counts up every CPU cycle, and stops when it fires at $7FFF
+  fn tick(&mut self, cpu_cycles: usize) {
+    if !self.sound_disabled {
+      for _ in 0..cpu_cycles {
+        self.audio.tick();
+      }
+    }
+
+    if self.irq_enabled && self.irq_counter != 0x7fff {
+      self.irq_counter = (self.irq_counter as usize + cpu_cycles).min(0x7fff) as u16;
+      if self.irq_counter == 0x7fff {
+        self.irq_pending.set(true);
+      }
+    }
+  }
+
+  fn irq(&self) -> bool {
+    self.irq_pending.get()
+  }
+
+  fn audio_output(&self) -> f32 {
+    if self.sound_disabled {
+      0.0
+    } else {
+      self.audio.output()
+    }
+  }
+
+  fn prg_ram(&self) -> &[u8] {
+    if self.battery_internal_ram {
+      self.audio.ram()
+    } else {
+      self.cart.prg_ram()
+    }
+  }
+
+  fn import_prg_ram(&mut self, ram: &[u8]) -> Result<(), CartridgeError> {
+    if !self.battery_internal_ram {
+      return self.cart.import_prg_ram(ram);
+    }
+    if ram.len() != INTERNAL_RAM_SIZE {
+      return Err(CartridgeError::InvalidCartridge("prg ram size"));
+    }
+    self.audio.ram_mut().copy_from_slice(ram);
+    Ok(())
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    self.cart.save_state(w);
+    w.write_bytes(&self.prg_banks);
+    w.write_bytes(&self.chr_banks);
+    w.write_bytes(&self.nametable_banks);
+    self.prg_ram_writable.iter().for_each(|&b| w.write_bool(b));
+    w.write_bool(self.sound_disabled);
+    w.write_u16(self.irq_counter);
+    w.write_bool(self.irq_enabled);
+    w.write_bool(self.irq_pending.get());
+    self.audio.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.cart.load_state(r)?;
+    r.read_bytes(&mut self.prg_banks)?;
+    r.read_bytes(&mut self.chr_banks)?;
+    r.read_bytes(&mut self.nametable_banks)?;
+    for b in self.prg_ram_writable.iter_mut() {
+      *b = r.read_bool()?;
+    }
+    self.sound_disabled = r.read_bool()?;
+    self.irq_counter = r.read_u16()? & 0x7fff;
+    self.irq_enabled = r.read_bool()?;
+    self.irq_pending.set(r.read_bool()?);
+    self.audio.load_state(r)
+  }
+}
+
+impl<R: Rom> Bus for N163<R> {
+  fn read8(&self, address: u16) -> u8 {
+    match address {
+      0x0000..=0x1fff => {
+        let chr = self.cart.chr();
+        chr[self.chr_index(address) % chr.len()]
+      }
+      0x4800..=0x5fff => self.read_register(address),
+      0x6000..=0x7fff => self.cart.prg_ram()[address as usize - 0x6000],
+      0x8000..=0xffff => self.read_prg(address),
+      _ => 0,
+    }
+  }
+
+  fn write8(&mut self, val: u8, address: u16) {
+    match address {
+      0x0000..=0x1fff => {
+        let index = self.chr_index(address);
+        let chr_ram = self.cart.chr_ram();
+        let len = chr_ram.len();
+        chr_ram[index % len] = val;
+      }
+      0x6000..=0x7fff if self.prg_ram_writable[(address as usize - 0x6000) / 0x800] => {
+        self.cart.prg_ram_mut()[address as usize - 0x6000] = val
+      }
+      0x4800..=0x5fff | 0x8000..=0xffff => self.write_register(val, address),
+      _ => (),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mappers::tests::battery_cart;
+  use crate::mappers::tests::cart;
+  use crate::mappers::tests::spy_mirroring;
+
+  fn sut() -> N163<crate::cartridge::HeapRom> {
+    N163::new(cart(19, kilobytes::KB32 * 4, kilobytes::KB32 * 4))
+  }
+
+  #[test]
+  fn banking() {
+    let mut n163 = sut();
+    n163.write8(3, 0xe000);
+    n163.write8(4, 0xe800);
+    n163.write8(5, 0xf000);
+    assert_eq!(
+      [0x8000, 0xa000, 0xc000, 0xe000].map(|a| n163.read8(a)),
+      [3, 4, 5, 15]
+    );
+
+    n163.write8(0x21, 0x8800);
+    n163.write8(0x42, 0xb800);
+    assert_eq!(n163.read8(0x0400), 0x21);
+    assert_eq!(n163.read8(0x1c00), 0x42);
+  }
+
+  #[test]
+  fn nametables_in_chr_rom_or_ciram() {
+    let mut n163 = sut();
+    let mirroring = spy_mirroring(&mut n163);
+    n163.write8(0xe0, 0xc000);
+    n163.write8(0xe1, 0xc800);
+    n163.write8(0x07, 0xd000);
+    n163.write8(0xff, 0xd800);
+    assert_eq!(
+      *mirroring.borrow(),
+      Some(Mirroring::Custom([
+        Nametable::Ciram(0),
+        Nametable::Ciram(1),
+        Nametable::ChrRom(7),
+        Nametable::Ciram(1),
+      ]))
+    );
+    assert_eq!(n163.read_nametable(Nametable::ChrRom(7), 0x3ff), 7);
+  }
+
+  #[test]
+  fn prg_ram_write_protection() {
+    let mut n163 = sut();
+    n163.write8(0x55, 0x6000);
+    assert_eq!(n163.read8(0x6000), 0);
+
+    // Enabled, but $6800-$6FFF protected
+    n163.write8(0x42, 0xf800);
+    n163.write8(0x55, 0x6000);
+    n163.write8(0x55, 0x6800);
+    assert_eq!(n163.read8(0x6000), 0x55);
+    assert_eq!(n163.read8(0x6800), 0);
+  }
+
+  #[test]
+  fn irq_counter() {
+    let mut n163 = sut();
+    n163.write8(0xfd, 0x5000);
+    n163.write8(0xff, 0x5800);
+    assert_eq!(n163.read8(0x5800), 0xff);
+    n163.tick(1);
+    assert!(!n163.irq());
+    n163.tick(1);
+    assert!(n163.irq());
+
+    // Stays at $7FFF, and pending until acknowledged
+    n163.tick(100);
+    assert!(n163.irq());
+    assert_eq!(n163.read8(0x5000), 0xff);
+    n163.tick(100);
+    assert!(!n163.irq());
+  }
+
+  #[test]
+  fn internal_ram() {
+    let mut n163 = sut();
+    n163.write8(0x80, 0xf800);
+    n163.write8(0x12, 0x4800);
+    n163.write8(0x34, 0x4800);
+    n163.write8(0x80, 0xf800);
+    assert_eq!(n163.read8(0x4800), 0x12);
+    assert_eq!(n163.read8(0x4800), 0x34);
+    // PRG RAM is what's battery backed by default
+    assert_eq!(n163.prg_ram().len(), kilobytes::KB8);
+  }
+
+  #[test]
+  fn battery_backed_internal_ram() {
+    // 128 B of PRG NVRAM
+    let mut n163 = N163::new(battery_cart(
+      19,
+      kilobytes::KB32 * 4,
+      kilobytes::KB32 * 4,
+      1,
+    ));
+    assert_eq!(n163.prg_ram().len(), INTERNAL_RAM_SIZE);
+
+    n163.import_prg_ram(&[0xaa; INTERNAL_RAM_SIZE]).unwrap();
+    n163.write8(0x00, 0xf800);
+    assert_eq!(n163.read8(0x4800), 0xaa);
+    assert!(n163.import_prg_ram(&[0; kilobytes::KB8]).is_err());
+  }
+}
@@ -0,0 +1,215 @@
+use core::cell::Cell;
+
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+// The Namco 163's wavetable synth. Up to eight channels play 4-bit samples
+// out of the chip's 128 bytes of internal RAM, which is shared with the game.
+// https://www.nesdev.org/wiki/Namco_163_audio
+
+pub const INTERNAL_RAM_SIZE: usize = 128;
+
+// One channel is updated every 15 CPU cycles
+const CPU_CYCLES_PER_UPDATE: u8 = 15;
+
+// Channel registers live at the end of internal RAM, channel 8 at $78-$7F
+const CHANNEL_REGISTERS: usize = 0x40;
+
+// A full volume channel at full amplitude, about as loud as an APU pulse
+const AUDIO_SCALE: f32 = 0.00066;
+
+pub(crate) struct N163Audio {
+  ram: [u8; INTERNAL_RAM_SIZE],
+  // $F800, bits 0-6 address, bit 7 auto-increment. Reads increment too.
+  address: Cell<u8>,
+  auto_increment: bool,
+
+  cycles: u8,
+  // Counts down from the highest enabled channel to channel 8
+  channel: u8,
+  // Last output of each channel, 0..=225
+  outputs: [u8; 8],
+}
+
+impl N163Audio {
+  pub fn new() -> Self {
+    Self {
+      ram: [0; INTERNAL_RAM_SIZE],
+      address: Cell::new(0),
+      auto_increment: false,
+      cycles: 0,
+      channel: 7,
+      outputs: [0; 8],
+    }
+  }
+
+  pub fn ram(&self) -> &[u8] {
+    &self.ram
+  }
+
+  pub fn ram_mut(&mut self) -> &mut [u8] {
+    &mut self.ram
+  }
+
+  pub fn write_address(&mut self, val: u8) {
+    self.address.set(val & 0x7f);
+    self.auto_increment = val & 0x80 != 0;
+  }
+
+  // $4800
+  pub fn read_data(&self) -> u8 {
+    let val = self.ram[self.address.get() as usize];
+    self.increment_address();
+    val
+  }
+
+  pub fn write_data(&mut self, val: u8) {
+    self.ram[self.address.get() as usize] = val;
+    self.increment_address();
+  }
+
+  fn increment_address(&self) {
+    if self.auto_increment {
+      self.address.set((self.address.get() + 1) & 0x7f);
+    }
+  }
+
+  // 1 to 8, from $7F
+  fn enabled_channels(&self) -> u8 {
+    ((self.ram[0x7f] >> 4) & 0b111) + 1
+  }
+
+  // Every CPU cycle
+  pub fn tick(&mut self) {
+    self.cycles += 1;
+    if self.cycles < CPU_CYCLES_PER_UPDATE {
+      return;
+    }
+    self.cycles = 0;
+
+    self.update_channel(self.channel as usize);
+    let lowest = 8 - self.enabled_channels();
+    self.channel = if self.channel <= lowest {
+      7
+    } else {
+      self.channel - 1
+    };
+  }
+
+  // https://www.nesdev.org/wiki/Namco_163_audio#Channel_update
+  fn update_channel(&mut self, channel: usize) {
+    let base = CHANNEL_REGISTERS + channel * 8;
+    let regs = &self.ram[base..base + 8];
+
+    let frequency = ((regs[4] as u32 & 0b11) << 16) | ((regs[2] as u32) << 8) | regs[0] as u32;
+    let length = (256 - (regs[4] as u32 & 0xfc)) << 16;
+    let mut phase = ((regs[5] as u32) << 16) | ((regs[3] as u32) << 8) | regs[1] as u32;
+    phase = (phase + frequency) % length;
+
+    let nibble = ((phase >> 16) + regs[6] as u32) as usize & 0xff;
+    let sample = (self.ram[nibble / 2] >> ((nibble & 1) * 4)) & 0x0f;
+    self.outputs[channel] = sample * (regs[7] & 0x0f);
+
+    self.ram[base + 1] = phase as u8;
+    self.ram[base + 3] = (phase >> 8) as u8;
+    self.ram[base + 5] = (phase >> 16) as u8;
+  }
+
+  // The chip plays the channels one after the other, the average is what's heard
+  pub fn output(&self) -> f32 {
+    let enabled = self.enabled_channels();
+    let sum: u32 = self.outputs[(8 - enabled) as usize..]
+      .iter()
+      .map(|&o| o as u32)
+      .sum();
+    sum as f32 / enabled as f32 * AUDIO_SCALE
+  }
+
+  pub fn save_state(&self, w: &mut StateWriter) {
+    w.write_bytes(&self.ram);
+    w.write_u8(self.address.get());
+    w.write_bool(self.auto_increment);
+    w.write_u8(self.cycles);
+    w.write_u8(self.channel);
+    w.write_bytes(&self.outputs);
+  }
+
+  pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    r.read_bytes(&mut self.ram)?;
+    self.address.set(r.read_u8()? & 0x7f);
+    self.auto_increment = r.read_bool()?;
+    self.cycles = r.read_u8()?;
+    self.channel = r.read_u8()?;
+    r.read_bytes(&mut self.outputs)?;
+    if self.cycles >= CPU_CYCLES_PER_UPDATE || self.channel > 7 {
+      return Err(SaveStateError::InvalidSaveState("n163 audio"));
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+
+  use super::*;
+
+  #[test]
+  fn auto_increment() {
+    let mut audio = N163Audio::new();
+    audio.write_address(0x80 | 0x7f);
+    audio.write_data(1);
+    audio.write_data(2);
+    assert_eq!(audio.ram[0x7f], 1);
+    assert_eq!(audio.ram[0x00], 2);
+
+    audio.write_address(0x80 | 0x10);
+    audio.write_data(3);
+    audio.write_data(4);
+    audio.write_address(0x80 | 0x10);
+    assert_eq!(audio.read_data(), 3);
+    assert_eq!(audio.read_data(), 4);
+
+    audio.write_address(0x10);
+    audio.write_data(5);
+    assert_eq!(audio.read_data(), 5);
+    assert_eq!(audio.read_data(), 5);
+  }
+
+  #[test]
+  fn wavetable_channel() {
+    let mut audio = N163Audio::new();
+    // Channel 8 alone, a square wave of 15s and 0s over 4 samples
+    audio.ram[0] = 0xff;
+    audio.ram[1] = 0x00;
+    let regs = CHANNEL_REGISTERS + 7 * 8;
+    audio.ram[regs + 2] = 0x80; // Half a sample per update
+    audio.ram[regs + 4] = 0xfc; // Length 4
+    audio.ram[regs + 7] = 0x0f;
+
+    let outputs: Vec<u8> = (0..8)
+      .map(|_| {
+        (0..CPU_CYCLES_PER_UPDATE).for_each(|_| audio.tick());
+        audio.outputs[7]
+      })
+      .collect();
+    assert_eq!(outputs, [225, 225, 225, 0, 0, 0, 0, 225]);
+    assert!(audio.output() > 0.0);
+  }
+
+  #[test]
+  fn channels_take_turns() {
+    let mut audio = N163Audio::new();
+    audio.ram[0] = 0xff;
+    audio.ram[CHANNEL_REGISTERS + 6 * 8 + 7] = 0x0f;
+    // Two channels, $7F is also channel 8's volume
+    audio.ram[CHANNEL_REGISTERS + 7 * 8 + 7] = 0x1f;
+
+    (0..CPU_CYCLES_PER_UPDATE).for_each(|_| audio.tick());
+    assert_eq!(audio.outputs[6..], [0, 225]);
+    (0..CPU_CYCLES_PER_UPDATE).for_each(|_| audio.tick());
+    assert_eq!(audio.outputs[6..], [225, 225]);
+    assert_eq!(audio.channel, 7);
+  }
+}
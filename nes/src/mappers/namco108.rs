@@ -0,0 +1,137 @@
+use common::kilobytes;
+use mos6502::memory::Bus;
+
+use super::Mapper;
+use crate::cartridge::Cartridge;
+use crate::cartridge::Nametable;
+use crate::cartridge::Rom;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+// Mapper 206, Namco 108 on NAMCOT-3446 and friends (DxROM). The MMC3's predecessor:
+// the same bank registers without the mode bits, IRQ, PRG RAM or mirroring control.
+// https://www.nesdev.org/wiki/INES_Mapper_206
+pub struct Namco108<R: Rom> {
+  cart: Cartridge<R>,
+  prg_rom_banks_total: usize,
+  registers: [u8; 8],
+  register_to_update: u8, // 3 bits
+}
+
+impl<R: Rom> Mapper for Namco108<R> {
+  // DRROM carries four-screen RAM
+  fn read_nametable(&self, nametable: Nametable, offset: u16) -> u8 {
+    match nametable {
+      Nametable::CartridgeRam(page) => self.cart.read_nametable_ram(page, offset),
+      _ => 0,
+    }
+  }
+
+  fn write_nametable(&mut self, val: u8, nametable: Nametable, offset: u16) {
+    if let Nametable::CartridgeRam(page) = nametable {
+      self.cart.write_nametable_ram(val, page, offset);
+    }
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    self.cart.save_state(w);
+    w.write_bytes(&self.registers);
+    w.write_u8(self.register_to_update);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.cart.load_state(r)?;
+    r.read_bytes(&mut self.registers)?;
+    self.register_to_update = r.read_u8()? & 0b111;
+    Ok(())
+  }
+}
+
+impl<R: Rom> Namco108<R> {
+  pub fn new(cart: Cartridge<R>) -> Self {
+    Self {
+      prg_rom_banks_total: cart.prg().len() / kilobytes::KB8,
+      cart,
+      registers: [0; 8],
+      register_to_update: 0,
+    }
+  }
+
+  fn read_prg(&self, address: u16) -> u8 {
+    let bank = match address {
+      0x8000..=0x9fff => self.registers[6] as usize,
+      0xa000..=0xbfff => self.registers[7] as usize,
+      0xc000..=0xdfff => self.prg_rom_banks_total - 2,
+      _ => self.prg_rom_banks_total - 1,
+    };
+    let bank = bank % self.prg_rom_banks_total;
+    self.cart.prg()[(bank * kilobytes::KB8) + (address as usize & 0x1fff)]
+  }
+
+  // R0 and R1 are 2 KB banks at $0000 and $0800, R2-R5 1 KB banks at $1000-$1FFF
+  fn read_chr(&self, address: u16) -> u8 {
+    let bank = match address {
+      0x0000..=0x07ff => (self.registers[0] & 0xfe) | ((address >> 10) as u8 & 1),
+      0x0800..=0x0fff => (self.registers[1] & 0xfe) | ((address >> 10) as u8 & 1),
+      _ => self.registers[2 + ((address as usize - 0x1000) / kilobytes::KB1)],
+    };
+    let chr = self.cart.chr();
+    chr[(bank as usize * kilobytes::KB1 + (address as usize & 0x3ff)) % chr.len()]
+  }
+}
+
+impl<R: Rom> Bus for Namco108<R> {
+  fn read8(&self, address: u16) -> u8 {
+    match address {
+      0x0000..=0x1fff => self.read_chr(address),
+      0x8000..=0xffff => self.read_prg(address),
+      _ => 0,
+    }
+  }
+
+  fn write8(&mut self, val: u8, address: u16) {
+    match (address, address & 1) {
+      (0x8000..=0x9fff, 0) => self.register_to_update = val & 0b111,
+      (0x8000..=0x9fff, _) => {
+        let mask = if self.register_to_update >= 6 {
+          0x0f
+        } else {
+          0x3f
+        };
+        self.registers[self.register_to_update as usize] = val & mask;
+      }
+      _ => (),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mappers::tests::cart;
+
+  #[test]
+  fn banking() {
+    let mut namco = Namco108::new(cart(206, kilobytes::KB32 * 4, kilobytes::KB32 * 2));
+    let mut select = |register: u8, bank: u8| {
+      namco.write8(register, 0x8000);
+      namco.write8(bank, 0x8001);
+    };
+    select(6, 3);
+    select(7, 4);
+    select(0, 9); // Low bit ignored
+    select(1, 12);
+    select(2, 40);
+    select(5, 63);
+
+    assert_eq!(
+      [0x8000, 0xa000, 0xc000, 0xe000].map(|a| namco.read8(a)),
+      [3, 4, 14, 15]
+    );
+    assert_eq!(
+      [0x0000, 0x0400, 0x0800, 0x0c00, 0x1000, 0x1c00].map(|a| namco.read8(a)),
+      [8, 9, 12, 13, 40, 63]
+    );
+  }
+}
@@ -4,15 +4,36 @@ use common::kilobytes;
 use mos6502::memory::Bus;
 
 use super::Mapper;
+use crate::cartridge::error::CartridgeError;
 use crate::cartridge::Cartridge;
 use crate::cartridge::Rom;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct NROM<R: Rom> {
   cart: Cartridge<R>,
   is_16kb: bool,
 }
 
-impl<R: Rom> Mapper for NROM<R> {}
+impl<R: Rom> Mapper for NROM<R> {
+  fn prg_ram(&self) -> &[u8] {
+    self.cart.prg_ram()
+  }
+
+  fn import_prg_ram(&mut self, ram: &[u8]) -> Result<(), CartridgeError> {
+    self.cart.import_prg_ram(ram)
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    self.cart.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.cart.load_state(r)
+  }
+}
 
 impl<R: Rom> NROM<R> {
   pub fn new(cart: Cartridge<R>) -> Self {
@@ -29,8 +50,8 @@ impl<R: Rom> Bus for NROM<R> {
   fn read8(&self, address: u16) -> u8 {
     match address {
       0x0000..=0x1fff => self.cart.chr()[address as usize], // PPU
-      // TODO: Mirrored, Write protectable w external switch
-      // 0x6000..=0x7fff => self.cart.prg_ram()[address as usize - 0x6000],
+      // Family Basic, and trainers. TODO: Write protectable w external switch
+      0x6000..=0x7fff => self.cart.prg_ram()[address as usize - 0x6000],
       0x8000..=0xbfff => self.cart.prg()[address as usize - 0x8000],
       0xc000..=0xffff => {
         if self.is_16kb {
@@ -48,6 +69,7 @@ impl<R: Rom> Bus for NROM<R> {
   fn write8(&mut self, v: u8, address: u16) {
     match address {
       0x0000..=0x1fff => self.cart.chr_ram()[address as usize] = v,
+      0x6000..=0x7fff => self.cart.prg_ram_mut()[address as usize - 0x6000] = v,
       _ => (),
     }
   }
@@ -1,22 +1,49 @@
 use common::kilobytes;
 use mos6502::memory::Bus;
 
+use super::bus_conflicts;
 use super::Mapper;
+use crate::cartridge::error::CartridgeError;
 use crate::cartridge::Cartridge;
 use crate::cartridge::Rom;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
 
 pub struct UxROM<R: Rom> {
   cart: Cartridge<R>,
   bank: u8,
   num_banks: usize,
+  bus_conflicts: bool,
 }
 
-impl<R: Rom> Mapper for UxROM<R> {}
+impl<R: Rom> Mapper for UxROM<R> {
+  fn prg_ram(&self) -> &[u8] {
+    self.cart.prg_ram()
+  }
+
+  fn import_prg_ram(&mut self, ram: &[u8]) -> Result<(), CartridgeError> {
+    self.cart.import_prg_ram(ram)
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    self.cart.save_state(w);
+    w.write_u8(self.bank);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.cart.load_state(r)?;
+    self.bank = r.read_u8()?;
+    Ok(())
+  }
+}
 
 impl<R: Rom> UxROM<R> {
   pub fn new(cart: Cartridge<R>) -> Self {
     Self {
       num_banks: cart.prg().len() / kilobytes::KB16,
+      // UNROM and UOROM have them, so unless the header says otherwise
+      bus_conflicts: bus_conflicts(&cart, true, true),
       cart,
       bank: 0,
     }
@@ -26,10 +53,12 @@ impl<R: Rom> UxROM<R> {
 impl<R: Rom> Bus for UxROM<R> {
   fn read8(&self, address: u16) -> u8 {
     let address = address as usize;
-    let selected_bank = self.bank as usize;
+    // Bits past the bank count aren't wired, on hardware or in a crafted save state
+    let selected_bank = self.bank as usize % self.num_banks;
     let last_bank = self.num_banks - 1;
     match address {
       0x0000..=0x1fff => self.cart.chr()[address],
+      0x6000..=0x7fff => self.cart.prg_ram()[address - 0x6000],
       0x8000..=0xbfff => self.cart.prg()[(selected_bank * kilobytes::KB16) + (address - 0x8000)],
       0xc000..=0xffff => self.cart.prg()[(last_bank * kilobytes::KB16) + (address - 0xc000)],
       _ => 0,
@@ -39,8 +68,66 @@ impl<R: Rom> Bus for UxROM<R> {
   fn write8(&mut self, val: u8, address: u16) {
     match address {
       0x0000..=0x1fff => self.cart.chr_ram()[address as usize] = val,
+      0x6000..=0x7fff => self.cart.prg_ram_mut()[address as usize - 0x6000] = val,
+      0x8000..=0xffff if self.bus_conflicts => self.bank = val & self.read8(address),
       0x8000..=0xffff => self.bank = val,
       _ => (),
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mappers::tests::cart;
+  use crate::mappers::tests::nes2_cart;
+
+  #[test]
+  fn bus_conflicts_from_submapper() {
+    // Bank 0 is all zeros, so with bus conflicts a write there selects bank 0
+    let mut uxrom = UxROM::new(nes2_cart(2, 1, kilobytes::KB16 * 8, 0));
+    uxrom.write8(3, 0x8000);
+    assert_eq!(uxrom.read8(0x8000), 6);
+
+    // iNES, so the usual UNROM
+    let mut uxrom = UxROM::new(cart(2, kilobytes::KB16 * 8, 0));
+    uxrom.write8(3, 0x8000);
+    assert_eq!(uxrom.read8(0x8000), 0);
+
+    let mut uxrom = UxROM::new(nes2_cart(2, 2, kilobytes::KB16 * 8, 0));
+    uxrom.write8(3, 0x8000);
+    assert_eq!(uxrom.read8(0x8000), 0);
+    // $C000 holds 14, the last bank
+    uxrom.write8(3, 0xc000);
+    assert_eq!(uxrom.read8(0x8000), 4);
+  }
+
+  #[test]
+  fn bus_conflicts_override() {
+    let mut cart = nes2_cart(2, 1, kilobytes::KB16 * 8, 0);
+    cart.set_bus_conflicts(true);
+    let mut uxrom = UxROM::new(cart);
+    uxrom.write8(3, 0x8000);
+    assert_eq!(uxrom.read8(0x8000), 0);
+  }
+
+  #[test]
+  fn bank_wraps_at_bank_count() {
+    // No bus conflicts, so the 9 gets through
+    let mut uxrom = UxROM::new(nes2_cart(2, 1, kilobytes::KB16 * 4, 0));
+    uxrom.write8(9, 0x8000);
+    assert_eq!(uxrom.read8(0x8000), 2);
+
+    // Same from a crafted save state
+    let mut w = StateWriter::new();
+    uxrom.save_state(&mut w);
+    let mut state = w.finish(0);
+    *state.last_mut().unwrap() = 0xff;
+    let crc = common::hash::crc32(&state[18..]);
+    state[14..18].copy_from_slice(&crc.to_le_bytes());
+    uxrom
+      .load_state(&mut StateReader::open(&state, 0).unwrap())
+      .unwrap();
+    assert_eq!(uxrom.read8(0x8000), 6);
+  }
+}
@@ -0,0 +1,305 @@
+use common::kilobytes;
+use mos6502::memory::Bus;
+
+use super::notify_mirroring;
+use super::vrc_irq::VrcIrq;
+use super::Mapper;
+use super::MirroringCallback;
+use crate::cartridge::error::CartridgeError;
+use crate::cartridge::Cartridge;
+use crate::cartridge::Mirroring;
+use crate::cartridge::Rom;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Chip {
+  Vrc2,
+  Vrc4,
+}
+
+// The boards wire different CPU address lines to the chip's A0/A1. Masks for (A0, A1).
+// https://www.nesdev.org/wiki/VRC2_and_VRC4#Variants
+const VRC4A: (u16, u16) = (0x02, 0x04);
+const VRC4C: (u16, u16) = (0x40, 0x80);
+const VRC2A: (u16, u16) = (0x02, 0x01);
+const VRC4F: (u16, u16) = (0x01, 0x02);
+const VRC4E: (u16, u16) = (0x04, 0x08);
+const VRC4B: (u16, u16) = (0x02, 0x01);
+const VRC4D: (u16, u16) = (0x08, 0x04);
+
+// Mappers 21, 22, 23 and 25
+pub struct VRC4<R: Rom> {
+  cart: Cartridge<R>,
+  chip: Chip,
+  // Submapper 0 doesn't say which variant it is, so listen to all of them
+  address_lines: &'static [(u16, u16)],
+  // VRC2a drops the lowest CHR bank bit
+  chr_shift: u8,
+  prg_rom_banks_total: usize,
+
+  prg_banks: [u8; 2],
+  prg_swap_mode: bool,
+  chr_banks: [u16; 8],
+  mirroring_cb: Option<MirroringCallback>,
+
+  irq: VrcIrq,
+}
+
+impl<R: Rom> VRC4<R> {
+  pub fn new(cart: Cartridge<R>) -> Self {
+    let header = cart.header();
+    let (chip, address_lines): (Chip, &'static [(u16, u16)]) =
+      match (header.mapper, header.submapper) {
+        (21, 1) => (Chip::Vrc4, &[VRC4A]),
+        (21, 2) => (Chip::Vrc4, &[VRC4C]),
+        (21, _) => (Chip::Vrc4, &[VRC4A, VRC4C]),
+        (22, _) => (Chip::Vrc2, &[VRC2A]),
+        (23, 1) => (Chip::Vrc4, &[VRC4F]),
+        (23, 2) => (Chip::Vrc4, &[VRC4E]),
+        (23, 3) => (Chip::Vrc2, &[VRC4F]),
+        (23, _) => (Chip::Vrc4, &[VRC4F, VRC4E]),
+        (25, 1) => (Chip::Vrc4, &[VRC4B]),
+        (25, 2) => (Chip::Vrc4, &[VRC4D]),
+        (25, 3) => (Chip::Vrc2, &[VRC4B]),
+        (_, _) => (Chip::Vrc4, &[VRC4B, VRC4D]),
+      };
+
+    Self {
+      chr_shift: (header.mapper == 22) as u8,
+      prg_rom_banks_total: cart.prg().len() / kilobytes::KB8,
+      cart,
+      chip,
+      address_lines,
+      prg_banks: [0; 2],
+      prg_swap_mode: false,
+      chr_banks: [0; 8],
+      mirroring_cb: None,
+      irq: VrcIrq::default(),
+    }
+  }
+
+  // $x000-$x003, whatever lines the board uses
+  fn register(&self, address: u16) -> u16 {
+    let mut reg = 0;
+    for (a0, a1) in self.address_lines {
+      if address & a0 != 0 {
+        reg |= 1;
+      }
+      if address & a1 != 0 {
+        reg |= 2;
+      }
+    }
+    (address & 0xf000) | reg
+  }
+
+  fn read_prg(&self, address: u16) -> u8 {
+    let second_last = self.prg_rom_banks_total - 2;
+    let bank = match (address, self.prg_swap_mode) {
+      (0x8000..=0x9fff, false) => self.prg_banks[0] as usize,
+      (0x8000..=0x9fff, true) => second_last,
+      (0xa000..=0xbfff, _) => self.prg_banks[1] as usize,
+      (0xc000..=0xdfff, false) => second_last,
+      (0xc000..=0xdfff, true) => self.prg_banks[0] as usize,
+      _ => self.prg_rom_banks_total - 1,
+    };
+    let bank = bank % self.prg_rom_banks_total;
+    self.cart.prg()[bank * kilobytes::KB8 + (address as usize & 0x1fff)]
+  }
+
+  fn chr_index(&self, address: u16) -> usize {
+    let bank = (self.chr_banks[address as usize / kilobytes::KB1] >> self.chr_shift) as usize;
+    bank * kilobytes::KB1 + (address as usize & 0x3ff)
+  }
+
+  fn write_chr_bank(&mut self, val: u8, register: u16) {
+    // $B000-$E003, low and high halves of two banks per $x000
+    let n = (((register >> 12) - 0xb) * 2 + ((register & 2) >> 1)) as usize;
+    self.chr_banks[n] = if register & 1 == 0 {
+      (self.chr_banks[n] & 0x1f0) | (val as u16 & 0x0f)
+    } else {
+      (self.chr_banks[n] & 0x0f) | ((val as u16 & 0x1f) << 4)
+    };
+  }
+
+  fn write_mirroring(&mut self, val: u8) {
+    let mask = match self.chip {
+      Chip::Vrc2 => 0b01,
+      Chip::Vrc4 => 0b11,
+    };
+    let runtime_mirroring = match val & mask {
+      0 => Mirroring::Vertical,
+      1 => Mirroring::Horizontal,
+      2 => Mirroring::SingleScreenLower,
+      _ => Mirroring::SingleScreenUpper,
+    };
+    notify_mirroring(&mut self.mirroring_cb, runtime_mirroring)
+  }
+}
+
+impl<R: Rom> Mapper for VRC4<R> {
+  fn on_runtime_mirroring(&mut self, cb: MirroringCallback) {
+    self.mirroring_cb = Some(cb);
+  }
+
+  fn tick(&mut self, cpu_cycles: usize) {
+    if self.chip == Chip::Vrc4 {
+      self.irq.tick(cpu_cycles);
+    }
+  }
+
+  fn irq(&self) -> bool {
+    self.irq.pending()
+  }
+
+  fn prg_ram(&self) -> &[u8] {
+    self.cart.prg_ram()
+  }
+
+  fn import_prg_ram(&mut self, ram: &[u8]) -> Result<(), CartridgeError> {
+    self.cart.import_prg_ram(ram)
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    self.cart.save_state(w);
+    w.write_bytes(&self.prg_banks);
+    w.write_bool(self.prg_swap_mode);
+    for bank in &self.chr_banks {
+      w.write_u16(*bank);
+    }
+    self.irq.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.cart.load_state(r)?;
+    r.read_bytes(&mut self.prg_banks)?;
+    self.prg_swap_mode = r.read_bool()?;
+    for bank in self.chr_banks.iter_mut() {
+      *bank = r.read_u16()? & 0x1ff;
+    }
+    self.irq.load_state(r)
+  }
+}
+
+impl<R: Rom> Bus for VRC4<R> {
+  fn read8(&self, address: u16) -> u8 {
+    match address {
+      0x0000..=0x1fff => {
+        let chr = self.cart.chr();
+        chr[self.chr_index(address) % chr.len()]
+      }
+      0x6000..=0x7fff => self.cart.prg_ram()[address as usize - 0x6000],
+      0x8000..=0xffff => self.read_prg(address),
+      _ => 0,
+    }
+  }
+
+  fn write8(&mut self, val: u8, address: u16) {
+    if address < 0x8000 {
+      match address {
+        0x0000..=0x1fff => {
+          let index = self.chr_index(address);
+          let chr_ram = self.cart.chr_ram();
+          let len = chr_ram.len();
+          chr_ram[index % len] = val;
+        }
+        0x6000..=0x7fff => self.cart.prg_ram_mut()[address as usize - 0x6000] = val,
+        _ => (),
+      }
+      return;
+    }
+
+    let register = self.register(address);
+    match (register, self.chip) {
+      (0x8000..=0x8003, _) => self.prg_banks[0] = val & 0x1f,
+      (0x9000..=0x9001, Chip::Vrc4) | (0x9000..=0x9003, Chip::Vrc2) => self.write_mirroring(val),
+      (0x9002..=0x9003, Chip::Vrc4) => self.prg_swap_mode = val & 0b10 != 0,
+      (0xa000..=0xa003, _) => self.prg_banks[1] = val & 0x1f,
+      (0xb000..=0xefff, _) => self.write_chr_bank(val, register),
+      (0xf000, Chip::Vrc4) => self.irq.write_latch_low(val),
+      (0xf001, Chip::Vrc4) => self.irq.write_latch_high(val),
+      (0xf002, Chip::Vrc4) => self.irq.write_control(val),
+      (0xf003, Chip::Vrc4) => self.irq.acknowledge(),
+      _ => (),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mappers::tests::cart;
+  use crate::mappers::tests::nes2_cart;
+  use crate::mappers::tests::spy_mirroring;
+
+  #[test]
+  fn address_line_variants() {
+    // VRC4e, CHR bank 0 high/low at $B000/$B004 (A2)
+    let mut vrc = VRC4::new(nes2_cart(23, 2, kilobytes::KB32 * 4, kilobytes::KB32 * 4));
+    vrc.write8(0x05, 0xb000);
+    vrc.write8(0x01, 0xb004);
+    assert_eq!(vrc.read8(0x0000), 0x15);
+    // A0 (VRC4f) is ignored
+    vrc.write8(0x03, 0xb001);
+    assert_eq!(vrc.read8(0x0000), 0x13);
+
+    // Unknown submapper, both VRC4a ($x002) and VRC4c ($x040) work
+    let mut vrc = VRC4::new(cart(21, kilobytes::KB32 * 4, kilobytes::KB32 * 4));
+    vrc.write8(0x03, 0xb000);
+    vrc.write8(0x02, 0xb040);
+    assert_eq!(vrc.read8(0x0000), 0x23);
+    vrc.write8(0x01, 0xb002);
+    assert_eq!(vrc.read8(0x0000), 0x13);
+  }
+
+  #[test]
+  fn prg_swap_mode() {
+    let mut vrc = VRC4::new(cart(25, kilobytes::KB32 * 4, kilobytes::KB32 * 4));
+    vrc.write8(3, 0x8000);
+    vrc.write8(4, 0xa000);
+    assert_eq!(
+      [0x8000, 0xa000, 0xc000, 0xe000].map(|a| vrc.read8(a)),
+      [3, 4, 14, 15]
+    );
+
+    // VRC4b, $9002 is A1
+    vrc.write8(0b10, 0x9001);
+    assert_eq!(
+      [0x8000, 0xa000, 0xc000, 0xe000].map(|a| vrc.read8(a)),
+      [14, 4, 3, 15]
+    );
+  }
+
+  #[test]
+  fn vrc2a_chr_and_mirroring() {
+    let mut vrc = VRC4::new(cart(22, kilobytes::KB32 * 4, kilobytes::KB32 * 4));
+    let mirroring = spy_mirroring(&mut vrc);
+    vrc.write8(0x06, 0xb000);
+    assert_eq!(vrc.read8(0x0000), 3);
+
+    vrc.write8(0x03, 0x9000);
+    assert_eq!(*mirroring.borrow(), Some(Mirroring::Horizontal));
+
+    // No IRQ on VRC2
+    vrc.write8(0xff, 0xf000);
+    vrc.write8(0b110, 0xf002);
+    vrc.tick(1000);
+    assert!(!vrc.irq());
+  }
+
+  #[test]
+  fn irq_from_cpu_cycles() {
+    let mut vrc = VRC4::new(nes2_cart(21, 1, kilobytes::KB32 * 4, kilobytes::KB32 * 4));
+    vrc.write8(0x0e, 0xf000);
+    vrc.write8(0x0f, 0xf002);
+    vrc.write8(0b110, 0xf004);
+    vrc.tick(1);
+    assert!(!vrc.irq());
+    vrc.tick(1);
+    assert!(vrc.irq());
+
+    vrc.write8(0, 0xf006);
+    assert!(!vrc.irq());
+  }
+}
@@ -0,0 +1,436 @@
+use common::kilobytes;
+use mos6502::memory::Bus;
+
+use super::notify_mirroring;
+use super::vrc_irq::VrcIrq;
+use super::Mapper;
+use super::MirroringCallback;
+use crate::cartridge::error::CartridgeError;
+use crate::cartridge::Cartridge;
+use crate::cartridge::Mirroring;
+use crate::cartridge::Rom;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+// Roughly the APU pulse's level for the same volume
+const AUDIO_SCALE: f32 = 0.00996;
+
+// https://www.nesdev.org/wiki/VRC6_audio#Pulse_Channels
+#[derive(Default)]
+struct PulseChannel {
+  volume: u8,
+  duty: u8,
+  ignore_duty: bool,
+  period: u16, // 12 bits
+  enabled: bool,
+  timer: u16,
+  step: u8,
+}
+
+impl PulseChannel {
+  fn write(&mut self, val: u8, register: u16) {
+    match register {
+      0 => {
+        // MDDD VVVV
+        self.ignore_duty = val & 0x80 != 0;
+        self.duty = (val >> 4) & 0x7;
+        self.volume = val & 0x0f;
+      }
+      1 => self.period = (self.period & 0xf00) | val as u16,
+      _ => {
+        self.period = (self.period & 0xff) | ((val as u16 & 0x0f) << 8);
+        self.enabled = val & 0x80 != 0;
+        if !self.enabled {
+          self.step = 15;
+        }
+      }
+    }
+  }
+
+  fn clock(&mut self, shift: u8) {
+    if !self.enabled {
+      return;
+    }
+    if self.timer == 0 {
+      self.timer = self.period >> shift;
+      self.step = self.step.wrapping_sub(1) & 0x0f;
+    } else {
+      self.timer -= 1;
+    }
+  }
+
+  fn output(&self) -> u8 {
+    if self.enabled && (self.ignore_duty || self.step <= self.duty) {
+      self.volume
+    } else {
+      0
+    }
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    w.write_u8(self.volume);
+    w.write_u8(self.duty);
+    w.write_bool(self.ignore_duty);
+    w.write_u16(self.period);
+    w.write_bool(self.enabled);
+    w.write_u16(self.timer);
+    w.write_u8(self.step);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.volume = r.read_u8()? & 0x0f;
+    self.duty = r.read_u8()? & 0x7;
+    self.ignore_duty = r.read_bool()?;
+    self.period = r.read_u16()? & 0xfff;
+    self.enabled = r.read_bool()?;
+    self.timer = r.read_u16()?;
+    self.step = r.read_u8()? & 0x0f;
+    Ok(())
+  }
+}
+
+// https://www.nesdev.org/wiki/VRC6_audio#Sawtooth_Channel
+#[derive(Default)]
+struct SawChannel {
+  rate: u8,
+  period: u16,
+  enabled: bool,
+  timer: u16,
+  step: u8,
+  accumulator: u8,
+}
+
+impl SawChannel {
+  fn write(&mut self, val: u8, register: u16) {
+    match register {
+      0 => self.rate = val & 0x3f,
+      1 => self.period = (self.period & 0xf00) | val as u16,
+      _ => {
+        self.period = (self.period & 0xff) | ((val as u16 & 0x0f) << 8);
+        self.enabled = val & 0x80 != 0;
+        if !self.enabled {
+          self.step = 0;
+          self.accumulator = 0;
+        }
+      }
+    }
+  }
+
+  fn clock(&mut self, shift: u8) {
+    if !self.enabled {
+      return;
+    }
+    if self.timer != 0 {
+      self.timer -= 1;
+      return;
+    }
+    self.timer = self.period >> shift;
+
+    // The accumulator takes the rate every other step, and resets on the 14th
+    self.step += 1;
+    if self.step == 14 {
+      self.step = 0;
+      self.accumulator = 0;
+    } else if self.step & 1 == 0 {
+      self.accumulator = self.accumulator.wrapping_add(self.rate);
+    }
+  }
+
+  fn output(&self) -> u8 {
+    self.accumulator >> 3
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    w.write_u8(self.rate);
+    w.write_u16(self.period);
+    w.write_bool(self.enabled);
+    w.write_u16(self.timer);
+    w.write_u8(self.step);
+    w.write_u8(self.accumulator);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.rate = r.read_u8()? & 0x3f;
+    self.period = r.read_u16()? & 0xfff;
+    self.enabled = r.read_bool()?;
+    self.timer = r.read_u16()?;
+    self.step = r.read_u8()? % 14;
+    self.accumulator = r.read_u8()?;
+    Ok(())
+  }
+}
+
+// Mappers 24 and 26, the latter with A0 and A1 swapped
+// https://www.nesdev.org/wiki/VRC6
+pub struct VRC6<R: Rom> {
+  cart: Cartridge<R>,
+  swap_address_lines: bool,
+  prg_rom_banks_total: usize,
+
+  prg_16k_bank: u8,
+  prg_8k_bank: u8,
+  chr_banks: [u8; 8],
+  prg_ram_enabled: bool,
+  mirroring_cb: Option<MirroringCallback>,
+
+  irq: VrcIrq,
+
+  pulse1: PulseChannel,
+  pulse2: PulseChannel,
+  saw: SawChannel,
+  // $9003
+  audio_halt: bool,
+  frequency_shift: u8,
+}
+
+impl<R: Rom> VRC6<R> {
+  pub fn new(cart: Cartridge<R>) -> Self {
+    Self {
+      swap_address_lines: cart.header().mapper == 26,
+      prg_rom_banks_total: cart.prg().len() / kilobytes::KB8,
+      cart,
+      prg_16k_bank: 0,
+      prg_8k_bank: 0,
+      chr_banks: [0; 8],
+      prg_ram_enabled: false,
+      mirroring_cb: None,
+      irq: VrcIrq::default(),
+      pulse1: PulseChannel::default(),
+      pulse2: PulseChannel::default(),
+      saw: SawChannel::default(),
+      audio_halt: false,
+      frequency_shift: 0,
+    }
+  }
+
+  fn register(&self, address: u16) -> u16 {
+    let reg = if self.swap_address_lines {
+      ((address & 1) << 1) | ((address & 2) >> 1)
+    } else {
+      address & 0b11
+    };
+    (address & 0xf000) | reg
+  }
+
+  fn read_prg(&self, address: u16) -> u8 {
+    let bank = match address {
+      0x8000..=0xbfff => self.prg_16k_bank as usize * 2 + ((address as usize >> 13) & 1),
+      0xc000..=0xdfff => self.prg_8k_bank as usize,
+      _ => self.prg_rom_banks_total - 1,
+    };
+    let bank = bank % self.prg_rom_banks_total;
+    self.cart.prg()[bank * kilobytes::KB8 + (address as usize & 0x1fff)]
+  }
+
+  fn chr_index(&self, address: u16) -> usize {
+    let bank = self.chr_banks[address as usize / kilobytes::KB1] as usize;
+    bank * kilobytes::KB1 + (address as usize & 0x3ff)
+  }
+
+  // Only the mirroring bits of $B003, the nametable-from-CHR modes are unused by games
+  fn write_banking_control(&mut self, val: u8) {
+    self.prg_ram_enabled = val & 0x80 != 0;
+    let runtime_mirroring = match (val >> 2) & 0b11 {
+      0 => Mirroring::Vertical,
+      1 => Mirroring::Horizontal,
+      2 => Mirroring::SingleScreenLower,
+      _ => Mirroring::SingleScreenUpper,
+    };
+    notify_mirroring(&mut self.mirroring_cb, runtime_mirroring)
+  }
+
+  fn clock_audio(&mut self) {
+    if self.audio_halt {
+      return;
+    }
+    self.pulse1.clock(self.frequency_shift);
+    self.pulse2.clock(self.frequency_shift);
+    self.saw.clock(self.frequency_shift);
+  }
+}
+
+impl<R: Rom> Mapper for VRC6<R> {
+  fn on_runtime_mirroring(&mut self, cb: MirroringCallback) {
+    self.mirroring_cb = Some(cb);
+  }
+
+  fn tick(&mut self, cpu_cycles: usize) {
+    for _ in 0..cpu_cycles {
+      self.clock_audio();
+    }
+    self.irq.tick(cpu_cycles);
+  }
+
+  fn irq(&self) -> bool {
+    self.irq.pending()
+  }
+
+  fn audio_output(&self) -> f32 {
+    let sum = self.pulse1.output() + self.pulse2.output() + self.saw.output();
+    sum as f32 * AUDIO_SCALE
+  }
+
+  fn prg_ram(&self) -> &[u8] {
+    self.cart.prg_ram()
+  }
+
+  fn import_prg_ram(&mut self, ram: &[u8]) -> Result<(), CartridgeError> {
+    self.cart.import_prg_ram(ram)
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    self.cart.save_state(w);
+    w.write_u8(self.prg_16k_bank);
+    w.write_u8(self.prg_8k_bank);
+    w.write_bytes(&self.chr_banks);
+    w.write_bool(self.prg_ram_enabled);
+    self.irq.save_state(w);
+    self.pulse1.save_state(w);
+    self.pulse2.save_state(w);
+    self.saw.save_state(w);
+    w.write_bool(self.audio_halt);
+    w.write_u8(self.frequency_shift);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.cart.load_state(r)?;
+    self.prg_16k_bank = r.read_u8()?;
+    self.prg_8k_bank = r.read_u8()?;
+    r.read_bytes(&mut self.chr_banks)?;
+    self.prg_ram_enabled = r.read_bool()?;
+    self.irq.load_state(r)?;
+    self.pulse1.load_state(r)?;
+    self.pulse2.load_state(r)?;
+    self.saw.load_state(r)?;
+    self.audio_halt = r.read_bool()?;
+    self.frequency_shift = r.read_u8()?;
+    if ![0, 4, 8].contains(&self.frequency_shift) {
+      return Err(SaveStateError::InvalidSaveState("vrc6 frequency shift"));
+    }
+    Ok(())
+  }
+}
+
+impl<R: Rom> Bus for VRC6<R> {
+  fn read8(&self, address: u16) -> u8 {
+    match address {
+      0x0000..=0x1fff => {
+        let chr = self.cart.chr();
+        chr[self.chr_index(address) % chr.len()]
+      }
+      0x6000..=0x7fff if self.prg_ram_enabled => self.cart.prg_ram()[address as usize - 0x6000],
+      0x8000..=0xffff => self.read_prg(address),
+      _ => 0,
+    }
+  }
+
+  fn write8(&mut self, val: u8, address: u16) {
+    if address < 0x8000 {
+      match address {
+        0x0000..=0x1fff => {
+          let index = self.chr_index(address);
+          let chr_ram = self.cart.chr_ram();
+          let len = chr_ram.len();
+          chr_ram[index % len] = val;
+        }
+        0x6000..=0x7fff if self.prg_ram_enabled => {
+          self.cart.prg_ram_mut()[address as usize - 0x6000] = val
+        }
+        _ => (),
+      }
+      return;
+    }
+
+    let register = self.register(address);
+    match register {
+      0x8000..=0x8003 => self.prg_16k_bank = val & 0x0f,
+      0x9000..=0x9002 => self.pulse1.write(val, register & 0b11),
+      0x9003 => {
+        self.audio_halt = val & 1 != 0;
+        self.frequency_shift = if val & 0b100 != 0 {
+          8
+        } else if val & 0b010 != 0 {
+          4
+        } else {
+          0
+        };
+      }
+      0xa000..=0xa002 => self.pulse2.write(val, register & 0b11),
+      0xb000..=0xb002 => self.saw.write(val, register & 0b11),
+      0xb003 => self.write_banking_control(val),
+      0xc000..=0xc003 => self.prg_8k_bank = val & 0x1f,
+      0xd000..=0xd003 => self.chr_banks[(register & 0b11) as usize] = val,
+      0xe000..=0xe003 => self.chr_banks[4 + (register & 0b11) as usize] = val,
+      0xf000 => self.irq.write_latch(val),
+      0xf001 => self.irq.write_control(val),
+      0xf002 => self.irq.acknowledge(),
+      _ => (),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mappers::tests::cart;
+  use crate::mappers::tests::spy_mirroring;
+
+  #[test]
+  fn banking() {
+    let mut vrc = VRC6::new(cart(24, kilobytes::KB32 * 4, kilobytes::KB32 * 4));
+    let mirroring = spy_mirroring(&mut vrc);
+    vrc.write8(2, 0x8000);
+    vrc.write8(9, 0xc000);
+    assert_eq!(
+      [0x8000, 0xa000, 0xc000, 0xe000].map(|a| vrc.read8(a)),
+      [4, 5, 9, 15]
+    );
+
+    vrc.write8(0x21, 0xd002);
+    vrc.write8(0x42, 0xe003);
+    assert_eq!(vrc.read8(0x0800), 0x21);
+    assert_eq!(vrc.read8(0x1c00), 0x42);
+
+    vrc.write8(0x84, 0xb003);
+    assert_eq!(*mirroring.borrow(), Some(Mirroring::Horizontal));
+    vrc.write8(0x55, 0x6000);
+    assert_eq!(vrc.read8(0x6000), 0x55);
+  }
+
+  #[test]
+  fn swapped_address_lines() {
+    let mut vrc = VRC6::new(cart(26, kilobytes::KB32 * 4, kilobytes::KB32 * 4));
+    // $D001 is CHR bank 2 on mapper 26
+    vrc.write8(0x21, 0xd001);
+    assert_eq!(vrc.read8(0x0800), 0x21);
+  }
+
+  #[test]
+  fn pulse_duty_cycle() {
+    let mut vrc = VRC6::new(cart(24, kilobytes::KB32 * 4, kilobytes::KB32 * 4));
+    vrc.write8(0x3f, 0x9000); // 4/16 duty, full volume
+    vrc.write8(0x00, 0x9001);
+    vrc.write8(0x80, 0x9002); // Period 0, a step per CPU cycle
+
+    let high = (0..16)
+      .filter(|_| {
+        vrc.tick(1);
+        vrc.audio_output() > 0.0
+      })
+      .count();
+    assert_eq!(high, 4);
+  }
+
+  #[test]
+  fn saw_accumulates() {
+    let mut vrc = VRC6::new(cart(24, kilobytes::KB32 * 4, kilobytes::KB32 * 4));
+    vrc.write8(0x10, 0xb000);
+    vrc.write8(0x80, 0xb002);
+    vrc.tick(12);
+    // Six additions of 16, the top five bits
+    assert_eq!(vrc.saw.output(), 96 >> 3);
+    vrc.tick(2);
+    assert_eq!(vrc.saw.output(), 0);
+  }
+}
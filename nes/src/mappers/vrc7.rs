@@ -0,0 +1,249 @@
+use common::kilobytes;
+use mos6502::memory::Bus;
+
+use super::notify_mirroring;
+use super::vrc7_audio::Opll;
+use super::vrc_irq::VrcIrq;
+use super::Mapper;
+use super::MirroringCallback;
+use crate::cartridge::error::CartridgeError;
+use crate::cartridge::Cartridge;
+use crate::cartridge::Mirroring;
+use crate::cartridge::Rom;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+// Mapper 85
+// https://www.nesdev.org/wiki/VRC7
+pub struct VRC7<R: Rom> {
+  cart: Cartridge<R>,
+  // VRC7a (Lagrange Point) uses A4 for $x010, VRC7b (Tiny Toon Adventures 2) A3 for $x008
+  address_line: u16,
+  prg_rom_banks_total: usize,
+
+  prg_banks: [u8; 3],
+  chr_banks: [u8; 8],
+  prg_ram_enabled: bool,
+  mirroring_cb: Option<MirroringCallback>,
+
+  irq: VrcIrq,
+
+  opll: Opll,
+  audio_silenced: bool,
+}
+
+impl<R: Rom> VRC7<R> {
+  pub fn new(cart: Cartridge<R>) -> Self {
+    let address_line = match cart.header().submapper {
+      1 => 0x08,
+      2 => 0x10,
+      _ => 0x18,
+    };
+    Self {
+      address_line,
+      prg_rom_banks_total: cart.prg().len() / kilobytes::KB8,
+      cart,
+      prg_banks: [0; 3],
+      chr_banks: [0; 8],
+      prg_ram_enabled: false,
+      mirroring_cb: None,
+      irq: VrcIrq::default(),
+      opll: Opll::new(),
+      audio_silenced: false,
+    }
+  }
+
+  // $x000 or $x010, plus $9030 for audio data
+  fn register(&self, address: u16) -> u16 {
+    let mut reg = address & 0xf000;
+    if address & self.address_line != 0 {
+      reg |= 0x10;
+    }
+    if reg == 0x9010 && address & 0x20 != 0 {
+      reg |= 0x20;
+    }
+    reg
+  }
+
+  fn read_prg(&self, address: u16) -> u8 {
+    let bank = match address {
+      0x8000..=0xdfff => self.prg_banks[(address as usize - 0x8000) / kilobytes::KB8] as usize,
+      _ => self.prg_rom_banks_total - 1,
+    };
+    let bank = bank % self.prg_rom_banks_total;
+    self.cart.prg()[bank * kilobytes::KB8 + (address as usize & 0x1fff)]
+  }
+
+  fn chr_index(&self, address: u16) -> usize {
+    let bank = self.chr_banks[address as usize / kilobytes::KB1] as usize;
+    bank * kilobytes::KB1 + (address as usize & 0x3ff)
+  }
+
+  fn write_control(&mut self, val: u8) {
+    self.prg_ram_enabled = val & 0x80 != 0;
+    self.audio_silenced = val & 0x40 != 0;
+    let runtime_mirroring = match val & 0b11 {
+      0 => Mirroring::Vertical,
+      1 => Mirroring::Horizontal,
+      2 => Mirroring::SingleScreenLower,
+      _ => Mirroring::SingleScreenUpper,
+    };
+    notify_mirroring(&mut self.mirroring_cb, runtime_mirroring)
+  }
+}
+
+impl<R: Rom> Mapper for VRC7<R> {
+  fn on_runtime_mirroring(&mut self, cb: MirroringCallback) {
+    self.mirroring_cb = Some(cb);
+  }
+
+  fn tick(&mut self, cpu_cycles: usize) {
+    for _ in 0..cpu_cycles {
+      self.opll.tick();
+    }
+    self.irq.tick(cpu_cycles);
+  }
+
+  fn irq(&self) -> bool {
+    self.irq.pending()
+  }
+
+  fn audio_output(&self) -> f32 {
+    if self.audio_silenced {
+      0.0
+    } else {
+      self.opll.output()
+    }
+  }
+
+  fn prg_ram(&self) -> &[u8] {
+    self.cart.prg_ram()
+  }
+
+  fn import_prg_ram(&mut self, ram: &[u8]) -> Result<(), CartridgeError> {
+    self.cart.import_prg_ram(ram)
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    self.cart.save_state(w);
+    w.write_bytes(&self.prg_banks);
+    w.write_bytes(&self.chr_banks);
+    w.write_bool(self.prg_ram_enabled);
+    self.irq.save_state(w);
+    self.opll.save_state(w);
+    w.write_bool(self.audio_silenced);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.cart.load_state(r)?;
+    r.read_bytes(&mut self.prg_banks)?;
+    r.read_bytes(&mut self.chr_banks)?;
+    self.prg_ram_enabled = r.read_bool()?;
+    self.irq.load_state(r)?;
+    self.opll.load_state(r)?;
+    self.audio_silenced = r.read_bool()?;
+    Ok(())
+  }
+}
+
+impl<R: Rom> Bus for VRC7<R> {
+  fn read8(&self, address: u16) -> u8 {
+    match address {
+      0x0000..=0x1fff => {
+        let chr = self.cart.chr();
+        chr[self.chr_index(address) % chr.len()]
+      }
+      0x6000..=0x7fff if self.prg_ram_enabled => self.cart.prg_ram()[address as usize - 0x6000],
+      0x8000..=0xffff => self.read_prg(address),
+      _ => 0,
+    }
+  }
+
+  fn write8(&mut self, val: u8, address: u16) {
+    if address < 0x8000 {
+      match address {
+        0x0000..=0x1fff => {
+          let index = self.chr_index(address);
+          let chr_ram = self.cart.chr_ram();
+          let len = chr_ram.len();
+          chr_ram[index % len] = val;
+        }
+        0x6000..=0x7fff if self.prg_ram_enabled => {
+          self.cart.prg_ram_mut()[address as usize - 0x6000] = val
+        }
+        _ => (),
+      }
+      return;
+    }
+
+    match self.register(address) {
+      0x8000 => self.prg_banks[0] = val & 0x3f,
+      0x8010 => self.prg_banks[1] = val & 0x3f,
+      0x9000 => self.prg_banks[2] = val & 0x3f,
+      0x9010 => self.opll.write_address(val),
+      0x9030 => self.opll.write_data(val),
+      reg @ 0xa000..=0xd010 => {
+        let n = ((reg >> 12) - 0xa) * 2 + ((reg >> 4) & 1);
+        self.chr_banks[n as usize] = val;
+      }
+      0xe000 => self.write_control(val),
+      0xe010 => self.irq.write_latch(val),
+      0xf000 => self.irq.write_control(val),
+      0xf010 => self.irq.acknowledge(),
+      _ => (),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mappers::tests::cart;
+  use crate::mappers::tests::nes2_cart;
+  use crate::mappers::tests::spy_mirroring;
+
+  #[test]
+  fn vrc7a_banking() {
+    let mut vrc = VRC7::new(nes2_cart(85, 2, kilobytes::KB32 * 4, kilobytes::KB32 * 4));
+    let mirroring = spy_mirroring(&mut vrc);
+    vrc.write8(3, 0x8000);
+    vrc.write8(4, 0x8010);
+    vrc.write8(5, 0x9000);
+    assert_eq!(
+      [0x8000, 0xa000, 0xc000, 0xe000].map(|a| vrc.read8(a)),
+      [3, 4, 5, 15]
+    );
+
+    vrc.write8(0x21, 0xa010);
+    vrc.write8(0x42, 0xd010);
+    assert_eq!(vrc.read8(0x0400), 0x21);
+    assert_eq!(vrc.read8(0x1c00), 0x42);
+
+    // A3 isn't wired on VRC7a
+    vrc.write8(0x11, 0xa008);
+    assert_eq!(vrc.read8(0x0000), 0x11);
+
+    vrc.write8(0x81, 0xe000);
+    assert_eq!(*mirroring.borrow(), Some(Mirroring::Horizontal));
+    vrc.write8(0x55, 0x6000);
+    assert_eq!(vrc.read8(0x6000), 0x55);
+  }
+
+  #[test]
+  fn vrc7b_registers() {
+    let mut vrc = VRC7::new(cart(85, kilobytes::KB32 * 4, kilobytes::KB32 * 4));
+    vrc.write8(4, 0x8008);
+    assert_eq!(vrc.read8(0xa000), 4);
+  }
+
+  #[test]
+  fn silenced_audio() {
+    let mut vrc = VRC7::new(cart(85, kilobytes::KB32 * 4, kilobytes::KB32 * 4));
+    let _mirroring = spy_mirroring(&mut vrc);
+    vrc.tick(36);
+    assert!(vrc.audio_output() > 0.0);
+    vrc.write8(0x40, 0xe000);
+    assert_eq!(vrc.audio_output(), 0.0);
+  }
+}
@@ -0,0 +1,541 @@
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+// A simplified YM2413 (OPLL), the FM synth inside the VRC7. Six two-operator
+// channels and no rhythm section. It's not bit-exact, envelope and modulation
+// depths are approximated from the datasheet.
+// https://www.nesdev.org/wiki/VRC7_audio
+
+// One sample every 36 CPU cycles, ~49.7 kHz
+const CPU_CYCLES_PER_SAMPLE: u8 = 36;
+
+// Built-in instruments 1-15, 0 is the custom one in $00-$07
+// https://www.nesdev.org/wiki/VRC7_audio#Instruments
+const PATCHES: [[u8; 8]; 15] = [
+  [0x03, 0x21, 0x05, 0x06, 0xe8, 0x81, 0x42, 0x27],
+  [0x13, 0x41, 0x14, 0x0d, 0xd8, 0xf6, 0x23, 0x12],
+  [0x11, 0x11, 0x08, 0x08, 0xfa, 0xb2, 0x20, 0x12],
+  [0x31, 0x61, 0x0c, 0x07, 0xa8, 0x64, 0x61, 0x27],
+  [0x32, 0x21, 0x1e, 0x06, 0xe1, 0x76, 0x01, 0x28],
+  [0x02, 0x01, 0x06, 0x00, 0xa3, 0xe2, 0xf4, 0xf4],
+  [0x21, 0x61, 0x1d, 0x07, 0x82, 0x81, 0x11, 0x07],
+  [0x23, 0x21, 0x22, 0x17, 0xa2, 0x72, 0x01, 0x17],
+  [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+  [0xb5, 0x01, 0x0f, 0x0f, 0xa8, 0xa5, 0x51, 0x02],
+  [0x17, 0xc1, 0x24, 0x07, 0xf8, 0xf8, 0x22, 0x12],
+  [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+  [0x01, 0x02, 0xd3, 0x05, 0xc9, 0x95, 0x03, 0x02],
+  [0x61, 0x63, 0x0c, 0x00, 0x94, 0xc0, 0x33, 0xf6],
+  [0x21, 0x72, 0x0d, 0x00, 0xc1, 0xd5, 0x56, 0x06],
+];
+
+// Frequency multiplier, times two
+const MULTIPLIERS: [u32; 16] = [1, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 20, 24, 24, 30, 30];
+
+// Key scale level per top four F-number bits, in 0.375 dB
+const KEY_SCALE_LEVELS: [u8; 16] = [
+  0, 24, 32, 37, 40, 43, 45, 47, 48, 50, 51, 52, 53, 54, 55, 56,
+];
+
+// 18 bits of phase is one period of the sine table
+const PHASE_BITS: u32 = 18;
+const SINE_SIZE: usize = 1024;
+
+// Attenuation is counted in 0.375 dB steps, the envelope has 128 of them
+// and 15 fractional bits.
+const ENVELOPE_FRACTION: u32 = 15;
+const ENVELOPE_MAX: u32 = 128 << ENVELOPE_FRACTION;
+const ATTENUATION_STEPS: usize = 256;
+
+// Tremolo (4.8 dB at 3.7 Hz) and vibrato (~7 cents at 6.4 Hz), in samples
+const AM_PERIOD: u16 = 13436;
+const AM_DEPTH: u32 = 13;
+const VIBRATO_PERIOD: u16 = 7768;
+
+// Keeps the signed output above the APU's silence, see Apu::mix
+const OUTPUT_BIAS: f32 = 0.15;
+const CHANNEL_SCALE: f32 = 0.025;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Envelope {
+  Attack = 0,
+  Decay = 1,
+  Sustain = 2,
+  Release = 3,
+  Off = 4,
+}
+
+impl TryFrom<u8> for Envelope {
+  type Error = SaveStateError;
+
+  fn try_from(i: u8) -> Result<Self, Self::Error> {
+    match i {
+      0 => Ok(Envelope::Attack),
+      1 => Ok(Envelope::Decay),
+      2 => Ok(Envelope::Sustain),
+      3 => Ok(Envelope::Release),
+      4 => Ok(Envelope::Off),
+      _ => Err(SaveStateError::InvalidSaveState("vrc7 envelope")),
+    }
+  }
+}
+
+// One operator's half of a patch. Byte layout differs between modulator and carrier.
+struct OperatorPatch {
+  tremolo: bool,
+  vibrato: bool,
+  sustained: bool,
+  key_scale_rate: bool,
+  multiplier: u32,
+  key_scale_level: u8,
+  rectify: bool,
+  attack: u8,
+  decay: u8,
+  sustain_level: u32,
+  release: u8,
+}
+
+impl OperatorPatch {
+  fn from(patch: &[u8; 8], carrier: bool) -> Self {
+    let i = carrier as usize;
+    Self {
+      tremolo: patch[i] & 0x80 != 0,
+      vibrato: patch[i] & 0x40 != 0,
+      sustained: patch[i] & 0x20 != 0,
+      key_scale_rate: patch[i] & 0x10 != 0,
+      multiplier: MULTIPLIERS[(patch[i] & 0x0f) as usize],
+      key_scale_level: patch[2 + i] >> 6,
+      rectify: patch[3] & if carrier { 0x10 } else { 0x08 } != 0,
+      attack: patch[4 + i] >> 4,
+      decay: patch[4 + i] & 0x0f,
+      sustain_level: ((patch[6 + i] >> 4) as u32 * 8) << ENVELOPE_FRACTION,
+      release: patch[6 + i] & 0x0f,
+    }
+  }
+}
+
+#[derive(Clone, Copy)]
+struct Operator {
+  phase: u32,
+  envelope: u32,
+  state: Envelope,
+}
+
+impl Default for Operator {
+  fn default() -> Self {
+    Self {
+      phase: 0,
+      envelope: ENVELOPE_MAX,
+      state: Envelope::Off,
+    }
+  }
+}
+
+impl Operator {
+  fn key_on(&mut self) {
+    self.phase = 0;
+    self.state = Envelope::Attack;
+  }
+
+  fn key_off(&mut self) {
+    if self.state != Envelope::Off {
+      self.state = Envelope::Release;
+    }
+  }
+
+  // Per-sample envelope step for a 4-bit rate, 0 is "never"
+  fn rate_step(rate: u8, key_scale: u8, patch: &OperatorPatch) -> u32 {
+    if rate == 0 {
+      return 0;
+    }
+    let ksr = if patch.key_scale_rate {
+      key_scale
+    } else {
+      key_scale >> 2
+    };
+    let rks = (rate * 4 + ksr).min(63);
+    [4, 5, 6, 7][(rks & 3) as usize] << (rks >> 2)
+  }
+
+  fn clock_envelope(&mut self, patch: &OperatorPatch, key_scale: u8, channel_sustain: bool) {
+    match self.state {
+      Envelope::Attack => {
+        let step = Self::rate_step(patch.attack, key_scale, patch);
+        if step >= 1 << 15 {
+          self.envelope = 0;
+        } else if step > 0 {
+          let dec = ((self.envelope as u64 * step as u64) >> 18) as u32 + step;
+          self.envelope = self.envelope.saturating_sub(dec);
+        }
+        if self.envelope == 0 {
+          self.state = Envelope::Decay;
+        }
+      }
+      Envelope::Decay => {
+        self.envelope += Self::rate_step(patch.decay, key_scale, patch);
+        if self.envelope >= patch.sustain_level {
+          self.envelope = patch.sustain_level;
+          self.state = Envelope::Sustain;
+        }
+      }
+      Envelope::Sustain => {
+        // Percussive patches keep fading while the key is held
+        if !patch.sustained {
+          self.envelope += Self::rate_step(patch.release, key_scale, patch);
+        }
+      }
+      Envelope::Release => {
+        let rate = if channel_sustain {
+          5
+        } else if patch.sustained {
+          patch.release
+        } else {
+          7
+        };
+        self.envelope += Self::rate_step(rate, key_scale, patch);
+      }
+      Envelope::Off => (),
+    }
+
+    if self.envelope >= ENVELOPE_MAX {
+      self.envelope = ENVELOPE_MAX;
+      if self.state != Envelope::Attack {
+        self.state = Envelope::Off;
+      }
+    }
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    w.write_usize(self.phase as usize);
+    w.write_usize(self.envelope as usize);
+    w.write_u8(self.state as u8);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.phase = r.read_usize()? as u32 & ((1 << PHASE_BITS) - 1);
+    self.envelope = (r.read_usize()? as u32).min(ENVELOPE_MAX);
+    self.state = r.read_u8()?.try_into()?;
+    Ok(())
+  }
+}
+
+#[derive(Default, Clone, Copy)]
+struct Channel {
+  fnum: u16, // 9 bits
+  block: u8,
+  key_on: bool,
+  sustain: bool,
+  instrument: u8,
+  volume: u8,
+  modulator: Operator,
+  carrier: Operator,
+  // The modulator's last two outputs, for feedback
+  feedback: [f32; 2],
+}
+
+impl Channel {
+  // For key scale rate, 0..=15
+  fn key_scale(&self) -> u8 {
+    (self.block << 1) | (self.fnum >> 8) as u8
+  }
+
+  // Key scale level attenuation, in 0.375 dB
+  fn key_scale_attenuation(&self, patch: &OperatorPatch) -> u32 {
+    if patch.key_scale_level == 0 {
+      return 0;
+    }
+    // -6 dB per octave below the top one
+    let level = KEY_SCALE_LEVELS[(self.fnum >> 5) as usize] as i32 - 16 * (7 - self.block as i32);
+    (level.max(0) as u32) >> (3 - patch.key_scale_level)
+  }
+
+  fn phase_increment(&self, patch: &OperatorPatch, vibrato: i32) -> u32 {
+    let inc = (((self.fnum as u32) << self.block) * patch.multiplier) >> 2;
+    if patch.vibrato {
+      (inc as i32 + ((inc as i32 * vibrato) >> 11)) as u32
+    } else {
+      inc
+    }
+  }
+}
+
+pub(crate) struct Opll {
+  address: u8,
+  custom_patch: [u8; 8],
+  channels: [Channel; 6],
+
+  cycles: u8,
+  am_counter: u16,
+  vibrato_counter: u16,
+  output: f32,
+
+  sine: [f32; SINE_SIZE],
+  // 0.375 dB steps to linear gain
+  attenuation: [f32; ATTENUATION_STEPS],
+}
+
+impl Opll {
+  pub fn new() -> Self {
+    // No libm, rotate a unit vector instead of calling sin
+    let (cos_step, sin_step) = (0.9999811752826011f64, 0.006135884649154475f64);
+    let (mut x, mut y) = (1.0f64, 0.0f64);
+    let mut sine = [0.0; SINE_SIZE];
+    for s in sine.iter_mut() {
+      *s = y as f32;
+      (x, y) = (x * cos_step - y * sin_step, x * sin_step + y * cos_step);
+    }
+
+    // 10^(-0.375 / 20)
+    let mut attenuation = [0.0; ATTENUATION_STEPS];
+    let mut gain = 1.0f32;
+    for a in attenuation.iter_mut() {
+      *a = gain;
+      gain *= 0.957_745_2;
+    }
+
+    Self {
+      address: 0,
+      custom_patch: [0; 8],
+      channels: [Channel::default(); 6],
+      cycles: 0,
+      am_counter: 0,
+      vibrato_counter: 0,
+      output: 0.0,
+      sine,
+      attenuation,
+    }
+  }
+
+  pub fn write_address(&mut self, val: u8) {
+    self.address = val;
+  }
+
+  pub fn write_data(&mut self, val: u8) {
+    let n = (self.address & 0x0f) as usize;
+    match self.address {
+      0x00..=0x07 => self.custom_patch[n] = val,
+      0x10..=0x15 => {
+        let ch = &mut self.channels[n];
+        ch.fnum = (ch.fnum & 0x100) | val as u16;
+      }
+      0x20..=0x25 => {
+        let ch = &mut self.channels[n];
+        ch.fnum = (ch.fnum & 0xff) | ((val as u16 & 1) << 8);
+        ch.block = (val >> 1) & 0b111;
+        ch.sustain = val & 0x20 != 0;
+        let key_on = val & 0x10 != 0;
+        if key_on && !ch.key_on {
+          ch.modulator.key_on();
+          ch.carrier.key_on();
+        } else if !key_on && ch.key_on {
+          ch.modulator.key_off();
+          ch.carrier.key_off();
+        }
+        ch.key_on = key_on;
+      }
+      0x30..=0x35 => {
+        let ch = &mut self.channels[n];
+        ch.instrument = val >> 4;
+        ch.volume = val & 0x0f;
+      }
+      _ => (),
+    }
+  }
+
+  fn patch(&self, instrument: u8) -> &[u8; 8] {
+    match instrument {
+      0 => &self.custom_patch,
+      i => &PATCHES[i as usize - 1],
+    }
+  }
+
+  pub fn tick(&mut self) {
+    self.cycles += 1;
+    if self.cycles == CPU_CYCLES_PER_SAMPLE {
+      self.cycles = 0;
+      self.output = self.sample();
+    }
+  }
+
+  // Triangles, 0..=AM_DEPTH and -8..=8
+  fn lfo(&mut self) -> (u32, i32) {
+    self.am_counter = (self.am_counter + 1) % AM_PERIOD;
+    self.vibrato_counter = (self.vibrato_counter + 1) % VIBRATO_PERIOD;
+
+    let half = AM_PERIOD / 2;
+    let am = half.abs_diff(self.am_counter) as u32 * AM_DEPTH / half as u32;
+    let quarter = (VIBRATO_PERIOD / 4) as i32;
+    let v = self.vibrato_counter as i32;
+    let triangle = if v < quarter {
+      v
+    } else if v < 3 * quarter {
+      2 * quarter - v
+    } else {
+      v - 4 * quarter
+    };
+    let vibrato = triangle * 8 / quarter;
+    (AM_DEPTH - am, vibrato)
+  }
+
+  fn operator_output(
+    &self,
+    op: &Operator,
+    patch: &OperatorPatch,
+    phase_offset: f32,
+    attenuation: u32,
+  ) -> f32 {
+    if op.state == Envelope::Off {
+      return 0.0;
+    }
+    let index = ((op.phase >> (PHASE_BITS - 10)) as i32 + (phase_offset * SINE_SIZE as f32) as i32)
+      as usize
+      & (SINE_SIZE - 1);
+    let s = self.sine[index];
+    if patch.rectify && s < 0.0 {
+      return 0.0;
+    }
+    let total = (op.envelope >> ENVELOPE_FRACTION) + attenuation;
+    match self.attenuation.get(total as usize) {
+      Some(gain) => s * gain,
+      None => 0.0,
+    }
+  }
+
+  fn sample(&mut self) -> f32 {
+    let (am, vibrato) = self.lfo();
+    let mut sum = 0.0;
+
+    for n in 0..self.channels.len() {
+      let ch = self.channels[n];
+      let patch = self.patch(ch.instrument);
+      let m = OperatorPatch::from(patch, false);
+      let c = OperatorPatch::from(patch, true);
+      let total_level = (patch[2] & 0x3f) as u32 * 2;
+      let feedback = patch[3] & 0b111;
+
+      // Feedback level n is a modulation index of π/16 * 2^(n-1), in periods
+      let fb = if feedback == 0 {
+        0.0
+      } else {
+        (ch.feedback[0] + ch.feedback[1]) / 2.0 * (1 << (feedback - 1)) as f32 / 32.0
+      };
+      let m_att = total_level + ch.key_scale_attenuation(&m) + if m.tremolo { am } else { 0 };
+      let m_out = self.operator_output(&ch.modulator, &m, fb, m_att);
+
+      // Full scale modulator output moves the carrier by 4π
+      let c_att =
+        ch.volume as u32 * 8 + ch.key_scale_attenuation(&c) + if c.tremolo { am } else { 0 };
+      let c_out = self.operator_output(&ch.carrier, &c, m_out * 2.0, c_att);
+      sum += c_out;
+
+      let key_scale = ch.key_scale();
+      let m_inc = ch.phase_increment(&m, vibrato);
+      let c_inc = ch.phase_increment(&c, vibrato);
+      let ch = &mut self.channels[n];
+      ch.feedback = [ch.feedback[1], m_out];
+      ch.modulator.phase = (ch.modulator.phase + m_inc) & ((1 << PHASE_BITS) - 1);
+      ch.carrier.phase = (ch.carrier.phase + c_inc) & ((1 << PHASE_BITS) - 1);
+      ch.modulator.clock_envelope(&m, key_scale, ch.sustain);
+      ch.carrier.clock_envelope(&c, key_scale, ch.sustain);
+    }
+
+    OUTPUT_BIAS + sum * CHANNEL_SCALE
+  }
+
+  pub fn output(&self) -> f32 {
+    self.output
+  }
+
+  // The generated tables aren't state
+  pub fn save_state(&self, w: &mut StateWriter) {
+    w.write_u8(self.address);
+    w.write_bytes(&self.custom_patch);
+    for ch in &self.channels {
+      w.write_u16(ch.fnum);
+      w.write_u8(ch.block);
+      w.write_bool(ch.key_on);
+      w.write_bool(ch.sustain);
+      w.write_u8(ch.instrument);
+      w.write_u8(ch.volume);
+      ch.modulator.save_state(w);
+      ch.carrier.save_state(w);
+    }
+    w.write_u8(self.cycles);
+    w.write_u16(self.am_counter);
+    w.write_u16(self.vibrato_counter);
+  }
+
+  pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.address = r.read_u8()?;
+    r.read_bytes(&mut self.custom_patch)?;
+    for ch in self.channels.iter_mut() {
+      ch.fnum = r.read_u16()? & 0x1ff;
+      ch.block = r.read_u8()? & 0b111;
+      ch.key_on = r.read_bool()?;
+      ch.sustain = r.read_bool()?;
+      ch.instrument = r.read_u8()? & 0x0f;
+      ch.volume = r.read_u8()? & 0x0f;
+      ch.modulator.load_state(r)?;
+      ch.carrier.load_state(r)?;
+      ch.feedback = [0.0; 2];
+    }
+    self.cycles = r.read_u8()? % CPU_CYCLES_PER_SAMPLE;
+    self.am_counter = r.read_u16()? % AM_PERIOD;
+    self.vibrato_counter = r.read_u16()? % VIBRATO_PERIOD;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn key_on(opll: &mut Opll, instrument: u8) {
+    opll.write_address(0x30);
+    opll.write_data(instrument << 4);
+    opll.write_address(0x10);
+    opll.write_data(0x22); // fnum 290, A4 in block 4
+    opll.write_address(0x20);
+    opll.write_data(0x19);
+  }
+
+  fn samples(opll: &mut Opll, n: usize) -> impl Iterator<Item = f32> + '_ {
+    (0..n).map(|_| {
+      for _ in 0..CPU_CYCLES_PER_SAMPLE {
+        opll.tick();
+      }
+      opll.output() - OUTPUT_BIAS
+    })
+  }
+
+  #[test]
+  fn silent_until_key_on() {
+    let mut opll = Opll::new();
+    assert!(samples(&mut opll, 1000).all(|s| s == 0.0));
+
+    key_on(&mut opll, 3);
+    assert!(samples(&mut opll, 1000).any(|s| s.abs() > 0.001));
+  }
+
+  #[test]
+  fn tables() {
+    let opll = Opll::new();
+    assert!((opll.sine[SINE_SIZE / 4] - 1.0).abs() < 1e-4);
+    assert!(opll.sine[SINE_SIZE / 2].abs() < 1e-4);
+    // 48 dB
+    assert!((opll.attenuation[128] - 0.00398).abs() < 1e-4);
+  }
+
+  #[test]
+  fn key_off_releases() {
+    let mut opll = Opll::new();
+    key_on(&mut opll, 1);
+    samples(&mut opll, 1000).for_each(drop);
+    opll.write_address(0x20);
+    opll.write_data(0x09);
+    samples(&mut opll, 200_000).for_each(drop);
+    assert_eq!(opll.channels[0].carrier.state, Envelope::Off);
+    assert!(samples(&mut opll, 100).all(|s| s == 0.0));
+  }
+}
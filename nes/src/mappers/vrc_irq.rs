@@ -0,0 +1,144 @@
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+// Shared by VRC4, VRC6 and VRC7. Runs off CPU cycles, in scanline mode a
+// prescaler turns 341 PPU dots (113.667 CPU cycles) into one clock.
+// https://www.nesdev.org/wiki/VRC_IRQ
+#[derive(Default)]
+pub(crate) struct VrcIrq {
+  latch: u8,
+  counter: u8,
+  prescaler: i16,
+  enabled: bool,
+  enable_after_ack: bool,
+  cycle_mode: bool,
+  pending: bool,
+}
+
+impl VrcIrq {
+  pub fn write_latch(&mut self, val: u8) {
+    self.latch = val;
+  }
+
+  pub fn write_latch_low(&mut self, val: u8) {
+    self.latch = (self.latch & 0xf0) | (val & 0x0f);
+  }
+
+  pub fn write_latch_high(&mut self, val: u8) {
+    self.latch = (self.latch & 0x0f) | (val << 4);
+  }
+
+  pub fn write_control(&mut self, val: u8) {
+    self.enable_after_ack = val & 0b001 != 0;
+    self.enabled = val & 0b010 != 0;
+    self.cycle_mode = val & 0b100 != 0;
+    self.pending = false;
+    if self.enabled {
+      self.counter = self.latch;
+      self.prescaler = 341;
+    }
+  }
+
+  pub fn acknowledge(&mut self) {
+    self.pending = false;
+    self.enabled = self.enable_after_ack;
+  }
+
+  // The /IRQ line, held from the counter wrapping until acknowledged
+  pub fn pending(&self) -> bool {
+    self.pending
+  }
+
+  pub fn tick(&mut self, cpu_cycles: usize) {
+    if !self.enabled {
+      return;
+    }
+
+    for _ in 0..cpu_cycles {
+      if self.cycle_mode {
+        self.clock();
+      } else {
+        self.prescaler -= 3;
+        if self.prescaler <= 0 {
+          self.prescaler += 341;
+          self.clock();
+        }
+      }
+    }
+  }
+
+  fn clock(&mut self) {
+    if self.counter == 0xff {
+      self.counter = self.latch;
+      self.pending = true;
+    } else {
+      self.counter += 1;
+    }
+  }
+
+  pub fn save_state(&self, w: &mut StateWriter) {
+    w.write_u8(self.latch);
+    w.write_u8(self.counter);
+    w.write_u16(self.prescaler as u16);
+    w.write_bool(self.enabled);
+    w.write_bool(self.enable_after_ack);
+    w.write_bool(self.cycle_mode);
+    w.write_bool(self.pending);
+  }
+
+  pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.latch = r.read_u8()?;
+    self.counter = r.read_u8()?;
+    self.prescaler = r.read_u16()? as i16;
+    if !(-2..=341).contains(&self.prescaler) {
+      return Err(SaveStateError::InvalidSaveState("vrc irq prescaler"));
+    }
+    self.enabled = r.read_bool()?;
+    self.enable_after_ack = r.read_bool()?;
+    self.cycle_mode = r.read_bool()?;
+    self.pending = r.read_bool()?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn cycle_mode() {
+    let mut irq = VrcIrq::default();
+    irq.write_latch(0xfd);
+    irq.write_control(0b111);
+    irq.tick(2);
+    assert!(!irq.pending());
+    irq.tick(1);
+    assert!(irq.pending());
+
+    // Held, and reloaded from the latch
+    irq.tick(2);
+    assert!(irq.pending());
+    irq.acknowledge();
+    assert!(!irq.pending());
+    irq.tick(1);
+    assert!(irq.pending());
+  }
+
+  #[test]
+  fn scanline_mode() {
+    let mut irq = VrcIrq::default();
+    irq.write_latch(0xfe);
+    irq.write_control(0b010);
+    // Two scanlines, 2 * 341 / 3 CPU cycles
+    irq.tick(227);
+    assert!(!irq.pending());
+    irq.tick(1);
+    assert!(irq.pending());
+
+    // Not re-enabled on acknowledge
+    irq.acknowledge();
+    irq.tick(10_000);
+    assert!(!irq.pending());
+  }
+}
@@ -1,6 +1,7 @@
 use alloc::boxed::Box;
 use alloc::rc::Rc;
 use alloc::string::ToString;
+use alloc::vec::Vec;
 use core::cell::RefCell;
 use core::time::Duration;
 use mos6502::cpu::AC;
@@ -9,22 +10,36 @@ use mos6502::cpu::X;
 use mos6502::cpu::Y;
 
 use mos6502::cpu::Cpu;
+use mos6502::cpu::Flag;
+use mos6502::cpu::InterruptLines;
+use mos6502::cpu::InterruptState;
+use mos6502::cpu::Stepping;
+use mos6502::cpu::Variant;
 #[cfg(feature = "debugger")]
 use mos6502::debugger::AttachedDebugger;
 use mos6502::mos6502::Mos6502;
 
+use crate::apu::apu::Apu;
+use crate::audio::AudioOutput;
+use crate::audio::AudioSpec;
+use crate::cartridge::error::CartridgeError;
 use crate::cartridge::Cartridge;
 use crate::cartridge::Rom;
+use crate::controller::Controllers;
+use crate::controller::Peripheral;
 use crate::fonts;
 use crate::frame::PixelFormatRGB565;
 use crate::frame::PixelFormatRGB888;
 use crate::frame::RenderFrame;
 use crate::joypad::Joypad;
+use crate::mappers::Mapper;
 use crate::nesbus::NesBus;
 use crate::ppu::ppu::Ppu;
-use crate::ppu::ppu::TickEvent;
+use crate::region::Region;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
 
-const DEFAULT_FPS_MAX: usize = 60;
+pub use crate::savestate::error::SaveStateError;
 
 #[derive(PartialEq, Eq)]
 pub enum Shutdown {
@@ -54,6 +69,24 @@ pub trait HostPlatform {
   fn render(&mut self, frame: &RenderFrame);
   fn poll_events(&mut self, joypad: &mut Joypad) -> Shutdown;
 
+  fn poll_joypad_2(&mut self, _: &mut Joypad) {
+    // Not required. Player 2 on $4017, polled right after poll_events.
+  }
+
+  fn poll_peripheral(&mut self, _: Peripheral) {
+    // Not required. Polled after poll_joypad_2 when the cartridge's expansion device
+    // asks for a Four Score, one or two Zappers, an Arkanoid paddle or a Power Pad.
+  }
+
+  fn audio_spec(&self) -> Option<AudioSpec> {
+    // Not required. No audio is produced unless the platform asks for it.
+    None
+  }
+
+  fn play_audio(&mut self, _: &[f32]) {
+    // Called with AudioSpec::buffer_size sample frames, -1.0..=1.0, channels interleaved.
+  }
+
   fn elapsed_millis(&self) -> usize {
     // Not required. Up to platform to implement for FPS control.
     0
@@ -88,11 +121,49 @@ impl HostPlatform for HeadlessHost {
   fn delay(&self, _: Duration) {}
 }
 
+// Collects audio instead of playing it. Grab samples() before handing it to Nes::insert.
+pub struct HeadlessAudioHost {
+  spec: AudioSpec,
+  samples: Rc<RefCell<Vec<f32>>>,
+}
+
+impl HeadlessAudioHost {
+  pub fn new(spec: AudioSpec) -> Self {
+    Self {
+      spec,
+      samples: Rc::new(RefCell::new(Vec::new())),
+    }
+  }
+
+  pub fn samples(&self) -> Rc<RefCell<Vec<f32>>> {
+    self.samples.clone()
+  }
+}
+
+impl HostPlatform for HeadlessAudioHost {
+  fn render(&mut self, _: &RenderFrame) {}
+  fn poll_events(&mut self, _: &mut Joypad) -> Shutdown {
+    Shutdown::No
+  }
+  fn audio_spec(&self) -> Option<AudioSpec> {
+    Some(self.spec)
+  }
+  fn play_audio(&mut self, samples: &[f32]) {
+    self.samples.borrow_mut().extend_from_slice(samples);
+  }
+}
+
 pub struct Nes {
   machine: Mos6502<NesBus>,
+  rom_mapper: Rc<RefCell<dyn Mapper>>,
+  rom_hash: u32,
+  battery: bool,
   ppu: Rc<RefCell<Ppu>>,
+  apu: Rc<RefCell<Apu>>,
   host: Box<dyn HostPlatform>,
-  joypad: Rc<RefCell<Joypad>>,
+  audio: Option<AudioOutput>,
+  controllers: Controllers,
+  region: Region,
   timing: FrameTiming,
   pub show_fps: bool,
   shutdown: Shutdown,
@@ -104,24 +175,52 @@ impl Nes {
     host: H,
   ) -> Self {
     let mirroring = cartridge.mirroring();
+    let rom_hash = cartridge.crc32();
+    let battery = cartridge.has_battery();
+    let region = cartridge.region();
+    let expansion_device = cartridge.header().expansion_device;
     let rom_mapper = crate::mappers::for_cart(cartridge);
 
     let frame = host.alloc_render_frame();
-    let ppu = Rc::new(RefCell::new(Ppu::new(rom_mapper.clone(), mirroring, frame)));
-    let joypad = Rc::new(RefCell::new(Joypad::default()));
-    let bus = NesBus::new(rom_mapper.clone(), ppu.clone(), joypad.clone());
-
-    let mut cpu = Cpu::new(bus);
+    let ppu = Rc::new(RefCell::new(Ppu::new(
+      rom_mapper.clone(),
+      mirroring,
+      frame,
+      region,
+    )));
+    let audio_spec = host.audio_spec();
+    let mut apu = Apu::new(rom_mapper.clone(), region);
+    apu.set_sample_rate(audio_spec.map(|spec| spec.sample_rate));
+    let apu = Rc::new(RefCell::new(apu));
+    let controllers = Controllers::new(expansion_device, ppu.clone());
+    let lines = Rc::<InterruptLines>::default();
+    let bus = NesBus::new(
+      rom_mapper.clone(),
+      ppu.clone(),
+      apu.clone(),
+      controllers.ports(),
+      region,
+      lines.clone(),
+    );
+
+    let mut cpu = Cpu::with_variant(bus, Variant::Rp2a03);
+    cpu.lines = lines;
     cpu.reset();
 
     let machine = Mos6502::new(cpu);
 
     Self {
       machine,
+      rom_mapper,
+      rom_hash,
+      battery,
       ppu,
+      apu,
       host: Box::new(host),
-      joypad,
-      timing: FrameTiming::new(),
+      audio: audio_spec.map(AudioOutput::new),
+      controllers,
+      region,
+      timing: FrameTiming::new(region.frame_rate()),
       shutdown: Shutdown::No,
       show_fps: false,
     }
@@ -133,30 +232,30 @@ impl Nes {
 
   pub fn tick(&mut self) {
     let cpu_cycles = self.machine.tick();
+    let cpu = &mut self.machine.cpu;
+    // Cycle-stepped, the bus has already been clocked between every access
+    if cpu.stepping == Stepping::Instruction {
+      cpu.bus.clock(cpu_cycles);
+    }
+    let events = cpu.bus.take_events();
 
-    let mut ppu = self.ppu.borrow_mut();
-    let ppu_event = ppu.tick(cpu_cycles * 3);
-
-    if ppu_event == TickEvent::EnteredVblank {
+    if events.entered_vblank {
+      let mut ppu = self.ppu.borrow_mut();
       if self.show_fps {
         let fps = self.timing.fps_avg(self.host.elapsed_millis());
         fonts::draw(fps.to_string().as_str(), (10, 10), ppu.frame_mut());
       }
 
       self.host.render(ppu.frame());
-      self.shutdown = self.host.poll_events(&mut self.joypad.borrow_mut());
+      if let Some(audio) = &mut self.audio {
+        audio.push(self.apu.borrow_mut().drain_samples());
+        audio.flush(self.host.as_mut());
+      }
+      self.shutdown = self.controllers.poll(self.host.as_mut());
       if let Some(delay) = self.timing.post_render(self.host.elapsed_millis()) {
         self.host.delay(delay);
       }
       self.timing.post_delay(self.host.elapsed_millis());
-
-      if ppu.nmi_on_vblank() {
-        self.machine.cpu.nmi();
-      }
-    }
-
-    if ppu_event == TickEvent::TriggerIrq {
-      self.machine.cpu.irq();
     }
 
     if self.shutdown == Shutdown::Reset {
@@ -165,8 +264,99 @@ impl Nes {
     }
   }
 
+  // Battery-backed PRG RAM should be persisted by the host, see export_prg_ram
+  pub fn has_battery(&self) -> bool {
+    self.battery
+  }
+
+  pub fn export_prg_ram(&self) -> Vec<u8> {
+    self.rom_mapper.borrow().prg_ram().to_vec()
+  }
+
+  pub fn import_prg_ram(&mut self, ram: &[u8]) -> Result<(), CartridgeError> {
+    self.rom_mapper.borrow_mut().import_prg_ram(ram)
+  }
+
+  // Snapshot of the whole machine, only loadable into a Nes running the same ROM.
+  pub fn save_state(&self) -> Vec<u8> {
+    let mut w = StateWriter::new();
+    w.write_u8(self.region as u8);
+
+    let cpu = &self.machine.cpu;
+    w.write_u16(cpu.pc);
+    w.write_u8(cpu.flags.bits());
+    w.write_bytes(&cpu.regs);
+    w.write_usize(cpu.extra_cycles);
+    w.write_usize(self.machine.total_cycles);
+    w.write_u8(cpu.bus.ppu_dot_fifths as u8);
+    let interrupt = cpu.interrupt;
+    [
+      interrupt.nmi_line,
+      interrupt.irq_line,
+      interrupt.nmi_pending,
+      interrupt.polled,
+      interrupt.irq_masked,
+      interrupt.delayed,
+    ]
+    .into_iter()
+    .for_each(|b| w.write_bool(b));
+
+    cpu.bus.save_state(&mut w);
+    self.ppu.borrow().save_state(&mut w);
+    self.apu.borrow().save_state(&mut w);
+    self.controllers.save_state(&mut w);
+    self.rom_mapper.borrow().save_state(&mut w);
+
+    w.finish(self.rom_hash)
+  }
+
+  // A state that's rejected halfway through leaves the machine as it was. Loading
+  // happens in place, so it's rolled back from a snapshot taken first.
+  pub fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+    let snapshot = self.save_state();
+    let result = self.load_state_in_place(state);
+    if result.is_err() {
+      self
+        .load_state_in_place(&snapshot)
+        .expect("own snapshot doesn't load");
+    }
+    result
+  }
+
+  fn load_state_in_place(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+    let mut r = StateReader::open(state, self.rom_hash)?;
+    if r.read_u8()? != self.region as u8 {
+      return Err(SaveStateError::InvalidSaveState("region"));
+    }
+
+    let cpu = &mut self.machine.cpu;
+    cpu.pc = r.read_u16()?;
+    cpu.flags = Flag::from_bits_truncate(r.read_u8()?);
+    r.read_bytes(&mut cpu.regs)?;
+    cpu.extra_cycles = r.read_usize()?;
+    self.machine.total_cycles = r.read_usize()?;
+    cpu.bus.ppu_dot_fifths = r.read_u8()? as usize % 5;
+    cpu.interrupt = InterruptState {
+      nmi_line: r.read_bool()?,
+      irq_line: r.read_bool()?,
+      nmi_pending: r.read_bool()?,
+      polled: r.read_bool()?,
+      irq_masked: r.read_bool()?,
+      delayed: r.read_bool()?,
+    };
+
+    cpu.bus.load_state(&mut r)?;
+    self.ppu.borrow_mut().load_state(&mut r)?;
+    self.apu.borrow_mut().load_state(&mut r)?;
+    self.controllers.load_state(&mut r)?;
+    self.rom_mapper.borrow_mut().load_state(&mut r)?;
+    self.machine.cpu.bus.drive_interrupt_lines();
+
+    r.finish()
+  }
+
   #[cfg(feature = "debugger")]
-  pub fn debugger(&mut self) -> AttachedDebugger<NesBus> {
+  pub fn debugger(&mut self) -> AttachedDebugger<'_, NesBus> {
     self.machine.debugger()
   }
 
@@ -186,6 +376,10 @@ impl Nes {
     &self.machine.cpu.bus
   }
 
+  pub fn region(&self) -> Region {
+    self.region
+  }
+
   pub fn fps_max(&mut self, fps_max: usize) {
     self.timing.fps_max(fps_max);
   }
@@ -194,6 +388,17 @@ impl Nes {
     self.show_fps = show_fps;
   }
 
+  // Runs the CPU one cycle at a time with the PPU and APU clocked between bus accesses,
+  // instead of catching them up after each instruction. Slower, but register accesses,
+  // dummy reads and interrupts land on the right dot.
+  pub fn cycle_stepped(&mut self, cycle_stepped: bool) {
+    self.machine.cpu.stepping = if cycle_stepped {
+      Stepping::Cycle
+    } else {
+      Stepping::Instruction
+    };
+  }
+
   pub fn powered_on(&self) -> bool {
     self.shutdown != Shutdown::Yes
   }
@@ -206,11 +411,11 @@ struct FrameTiming {
 }
 
 impl FrameTiming {
-  pub fn new() -> Self {
+  pub fn new(fps_max: usize) -> Self {
     Self {
       frame_n: 0,
       last_frame_timestamp: 0,
-      frame_limit_ms: 1000 / DEFAULT_FPS_MAX,
+      frame_limit_ms: 1000 / fps_max,
     }
   }
 
@@ -220,11 +425,7 @@ impl FrameTiming {
 
   pub fn fps_avg(&mut self, elapsed: usize) -> usize {
     let secs = elapsed / 1000;
-    if secs != 0 {
-      self.frame_n / secs
-    } else {
-      0
-    }
+    self.frame_n.checked_div(secs).unwrap_or(0)
   }
 
   pub fn post_render(&mut self, elapsed: usize) -> Option<Duration> {
@@ -2,17 +2,40 @@ use alloc::rc::Rc;
 use core::cell::RefCell;
 
 use common::kilobytes;
+use mos6502::cpu::InterruptLines;
 use mos6502::memory::Bus;
 
-use crate::joypad::Joypad;
+use crate::apu::apu::Apu;
+use crate::controller::ControllerPortDevice;
 use crate::mappers::Mapper;
 use crate::ppu::ppu::Ppu;
+use crate::ppu::ppu::TickEvent;
+use crate::region::Region;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
 
 pub struct NesBus {
   ram: [u8; kilobytes::KB2],
   rom: Rc<RefCell<dyn Mapper>>,
   ppu: Rc<RefCell<Ppu>>,
-  joypad: Rc<RefCell<Joypad>>,
+  apu: Rc<RefCell<Apu>>,
+  ports: [Rc<RefCell<dyn ControllerPortDevice>>; 2],
+  region: Region,
+  // PAL's 3.2 PPU dots per CPU cycle, the fifths that didn't make a whole dot yet
+  pub(crate) ppu_dot_fifths: usize,
+  events: ClockEvents,
+  lines: Rc<InterruptLines>,
+}
+
+// Who's holding /IRQ, see InterruptLines::set_irq
+const IRQ_APU: u32 = 1 << 0;
+const IRQ_CARTRIDGE: u32 = 1 << 1;
+
+// What happened while the system was clocked, since last taken
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct ClockEvents {
+  pub entered_vblank: bool,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -21,7 +44,7 @@ enum MappedDevice {
   Ppu,
   Apu,
   PpuOamDma,
-  Joypad,
+  ControllerPort,
   CpuTest,
   Cartridge,
 }
@@ -30,16 +53,66 @@ impl NesBus {
   pub fn new(
     rom: Rc<RefCell<dyn Mapper>>,
     ppu: Rc<RefCell<Ppu>>,
-    joypad: Rc<RefCell<Joypad>>,
+    apu: Rc<RefCell<Apu>>,
+    ports: [Rc<RefCell<dyn ControllerPortDevice>>; 2],
+    region: Region,
+    lines: Rc<InterruptLines>,
   ) -> Self {
     Self {
       rom,
       ram: [0; kilobytes::KB2],
       ppu,
-      joypad,
+      apu,
+      ports,
+      region,
+      ppu_dot_fifths: 0,
+      events: ClockEvents::default(),
+      lines,
     }
   }
 
+  // Catches the mapper, APU and PPU up with the CPU
+  pub(crate) fn clock(&mut self, cpu_cycles: usize) {
+    self.rom.borrow_mut().tick(cpu_cycles);
+    self.apu.borrow_mut().tick(cpu_cycles);
+    self.clock_ppu(cpu_cycles);
+    self.drive_interrupt_lines();
+  }
+
+  // Copies CPU page XX00..XXFF to OAM, a byte every other cycle. The CPU is
+  // halted throughout, everything else keeps running.
+  // https://www.nesdev.org/wiki/DMA#OAM_DMA
+  fn oam_dma(&mut self, page: u8) {
+    let cycles = self.ppu.borrow().oam_dma_cycles();
+    self.clock(cycles - 512);
+    let page_start = (page as u16) << 8;
+    for offset in 0..=0xff {
+      let byte = self.read8(page_start + offset);
+      self.clock(1);
+      self.ppu.borrow_mut().cpu_write_register(byte, 0x04);
+      self.clock(1);
+    }
+  }
+
+  // PAL's 3.2 dots per cycle don't divide, the remainder carries over in fifths
+  fn clock_ppu(&mut self, cpu_cycles: usize) {
+    let fifths = self.ppu_dot_fifths + cpu_cycles * self.region.ppu_fifth_dots_per_cpu_cycle();
+    self.ppu_dot_fifths = fifths % 5;
+    let ppu_event = self.ppu.borrow_mut().tick(fifths / 5);
+    self.events.entered_vblank |= ppu_event == TickEvent::EnteredVblank;
+  }
+
+  // The PPU holds /NMI, the APU and cartridge share /IRQ
+  pub(crate) fn drive_interrupt_lines(&self) {
+    self.lines.set_nmi(self.ppu.borrow().nmi_output());
+    self.lines.set_irq(IRQ_APU, self.apu.borrow().irq());
+    self.lines.set_irq(IRQ_CARTRIDGE, self.rom.borrow().irq());
+  }
+
+  pub(crate) fn take_events(&mut self) -> ClockEvents {
+    core::mem::take(&mut self.events)
+  }
+
   fn map(&self, address: u16) -> (MappedDevice, u16) {
     match address {
       0x0000..=0x07ff => (MappedDevice::Ram, address),
@@ -48,25 +121,38 @@ impl NesBus {
       0x2008..=0x3fff => (MappedDevice::Ppu, address % 8),
       0x4014 => (MappedDevice::PpuOamDma, address),
       0x4000..=0x4015 => (MappedDevice::Apu, address - 0x4000),
-      0x4016..=0x4017 => (MappedDevice::Joypad, address),
+      0x4016..=0x4017 => (MappedDevice::ControllerPort, address),
       0x4018..=0x401f => (MappedDevice::CpuTest, address - 0x4018),
       0x4020..=0xffff => (MappedDevice::Cartridge, address),
     }
   }
+
+  pub(crate) fn save_state(&self, w: &mut StateWriter) {
+    w.write_bytes(&self.ram);
+  }
+
+  pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    r.read_bytes(&mut self.ram)
+  }
 }
 
 impl Bus for NesBus {
+  // Cycle-stepped, the rest of the system runs in between CPU bus accesses
+  fn on_cycle(&mut self) {
+    self.clock(1);
+  }
+
   fn read8(&self, address: u16) -> u8 {
     let (device, mapped_address) = self.map(address);
     match device {
       MappedDevice::Ram => self.ram[mapped_address as usize],
       MappedDevice::Ppu => self.ppu.borrow_mut().cpu_read_register(mapped_address),
-      MappedDevice::Apu => 0,
+      MappedDevice::Apu => self.apu.borrow_mut().cpu_read_register(mapped_address),
       MappedDevice::PpuOamDma => 0,
-      MappedDevice::Joypad => {
+      MappedDevice::ControllerPort => {
         match address {
-          0x4016 => self.joypad.borrow_mut().read(), // Joystick 1 data
-          0x4017 => 0,                               // Joystick 2 data
+          0x4016 => self.ports[0].borrow_mut().read(), // Port 1 data
+          0x4017 => self.ports[1].borrow_mut().read(), // Port 2 data
           _ => unreachable!(),
         }
       }
@@ -84,18 +170,18 @@ impl Bus for NesBus {
         .ppu
         .borrow_mut()
         .cpu_write_register(val, mapped_address),
-      MappedDevice::Apu => (),
-      MappedDevice::PpuOamDma => {
-        // Dump CPU page XX00..XXFF to PPU OAM
-        let page_start = (val as u16) << 8;
-        let mem = (page_start..=page_start + 0xff).map(|addr| self.read8(addr));
-        // println!("{:#04x} - dumping {:#06x}..{:#06x}", val, page_start, page_start+0xff);
-        self.ppu.borrow_mut().cpu_oam_dma(mem);
-      }
-      MappedDevice::Joypad => {
+      MappedDevice::Apu => self
+        .apu
+        .borrow_mut()
+        .cpu_write_register(val, mapped_address),
+      MappedDevice::PpuOamDma => self.oam_dma(val),
+      MappedDevice::ControllerPort => {
         match address {
-          0x4016 => self.joypad.borrow_mut().strobe(val), // Joystick strobe
-          0x4017 => (),                                   // APU Frame counter control
+          0x4016 => {
+            // Strobe, both ports share it
+            self.ports.iter().for_each(|p| p.borrow_mut().strobe(val));
+          }
+          0x4017 => self.apu.borrow_mut().cpu_write_register(val, 0x17), // APU Frame counter control
           _ => unreachable!(),
         }
       }
@@ -107,18 +193,25 @@ impl Bus for NesBus {
 
 #[cfg(test)]
 mod tests {
+  use alloc::vec::Vec;
+
   use super::*;
   use crate::cartridge::Mirroring;
   use crate::frame::PixelFormatRGB888;
   use crate::frame::RenderFrame;
+  use crate::joypad::Joypad;
+  use crate::joypad::JoypadButton;
+  use crate::joypad::JoypadEvent;
+  use crate::region::Region;
 
   struct TestBus {}
 
   impl Mapper for TestBus {}
 
   impl Bus for TestBus {
+    // Blank CHR, for the PPU to fetch while it runs
     fn read8(&self, _: u16) -> u8 {
-      todo!()
+      0
     }
 
     fn write8(&mut self, _: u8, _: u16) {
@@ -127,13 +220,28 @@ mod tests {
   }
 
   fn sut() -> NesBus {
+    sut_with(Region::Ntsc, Rc::default(), Rc::default())
+  }
+
+  fn sut_with(
+    region: Region,
+    joypad: Rc<RefCell<Joypad>>,
+    joypad_2: Rc<RefCell<Joypad>>,
+  ) -> NesBus {
     let bus = Rc::new(RefCell::new(TestBus {}));
-    let joypad = Joypad::default();
     let frame = RenderFrame::new::<PixelFormatRGB888>();
     NesBus::new(
       bus.clone(),
-      Rc::new(RefCell::new(Ppu::new(bus, Mirroring::Horizontal, frame))),
-      Rc::new(RefCell::new(joypad)),
+      Rc::new(RefCell::new(Ppu::new(
+        bus.clone(),
+        Mirroring::Horizontal,
+        frame,
+        region,
+      ))),
+      Rc::new(RefCell::new(Apu::new(bus, region))),
+      [joypad, joypad_2],
+      region,
+      Rc::default(),
     )
   }
 
@@ -170,4 +278,55 @@ mod tests {
       assert_eq!(bus.map(a), (MappedDevice::Ppu, 0));
     }
   }
+
+  #[test]
+  fn second_joypad_on_4017() {
+    let joypad: Rc<RefCell<Joypad>> = Rc::default();
+    let joypad_2: Rc<RefCell<Joypad>> = Rc::default();
+    let mut bus = sut_with(Region::Ntsc, joypad.clone(), joypad_2.clone());
+    joypad
+      .borrow_mut()
+      .on_event(JoypadEvent::Press(JoypadButton::A));
+    joypad_2
+      .borrow_mut()
+      .on_event(JoypadEvent::Press(JoypadButton::B));
+
+    bus.write8(1, 0x4016);
+    bus.write8(0, 0x4016);
+    let read = |bus: &NesBus, port| (0..2).map(|_| bus.read8(port)).collect::<Vec<_>>();
+    assert_eq!(read(&bus, 0x4016), [1, 0]);
+    assert_eq!(read(&bus, 0x4017), [0, 1]);
+  }
+
+  #[test]
+  fn oam_dma_keeps_pal_dot_fraction() {
+    let mut bus = sut_with(Region::Pal, Rc::default(), Rc::default());
+    bus.clock(1);
+    assert_eq!(bus.ppu_dot_fifths, 1);
+    // 514 cycles on the first frame, 1644.8 dots
+    bus.write8(0x02, 0x4014);
+    assert_eq!(bus.ppu_dot_fifths, (1 + 514 * 16) % 5);
+    bus.clock(1);
+    assert_eq!(bus.ppu_dot_fifths, 1);
+  }
+
+  #[test]
+  fn oam_dma_halts_for_a_page_copy() {
+    let mut bus = sut();
+    for i in 0..=0xff {
+      bus.write8(i as u8, 0x0200 + i);
+    }
+    let position = |bus: &NesBus| {
+      let ppu = bus.ppu.borrow();
+      ppu.scanline() * 341 + ppu.cycle()
+    };
+    bus.clock(1);
+    let before = position(&bus);
+
+    bus.write8(0x02, 0x4014);
+    // 514 cycles on the first frame, at 3 dots each
+    assert_eq!(position(&bus) - before, 514 * 3);
+    bus.write8(0x10, 0x2003);
+    assert_eq!(bus.read8(0x2004), 0x10);
+  }
 }
@@ -2,4 +2,5 @@ mod palette;
 mod state;
 mod vram;
 
+#[allow(clippy::module_inception)]
 pub(crate) mod ppu;
@@ -1,3 +1,8 @@
+use crate::region::Region;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
 const PALETTE_SIZE: usize = 32;
 
 // AKA boot palette?
@@ -74,14 +79,88 @@ static PALETTE_RGB: [(u8, u8, u8); 64] = [
   (0, 0, 0),
 ];
 
+// The 2C07 decodes hues half a step (15°) off the 2C02's, this is the table
+// above rotated in YUV with luma kept. Dendies put out PAL video too.
+static PALETTE_RGB_PAL: [(u8, u8, u8); 64] = [
+  (101, 101, 101),
+  (11, 36, 120),
+  (33, 23, 133),
+  (80, 16, 111),
+  (102, 13, 73),
+  (114, 17, 23),
+  (105, 25, 0),
+  (82, 34, 0),
+  (46, 44, 0),
+  (7, 52, 12),
+  (0, 59, 17),
+  (0, 58, 34),
+  (5, 50, 82),
+  (0, 0, 0),
+  (0, 0, 0),
+  (0, 0, 0),
+  (174, 174, 174),
+  (31, 85, 207),
+  (82, 70, 216),
+  (135, 60, 190),
+  (175, 56, 136),
+  (191, 61, 69),
+  (179, 73, 7),
+  (143, 87, 0),
+  (96, 100, 0),
+  (43, 112, 15),
+  (0, 117, 37),
+  (0, 114, 98),
+  (9, 102, 161),
+  (0, 0, 0),
+  (0, 0, 0),
+  (0, 0, 0),
+  (254, 254, 255),
+  (109, 165, 255),
+  (157, 153, 255),
+  (211, 141, 240),
+  (255, 135, 217),
+  (255, 138, 155),
+  (246, 151, 90),
+  (223, 168, 45),
+  (171, 183, 36),
+  (118, 193, 61),
+  (77, 196, 116),
+  (61, 192, 183),
+  (72, 181, 247),
+  (78, 78, 78),
+  (0, 0, 0),
+  (0, 0, 0),
+  (254, 254, 255),
+  (195, 217, 255),
+  (215, 213, 255),
+  (237, 208, 249),
+  (254, 206, 240),
+  (255, 207, 214),
+  (251, 212, 187),
+  (241, 219, 168),
+  (221, 224, 164),
+  (198, 229, 175),
+  (182, 231, 198),
+  (175, 228, 226),
+  (179, 224, 251),
+  (182, 182, 182),
+  (0, 0, 0),
+  (0, 0, 0),
+];
+
 pub struct Palette {
   data: [u8; PALETTE_SIZE],
+  rgb: &'static [(u8, u8, u8); 64],
 }
 
 impl Palette {
-  pub fn new() -> Self {
+  pub fn new(region: Region) -> Self {
     Self {
       data: BLARRG_PALETTE,
+      rgb: match region {
+        Region::Ntsc => &PALETTE_RGB,
+        Region::Pal | Region::Dendy => &PALETTE_RGB_PAL,
+      },
     }
   }
 
@@ -96,7 +175,7 @@ impl Palette {
   }
 
   pub fn rgb_from_index(&self, index: u8) -> (u8, u8, u8) {
-    PALETTE_RGB[self.data[index as usize] as usize % 64]
+    self.rgb[self.data[index as usize] as usize % 64]
   }
 
   // 0x3f00..=0x3fff
@@ -117,11 +196,21 @@ impl Palette {
       _ => mirrored,
     }
   }
+
+  pub fn save_state(&self, w: &mut StateWriter) {
+    w.write_bytes(&self.data);
+  }
+
+  pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    r.read_bytes(&mut self.data)?;
+    Ok(())
+  }
 }
 
 #[cfg(test)]
 mod tests {
   use crate::ppu::palette::Palette;
+  use crate::region::Region;
 
   #[test]
   fn palette_mirror() {
@@ -137,4 +226,22 @@ mod tests {
     assert_eq!(Palette::mirror(0x3f18), 0x3f08);
     assert_eq!(Palette::mirror(0x3f1c), 0x3f0c);
   }
+
+  #[test]
+  fn region_table() {
+    let mut ntsc = Palette::new(Region::Ntsc);
+    let mut pal = Palette::new(Region::Pal);
+    for palette in [&mut ntsc, &mut pal] {
+      palette.write(0x30, 0x3f00);
+      palette.write(0x16, 0x3f01);
+    }
+    // Grays have no hue to shift
+    assert_eq!(ntsc.rgb_from_index(0), pal.rgb_from_index(0));
+    assert_eq!(ntsc.rgb_from_index(1), (189, 60, 48));
+    assert_ne!(pal.rgb_from_index(1), ntsc.rgb_from_index(1));
+    assert_eq!(
+      Palette::new(Region::Dendy).rgb_from_index(1),
+      Palette::new(Region::Pal).rgb_from_index(1)
+    );
+  }
 }
@@ -8,8 +8,17 @@ use super::vram::Vram;
 use crate::cartridge::Mirroring;
 use crate::frame::RenderFrame;
 use crate::mappers::Mapper;
+use crate::mappers::PpuFetch;
 use crate::ppu::state::Phase;
 use crate::ppu::state::Rendering;
+use crate::region::Region;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+// How long A12 has to stay low before a mapper counts it rising again.
+// MMC3 wants about three M2 (CPU) cycles, the 4 dot gaps between fetches don't count.
+const A12_FILTER_DOTS: usize = 10;
 
 #[derive(Default, Clone, Copy, Debug)]
 struct Sprite {
@@ -19,6 +28,16 @@ struct Sprite {
   zero: bool,
 }
 
+// Background tile the PPU last fetched, pixels in the same tile reuse it
+#[derive(Clone, Copy)]
+struct BackgroundTile {
+  nametable: u16,
+  offset: u16,
+  pattern_address: u16,
+  planes: (u8, u8),
+  color_bits: u8,
+}
+
 #[derive(Debug)]
 #[repr(u16)]
 #[allow(dead_code)]
@@ -44,7 +63,6 @@ impl From<u16> for Register {
 pub enum TickEvent {
   Nothing,
   EnteredVblank,
-  TriggerIrq,
 }
 
 #[allow(dead_code)]
@@ -58,6 +76,9 @@ pub struct Ppu {
   oam: [u8; 256],
   oam_address: u8,
   sprites: Vec<Sprite>, // AKA secondary OAM
+  // Pattern table each slot is fetched from during dots 257-320, empty slots included
+  sprite_fetch_tables: [u16; 8],
+  background_tile: Option<BackgroundTile>,
 
   v: u16,     // Current VRAM address (15 bits)
   t: u16, // Temporary VRAM address (15 bits); can also be thought of as the address of the top left onscreen tile.
@@ -81,6 +102,10 @@ pub struct Ppu {
   show_sprites: bool,
   show_sprites_left: bool,
   rendering_enabled: bool,
+
+  // Address line A12, as seen by the mapper
+  a12: bool,
+  a12_low_dots: usize,
 }
 
 #[allow(dead_code)]
@@ -89,17 +114,20 @@ impl Ppu {
     mapper: Rc<RefCell<dyn Mapper>>,
     cart_mirroring: Mirroring,
     frame: RenderFrame,
+    region: Region,
   ) -> Ppu {
     Ppu {
       vram: Vram::new(mapper.clone(), cart_mirroring),
       rom_mapper: mapper,
-      palette: Palette::new(),
+      palette: Palette::new(region),
       frame,
-      state: State::default(),
+      state: State::new(region),
 
       oam: [0; 256],
       oam_address: 0,
       sprites: Vec::with_capacity(8),
+      sprite_fetch_tables: [0; 8],
+      background_tile: None,
 
       v: 0,
       t: 0,
@@ -123,6 +151,9 @@ impl Ppu {
       show_sprites: false,
       show_sprites_left: false,
       rendering_enabled: false,
+
+      a12: false,
+      a12_low_dots: 0,
     }
   }
 
@@ -149,8 +180,9 @@ impl Ppu {
       Register::OamData2004 => self.oam[self.oam_address as usize],
       Register::Data2007 => {
         let address = self.v & 0x3fff; // 14 bits wide
+        self.set_a12(address & 0x1000 != 0);
         let value = match address {
-          0x0000..=0x1fff => self.rom_mapper.borrow().read8(address), // CHR
+          0x0000..=0x1fff => self.read_chr_rom(address, PpuFetch::Cpu), // CHR
           0x2000..=0x2fff => self.vram.read(address),
           0x3000..=0x3eff => self.vram.read(address - 0x1000),
           0x3f00..=0x3fff => self.palette.read(address),
@@ -170,6 +202,11 @@ impl Ppu {
   }
 
   pub fn cpu_write_register(&mut self, val: u8, address: u16) {
+    self
+      .rom_mapper
+      .borrow_mut()
+      .ppu_register_write(val, address);
+
     match Register::from(address) {
       Register::Ctrl2000 => {
         self.vram_addr_inc = if val & 0x04 == 0x04 { 32 } else { 1 };
@@ -219,12 +256,14 @@ impl Ppu {
           self.t &= 0xff00;
           self.t |= val as u16;
           self.v = self.t;
+          self.set_a12(self.v & 0x1000 != 0);
         }
 
         self.w_latch = !self.w_latch;
       }
       Register::Data2007 => {
         let address = self.v & 0x3fff; // It's only 14 bits wide
+        self.set_a12(address & 0x1000 != 0);
         match address {
           0x0000..=0x1fff => self.rom_mapper.borrow_mut().write8(val, address), // CHR RAM
           0x2000..=0x2fff => self.vram.write(val, address),
@@ -240,10 +279,32 @@ impl Ppu {
 
   pub fn tick(&mut self, ppu_cycles_to_tick: usize) -> TickEvent {
     let vblank_pre_ticks = self.in_vblank;
-    let mut irq = false;
 
     for _ in 0..ppu_cycles_to_tick {
-      match self.state.next(self.rendering_enabled) {
+      let dot = self.state.next(self.rendering_enabled);
+      if dot.1 == 0 {
+        self
+          .rom_mapper
+          .borrow_mut()
+          .ppu_scanline(self.state.scanline(), self.rendering_enabled);
+      }
+
+      if dot.0 == Phase::Render && dot.1 == 257 {
+        // Load sprites for next line, at the start of the sprite tile loading interval
+        // https://www.nesdev.org/wiki/PPU_rendering#Cycles_257-320
+        self.load_sprites_for_next_scanline();
+      }
+
+      if let (Phase::Render | Phase::PreRender, cycle, Rendering::Enabled) = dot {
+        if let Some(high) = self.fetch_a12(cycle) {
+          self.set_a12(high);
+        }
+      }
+      if !self.a12 {
+        self.a12_low_dots = self.a12_low_dots.saturating_add(1);
+      }
+
+      match dot {
         (Phase::PreRender, 1, _) => {
           self.in_vblank = false;
           self.sprite_0_hit = false;
@@ -258,6 +319,10 @@ impl Ppu {
           // Visible pixels
           let x = self.state.cycle();
           let y = self.state.scanline();
+          if x == 0 {
+            // Banks may have changed since the last scanline
+            self.background_tile = None;
+          }
           let mut bg_pixel_drawn = false;
           let show_bg = self.show_background && (self.show_background_left || x >= 8);
           if show_bg {
@@ -269,29 +334,45 @@ impl Ppu {
             self.render_sprite_pixel(x, y, bg_pixel_drawn);
           }
         }
-        (Phase::Render, 320, _) => {
-          // Load sprites for next line (sprite tile loading interval)
-          // https://www.nesdev.org/wiki/PPU_rendering#Cycles_257-320
-          // 320 is the end of sprite (secondary OAM) loading interval.
-          self.load_sprites_for_next_scanline();
-        }
         (Phase::EnteringVblank, 1, _) => self.in_vblank = true,
-        (Phase::Render | Phase::PostRender, 260, Rendering::Enabled) => {
-          irq = self.rom_mapper.borrow_mut().irq()
-        }
         _ => (),
       }
     }
 
     if !vblank_pre_ticks && self.in_vblank {
       TickEvent::EnteredVblank
-    } else if irq {
-      TickEvent::TriggerIrq
     } else {
       TickEvent::Nothing
     }
   }
 
+  // A12 of what the PPU puts on the bus at `cycle` of a rendered line. Pixels are
+  // drawn in one go, but mappers watching A12 (MMC3) need the real fetch pattern.
+  // https://www.nesdev.org/wiki/PPU_rendering#Line-by-line_timing
+  fn fetch_a12(&self, cycle: usize) -> Option<bool> {
+    match cycle {
+      // Nametable and attribute bytes ($2xxx), then the two pattern bytes
+      1..=256 | 321..=336 => Some((cycle - 1) % 8 >= 4 && self.background_table_address == 0x1000),
+      257..=320 => {
+        let slot = (cycle - 257) / 8;
+        Some((cycle - 257) % 8 >= 4 && self.sprite_fetch_tables[slot] == 0x1000)
+      }
+      337..=340 => Some(false),
+      _ => None,
+    }
+  }
+
+  // https://www.nesdev.org/wiki/MMC3#IRQ_Specifics
+  fn set_a12(&mut self, high: bool) {
+    if high && !self.a12 && self.a12_low_dots >= A12_FILTER_DOTS {
+      self.rom_mapper.borrow_mut().ppu_a12_rise();
+    }
+    if high {
+      self.a12_low_dots = 0;
+    }
+    self.a12 = high;
+  }
+
   fn render_background_pixel(&mut self, x: usize, y: usize) -> bool {
     let v = self.v;
     let fine_x = self.fine_x as u16;
@@ -321,23 +402,25 @@ impl Ppu {
       .vram
       .read_indexed(virtual_nametable_index, nametable_offset);
 
-    let vertical_attr = vertical_tile / 4;
-    let horizontal_attr = horizontal_tile / 4;
-
-    let attr_offset = 0x3c0 + vertical_attr * 8 + horizontal_attr;
-    let attr = self.vram.read_indexed(virtual_nametable_index, attr_offset);
-
-    let horizontal_box_pos = (horizontal_tile % 4) / 2;
-    let vertical_box_pos = (vertical_tile % 4) / 2;
-
-    let color_bits = (attr >> ((horizontal_box_pos * 2) + (vertical_box_pos * 4))) & 0x3;
-
-    let first_plane_byte = self.read_chr_rom(
-      self.background_table_address + (nametable_entry as u16 * 0x10 + virtual_y % 8),
-    );
-    let second_plane_byte = self.read_chr_rom(
-      self.background_table_address + (nametable_entry as u16 * 0x10 + (virtual_y % 8) + 8),
-    );
+    let pattern_address =
+      self.background_table_address + (nametable_entry as u16 * 0x10 + virtual_y % 8);
+    let tile = match self.background_tile {
+      Some(tile)
+        if tile.nametable == virtual_nametable_index
+          && tile.offset == nametable_offset
+          && tile.pattern_address == pattern_address =>
+      {
+        tile
+      }
+      _ => {
+        let tile =
+          self.fetch_background_tile(virtual_nametable_index, nametable_offset, pattern_address);
+        self.background_tile = Some(tile);
+        tile
+      }
+    };
+    let (first_plane_byte, second_plane_byte) = tile.planes;
+    let color_bits = tile.color_bits;
 
     let first_plane_bit = first_plane_byte >> (7 - virtual_x % 8) & 0x1;
     let second_plane_bit = second_plane_byte >> (7 - virtual_x % 8) & 0x1;
@@ -355,6 +438,45 @@ impl Ppu {
     }
   }
 
+  // Once per tile, so mappers watching the fetches (MMC2 latches, MMC5 extended
+  // attributes) aren't asked for every pixel
+  fn fetch_background_tile(
+    &self,
+    nametable: u16,
+    offset: u16,
+    pattern_address: u16,
+  ) -> BackgroundTile {
+    let vertical_tile = offset / 32;
+    let horizontal_tile = offset % 32;
+
+    let vertical_attr = vertical_tile / 4;
+    let horizontal_attr = horizontal_tile / 4;
+
+    let attr_offset = 0x3c0 + vertical_attr * 8 + horizontal_attr;
+    let attr = self.vram.read_indexed(nametable, attr_offset);
+
+    let horizontal_box_pos = (horizontal_tile % 4) / 2;
+    let vertical_box_pos = (vertical_tile % 4) / 2;
+
+    let color_bits = match self.rom_mapper.borrow_mut().ppu_background_tile(offset) {
+      Some(palette) => palette & 0x3,
+      None => (attr >> ((horizontal_box_pos * 2) + (vertical_box_pos * 4))) & 0x3,
+    };
+
+    let planes = (
+      self.read_chr_rom(pattern_address, PpuFetch::Background),
+      self.read_chr_rom(pattern_address + 8, PpuFetch::Background),
+    );
+
+    BackgroundTile {
+      nametable,
+      offset,
+      pattern_address,
+      planes,
+      color_bits,
+    }
+  }
+
   fn render_sprite_pixel(&mut self, x: usize, y: usize, bg_pixel_drawn: bool) {
     let x = x as u8;
 
@@ -389,6 +511,13 @@ impl Ppu {
     // Clear current sprites
     self.sprites.clear();
 
+    // Unused slots fetch tile $FF, which is in $1000 for 8x16 sprites
+    self.sprite_fetch_tables = if self.sprite_size_16 {
+      [0x1000; 8]
+    } else {
+      [self.sprite_table_address_8; 8]
+    };
+
     let sprite_height = if self.sprite_size_16 { 16 } else { 8 };
     let next_line = self.state.scanline() as u8 + 1;
     let mut sprite_n = 0;
@@ -418,6 +547,7 @@ impl Ppu {
           (self.sprite_table_address_8, self.oam[sprite_addr + 1])
         };
 
+        self.sprite_fetch_tables[sprite_n] = sprite_table;
         let attr = self.oam[sprite_addr + 2];
         let x = self.oam[sprite_addr + 3];
 
@@ -435,8 +565,8 @@ impl Ppu {
         };
 
         let address = sprite_table + index;
-        let first_plane = self.read_chr_rom(address);
-        let second_plane = self.read_chr_rom(address + 8);
+        let first_plane = self.read_chr_rom(address, PpuFetch::Sprite);
+        let second_plane = self.read_chr_rom(address + 8, PpuFetch::Sprite);
 
         // Read pixels for sprite row
         let mut pixels = [0u8; 8];
@@ -460,23 +590,16 @@ impl Ppu {
     }
   }
 
-  fn read_chr_rom(&self, address: u16) -> u8 {
-    self.rom_mapper.borrow().read8(address)
+  fn read_chr_rom(&self, address: u16, fetch: PpuFetch) -> u8 {
+    self.rom_mapper.borrow_mut().ppu_read(address, fetch)
   }
 
-  pub fn cpu_oam_dma(&mut self, mem: impl Iterator<Item = u8>) {
-    // assert!(mem.len() == 256);
-    for byte in mem {
-      self.oam[self.oam_address as usize] = byte;
-      self.oam_address = self.oam_address.wrapping_add(1);
-    }
-    // assert!(self.oam_address == 0x0000);
-    self.oam_address = 0;
-
+  // 256 writes to $2004, plus a halt cycle and sometimes one to align
+  pub fn oam_dma_cycles(&self) -> usize {
     if self.state.even_frame() {
-      self.tick(513 * 3);
+      513
     } else {
-      self.tick(514 * 3);
+      514
     }
   }
 
@@ -544,4 +667,161 @@ impl Ppu {
   pub fn nmi_on_vblank(&self) -> bool {
     self.nmi_at_start_of_vblank
   }
+
+  // The CPU's /NMI, low while in vblank with NMIs enabled. Enabling them in vblank
+  // (or a $2002 read racing the flag) makes an edge of its own.
+  // https://www.nesdev.org/wiki/NMI
+  pub fn nmi_output(&self) -> bool {
+    self.in_vblank && self.nmi_at_start_of_vblank
+  }
+
+  // The frame isn't saved, it's redrawn from the restored state.
+  pub fn save_state(&self, w: &mut StateWriter) {
+    self.vram.save_state(w);
+    self.palette.save_state(w);
+    self.state.save_state(w);
+
+    w.write_bytes(&self.oam);
+    w.write_u8(self.oam_address);
+    w.write_u8(self.sprites.len() as u8);
+    for sprite in &self.sprites {
+      w.write_bytes(&sprite.pixels);
+      w.write_bool(sprite.priority);
+      w.write_u8(sprite.x);
+      w.write_bool(sprite.zero);
+    }
+    for table in self.sprite_fetch_tables {
+      w.write_u16(table);
+    }
+
+    w.write_u16(self.v);
+    w.write_u16(self.t);
+    w.write_u8(self.fine_x);
+    w.write_bool(self.w_latch);
+
+    w.write_bool(self.in_vblank);
+    w.write_bool(self.sprite_0_hit);
+    w.write_bool(self.sprite_overflow);
+
+    w.write_u8(self.data_buffer);
+
+    w.write_u8(self.vram_addr_inc);
+    w.write_u16(self.sprite_table_address_8);
+    w.write_bool(self.sprite_size_16);
+    w.write_u16(self.background_table_address);
+    w.write_bool(self.nmi_at_start_of_vblank);
+
+    w.write_bool(self.show_background);
+    w.write_bool(self.show_background_left);
+    w.write_bool(self.show_sprites);
+    w.write_bool(self.show_sprites_left);
+    w.write_bool(self.rendering_enabled);
+
+    w.write_bool(self.a12);
+    w.write_usize(self.a12_low_dots);
+  }
+
+  pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.vram.load_state(r)?;
+    self.palette.load_state(r)?;
+    self.state.load_state(r)?;
+
+    r.read_bytes(&mut self.oam)?;
+    self.oam_address = r.read_u8()?;
+    let num_sprites = r.read_u8()?;
+    if num_sprites > 8 {
+      return Err(SaveStateError::InvalidSaveState("sprites"));
+    }
+    self.sprites.clear();
+    for _ in 0..num_sprites {
+      let mut sprite = Sprite::default();
+      r.read_bytes(&mut sprite.pixels)?;
+      sprite.priority = r.read_bool()?;
+      sprite.x = r.read_u8()?;
+      sprite.zero = r.read_bool()?;
+      self.sprites.push(sprite);
+    }
+    for table in self.sprite_fetch_tables.iter_mut() {
+      *table = r.read_u16()? & 0x1000;
+    }
+
+    self.v = r.read_u16()?;
+    self.t = r.read_u16()?;
+    self.fine_x = r.read_u8()? & 0x7;
+    self.w_latch = r.read_bool()?;
+
+    self.in_vblank = r.read_bool()?;
+    self.sprite_0_hit = r.read_bool()?;
+    self.sprite_overflow = r.read_bool()?;
+
+    self.data_buffer = r.read_u8()?;
+
+    self.vram_addr_inc = r.read_u8()?;
+    self.sprite_table_address_8 = r.read_u16()?;
+    self.sprite_size_16 = r.read_bool()?;
+    self.background_table_address = r.read_u16()?;
+    self.nmi_at_start_of_vblank = r.read_bool()?;
+
+    self.show_background = r.read_bool()?;
+    self.show_background_left = r.read_bool()?;
+    self.show_sprites = r.read_bool()?;
+    self.show_sprites_left = r.read_bool()?;
+    self.rendering_enabled = r.read_bool()?;
+
+    self.a12 = r.read_bool()?;
+    self.a12_low_dots = r.read_usize()?;
+    // Fetched again from the loaded banks
+    self.background_tile = None;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use mos6502::memory::Bus;
+
+  use super::*;
+  use crate::frame::PixelFormatRGB888;
+
+  // Counts the background tiles the PPU asked about
+  #[derive(Default)]
+  struct TestMapper {
+    background_tiles: usize,
+    background_fetches: usize,
+  }
+
+  impl Mapper for TestMapper {
+    fn ppu_read(&mut self, _: u16, fetch: PpuFetch) -> u8 {
+      if let PpuFetch::Background = fetch {
+        self.background_fetches += 1;
+      }
+      0xff
+    }
+
+    fn ppu_background_tile(&mut self, _: u16) -> Option<u8> {
+      self.background_tiles += 1;
+      None
+    }
+  }
+
+  impl Bus for TestMapper {
+    fn read8(&self, _: u16) -> u8 {
+      0
+    }
+
+    fn write8(&mut self, _: u8, _: u16) {}
+  }
+
+  #[test]
+  fn background_fetched_once_per_tile() {
+    let mapper = Rc::new(RefCell::new(TestMapper::default()));
+    let frame = RenderFrame::new::<PixelFormatRGB888>();
+    let mut ppu = Ppu::new(mapper.clone(), Mirroring::Horizontal, frame, Region::Ntsc);
+    ppu.cpu_write_register(0x0a, 0x01); // Background, left column included
+
+    ppu.tick(341 * 262);
+    // 32 tiles on each of 240 lines, two pattern bytes each
+    assert_eq!(mapper.borrow().background_tiles, 32 * 240);
+    assert_eq!(mapper.borrow().background_fetches, 32 * 240 * 2);
+  }
 }
@@ -1,3 +1,8 @@
+use crate::region::Region;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
 #[derive(Default, PartialEq, Eq, Copy, Clone)]
 pub(crate) enum Phase {
   PreRender,
@@ -20,25 +25,36 @@ pub(crate) struct State {
   scanline: usize,
   clock: usize,
   odd_frame: bool,
+  region: Region,
 }
 
 impl State {
+  pub fn new(region: Region) -> Self {
+    Self {
+      region,
+      ..Default::default()
+    }
+  }
+
   pub fn next(&mut self, rendering_enabled: bool) -> (Phase, usize, Rendering) {
     self.cycle = self.clock % 341;
     self.scanline = self.clock / 341;
     self.clock += 1;
 
+    let pre_render = self.region.scanlines() - 1;
+    let vblank = self.region.vblank_scanline();
     self.phase = match self.scanline {
-      261 => Phase::PreRender,
       0..=239 => Phase::Render,
-      240 => Phase::PostRender,
-      241 => Phase::EnteringVblank,
-      242..=260 => Phase::Vblank,
+      s if s == pre_render => Phase::PreRender,
+      s if s < vblank => Phase::PostRender,
+      s if s == vblank => Phase::EnteringVblank,
+      s if s < pre_render => Phase::Vblank,
       _ => unreachable!(),
     };
 
     if self.phase == Phase::PreRender {
-      if self.cycle == 339 && self.odd_frame && rendering_enabled {
+      let skip = self.region.skips_odd_frame_dot() && self.odd_frame && rendering_enabled;
+      if self.cycle == 339 && skip {
         self.clock = 0;
       }
       if self.cycle == 340 {
@@ -75,4 +91,59 @@ impl State {
   pub fn clock(&self) -> usize {
     self.clock
   }
+
+  pub fn save_state(&self, w: &mut StateWriter) {
+    w.write_u8(self.phase as u8);
+    w.write_usize(self.cycle);
+    w.write_usize(self.scanline);
+    w.write_usize(self.clock);
+    w.write_bool(self.odd_frame);
+  }
+
+  pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    self.phase = match r.read_u8()? {
+      0 => Phase::PreRender,
+      1 => Phase::Render,
+      2 => Phase::PostRender,
+      3 => Phase::EnteringVblank,
+      4 => Phase::Vblank,
+      _ => return Err(SaveStateError::InvalidSaveState("ppu phase")),
+    };
+    self.cycle = r.read_usize()?;
+    self.scanline = r.read_usize()?;
+    self.clock = r.read_usize()?;
+    if self.clock >= self.region.scanlines() * 341 {
+      return Err(SaveStateError::InvalidSaveState("ppu clock"));
+    }
+    self.odd_frame = r.read_bool()?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Dots until the frame wraps, and the scanline vblank starts on
+  fn frame(region: Region) -> (usize, usize) {
+    let mut state = State::new(region);
+    let mut vblank = 0;
+    for dots in 1.. {
+      let (phase, cycle, _) = state.next(false);
+      if phase == Phase::EnteringVblank && cycle == 1 {
+        vblank = state.scanline();
+      }
+      if state.clock() == 0 {
+        return (dots, vblank);
+      }
+    }
+    unreachable!()
+  }
+
+  #[test]
+  fn frame_layout() {
+    assert_eq!(frame(Region::Ntsc), (262 * 341, 241));
+    assert_eq!(frame(Region::Pal), (312 * 341, 241));
+    assert_eq!(frame(Region::Dendy), (312 * 341, 291));
+  }
 }
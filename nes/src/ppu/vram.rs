@@ -5,11 +5,16 @@ use core::cell::RefCell;
 use common::kilobytes;
 
 use crate::cartridge::Mirroring;
+use crate::cartridge::Nametable;
 use crate::mappers::Mapper;
+use crate::savestate::error::SaveStateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
 
 pub(crate) struct Vram {
   nametables: [[u8; kilobytes::KB1]; 2], // AKA CIRAM
-  mirror_map: Rc<RefCell<[u8; 4]>>,
+  mirror_map: Rc<RefCell<[Nametable; 4]>>,
+  mapper: Rc<RefCell<dyn Mapper>>,
 }
 
 impl Vram {
@@ -27,18 +32,23 @@ impl Vram {
     Self {
       nametables: [[0; kilobytes::KB1]; 2],
       mirror_map,
+      mapper,
     }
   }
 
-  pub fn setup_mirror_map(new_mirroring: &Mirroring) -> [u8; 4] {
+  pub fn setup_mirror_map(new_mirroring: &Mirroring) -> [Nametable; 4] {
+    use Nametable::CartridgeRam;
+    use Nametable::Ciram;
+
     // https://www.nesdev.org/wiki/PPU_nametables
     // https://www.nesdev.org/wiki/Mirroring#Nametable_Mirroring
     match new_mirroring {
-      Mirroring::Vertical => [0, 1, 0, 1],
-      Mirroring::Horizontal => [0, 0, 1, 1],
-      Mirroring::SingleScreenLower => [0, 0, 0, 0],
-      Mirroring::SingleScreenUpper => [1, 1, 1, 1],
-      _ => panic!(),
+      Mirroring::Vertical => [Ciram(0), Ciram(1), Ciram(0), Ciram(1)],
+      Mirroring::Horizontal => [Ciram(0), Ciram(0), Ciram(1), Ciram(1)],
+      Mirroring::SingleScreenLower => [Ciram(0); 4],
+      Mirroring::SingleScreenUpper => [Ciram(1); 4],
+      Mirroring::HardwiredFourScreen => [Ciram(0), Ciram(1), CartridgeRam(0), CartridgeRam(1)],
+      Mirroring::Custom(nametables) => *nametables,
     }
   }
 
@@ -50,9 +60,8 @@ impl Vram {
     );
 
     let virtual_index = Self::get_virtual_nametable_index(address);
-    let index = self.mirror_map.borrow()[virtual_index] as usize;
     let offset = address & 0x3ff; // Only lower 9 bits, higher are indexing
-    self.nametables[index][offset as usize]
+    self.read_indexed(virtual_index as u16, offset)
   }
 
   pub fn write(&mut self, val: u8, address: u16) {
@@ -63,14 +72,36 @@ impl Vram {
     );
 
     let virtual_index = Self::get_virtual_nametable_index(address);
-    let index = self.mirror_map.borrow()[virtual_index] as usize;
+    let nametable = self.mirror_map.borrow()[virtual_index];
     let offset = address & 0x3ff; // Only lower 9 bits, higher are indexing
-    self.nametables[index][offset as usize] = val;
+    match nametable {
+      Nametable::Ciram(i) => self.nametables[i as usize & 1][offset as usize] = val,
+      Nametable::ChrRom(_) => (),
+      // Four-screen boards supply the other 2 KB
+      Nametable::CartridgeRam(_) | Nametable::Mapper(_) => self
+        .mapper
+        .borrow_mut()
+        .write_nametable(val, nametable, offset),
+      Nametable::Fill { .. } => (),
+    }
   }
 
   pub fn read_indexed(&self, virtual_index: u16, offset: u16) -> u8 {
-    let index = self.mirror_map.borrow()[virtual_index as usize] as usize;
-    self.nametables[index][offset as usize]
+    let nametable = self.mirror_map.borrow()[virtual_index as usize];
+    match nametable {
+      Nametable::Ciram(i) => self.nametables[i as usize & 1][offset as usize],
+      Nametable::CartridgeRam(_) | Nametable::ChrRom(_) | Nametable::Mapper(_) => {
+        self.mapper.borrow().read_nametable(nametable, offset)
+      }
+      Nametable::Fill { tile, attribute } => {
+        if offset < 0x3c0 {
+          tile
+        } else {
+          // Same palette for all four quadrants
+          (attribute & 0x3) * 0x55
+        }
+      }
+    }
   }
 
   fn get_virtual_nametable_index(address: u16) -> usize {
@@ -78,11 +109,124 @@ impl Vram {
     // Start == 0x2000, bit 11 & 10 selects the nametable index.
     ((address >> 10) & 0b11) as usize
   }
+
+  pub fn save_state(&self, w: &mut StateWriter) {
+    for nametable in &self.nametables {
+      w.write_bytes(nametable);
+    }
+
+    for nametable in self.mirror_map.borrow().iter() {
+      match *nametable {
+        Nametable::Ciram(i) => {
+          w.write_u8(0);
+          w.write_u16(i as u16);
+        }
+        Nametable::CartridgeRam(i) => {
+          w.write_u8(1);
+          w.write_u16(i as u16);
+        }
+        Nametable::ChrRom(bank) => {
+          w.write_u8(2);
+          w.write_u16(bank);
+        }
+        Nametable::Mapper(i) => {
+          w.write_u8(3);
+          w.write_u16(i as u16);
+        }
+        Nametable::Fill { tile, attribute } => {
+          w.write_u8(4);
+          w.write_u16(((attribute as u16) << 8) | tile as u16);
+        }
+      }
+    }
+  }
+
+  // The mirror map is shared with the mapper's mirroring callback, so it's restored in place.
+  pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+    for nametable in &mut self.nametables {
+      r.read_bytes(nametable)?;
+    }
+
+    let mut mirror_map = [Nametable::Ciram(0); 4];
+    for nametable in mirror_map.iter_mut() {
+      let kind = r.read_u8()?;
+      let val = r.read_u16()?;
+      *nametable = match kind {
+        0 => Nametable::Ciram(val as u8 & 1),
+        1 => Nametable::CartridgeRam(val as u8),
+        2 => Nametable::ChrRom(val),
+        3 => Nametable::Mapper(val as u8),
+        4 => Nametable::Fill {
+          tile: val as u8,
+          attribute: (val >> 8) as u8,
+        },
+        _ => return Err(SaveStateError::InvalidSaveState("nametable")),
+      };
+    }
+    *self.mirror_map.borrow_mut() = mirror_map;
+    Ok(())
+  }
 }
 
 #[cfg(test)]
 mod tests {
-  use crate::ppu::vram::Vram;
+  use super::*;
+  use mos6502::memory::Bus;
+
+  struct TestMapper {
+    mirroring_cb: Option<crate::mappers::MirroringCallback>,
+    exram: [u8; kilobytes::KB1],
+    four_screen: [[u8; kilobytes::KB1]; 2],
+  }
+
+  impl Mapper for TestMapper {
+    fn on_runtime_mirroring(&mut self, cb: crate::mappers::MirroringCallback) {
+      self.mirroring_cb = Some(cb);
+    }
+
+    fn read_nametable(&self, nametable: Nametable, offset: u16) -> u8 {
+      match nametable {
+        Nametable::ChrRom(bank) => bank as u8,
+        Nametable::Mapper(_) => self.exram[offset as usize],
+        Nametable::CartridgeRam(page) => self.four_screen[page as usize][offset as usize],
+        _ => unreachable!(),
+      }
+    }
+
+    fn write_nametable(&mut self, val: u8, nametable: Nametable, offset: u16) {
+      match nametable {
+        Nametable::CartridgeRam(page) => self.four_screen[page as usize][offset as usize] = val,
+        _ => self.exram[offset as usize] = val,
+      }
+    }
+  }
+
+  impl Bus for TestMapper {
+    fn read8(&self, _: u16) -> u8 {
+      0
+    }
+
+    fn write8(&mut self, _: u8, _: u16) {}
+  }
+
+  fn sut(mirroring: Mirroring) -> (Vram, Rc<RefCell<TestMapper>>) {
+    let mapper = Rc::new(RefCell::new(TestMapper {
+      mirroring_cb: None,
+      exram: [0; kilobytes::KB1],
+      four_screen: [[0; kilobytes::KB1]; 2],
+    }));
+    (Vram::new(mapper.clone(), mirroring), mapper)
+  }
+
+  fn fill_each(vram: &mut Vram) {
+    for (i, base) in [0x2000, 0x2400, 0x2800, 0x2c00].into_iter().enumerate() {
+      vram.write(i as u8 + 1, base + 0x10);
+    }
+  }
+
+  fn read_each(vram: &Vram) -> [u8; 4] {
+    [0x2000, 0x2400, 0x2800, 0x2c00].map(|base| vram.read(base + 0x10))
+  }
 
   #[test]
   fn get_virtual_nametable_index() {
@@ -92,4 +236,44 @@ mod tests {
     assert_eq!(Vram::get_virtual_nametable_index(0x2c00), 3);
     assert_eq!(Vram::get_virtual_nametable_index(0x24ff), 1);
   }
+
+  #[test]
+  fn horizontal_and_vertical() {
+    let (mut vram, _) = sut(Mirroring::Horizontal);
+    fill_each(&mut vram);
+    assert_eq!(read_each(&vram), [2, 2, 4, 4]);
+
+    let (mut vram, _) = sut(Mirroring::Vertical);
+    fill_each(&mut vram);
+    assert_eq!(read_each(&vram), [3, 4, 3, 4]);
+  }
+
+  #[test]
+  fn four_screen() {
+    let (mut vram, mapper) = sut(Mirroring::HardwiredFourScreen);
+    fill_each(&mut vram);
+    assert_eq!(read_each(&vram), [1, 2, 3, 4]);
+    // $2800 and $2C00 live on the cartridge
+    assert_eq!(mapper.borrow().four_screen[1][0x10], 4);
+  }
+
+  #[test]
+  fn mapper_controlled() {
+    let (mut vram, mapper) = sut(Mirroring::Horizontal);
+    let layout = Mirroring::Custom([
+      Nametable::Ciram(1),
+      Nametable::Mapper(0),
+      Nametable::ChrRom(0x42),
+      Nametable::Fill {
+        tile: 0x24,
+        attribute: 2,
+      },
+    ]);
+    (mapper.borrow_mut().mirroring_cb.as_mut().unwrap())(&layout);
+
+    fill_each(&mut vram);
+    assert_eq!(read_each(&vram), [1, 2, 0x42, 0x24]);
+    assert_eq!(mapper.borrow().exram[0x10], 2);
+    assert_eq!(vram.read(0x2c00 + 0x3c0), 0xaa);
+  }
 }
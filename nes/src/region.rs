@@ -0,0 +1,82 @@
+use core::str::FromStr;
+
+use crate::cartridge::Timing;
+
+// Which console the game runs on, decides CPU/PPU clocks and frame layout.
+// https://www.nesdev.org/wiki/Cycle_reference_chart
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Region {
+  #[default]
+  Ntsc,
+  Pal,
+  // Famiclones with a PAL frame rate but NTSC-like CPU timing
+  Dendy,
+}
+
+impl Region {
+  pub fn cpu_clock(&self) -> usize {
+    match self {
+      Region::Ntsc => 1_789_773,
+      Region::Pal => 1_662_607,
+      Region::Dendy => 1_773_448,
+    }
+  }
+
+  pub fn frame_rate(&self) -> usize {
+    match self {
+      Region::Ntsc => 60,
+      Region::Pal | Region::Dendy => 50,
+    }
+  }
+
+  // Including vblank and the pre-render line
+  pub(crate) fn scanlines(&self) -> usize {
+    match self {
+      Region::Ntsc => 262,
+      Region::Pal | Region::Dendy => 312,
+    }
+  }
+
+  // Dendy waits 51 lines after rendering before vblank, so NMI lands
+  // about where NTSC games expect it relative to the end of the frame.
+  pub(crate) fn vblank_scanline(&self) -> usize {
+    match self {
+      Region::Ntsc | Region::Pal => 241,
+      Region::Dendy => 291,
+    }
+  }
+
+  // Only the NTSC PPU skips a dot on odd frames
+  pub(crate) fn skips_odd_frame_dot(&self) -> bool {
+    *self == Region::Ntsc
+  }
+
+  // In fifths, PAL runs 3.2 PPU dots per CPU cycle
+  pub(crate) fn ppu_fifth_dots_per_cpu_cycle(&self) -> usize {
+    match self {
+      Region::Ntsc | Region::Dendy => 15,
+      Region::Pal => 16,
+    }
+  }
+}
+
+impl From<Timing> for Region {
+  fn from(timing: Timing) -> Self {
+    match timing {
+      Timing::Ntsc | Timing::MultiRegion => Region::Ntsc,
+      Timing::Pal => Region::Pal,
+      Timing::Dendy => Region::Dendy,
+    }
+  }
+}
+
+impl FromStr for Region {
+  type Err = &'static str;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    [Region::Ntsc, Region::Pal, Region::Dendy]
+      .into_iter()
+      .find(|region| s.eq_ignore_ascii_case(&format!("{:?}", region)))
+      .ok_or("expected ntsc, pal or dendy")
+  }
+}
@@ -0,0 +1,223 @@
+use alloc::vec::Vec;
+
+use self::error::SaveStateError;
+
+const MAGIC: [u8; 4] = *b"PTSS";
+// Bump whenever anything written by a save_state changes.
+pub const VERSION: u16 = 9;
+// Magic, version, ROM hash, payload length, payload hash
+const HEADER_SIZE: usize = 4 + 2 + 4 + 4 + 4;
+
+pub mod error {
+  #[derive(Debug, PartialEq, Eq)]
+  pub enum SaveStateError {
+    InvalidSaveState(&'static str),
+    UnsupportedVersion(u16),
+    RomMismatch { expected: u32, found: u32 },
+  }
+
+  #[cfg(feature = "std")]
+  impl std::error::Error for SaveStateError {}
+
+  impl core::fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+      write!(f, "{:?}", self)
+    }
+  }
+}
+
+pub struct StateWriter {
+  buf: Vec<u8>,
+}
+
+impl StateWriter {
+  pub fn new() -> Self {
+    Self { buf: Vec::new() }
+  }
+
+  pub fn write_u8(&mut self, val: u8) {
+    self.buf.push(val);
+  }
+
+  pub fn write_bool(&mut self, val: bool) {
+    self.buf.push(val as u8);
+  }
+
+  pub fn write_u16(&mut self, val: u16) {
+    self.buf.extend_from_slice(&val.to_le_bytes());
+  }
+
+  pub fn write_u32(&mut self, val: u32) {
+    self.buf.extend_from_slice(&val.to_le_bytes());
+  }
+
+  pub fn write_usize(&mut self, val: usize) {
+    // Always 64 bits, states should move between hosts
+    self.buf.extend_from_slice(&(val as u64).to_le_bytes());
+  }
+
+  pub fn write_bytes(&mut self, bytes: &[u8]) {
+    self.buf.extend_from_slice(bytes);
+  }
+
+  // Wraps the payload in a header, see StateReader::open
+  pub fn finish(self, rom_hash: u32) -> Vec<u8> {
+    let mut state = Vec::with_capacity(HEADER_SIZE + self.buf.len());
+    state.extend_from_slice(&MAGIC);
+    state.extend_from_slice(&VERSION.to_le_bytes());
+    state.extend_from_slice(&rom_hash.to_le_bytes());
+    state.extend_from_slice(&(self.buf.len() as u32).to_le_bytes());
+    state.extend_from_slice(&common::hash::crc32(&self.buf).to_le_bytes());
+    state.extend_from_slice(&self.buf);
+    state
+  }
+}
+
+pub struct StateReader<'a> {
+  buf: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+  // Checks the header, ROM and checksum. The payload itself is only validated as it's
+  // read, see Nes::load_state for what happens to a state that turns out bad.
+  pub fn open(state: &'a [u8], rom_hash: u32) -> Result<Self, SaveStateError> {
+    if state.len() < HEADER_SIZE || state[0..4] != MAGIC {
+      return Err(SaveStateError::InvalidSaveState("magic"));
+    }
+
+    let mut header = Self {
+      buf: &state[4..HEADER_SIZE],
+    };
+    let version = header.read_u16()?;
+    if version != VERSION {
+      return Err(SaveStateError::UnsupportedVersion(version));
+    }
+
+    let found = header.read_u32()?;
+    if found != rom_hash {
+      return Err(SaveStateError::RomMismatch {
+        expected: rom_hash,
+        found,
+      });
+    }
+
+    let len = header.read_u32()? as usize;
+    let payload = &state[HEADER_SIZE..];
+    if payload.len() != len {
+      return Err(SaveStateError::InvalidSaveState("length"));
+    }
+
+    if header.read_u32()? != common::hash::crc32(payload) {
+      return Err(SaveStateError::InvalidSaveState("checksum"));
+    }
+
+    Ok(Self { buf: payload })
+  }
+
+  fn take(&mut self, n: usize) -> Result<&'a [u8], SaveStateError> {
+    if self.buf.len() < n {
+      return Err(SaveStateError::InvalidSaveState("truncated"));
+    }
+    let (head, tail) = self.buf.split_at(n);
+    self.buf = tail;
+    Ok(head)
+  }
+
+  pub fn read_u8(&mut self) -> Result<u8, SaveStateError> {
+    Ok(self.take(1)?[0])
+  }
+
+  pub fn read_bool(&mut self) -> Result<bool, SaveStateError> {
+    Ok(self.read_u8()? != 0)
+  }
+
+  pub fn read_u16(&mut self) -> Result<u16, SaveStateError> {
+    Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+  }
+
+  pub fn read_u32(&mut self) -> Result<u32, SaveStateError> {
+    Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+  }
+
+  pub fn read_usize(&mut self) -> Result<usize, SaveStateError> {
+    Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()) as usize)
+  }
+
+  pub fn read_bytes(&mut self, dst: &mut [u8]) -> Result<(), SaveStateError> {
+    dst.copy_from_slice(self.take(dst.len())?);
+    Ok(())
+  }
+
+  pub fn finish(self) -> Result<(), SaveStateError> {
+    if self.buf.is_empty() {
+      Ok(())
+    } else {
+      Err(SaveStateError::InvalidSaveState("trailing bytes"))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sut() -> Vec<u8> {
+    let mut w = StateWriter::new();
+    w.write_u8(0xaa);
+    w.write_bool(true);
+    w.write_u16(0xbeef);
+    w.write_usize(123456789);
+    w.write_bytes(&[1, 2, 3]);
+    w.finish(0x1234)
+  }
+
+  #[test]
+  fn roundtrip() {
+    let state = sut();
+    let mut r = StateReader::open(&state, 0x1234).unwrap();
+    assert_eq!(r.read_u8(), Ok(0xaa));
+    assert_eq!(r.read_bool(), Ok(true));
+    assert_eq!(r.read_u16(), Ok(0xbeef));
+    assert_eq!(r.read_usize(), Ok(123456789));
+    let mut bytes = [0; 3];
+    r.read_bytes(&mut bytes).unwrap();
+    assert_eq!(bytes, [1, 2, 3]);
+    assert_eq!(
+      r.read_u8(),
+      Err(SaveStateError::InvalidSaveState("truncated"))
+    );
+  }
+
+  #[test]
+  fn rejects_other_rom() {
+    assert_eq!(
+      StateReader::open(&sut(), 0x4321).err(),
+      Some(SaveStateError::RomMismatch {
+        expected: 0x4321,
+        found: 0x1234
+      })
+    );
+  }
+
+  #[test]
+  fn rejects_corrupt() {
+    let mut state = sut();
+    *state.last_mut().unwrap() ^= 0xff;
+    assert_eq!(
+      StateReader::open(&state, 0x1234).err(),
+      Some(SaveStateError::InvalidSaveState("checksum"))
+    );
+
+    state.pop();
+    assert_eq!(
+      StateReader::open(&state, 0x1234).err(),
+      Some(SaveStateError::InvalidSaveState("length"))
+    );
+
+    state[4] = 0xff;
+    assert_eq!(
+      StateReader::open(&state, 0x1234).err(),
+      Some(SaveStateError::UnsupportedVersion(0x00ff))
+    );
+  }
+}
@@ -0,0 +1,63 @@
+use nes::audio::AudioChannels;
+use nes::audio::AudioSpec;
+use nes::nes::HeadlessAudioHost;
+use nes::nes::Nes;
+
+mod common;
+
+#[rustfmt::skip]
+const PULSE_440HZ: [u8; 23] = [
+  0xa9, 0x01, 0x8d, 0x15, 0x40, // LDA #$01, STA $4015 (enable pulse 1)
+  0xa9, 0xbf, 0x8d, 0x00, 0x40, // LDA #$bf, STA $4000 (50% duty, halt, constant volume 15)
+  0xa9, 0xfd, 0x8d, 0x02, 0x40, // LDA #$fd, STA $4002
+  0xa9, 0x00, 0x8d, 0x03, 0x40, // LDA #$00, STA $4003
+  0x4c, 0x14, 0x80,             // JMP $8014
+];
+
+fn run(program: &[u8], spec: AudioSpec, ticks: usize) -> Vec<f32> {
+  let host = HeadlessAudioHost::new(spec);
+  let samples = host.samples();
+  let mut nes = Nes::insert(common::nrom(program), host);
+  for _ in 0..ticks {
+    nes.tick();
+  }
+  let samples = samples.borrow().clone();
+  samples
+}
+
+#[test]
+fn pulse_wave_is_played() {
+  let spec = AudioSpec::default();
+  let samples = run(&PULSE_440HZ, spec, 100_000);
+
+  assert!(!samples.is_empty());
+  assert_eq!(samples.len() % spec.buffer_size, 0);
+  assert!(samples.iter().all(|s| (-1.0..=1.0).contains(s)));
+
+  // Square wave, ~100 samples per period at 44.1kHz
+  let last_buffer = &samples[samples.len() - spec.buffer_size..];
+  let min = last_buffer.iter().cloned().fold(f32::MAX, f32::min);
+  let max = last_buffer.iter().cloned().fold(f32::MIN, f32::max);
+  assert!(max - min > 0.1, "min: {min} max: {max}");
+}
+
+#[test]
+fn silence_without_writes() {
+  let samples = run(&[0x4c, 0x00, 0x80], AudioSpec::default(), 100_000);
+  assert!(!samples.is_empty());
+  // Channels idle at a constant level (the triangle holds its last step)
+  assert!(samples.iter().all(|&s| s == samples[0]));
+}
+
+#[test]
+fn stereo_is_interleaved() {
+  let spec = AudioSpec {
+    sample_rate: 22_050,
+    buffer_size: 256,
+    channels: AudioChannels::Stereo,
+  };
+  let samples = run(&PULSE_440HZ, spec, 100_000);
+
+  assert_eq!(samples.len() % (spec.buffer_size * 2), 0);
+  assert!(samples.chunks(2).all(|lr| lr[0] == lr[1]));
+}
@@ -0,0 +1,85 @@
+use nes::cartridge::Cartridge;
+use nes::nes::Nes;
+
+mod common;
+
+const MMC1: u8 = 1;
+const BATTERY: u8 = 0b10;
+
+#[rustfmt::skip]
+const WRITE_6000: [u8; 8] = [
+  0xee, 0x00, 0x60, // INC $6000
+  0xa9, 0x00,       // LDA #$00 (spin)
+  0x4c, 0x03, 0x80, // JMP $8003
+];
+
+#[test]
+fn battery_flag() {
+  assert!(common::rom(MMC1, BATTERY, &WRITE_6000).has_battery());
+  assert!(!common::rom(MMC1, 0, &WRITE_6000).has_battery());
+}
+
+#[test]
+fn export_prg_ram() {
+  let mut nes = Nes::insert_headless_host(common::rom(MMC1, BATTERY, &WRITE_6000));
+  assert!(nes.has_battery());
+  assert_eq!(nes.export_prg_ram(), vec![0; 8 * 1024]);
+
+  for _ in 0..10 {
+    nes.tick();
+  }
+  assert_eq!(nes.export_prg_ram()[0], 1);
+}
+
+#[test]
+fn import_prg_ram() {
+  let mut ram = vec![0; 8 * 1024];
+  ram[0] = 0x41;
+  ram[0x1fff] = 0xff;
+
+  let mut cart = common::rom(MMC1, BATTERY, &WRITE_6000);
+  cart.import_prg_ram(&ram).unwrap();
+  let mut nes = Nes::insert_headless_host(cart);
+  for _ in 0..10 {
+    nes.tick();
+  }
+
+  let exported = nes.export_prg_ram();
+  assert_eq!(exported[0], 0x42);
+  assert_eq!(exported[0x1fff], 0xff);
+
+  // Same thing on a running machine
+  nes.import_prg_ram(&ram).unwrap();
+  assert_eq!(nes.export_prg_ram(), ram);
+  assert!(nes.import_prg_ram(&ram[1..]).is_err());
+}
+
+#[test]
+fn n163_internal_ram_round_trip() {
+  const N163: u8 = 19;
+  #[rustfmt::skip]
+  let program = [
+    0xa9, 0x00,       // LDA #$00
+    0x8d, 0x00, 0xf8, // STA $F800, internal RAM address 0
+    0xa9, 0x42,       // LDA #$42
+    0x8d, 0x00, 0x48, // STA $4800
+    0x4c, 0x0a, 0x80, // JMP $800A (spin)
+  ];
+  let mut rom = common::ines(N163, BATTERY, None, &program);
+  rom[7] |= 0x08; // NES 2.0
+  rom[10] = 0x10; // 128 B of PRG NVRAM, the chip's internal RAM
+  let cart = || Cartridge::blow_dust_vec(rom.clone()).unwrap();
+
+  let mut nes = Nes::insert_headless_host(cart());
+  for _ in 0..10 {
+    nes.tick();
+  }
+  let saved = nes.export_prg_ram();
+  assert_eq!(saved.len(), 128);
+  assert_eq!(saved[0], 0x42);
+
+  // Next launch, the host loads the .sav back through the mapper
+  let mut nes = Nes::insert_headless_host(cart());
+  nes.import_prg_ram(&saved).unwrap();
+  assert_eq!(nes.export_prg_ram(), saved);
+}
@@ -38,6 +38,59 @@ fn instr_misc() {
   run_blargg_test("instr_misc/instr_misc.nes", PassCond::Status(success, 1));
 }
 
+#[test]
+fn instr_misc_dummy_reads_cycle_stepped() {
+  run_blargg_test_cycle_stepped(
+    "instr_misc/rom_singles/03-dummy_reads.nes",
+    PassCond::Status("03-dummy_reads\n\nPassed", STATUS_SUCCESS),
+  );
+}
+
+// Interrupt timing is only exact cycle-stepped, instruction-stepped polls once
+// per instruction
+#[test]
+fn cpu_interrupts_v2_cli_latency() {
+  run_blargg_test_cycle_stepped(
+    "cpu_interrupts_v2/rom_singles/1-cli_latency.nes",
+    PassCond::Status("1-cli_latency\n\nPassed", STATUS_SUCCESS),
+  );
+}
+
+#[test]
+fn cpu_interrupts_v2_nmi_and_brk() {
+  run_blargg_test_cycle_stepped(
+    "cpu_interrupts_v2/rom_singles/2-nmi_and_brk.nes",
+    PassCond::Status("2-nmi_and_brk\n\nPassed", STATUS_SUCCESS),
+  );
+}
+
+#[test]
+fn cpu_interrupts_v2_nmi_and_irq() {
+  run_blargg_test_cycle_stepped(
+    "cpu_interrupts_v2/rom_singles/3-nmi_and_irq.nes",
+    PassCond::Status("3-nmi_and_irq\n\nPassed", STATUS_SUCCESS),
+  );
+}
+
+// The DMA halts the CPU right after the $4014 write instead of on its next read,
+// and IRQs aren't polled during the halt
+#[ignore = "not implemented"]
+#[test]
+fn cpu_interrupts_v2_irq_and_dma() {
+  run_blargg_test_cycle_stepped(
+    "cpu_interrupts_v2/rom_singles/4-irq_and_dma.nes",
+    PassCond::Status("4-irq_and_dma\n\nPassed", STATUS_SUCCESS),
+  );
+}
+
+#[test]
+fn cpu_interrupts_v2_branch_delays_irq() {
+  run_blargg_test_cycle_stepped(
+    "cpu_interrupts_v2/rom_singles/5-branch_delays_irq.nes",
+    PassCond::Status("5-branch_delays_irq\n\nPassed", STATUS_SUCCESS),
+  );
+}
+
 #[ignore = "bad test"]
 #[test]
 fn ppu_vbl_nmi() {
@@ -49,6 +102,21 @@ fn ppu_vbl_nmi() {
   );
 }
 
+// Instruction-stepped, $2002 reads see the PPU where it was before the
+// instruction, too early to time the VBL period
+#[test]
+fn ppu_vbl_nmi_cycle_stepped() {
+  run_blargg_test_cycle_stepped(
+    "ppu_vbl_nmi/rom_singles/01-vbl_basics.nes",
+    PassCond::Status(
+      "01-vbl_basics
+
+Passed",
+      STATUS_SUCCESS,
+    ),
+  );
+}
+
 #[ignore = "not implemented"]
 #[test]
 fn ppu_open_bus() {
@@ -113,8 +181,17 @@ fn ppu_read_buffer() {
 }
 
 fn run_blargg_test(test: &str, pass_condition: PassCond) {
+  run(test, pass_condition, false)
+}
+
+fn run_blargg_test_cycle_stepped(test: &str, pass_condition: PassCond) {
+  run(test, pass_condition, true)
+}
+
+fn run(test: &str, pass_condition: PassCond, cycle_stepped: bool) {
   let path = format!("../test-roms/nes-test-roms/{}", test);
   let mut nes = common::setup(path.into(), std::env::var("VERBOSE").is_ok());
+  nes.cycle_stepped(cycle_stepped);
 
   nes.debugger().verbose(true);
 
@@ -1,6 +1,9 @@
+#![allow(dead_code)]
+
 use std::path::PathBuf;
 
 use nes::cartridge::Cartridge;
+use nes::cartridge::HeapRom;
 use nes::nes::Nes;
 
 pub fn setup(path: PathBuf, verbose: bool) -> Nes {
@@ -9,3 +12,37 @@ pub fn setup(path: PathBuf, verbose: bool) -> Nes {
   nes.debugger().verbose(verbose);
   nes
 }
+
+// NROM, 16KB PRG, 8KB CHR ROM, program at $8000
+pub fn nrom(program: &[u8]) -> Cartridge<HeapRom> {
+  rom(0, 0, program)
+}
+
+// iNES, 16KB PRG, 8KB CHR ROM, program at $8000 (and $c000)
+pub fn rom(mapper: u8, flags6: u8, program: &[u8]) -> Cartridge<HeapRom> {
+  Cartridge::blow_dust_vec(ines(mapper, flags6, None, program)).unwrap()
+}
+
+pub fn rom_with_trainer(mapper: u8, trainer: &[u8], program: &[u8]) -> Cartridge<HeapRom> {
+  Cartridge::blow_dust_vec(ines(mapper, 0b100, Some(trainer), program)).unwrap()
+}
+
+pub fn ines(mapper: u8, flags6: u8, trainer: Option<&[u8]>, program: &[u8]) -> Vec<u8> {
+  let flags6 = (mapper << 4) | (flags6 & 0x0f);
+  let flags7 = mapper & 0xf0;
+  let mut rom = vec![
+    0x4e, 0x45, 0x53, 0x1a, 1, 1, flags6, flags7, 0, 0, 0, 0, 0, 0, 0, 0,
+  ];
+  if let Some(trainer) = trainer {
+    let mut padded = vec![0; 512];
+    padded[..trainer.len()].copy_from_slice(trainer);
+    rom.extend(padded);
+  }
+  let mut prg = vec![0xea; 16 * 1024];
+  prg[..program.len()].copy_from_slice(program);
+  prg[0x3ffc] = 0x00; // Reset vector -> $8000
+  prg[0x3ffd] = 0x80;
+  rom.extend(prg);
+  rom.extend(vec![0; 8 * 1024]);
+  rom
+}
@@ -0,0 +1,90 @@
+use mos6502::memory::Bus;
+use nes::cartridge::Cartridge;
+use nes::nes::Nes;
+
+mod common;
+
+const IRQ_HANDLER: u16 = 0x8100;
+
+// Arms the mapper's IRQ with I set and waits long enough for it to fire, then
+// marks $0200 and clears I. The handler copies the mark to $0201, so a handler
+// that ran before CLI leaves 0 there, and one that never ran too.
+fn taken_after_cli(mapper: u8, arm: &[u8], acknowledge: &[u8]) -> u8 {
+  #[rustfmt::skip]
+  let mut program = vec![
+    0x78,             // SEI
+    0xa9, 0x40,       // LDA #$40
+    0x8d, 0x17, 0x40, // STA $4017, no APU frame IRQ
+  ];
+  program.extend(arm);
+  #[rustfmt::skip]
+  program.extend([
+    0xa2, 0x00,       // LDX #0
+    0xca,             // DEX
+    0xd0, 0xfd,       // BNE -3, ~1280 cycles
+    0xa9, 0x01,       // LDA #1
+    0x8d, 0x00, 0x02, // STA $0200
+    0x58,             // CLI
+    0xea,             // NOP
+  ]);
+  let spin = 0x8000 + program.len() as u16;
+  program.extend([0x4c, spin as u8, (spin >> 8) as u8]); // JMP spin
+
+  program.resize((IRQ_HANDLER - 0x8000) as usize, 0xea);
+  #[rustfmt::skip]
+  program.extend([
+    0xad, 0x00, 0x02, // LDA $0200
+    0x8d, 0x01, 0x02, // STA $0201
+  ]);
+  program.extend(acknowledge);
+  program.push(0x40); // RTI
+
+  let mut rom = common::ines(mapper, 0, None, &program);
+  // IRQ vector, $FFFE in the last 8K bank
+  rom[16 + 0x3ffe] = IRQ_HANDLER as u8;
+  rom[16 + 0x3fff] = (IRQ_HANDLER >> 8) as u8;
+
+  let mut nes = Nes::insert_headless_host(Cartridge::blow_dust_vec(rom).unwrap());
+  while nes.cpu().pc != spin {
+    nes.tick();
+  }
+  for _ in 0..100 {
+    nes.tick();
+  }
+  nes.bus().read8(0x0201)
+}
+
+#[test]
+fn vrc_irq_held_through_sei() {
+  #[rustfmt::skip]
+  let arm = [
+    0xa9, 0xfd,       // LDA #$FD
+    0x8d, 0x00, 0xf0, // STA $F000, latch
+    0xa9, 0x07,       // LDA #%111
+    0x8d, 0x01, 0xf0, // STA $F001, cycle mode, enabled
+  ];
+  let acknowledge = [0x8d, 0x02, 0xf0]; // STA $F002
+  assert_eq!(taken_after_cli(24, &arm, &acknowledge), 1);
+}
+
+#[test]
+fn fme7_irq_held_through_sei() {
+  #[rustfmt::skip]
+  let arm = [
+    0xa9, 0x0e,       // LDA #$E
+    0x8d, 0x00, 0x80, // STA $8000, counter low
+    0xa9, 0x00,       // LDA #0
+    0x8d, 0x00, 0xa0, // STA $A000
+    0xa9, 0x0f,       // LDA #$F
+    0x8d, 0x00, 0x80, // STA $8000, counter high
+    0xa9, 0x01,       // LDA #1
+    0x8d, 0x00, 0xa0, // STA $A000, 256 cycles
+    0xa9, 0x0d,       // LDA #$D
+    0x8d, 0x00, 0x80, // STA $8000, IRQ control
+    0xa9, 0x81,       // LDA #$81
+    0x8d, 0x00, 0xa0, // STA $A000, counting, IRQ enabled
+  ];
+  // Command is still $D, and any value acknowledges
+  let acknowledge = [0x8d, 0x00, 0xa0]; // STA $A000
+  assert_eq!(taken_after_cli(69, &arm, &acknowledge), 1);
+}
@@ -24,7 +24,18 @@ const ENABLE_TEST_CYCLES: bool = false;
 
 #[test]
 fn nestest() {
+  run_nestest(false);
+}
+
+// Same cycles when every bus access is its own
+#[test]
+fn nestest_cycle_stepped() {
+  run_nestest(true);
+}
+
+fn run_nestest(cycle_stepped: bool) {
   let mut nes = common::setup("../test-roms/nestest/nestest.nes".into(), false);
+  nes.cycle_stepped(cycle_stepped);
 
   let logf =
     File::open("../test-roms/nestest/nestest_cycles.log").expect("failed to read test log");
@@ -59,6 +70,17 @@ fn nestest() {
     let mut sts = String::new();
     write!(&mut sts, "{:?}", nes).unwrap();
 
+    // Every instruction's cycle count, illegal ones like ISC (zp),Y included.
+    // The log counts the 7 reset cycles too.
+    let expected_cycles: usize = log[i].rsplit("CYC:").next().unwrap().parse().unwrap();
+    assert_eq!(
+      nes.cpu_cycles() + 7,
+      expected_cycles,
+      "cycle count before line {}: {}",
+      i + 1,
+      log[i]
+    );
+
     nes.tick();
     if log[i] != sts && ENABLE_TEST_CYCLES {
       // nes.dump_backtrace();
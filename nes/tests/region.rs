@@ -0,0 +1,47 @@
+use nes::cartridge::Cartridge;
+use nes::mos6502::memory::Bus;
+use nes::nes::Nes;
+use nes::region::Region;
+
+mod common;
+
+#[rustfmt::skip]
+const COUNT_NMIS: [u8; 11] = [
+  0xa9, 0x80,       // LDA #$80
+  0x8d, 0x00, 0x20, // STA $2000 (NMI on vblank)
+  0x4c, 0x05, 0x80, // JMP $8005 (spin)
+  0xe6, 0x00,       // NMI: INC $00
+  0x40,             // RTI
+];
+
+// Frames in one second of emulated time
+fn frames_per_second(region: Region) -> u8 {
+  let mut rom = common::ines(0, 0, None, &COUNT_NMIS);
+  rom[16 + 0x3ffa] = 0x08; // NMI vector -> $8008
+  rom[16 + 0x3ffb] = 0x80;
+  let mut cart = Cartridge::blow_dust_vec(rom).unwrap();
+  cart.set_region(region);
+
+  let mut nes = Nes::insert_headless_host(cart);
+  assert_eq!(nes.region(), region);
+  while nes.cpu_cycles() < region.cpu_clock() {
+    nes.tick();
+  }
+  nes.bus().read8(0x0000)
+}
+
+#[test]
+fn frame_rates() {
+  assert_eq!(frames_per_second(Region::Ntsc), 60);
+  assert_eq!(frames_per_second(Region::Pal), 50);
+  assert_eq!(frames_per_second(Region::Dendy), 50);
+}
+
+#[test]
+fn rejects_state_from_other_region() {
+  let ntsc = Nes::insert_headless_host(common::nrom(&COUNT_NMIS));
+  let mut cart = common::nrom(&COUNT_NMIS);
+  cart.set_region(Region::Pal);
+  let mut pal = Nes::insert_headless_host(cart);
+  assert!(pal.load_state(&ntsc.save_state()).is_err());
+}
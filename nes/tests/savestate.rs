@@ -0,0 +1,86 @@
+use nes::nes::Nes;
+use nes::nes::SaveStateError;
+
+mod common;
+
+fn nestest() -> Nes {
+  common::setup("../test-roms/nestest/nestest.nes".into(), false)
+}
+
+fn trace(nes: &mut Nes, ticks: usize) -> Vec<String> {
+  (0..ticks)
+    .map(|_| {
+      nes.tick();
+      format!("{:?}", nes)
+    })
+    .collect()
+}
+
+#[test]
+fn restore_replays_identically() {
+  let mut nes = nestest();
+  trace(&mut nes, 100_000);
+
+  let state = nes.save_state();
+  let expected = trace(&mut nes, 50_000);
+  let expected_state = nes.save_state();
+
+  let mut restored = nestest();
+  restored.load_state(&state).unwrap();
+  assert_eq!(trace(&mut restored, 50_000), expected);
+  assert_eq!(restored.save_state(), expected_state);
+
+  // Rewinding the same machine works too
+  nes.load_state(&state).unwrap();
+  assert_eq!(trace(&mut nes, 50_000), expected);
+}
+
+#[test]
+fn rejects_state_from_other_rom() {
+  let nes = nestest();
+  let mut other = Nes::insert_headless_host(common::nrom(&[0x4c, 0x00, 0x80]));
+
+  let before = other.save_state();
+  assert!(matches!(
+    other.load_state(&nes.save_state()),
+    Err(SaveStateError::RomMismatch { .. })
+  ));
+  assert_eq!(other.save_state(), before);
+}
+
+#[test]
+fn rejects_garbage() {
+  let mut nes = nestest();
+  assert!(nes.load_state(&[]).is_err());
+  assert!(nes.load_state(b"PTSS but not really").is_err());
+
+  let mut state = nes.save_state();
+  state.truncate(state.len() - 1);
+  assert!(nes.load_state(&state).is_err());
+}
+
+// Fixes up the header's length and checksum after the payload was tampered with
+fn reseal(state: &mut [u8]) {
+  let payload_len = (state.len() - 18) as u32;
+  let crc = ::common::hash::crc32(&state[18..]);
+  state[10..14].copy_from_slice(&payload_len.to_le_bytes());
+  state[14..18].copy_from_slice(&crc.to_le_bytes());
+}
+
+#[test]
+fn rejected_state_leaves_machine_unchanged() {
+  let mut nes = nestest();
+  trace(&mut nes, 10_000);
+  let mut state = nes.save_state();
+  trace(&mut nes, 10_000);
+  let before = nes.save_state();
+
+  // Passes the header checks, fails at the very end, after the CPU and RAM were read
+  state.pop();
+  reseal(&mut state);
+  assert_eq!(
+    nes.load_state(&state),
+    Err(SaveStateError::InvalidSaveState("truncated"))
+  );
+  assert_eq!(nes.save_state(), before);
+}
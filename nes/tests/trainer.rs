@@ -0,0 +1,36 @@
+use mos6502::memory::Bus;
+use nes::nes::Nes;
+
+mod common;
+
+#[rustfmt::skip]
+const TRAINER: [u8; 8] = [
+  0xa9, 0x42,       // LDA #$42
+  0x8d, 0x00, 0x02, // STA $0200
+  0x4c, 0x05, 0x70, // JMP $7005
+];
+
+const JMP_TRAINER: [u8; 3] = [0x4c, 0x00, 0x70]; // JMP $7000
+
+#[test]
+fn trainer_is_executed() {
+  for mapper in [0, 1, 2, 3, 4] {
+    let cart = common::rom_with_trainer(mapper, &TRAINER, &JMP_TRAINER);
+    let mut nes = Nes::insert_headless_host(cart);
+    for _ in 0..10 {
+      nes.tick();
+    }
+
+    assert_eq!(nes.cpu().pc, 0x7005, "mapper {mapper}");
+    assert_eq!(nes.bus().read8(0x0200), 0x42, "mapper {mapper}");
+  }
+}
+
+#[test]
+fn trainer_visible_at_7000() {
+  let nes = Nes::insert_headless_host(common::rom_with_trainer(0, &TRAINER, &JMP_TRAINER));
+  assert_eq!(nes.bus().read8(0x6fff), 0x00);
+  assert_eq!(nes.bus().read8(0x7000), 0xa9);
+  assert_eq!(nes.bus().read8(0x7007), 0x70);
+  assert_eq!(nes.bus().read8(0x7200), 0x00);
+}